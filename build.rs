@@ -0,0 +1,48 @@
+//! Embeds build metadata for `firstaide --version --format json`.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    // The commit from which we're building, if we're building from a git
+    // checkout. Builds from a source tarball, e.g. from Nix, have no commit.
+    let git_commit = git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=FIRSTAIDE_GIT_COMMIT={}", git_commit);
+    if Path::new(".git/HEAD").is_file() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed=.git/{}", head_ref);
+        }
+    }
+
+    // Honour SOURCE_DATE_EPOCH for reproducible builds.
+    let build_date = match env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(epoch) => chrono::NaiveDateTime::from_timestamp(epoch, 0).format("%Y-%m-%d"),
+        None => chrono::Utc::now().naive_utc().format("%Y-%m-%d"),
+    };
+    println!("cargo:rustc-env=FIRSTAIDE_BUILD_DATE={}", build_date);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Cargo tells us about enabled features via CARGO_FEATURE_* variables.
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FIRSTAIDE_FEATURES={}", features.join(","));
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().into())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,227 @@
+#[macro_use]
+extern crate clap;
+
+use firstaide::{cmds, error, logging, term, timings, version};
+use std::process;
+
+fn main() {
+    // A note on logging. There's --verbose (-v) and --quiet (-q), which cannot
+    // be used together, and each of which can be repeated to go further. For
+    // finer control, e.g. to debug just one module, there's FIRSTAIDE_LOG;
+    // see `logging::FILTER_VAR`.
+
+    let app = clap::App::new("firstaide")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("First, as in prior to, aide.")
+        .arg(
+            clap::Arg::with_name("version")
+                .short("V")
+                .long("version")
+                .help("Prints version information"),
+        )
+        .arg(
+            clap::Arg::with_name("version-format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .requires("version")
+                .help("The format for --version; json includes build metadata"),
+        )
+        .arg(
+            clap::Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .global(true)
+                .multiple(true)
+                .help("Be more verbose; repeat for more still")
+                .conflicts_with("quiet"),
+        )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .global(true)
+                .multiple(true)
+                .help("Be quieter; repeat for quieter still")
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            clap::Arg::with_name("timings")
+                .long("timings")
+                .global(true)
+                .help("Report how long each step took, on stderr, at the end"),
+        )
+        .arg(
+            clap::Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .global(true)
+                .possible_values(&["text", "json"])
+                .help("The format for log messages; json gives one object per line"),
+        )
+        .arg(
+            clap::Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .global(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["auto", "always", "never"])
+                .help("Color output: auto, the default, always, or never; --color alone is always"),
+        )
+        .subcommand(cmds::build::argspec())
+        .subcommand(cmds::status::argspec())
+        .subcommand(cmds::clean::argspec())
+        .subcommand(cmds::hook::argspec())
+        .subcommand(cmds::shell::argspec())
+        .subcommand(cmds::diff::argspec())
+        .subcommand(cmds::sums::argspec())
+        .subcommand(cmds::export::argspec())
+        .subcommand(cmds::devcontainer::argspec())
+        .subcommand(cmds::apply::argspec())
+        .subcommand(cmds::cache::argspec())
+        .subcommand(cmds::ci::argspec())
+        .subcommand(cmds::login::argspec())
+        .subcommand(cmds::config::argspec())
+        .subcommand(cmds::watch::argspec())
+        .subcommand(cmds::daemon::argspec())
+        .subcommand(cmds::serve::argspec())
+        .subcommand(cmds::audit_log::argspec())
+        .subcommand(cmds::history::argspec())
+        .subcommand(cmds::doctor::argspec())
+        .subcommand(cmds::upgrade_check::argspec())
+        .subcommand(cmds::env::argspec().setting(clap::AppSettings::Hidden))
+        .setting(clap::AppSettings::DeriveDisplayOrder)
+        // We handle --version ourselves so that it can be formatted. For the
+        // same reason we cannot use `SubcommandRequiredElseHelp`; see below.
+        .setting(clap::AppSettings::DisableVersion);
+    let matches = match app.clone().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(err) => usage_error(err),
+    };
+
+    if matches.is_present("version") {
+        match matches.value_of("version-format") {
+            Some("json") => println!("{}", version::json()),
+            _ => println!("{}", version::text()),
+        }
+        process::exit(0);
+    }
+    if matches.subcommand_name().is_none() {
+        // As `SubcommandRequiredElseHelp` would: help, on stderr, as an error.
+        let mut help = Vec::new();
+        app.write_help(&mut help).expect("could not render help");
+        usage_error(clap::Error {
+            message: String::from_utf8_lossy(&help).into_owned(),
+            kind: clap::ErrorKind::MissingArgumentOrSubcommand,
+            info: None,
+        });
+    }
+
+    let log_levels = logging::levels(
+        matches.occurrences_of("verbose"),
+        matches.occurrences_of("quiet"),
+    );
+    let log_format = logging::Format::from_name(matches.value_of("log-format"));
+    if let Err(err) = logging::init(log_levels, log_format) {
+        eprintln!("{}", err);
+        process::exit(error::Code::Other.exit_code() as i32);
+    };
+
+    if matches.is_present("timings") {
+        timings::enable();
+    }
+
+    term::init(match matches.value_of("color") {
+        None if matches.is_present("color") => term::Choice::Always,
+        name => term::Choice::from_name(name),
+    });
+
+    use error::Error::*;
+    let result: Result<u8, error::Error> = match matches.subcommand() {
+        (cmds::build::NAME, Some(subm)) => cmds::build::run(subm).map_err(BuildError),
+        (cmds::status::NAME, Some(subm)) => cmds::status::run(subm).map_err(StatusError),
+        (cmds::clean::NAME, Some(subm)) => cmds::clean::run(subm).map_err(CleanError),
+        (cmds::hook::NAME, Some(subm)) => cmds::hook::run(subm).map_err(HookError),
+        (cmds::env::NAME, Some(subm)) => cmds::env::run(subm).map_err(EnvError),
+        (cmds::diff::NAME, Some(subm)) => cmds::diff::run(subm).map_err(DiffError),
+        (cmds::sums::NAME, Some(subm)) => cmds::sums::run(subm).map_err(SumsError),
+        (cmds::export::NAME, Some(subm)) => cmds::export::run(subm).map_err(ExportError),
+        (cmds::devcontainer::NAME, Some(subm)) => {
+            cmds::devcontainer::run(subm).map_err(DevcontainerError)
+        }
+        (cmds::apply::NAME, Some(subm)) => cmds::apply::run(subm).map_err(ApplyError),
+        (cmds::cache::NAME, Some(subm)) => cmds::cache::run(subm).map_err(CacheError),
+        (cmds::ci::NAME, Some(subm)) => cmds::ci::run(subm).map_err(CiError),
+        (cmds::login::NAME, Some(subm)) => cmds::login::run(subm).map_err(LoginError),
+        (cmds::config::NAME, Some(subm)) => cmds::config::run(subm).map_err(ConfigError),
+        (cmds::watch::NAME, Some(subm)) => cmds::watch::run(subm).map_err(WatchError),
+        (cmds::daemon::NAME, Some(subm)) => cmds::daemon::run(subm).map_err(DaemonError),
+        (cmds::serve::NAME, Some(subm)) => cmds::serve::run(subm).map_err(ServeError),
+        (cmds::audit_log::NAME, Some(subm)) => cmds::audit_log::run(subm).map_err(AuditLogError),
+        (cmds::history::NAME, Some(subm)) => cmds::history::run(subm).map_err(HistoryError),
+        (cmds::shell::NAME, Some(subm)) => cmds::shell::run(subm).map_err(ShellError),
+        (cmds::doctor::NAME, Some(subm)) => cmds::doctor::run(subm).map_err(DoctorError),
+        (cmds::upgrade_check::NAME, Some(subm)) => {
+            cmds::upgrade_check::run(subm).map_err(UpgradeCheckError)
+        }
+        // This last branch should not be taken since we check for a missing
+        // subcommand above, but Rust insists that we cater for it, so we do.
+        (name, _) => Err(CommandNotFound(name.into())),
+    };
+
+    if let Err(err) = timings::report() {
+        log::warn!("Could not report timings: {}", err);
+    }
+
+    match result {
+        Err(err) => {
+            // Machines get the error where they expect the output, as well.
+            if prints_json(&matches) {
+                println!("{}", err.to_json());
+            }
+            let color = log_format == logging::Format::Text && term::enabled_stderr();
+            logging::event(
+                module_path!(),
+                log::Level::Error,
+                "failed",
+                vec![("code", err.code().name().into())],
+                format_args!("{}", err.report(matches.is_present("verbose"), color)),
+            );
+            process::exit(err.code().exit_code() as i32);
+        }
+        Ok(code) => {
+            process::exit(code as i32);
+        }
+    };
+}
+
+/// Report a usage error as clap would, but exit with our code for usage
+/// errors rather than clap's, which would mean "stale" from `status`. Help and
+/// version information are not errors.
+fn usage_error(err: clap::Error) -> ! {
+    match err.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.exit(),
+        _ => {
+            eprintln!("{}", err.message);
+            process::exit(error::Code::Usage.exit_code() as i32)
+        }
+    }
+}
+
+/// Does the command print JSON on stdout, for which its failures should be
+/// JSON too? `hook --format direnv-dump` does not count: its dump is for
+/// direnv, which knows nothing of our errors.
+fn prints_json(matches: &clap::ArgMatches) -> bool {
+    match matches.subcommand() {
+        (cmds::apply::NAME, Some(subm)) | (cmds::diff::NAME, Some(subm)) => {
+            subm.value_of("format") == Some("json")
+        }
+        (cmds::export::NAME, Some(subm)) => {
+            subm.value_of("format") == Some("json") && !subm.is_present("out")
+        }
+        (cmds::history::NAME, Some(subm)) => subm.is_present("json"),
+        _ => false,
+    }
+}
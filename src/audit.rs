@@ -0,0 +1,131 @@
+//! An audit log of the variables that builds export to developers' shells.
+//!
+//! Each line records one variable of one cache generation, separated by tabs:
+//! the time, the cache signature, the kind of change, the variable's name,
+//! and its value – or rather, by default, `-` in place of the value, or
+//! `sha256:<hex>` when `values = "hash"`, or `redacted`. Values themselves
+//! are never recorded.
+
+use crate::config;
+use crate::env;
+use crate::os::OsStrExt;
+use crate::rotate::{rotate, rotated};
+use crypto_hash::{hex_digest, Algorithm};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+pub struct Record {
+    pub time: String,
+    pub sig: String,
+    pub kind: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t');
+        Some(Self {
+            time: fields.next()?.into(),
+            sig: fields.next()?.into(),
+            kind: fields.next()?.into(),
+            name: fields.next()?.into(),
+            value: fields.next()?.into(),
+        })
+    }
+}
+
+/// Append a record for each change in `diff` to the audit log, rotating the
+/// log first if it has grown too large.
+pub fn record(config: &config::Config, sig: &str, diff: &env::Diff) -> io::Result<()> {
+    let path = config.audit_log_file();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    rotate(&path, config.audit.max_size, config.audit.keep)?;
+    let time = chrono::offset::Local::now().format("%+").to_string();
+    let mut lines = String::new();
+    for change in diff {
+        let (kind, value) = match change {
+            env::Change::Added(_, value) => ("added", Some(value)),
+            env::Change::Changed(_, _, value) => ("changed", Some(value)),
+            env::Change::Removed(_, _) => ("removed", None),
+        };
+        let name = change.name();
+        lines.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            time,
+            sig,
+            kind,
+            name.to_string_lossy(),
+            describe(&config.audit, name, value.map(|value| value.as_os_str())),
+        ));
+    }
+    // One write, so that records from concurrent builds do not interleave.
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)?;
+    log.write_all(lines.as_bytes())?;
+    log.sync_all()
+}
+
+fn describe(audit: &config::Audit, name: &OsStr, value: Option<&OsStr>) -> String {
+    match (value, audit.values) {
+        (None, _) | (_, config::AuditValues::None) => "-".into(),
+        (Some(_), _) if audit.redacts(name.as_bytes()) => "redacted".into(),
+        (Some(value), config::AuditValues::Hash) => {
+            format!("sha256:{}", hex_digest(Algorithm::SHA256, value.as_bytes()))
+        }
+    }
+}
+
+/// Read all records, oldest first, from rotated logs too.
+pub fn read(config: &config::Config) -> io::Result<Vec<Record>> {
+    let path = config.audit_log_file();
+    let mut paths: Vec<PathBuf> = (1..=config.audit.keep)
+        .rev()
+        .map(|n| rotated(&path, n))
+        .collect();
+    paths.push(path);
+    let mut records = Vec::new();
+    for path in paths {
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for line in io::BufReader::new(file).lines() {
+            match Record::parse(&line?) {
+                Some(record) => records.push(record),
+                None => log::warn!("Malformed line in audit log {:?}.", path),
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_values_unless_redacted() {
+        let audit = config::Audit {
+            values: config::AuditValues::Hash,
+            redact: vec!["*_TOKEN".into()],
+            ..Default::default()
+        };
+        let value = Some(OsStr::new("alice"));
+        assert_eq!(
+            format!("sha256:{}", hex_digest(Algorithm::SHA256, b"alice")),
+            describe(&audit, OsStr::new("ALICE"), value)
+        );
+        assert_eq!("redacted", describe(&audit, OsStr::new("API_TOKEN"), value));
+        assert_eq!("-", describe(&audit, OsStr::new("ALICE"), None));
+        let audit = config::Audit::default();
+        assert_eq!("-", describe(&audit, OsStr::new("ALICE"), value));
+    }
+}
@@ -1,30 +1,639 @@
+use crate::crypt;
 use crate::env;
+use crate::error::Code;
+use crate::git;
 use crate::sums;
+use crate::timings;
 use bincode;
+use crypto_hash::{hex_digest, Algorithm};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Every cache file starts with this, followed by the format version as a
+/// little-endian `u32`, followed by the bincode-serialized `Cache`.
+const MAGIC: &[u8] = b"firstaide-cache\0";
+
+/// An alias starts with this, followed by the bincode-serialized file name of
+/// the cache it stands in for, and its own checksums. See `dedupe_builds`.
+const ALIAS_MAGIC: &[u8] = b"firstaide-alias\0";
+
+/// With `cache_objects`, the directory within the cache dir in which each
+/// distinct environment is stored once, named for its content. Cache files are
+/// then aliases to these objects.
+pub const OBJECTS_DIR: &str = "objects";
+
+/// Bump this whenever the serialized form of `Cache` changes, and teach
+/// `Cache::decode` how to read (or reject) the previous versions. Version 2
+/// added `firstaide`, version 3 checksums of variables, and version 4 `git`.
+pub const VERSION: u32 = 4;
+
+/// In relocatable mode, occurrences of the build directory in cached values
+/// are replaced with this, and replaced back when the cache is used.
+pub const BUILD_DIR_PLACEHOLDER: &[u8] = b"@FIRSTAIDE_BUILD_DIR@";
+
+/// Caches may come from elsewhere – shared or remote caches – so we don't
+/// trust them not to be enormous, whether by accident or by design. This is
+/// far larger than any real cache.
+pub(crate) const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Compressed caches start with this: they're gzip files. Caches are
+/// compressed before they're encrypted, and decompressed after they're
+/// decrypted; those that aren't compressed are recognised by their absence.
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Decode(bincode::Error),
+    Encode(bincode::Error),
+    Outdated(u32),
+    TooNew(u32),
+    TooLarge(u64),
+    Crypt(crypt::Error),
+    Decompress(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Decode(err) => write!(f, "cache could not be decoded: {}", err),
+            Encode(err) => write!(f, "cache could not be encoded: {}", err),
+            Outdated(version) => write!(
+                f,
+                "cache (format {}) was written by an older firstaide, rebuilding required",
+                version
+            ),
+            TooNew(version) => write!(
+                f,
+                "cache (format {}) was written by a newer firstaide, rebuilding required",
+                version
+            ),
+            TooLarge(size) => write!(
+                f,
+                "cache is too large ({} bytes; the limit is {} bytes)",
+                size, MAX_SIZE
+            ),
+            Crypt(err) => write!(f, "{}", err),
+            Decompress(err) => write!(f, "cache could not be decompressed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            Decode(err) => Some(err),
+            Encode(err) => Some(err),
+            Crypt(err) => Some(err),
+            Decompress(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Io(_) => Code::Io,
+            Decode(_) | Decompress(_) | TooLarge(_) => Code::CacheCorrupt,
+            Crypt(err) => err.code(),
+            Outdated(_) | TooNew(_) => Code::CacheVersion,
+            Encode(_) => Code::Other,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<crypt::Error> for Error {
+    fn from(error: crypt::Error) -> Self {
+        Error::Crypt(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Serialize, Deserialize)]
 pub struct Cache {
     pub diff: env::Diff,
     pub sums: sums::Checksums,
+    /// The version of firstaide that wrote this cache, or empty if it was
+    /// written before caches recorded it.
+    pub firstaide: String,
+    /// The Git branch and commit of the project when this cache was built, if
+    /// it's in Git.
+    pub git: Option<git::Head>,
+}
+
+/// The layout of format versions 2 and 3.
+#[derive(Deserialize)]
+struct CacheV2 {
+    diff: env::Diff,
+    sums: sums::Checksums,
+    firstaide: String,
+}
+
+impl From<CacheV2> for Cache {
+    fn from(cache: CacheV2) -> Self {
+        Self {
+            diff: cache.diff,
+            sums: cache.sums,
+            firstaide: cache.firstaide,
+            git: None,
+        }
+    }
+}
+
+/// The layout of format version 1, and of unversioned caches.
+#[derive(Deserialize)]
+struct CacheV1 {
+    diff: env::Diff,
+    sums: sums::Checksums,
+}
+
+impl From<CacheV1> for Cache {
+    fn from(cache: CacheV1) -> Self {
+        Self {
+            diff: cache.diff,
+            sums: cache.sums,
+            firstaide: String::new(),
+            git: None,
+        }
+    }
 }
 
 impl Cache {
-    pub fn load<T: AsRef<Path>>(filename: T) -> bincode::Result<Self> {
-        let data = fs::read(filename)?;
-        bincode::deserialize(&data)
+    /// A cache written by this firstaide.
+    pub fn new(diff: env::Diff, sums: sums::Checksums) -> Self {
+        Self {
+            diff,
+            sums,
+            firstaide: crate_version!().into(),
+            git: None,
+        }
     }
 
-    pub fn load_with_fallback<T: AsRef<Path>>(filename: T, fallback: T) -> bincode::Result<Self> {
-        Self::load(filename).or_else(|_err| {
-            log::debug!("Loading fallback, {}", fallback.as_ref().display());
-            Self::load(fallback)
+    /// Warn when this cache was written by a firstaide with a different major
+    /// or minor version: the format may be the same, but what went into it
+    /// may not be.
+    pub fn warn_if_foreign(&self) {
+        if !crate::version::compatible(&self.firstaide) {
+            log::warn!(
+                "Cache was written by firstaide {}, but this is firstaide {}; consider rebuilding.",
+                if self.firstaide.is_empty() {
+                    "(unknown)"
+                } else {
+                    &self.firstaide
+                },
+                crate_version!(),
+            );
+        }
+    }
+
+    /// Load a cache, decrypting it with `keys` if it's encrypted. An alias is
+    /// loaded as the cache it stands in for, but with its own checksums.
+    pub fn load<T: AsRef<Path>>(filename: T, keys: &crypt::Keys) -> Result<Self> {
+        timings::time("Load cache", || Self::load_untimed(filename.as_ref(), keys))
+    }
+
+    fn load_untimed(filename: &Path, keys: &crypt::Keys) -> Result<Self> {
+        let data = read(filename, keys)?;
+        match decode_alias(&data)? {
+            Some((target, sums, git)) => {
+                let target = Self::decode(&read(&sibling(filename, &target), keys)?)?;
+                Ok(Self {
+                    sums,
+                    git,
+                    ..target
+                })
+            }
+            None => Self::decode(&data),
+        }
+    }
+
+    /// Save a cache, compressed, and encrypted if `keys` says so.
+    pub fn save<T: AsRef<Path>>(&self, filename: T, keys: &crypt::Keys) -> Result<()> {
+        timings::time("Save cache", || {
+            Ok(fs::write(filename, self.to_bytes(keys)?)?)
         })
     }
 
-    pub fn save<T: AsRef<Path>>(&self, filename: T) -> bincode::Result<()> {
-        Ok(fs::write(filename, bincode::serialize(self)?)?)
+    /// Save, in place of a cache, an alias to `target`, an existing cache file
+    /// with an identical diff, either in the same directory or one of its
+    /// objects.
+    pub fn save_alias<T: AsRef<Path>>(
+        &self,
+        filename: T,
+        target: &Path,
+        keys: &crypt::Keys,
+    ) -> Result<()> {
+        let dir = filename.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        let target = target.strip_prefix(dir).unwrap_or(target);
+        let target = target.to_string_lossy();
+        let mut data = Vec::new();
+        data.extend(ALIAS_MAGIC);
+        bincode::serialize_into(&mut data, &(target, &self.sums, &self.git))
+            .map_err(Error::Encode)?;
+        Ok(fs::write(filename, keys.seal(data)?)?)
+    }
+
+    /// The name of this cache's object: a digest of its diff, so that caches
+    /// with identical diffs share an object.
+    pub fn object_name(&self) -> Result<String> {
+        let diff = bincode::serialize(&self.diff).map_err(Error::Encode)?;
+        Ok(hex_digest(Algorithm::SHA256, &diff))
+    }
+
+    /// Save this cache's diff, if it's not already there, as an object in
+    /// `dir`, and an alias to it in `filename`. Returns the object's path, and
+    /// whether it was already there.
+    pub fn save_object<T: AsRef<Path>>(
+        &self,
+        filename: T,
+        dir: &Path,
+        keys: &crypt::Keys,
+    ) -> Result<(PathBuf, bool)> {
+        let object = dir.join(self.object_name()?);
+        let existed = object.is_file();
+        if !existed {
+            fs::create_dir_all(dir)?;
+            // Objects are shared, so what's particular to this build – its
+            // checksums and Git state – stays in the alias.
+            let payload = Self {
+                diff: self.diff.clone(),
+                sums: sums::Checksums::default(),
+                firstaide: self.firstaide.clone(),
+                git: None,
+            };
+            // Write atomically; a concurrent build may want the same object.
+            let temp = object.with_extension("new");
+            payload.save(&temp, keys)?;
+            fs::rename(&temp, &object)?;
+        }
+        self.save_alias(filename, &object, keys)?;
+        Ok((object, existed))
+    }
+
+    /// Decode a cache from `data`, as from `to_bytes`, decrypting it if `keys`
+    /// say so. It must be a whole cache, not an alias.
+    pub fn from_bytes(data: &[u8], keys: &crypt::Keys) -> Result<Self> {
+        if data.len() as u64 > MAX_SIZE {
+            return Err(Error::TooLarge(data.len() as u64));
+        }
+        Self::decode(&decompress(keys.open(data.to_vec())?)?)
+    }
+
+    /// The bytes of this cache as `save` would write them.
+    pub fn to_bytes(&self, keys: &crypt::Keys) -> Result<Vec<u8>> {
+        Ok(keys.seal(compress(self.encode()?))?)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        data.extend(MAGIC);
+        data.extend(&VERSION.to_le_bytes());
+        bincode::serialize_into(&mut data, self).map_err(Error::Encode)?;
+        Ok(data)
+    }
+
+    /// Decode a cache. This must not panic, nor allocate without bound, no
+    /// matter what `data` contains; see the fuzz targets in `fuzz`.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() as u64 > MAX_SIZE {
+            return Err(Error::TooLarge(data.len() as u64));
+        }
+        if data.starts_with(MAGIC) && data.len() >= MAGIC.len() + 4 {
+            let (version, payload) = data[MAGIC.len()..].split_at(4);
+            let mut version_bytes = [0u8; 4];
+            version_bytes.copy_from_slice(version);
+            match u32::from_le_bytes(version_bytes) {
+                VERSION => deserialize(payload).map_err(Error::Decode),
+                // Version 2 differs from 3 only in having no checksums of
+                // variables, which leaves its layout readable as version 3's.
+                2 | 3 => deserialize::<CacheV2>(payload)
+                    .map(Cache::from)
+                    .map_err(Error::Decode),
+                1 => deserialize::<CacheV1>(payload)
+                    .map(Cache::from)
+                    .map_err(Error::Decode),
+                version if version < VERSION => Err(Error::Outdated(version)),
+                version => Err(Error::TooNew(version)),
+            }
+        } else {
+            // Caches from before the format was versioned have no header. Their
+            // layout is the same as version 1, so we can migrate them; if that
+            // fails it's not something we understand.
+            log::debug!("Cache has no header; migrating from unversioned format.");
+            deserialize::<CacheV1>(data)
+                .map(Cache::from)
+                .map_err(|_err| Error::Outdated(0))
+        }
+    }
+}
+
+/// Read, with a limit, decrypt, and decompress.
+fn read(filename: &Path, keys: &crypt::Keys) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    fs::File::open(filename)?
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_SIZE {
+        return Err(Error::TooLarge(data.len() as u64));
+    }
+    decompress(keys.open(data)?)
+}
+
+/// Compress `data` with gzip, in memory.
+fn compress(data: Vec<u8>) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into memory does not fail.
+    (encoder.write_all(&data))
+        .and_then(|()| encoder.finish())
+        .expect("could not compress in memory")
+}
+
+/// Decompress `data` if it's compressed; otherwise return it unchanged, so
+/// that caches written before compression was added can still be read. This
+/// reads no more than one byte past `MAX_SIZE`, however much `data` claims to
+/// hold.
+fn decompress(data: Vec<u8>) -> Result<Vec<u8>> {
+    if !data.starts_with(GZIP_MAGIC) {
+        return Ok(data);
+    }
+    let mut output = Vec::new();
+    (GzDecoder::new(&data[..]).take(MAX_SIZE + 1))
+        .read_to_end(&mut output)
+        .map_err(Error::Decompress)?;
+    if output.len() as u64 > MAX_SIZE {
+        return Err(Error::TooLarge(output.len() as u64));
+    }
+    Ok(output)
+}
+
+type Alias = (String, sums::Checksums, Option<git::Head>);
+
+/// Decode an alias: its target, its checksums, and, for aliases written since
+/// caches recorded it, its Git state.
+fn decode_alias(data: &[u8]) -> Result<Option<Alias>> {
+    let payload = match data.strip_prefix(ALIAS_MAGIC) {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    match deserialize::<Alias>(payload) {
+        Ok(alias) => Ok(Some(alias)),
+        Err(_) => deserialize::<(String, sums::Checksums)>(payload)
+            .map(|(target, sums)| Some((target, sums, None)))
+            .map_err(Error::Decode),
+    }
+}
+
+/// The file that the alias `filename` stands in for, or `None` if it's not
+/// an alias, or cannot be read.
+pub fn alias_target(filename: &Path, keys: &crypt::Keys) -> Option<PathBuf> {
+    match decode_alias(&read(filename, keys).ok()?) {
+        Ok(Some((target, _, _))) => Some(sibling(filename, &target)),
+        _ => None,
+    }
+}
+
+/// Where the alias `filename` says its target, `name`, is: a sibling, or an
+/// object. Nothing else is allowed, lest an alias point anywhere at all.
+fn sibling(filename: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    let file_name = name.file_name().unwrap_or_default();
+    match name.parent() {
+        Some(parent) if parent == Path::new(OBJECTS_DIR) => {
+            filename.with_file_name(OBJECTS_DIR).join(file_name)
+        }
+        _ => filename.with_file_name(file_name),
+    }
+}
+
+/// Deserialize with a limit, so that a bogus length prefix cannot make us
+/// allocate more than the size of the input.
+fn deserialize<T: DeserializeOwned>(data: &[u8]) -> bincode::Result<T> {
+    bincode::config().limit(data.len() as u64).deserialize(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let cache = example();
+        let decoded = Cache::decode(&cache.encode().ok().unwrap()).ok().unwrap();
+        assert_eq!(cache.diff, decoded.diff);
+        assert!(sums::equal(&cache.sums, &decoded.sums));
+        assert_eq!(crate_version!(), decoded.firstaide);
+    }
+
+    #[test]
+    fn migrates_unversioned_caches() {
+        let cache = example();
+        let data = bincode::serialize(&(&cache.diff, &cache.sums)).unwrap();
+        let decoded = Cache::decode(&data).ok().unwrap();
+        assert_eq!(cache.diff, decoded.diff);
+        assert_eq!("", decoded.firstaide);
+    }
+
+    #[test]
+    fn migrates_version_1_caches() {
+        let cache = example();
+        let mut data = Vec::new();
+        data.extend(MAGIC);
+        data.extend(&1u32.to_le_bytes());
+        bincode::serialize_into(&mut data, &(&cache.diff, &cache.sums)).unwrap();
+        let decoded = Cache::decode(&data).ok().unwrap();
+        assert_eq!(cache.diff, decoded.diff);
+        assert_eq!("", decoded.firstaide);
+    }
+
+    #[test]
+    fn migrates_version_2_and_3_caches() {
+        let cache = example();
+        for version in [2u32, 3] {
+            let mut data = Vec::new();
+            data.extend(MAGIC);
+            data.extend(&version.to_le_bytes());
+            bincode::serialize_into(&mut data, &(&cache.diff, &cache.sums, "0.1.0")).unwrap();
+            let decoded = Cache::decode(&data).ok().unwrap();
+            assert_eq!(cache.diff, decoded.diff);
+            assert_eq!("0.1.0", decoded.firstaide);
+            assert_eq!(None, decoded.git);
+        }
+    }
+
+    #[test]
+    fn aliases_keep_their_own_git_state() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = crypt::Keys::new(None);
+        let (target, alias) = (dir.path().join("cache.a"), dir.path().join("cache.b"));
+        example().save(&target, &keys).ok().unwrap();
+        let head = git::Head {
+            branch: Some("main".into()),
+            commit: "abc123".into(),
+        };
+        let cache = Cache {
+            git: Some(head.clone()),
+            ..example()
+        };
+        cache.save_alias(&alias, &target, &keys).ok().unwrap();
+        assert_eq!(Some(head), Cache::load(&alias, &keys).ok().unwrap().git);
+
+        // Aliases from before aliases recorded it have none.
+        let mut data = Vec::new();
+        data.extend(ALIAS_MAGIC);
+        bincode::serialize_into(&mut data, &("cache.a", &cache.sums)).unwrap();
+        fs::write(&alias, data).unwrap();
+        let loaded = Cache::load(&alias, &keys).ok().unwrap();
+        assert_eq!(None, loaded.git);
+        assert_eq!(example().diff, loaded.diff);
+    }
+
+    #[test]
+    fn aliases_load_their_targets_diff_with_their_own_sums() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = crypt::Keys::new(None);
+        let (target, alias) = (dir.path().join("cache.a"), dir.path().join("cache.b"));
+        example().save(&target, &keys).ok().unwrap();
+        let watched = dir.path().join("watched");
+        let cache = Cache::new(
+            env::Diff::new(),
+            sums::Checksums::from(&[&watched]).unwrap(),
+        );
+        cache.save_alias(&alias, &target, &keys).ok().unwrap();
+        assert_eq!(Some(target), alias_target(&alias, &keys));
+        let loaded = Cache::load(&alias, &keys).ok().unwrap();
+        assert_eq!(example().diff, loaded.diff);
+        assert!(sums::equal(&cache.sums, &loaded.sums));
+    }
+
+    #[test]
+    fn objects_are_shared_by_caches_with_identical_diffs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let objects = dir.path().join(OBJECTS_DIR);
+        let keys = crypt::Keys::new(None);
+        let watched = dir.path().join("watched");
+        let (a, b) = (dir.path().join("cache.a"), dir.path().join("cache.b"));
+        let (object, existed) = example().save_object(&a, &objects, &keys).ok().unwrap();
+        assert!(!existed);
+        assert!(object.starts_with(&objects));
+        let cache = Cache::new(example().diff, sums::Checksums::from(&[&watched]).unwrap());
+        let (again, existed) = cache.save_object(&b, &objects, &keys).ok().unwrap();
+        assert!(existed);
+        assert_eq!(object, again);
+        assert_eq!(Some(object.clone()), alias_target(&b, &keys));
+        let loaded = Cache::load(&b, &keys).ok().unwrap();
+        assert_eq!(example().diff, loaded.diff);
+        assert!(sums::equal(&cache.sums, &loaded.sums));
+        assert_eq!(1, fs::read_dir(&objects).unwrap().count());
+    }
+
+    #[test]
+    fn aliases_cannot_point_elsewhere() {
+        let alias = Path::new("/cache/cache.a");
+        assert_eq!(Path::new("/cache/cache.b"), sibling(alias, "cache.b"));
+        assert_eq!(Path::new("/cache/objects/x"), sibling(alias, "objects/x"));
+        assert_eq!(Path::new("/cache/x"), sibling(alias, "../../x"));
+        assert_eq!(Path::new("/cache/x"), sibling(alias, "/etc/x"));
+    }
+
+    #[test]
+    fn compresses_and_decompresses() {
+        let keys = crypt::Keys::new(None);
+        let cache = example();
+        let data = cache.to_bytes(&keys).ok().unwrap();
+        let decoded = Cache::from_bytes(&data, &keys).ok().unwrap();
+        assert_eq!(cache.diff, decoded.diff);
+        // Uncompressed caches, as written before compression, still load.
+        let decoded = Cache::from_bytes(&cache.encode().ok().unwrap(), &keys)
+            .ok()
+            .unwrap();
+        assert_eq!(cache.diff, decoded.diff);
+    }
+
+    #[test]
+    fn rejects_oversized_decompressed_caches() {
+        let data = compress(vec![0u8; MAX_SIZE as usize + 2]);
+        match decompress(data) {
+            Err(Error::TooLarge(size)) if size == MAX_SIZE + 1 => (),
+            _ => panic!("expected TooLarge"),
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_compressed_caches() {
+        let mut data = compress(b"cache".to_vec());
+        data.truncate(GZIP_MAGIC.len() + 4);
+        match decompress(data) {
+            Err(Error::Decompress(_)) => (),
+            _ => panic!("expected Decompress"),
+        }
+    }
+
+    #[test]
+    fn rejects_unrecognised_caches() {
+        match Cache::decode(b"garbage") {
+            Err(Error::Outdated(0)) => (),
+            _ => panic!("expected Outdated(0)"),
+        }
+    }
+
+    #[test]
+    fn rejects_caches_from_newer_versions() {
+        let mut data = Vec::new();
+        data.extend(MAGIC);
+        data.extend(&(VERSION + 1).to_le_bytes());
+        match Cache::decode(&data) {
+            Err(Error::TooNew(version)) if version == VERSION + 1 => (),
+            _ => panic!("expected TooNew"),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_caches() {
+        let data = vec![0u8; MAX_SIZE as usize + 1];
+        match Cache::decode(&data) {
+            Err(Error::TooLarge(size)) if size == MAX_SIZE + 1 => (),
+            _ => panic!("expected TooLarge"),
+        }
+    }
+
+    #[test]
+    fn rejects_bogus_lengths() {
+        // A header followed by a huge length prefix for the diff's changes.
+        let mut data = Vec::new();
+        data.extend(MAGIC);
+        data.extend(&VERSION.to_le_bytes());
+        data.extend(&u64::MAX.to_le_bytes());
+        match Cache::decode(&data) {
+            Err(Error::Decode(_)) => (),
+            _ => panic!("expected Decode"),
+        }
+    }
+
+    fn example() -> Cache {
+        Cache::new(
+            env::Diff::from(&[env::Added("ALICE".into(), "alice".into())]),
+            sums::Checksums::from::<&str>(&[]).unwrap(),
+        )
     }
 }
@@ -1,5 +1,61 @@
+pub mod apply;
+pub mod audit_log;
 pub mod build;
+pub mod cache;
+pub mod ci;
 pub mod clean;
+pub mod config;
+pub mod daemon;
+pub mod devcontainer;
+pub mod diff;
+pub mod doctor;
 pub mod env;
+pub mod export;
+pub mod history;
 pub mod hook;
+pub mod login;
+pub mod serve;
+pub mod shell;
 pub mod status;
+pub mod sums;
+pub mod upgrade_check;
+pub mod watch;
+
+use crate::logging;
+use std::path::PathBuf;
+
+/// Load the configuration, as commands that work on a project do, and log to
+/// the project's log file from now on as well; see `logging::log_to_file`.
+/// Observers write nothing, and we don't create the cache dir just to log into
+/// it.
+pub fn load_config<T: Into<PathBuf>>(
+    dir: Option<T>,
+) -> Result<crate::config::Config, crate::config::Error> {
+    let config = crate::config::Config::load(dir)?;
+    if let Some(log_file) = &config.log_file {
+        if !config.observer && config.cache_dir.is_dir() {
+            if let Err(err) = logging::log_to_file(log_file) {
+                log::warn!("Could not log to {:?}: {}", log_file, err);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// For clap: is `value` a whole number? Checked as arguments are parsed, a bad
+/// one is a usage error like any other, so `value_t!` cannot then fail.
+pub fn is_number(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{:?} is not a whole number", value)),
+    }
+}
+
+/// For clap: is `value` a whole number greater than zero?
+pub fn is_positive(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(0) => Err("must be greater than zero".into()),
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{:?} is not a whole number", value)),
+    }
+}
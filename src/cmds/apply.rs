@@ -0,0 +1,128 @@
+use crate::cmds;
+use crate::cmds::hook;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use crate::shells;
+use crate::status::EnvironmentStatus;
+use std::env::vars_os;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const NAME: &str = "apply";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Hook(hook::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Hook(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Hook(err) => Some(err),
+            Io(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Hook(err) => err.code(),
+            Error::Io(_) => Code::Io,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<hook::Error> for Error {
+    fn from(error: hook::Error) -> Self {
+        Error::Hook(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Prints the environment that the hook would apply, without direnv's help")
+        .long_about(concat!(
+            "Prints the environment that the hook would apply: the parent environment, ",
+            "with the cached environment composed on top, just as the hook does it. For ",
+            "editors, launchers, and other tools that don't evaluate .envrc.\n",
+            "With --format bash, fish, or another shell, prints commands that turn the ",
+            "environment here into that environment.\n",
+            "With --format json, prints that environment in full, as an object mapping ",
+            "names to values.\n",
+            "The status of the environment is logged to stderr.",
+        ))
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(shells::NAMES)
+                .possible_value("json")
+                .default_value("bash")
+                .help("The output format"),
+        )
+        .arg(
+            clap::Arg::with_name("no-cache-outside")
+                .long("no-cache-outside")
+                .help("Capture the outside environment afresh; see `hook.outside_ttl` in config"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let cache_outside = !args.is_present("no-cache-outside");
+    let (status, env_diff) = hook::environment(&config, cache_outside)?;
+    match status {
+        EnvironmentStatus::Okay => log::info!("{}", status),
+        _ => log::warn!("{}", status),
+    }
+
+    let output = match args.value_of("format") {
+        Some("json") => {
+            let env_here: env::Env = vars_os().collect();
+            format!("{}\n", env::env_to_json(&env::apply(&env_here, &env_diff))).into_bytes()
+        }
+        shell => (shell.and_then(shells::renderer))
+            .unwrap_or_else(|| Box::new(shells::Bash))
+            .dump(&env_diff),
+    };
+    io::stdout().lock().write_all(&output)?;
+    Ok(0)
+}
@@ -0,0 +1,107 @@
+use crate::audit;
+use crate::cmds;
+use crate::config;
+use crate::error::Code;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const NAME: &str = "audit-log";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Shows which variables builds have exported")
+        .long_about(concat!(
+            "Shows which variables builds have exported, oldest first.\n",
+            "Records are written to the audit log by each build that creates a ",
+            "new cache, when enabled in the [audit] section of the ",
+            "configuration. Each shows the time, cache signature, kind of ",
+            "change, variable name, and value (or its hash, if configured).",
+        ))
+        .arg(
+            clap::Arg::with_name("var")
+                .long("var")
+                .value_name("NAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Show only records for this variable"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let vars: Vec<&str> = args.values_of("var").into_iter().flatten().collect();
+
+    if !config.audit.enabled {
+        log::warn!("The audit log is not enabled; see [audit] in the configuration.");
+    }
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    for record in audit::read(&config)? {
+        if vars.is_empty() || vars.contains(&record.name.as_str()) {
+            writeln!(
+                &mut handle,
+                "{}  {}  {:<7}  {}  {}",
+                record.time, record.sig, record.kind, record.name, record.value
+            )?;
+        }
+    }
+
+    Ok(0)
+}
@@ -1,28 +1,51 @@
+use crate::audit;
 use crate::cache;
+use crate::cmds;
 use crate::config;
 use crate::env;
+use crate::error::Code;
+use crate::git;
+use crate::gitignore;
+use crate::history;
+use crate::lock;
+use crate::logging;
+use crate::nix;
+use crate::os::{self, OsStrExt};
+use crate::progress;
+use crate::remote;
+use crate::rotate;
+use crate::status::EnvironmentStatus;
 use crate::sums;
-use bincode;
-use spinners::{Spinner, Spinners};
+use crate::term;
+use crate::workspace;
+use bstr::ByteSlice;
+use shell_quote::bash;
 use std::fmt;
 use std::fs;
 use std::io::{self, Write};
-use std::os::unix;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 use tempfile;
 
 pub const NAME: &str = "build";
 
 type Result = std::result::Result<u8, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Config(config::Error),
     Io(io::Error),
     DirEnv(String),
-    EnvOutsideCapture,
-    EnvOutsideDecode(bincode::Error),
-    EnvInsideCapture,
-    EnvInsideDecode(bincode::Error),
-    Cache(bincode::Error),
+    EnvOutside(env::CaptureError),
+    EnvInside(env::CaptureError),
+    Cache(cache::Error),
+    Locked(PathBuf),
+    PreBuild(String),
+    PostBuild(String),
+    FromDump(String),
+    Workspace(workspace::Error),
+    Members(Vec<String>),
 }
 
 impl fmt::Display for Error {
@@ -32,11 +55,47 @@ impl fmt::Display for Error {
             Config(err) => write!(f, "{}", err),
             Io(err) => write!(f, "input/output error: {}", err),
             DirEnv(message) => write!(f, "direnv broke: {}", message),
-            EnvOutsideCapture => write!(f, "could not capture outside environment"),
-            EnvOutsideDecode(err) => write!(f, "problem decoding outside environment: {}", err),
-            EnvInsideCapture => write!(f, "could not capture inside environment"),
-            EnvInsideDecode(err) => write!(f, "problem decoding inside environment: {}", err),
+            EnvOutside(err) => write!(f, "could not capture outside environment: {}", err),
+            EnvInside(err) => write!(f, "could not capture inside environment: {}", err),
             Cache(err) => write!(f, "cache could not be saved: {}", err),
+            Locked(path) => write!(f, "another build is in progress; {:?} is locked", path),
+            PreBuild(message) => write!(f, "pre-build hook failed: {}", message),
+            PostBuild(message) => write!(f, "post-build hook failed: {}", message),
+            FromDump(message) => write!(f, "cannot build from dump: {}", message),
+            Workspace(err) => write!(f, "{}", err),
+            Members(members) => write!(f, "could not build {}", members.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            EnvOutside(err) => Some(err),
+            EnvInside(err) => Some(err),
+            Cache(err) => Some(err),
+            Workspace(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            DirEnv(_) => Code::Direnv,
+            EnvOutside(err) | EnvInside(err) => err.code(),
+            Cache(err) => err.code(),
+            Locked(_) => Code::Locked,
+            PreBuild(_) | PostBuild(_) | FromDump(_) | Members(_) => Code::Build,
+            Workspace(err) => err.code(),
         }
     }
 }
@@ -49,7 +108,7 @@ impl From<config::Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
@@ -61,36 +120,454 @@ pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
                 .value_name("DIR")
                 .help("The directory in which to build"),
         )
+        .arg(
+            clap::Arg::with_name("wait")
+                .long("wait")
+                .help("Wait for another build in progress to finish (default)"),
+        )
+        .arg(
+            clap::Arg::with_name("no-wait")
+                .long("no-wait")
+                .conflicts_with("wait")
+                .help("Fail if another build is in progress"),
+        )
+        .arg(
+            clap::Arg::with_name("force")
+                .long("force")
+                .help("Build even if the environment is up to date"),
+        )
+        .arg(
+            clap::Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Report what a build would do without doing it"),
+        )
+        .arg(
+            clap::Arg::with_name("show-output")
+                .long("show-output")
+                .help("Show the build's output as it happens (it's always in the build log)"),
+        )
+        .arg(
+            clap::Arg::with_name("from-dump")
+                .long("from-dump")
+                .value_name("INSIDE_FILE")
+                .help("Use an inside environment dumped earlier by `firstaide env --out`"),
+        )
+        .arg(
+            clap::Arg::with_name("outside-dump")
+                .long("outside-dump")
+                .value_name("OUTSIDE_FILE")
+                .requires("from-dump")
+                .help("Use an outside environment dumped earlier, too"),
+        )
+        .arg(
+            clap::Arg::with_name("detach")
+                .long("detach")
+                .conflicts_with("dry-run")
+                .help("Build in the background; see `status` for progress"),
+        )
+        .arg(
+            clap::Arg::with_name("all")
+                .long("all")
+                .conflicts_with_all(&["from-dump", "detach"])
+                .help("Build every member of the workspace that DIR is in, in turn"),
+        )
+}
+
+pub struct Options {
+    /// Wait for a concurrent build to finish rather than failing.
+    pub wait: bool,
+    /// Build even when there's already a cache matching the watched files.
+    pub force: bool,
+    /// Copy the build's output to the terminal as well as to the build log.
+    pub show_output: bool,
+    /// Read the inside environment from here instead of building.
+    pub from_dump: Option<PathBuf>,
+    /// Read the outside environment from here instead of capturing it.
+    pub outside_dump: Option<PathBuf>,
+    /// Show the build's phases as they go by, when on a terminal.
+    pub display: bool,
+}
+
+impl Options {
+    fn from(args: &clap::ArgMatches, config: &config::Config) -> Self {
+        Self {
+            wait: !args.is_present("no-wait"),
+            force: args.is_present("force"),
+            show_output: args.is_present("show-output") || config.show_build_output,
+            from_dump: args.value_of_os("from-dump").map(PathBuf::from),
+            outside_dump: args.value_of_os("outside-dump").map(PathBuf::from),
+            display: true,
+        }
+    }
 }
 
 pub fn run(args: &clap::ArgMatches) -> Result {
-    let config = config::Config::load(args.value_of_os("dir"))?;
-    build(config)
-}
-
-fn spin<F, T>(f: F) -> T
-where
-    F: FnOnce() -> T,
-{
-    if atty::is(atty::Stream::Stdout) {
-        let spinner = Spinner::new(Spinners::Dots, "".into());
-        let result = f();
-        spinner.stop();
-        print!("\x08\x08"); // Backspace over the spinner.
-        result
+    if args.is_present("all") {
+        return build_all(args);
+    }
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    config.writable()?;
+    let options = Options::from(args, &config);
+    if options.from_dump.is_some() && !config.layers.is_empty() {
+        return Err(Error::FromDump(
+            "a single dump cannot stand in for [[environments.compose]] layers".into(),
+        ));
+    }
+    if args.is_present("dry-run") {
+        dry_run(config, options)
+    } else if args.is_present("detach") {
+        detach(config, options)
+    } else {
+        build_logged(&config, options)
+    }
+}
+
+/// Build each member of a workspace in turn, carrying on past failures, which
+/// are reported together at the end.
+fn build_all(args: &clap::ArgMatches) -> Result {
+    let workspace =
+        workspace::Workspace::find(args.value_of_os("dir")).map_err(Error::Workspace)?;
+    let mut failed = Vec::new();
+    for member in &workspace.members {
+        let name = workspace.name(member).display().to_string();
+        log::info!("Workspace member {}:", name);
+        let result = config::Config::load(Some(member))
+            .map_err(Error::from)
+            .and_then(|config| {
+                config.writable()?;
+                let options = Options::from(args, &config);
+                if args.is_present("dry-run") {
+                    dry_run(config, options)
+                } else {
+                    build_logged(&config, options)
+                }
+            });
+        match result {
+            Ok(0) => (),
+            Ok(code) => {
+                log::error!("{}: exited with {}", name, code);
+                failed.push(name);
+            }
+            Err(err) => {
+                log::error!("{}: {}", name, err);
+                failed.push(name);
+            }
+        }
+    }
+    if failed.is_empty() {
+        log::info!("Built all {} workspace members.", workspace.members.len());
+        Ok(0)
     } else {
-        f()
+        Err(Error::Members(failed))
+    }
+}
+
+/// Build, logging when the build starts and finishes, with its duration.
+pub fn build_logged(config: &config::Config, options: Options) -> Result {
+    let build_dir = config.build_dir.to_string_lossy().into_owned();
+    logging::event(
+        module_path!(),
+        log::Level::Info,
+        "build.start",
+        vec![("build_dir", build_dir.as_str().into())],
+        format_args!("Start build in {:?}.", build_dir),
+    );
+    let started = Instant::now();
+    let started_at = chrono::Local::now();
+    let mut found = Found::default();
+    let result = build(config, options, &mut found);
+    let elapsed = started.elapsed();
+    let unchanged = found.unchanged;
+    let record = history::Record {
+        started: started_at.into(),
+        finished: chrono::Local::now().into(),
+        outcome: match (&result, found.up_to_date) {
+            (Ok(0), true) => history::Outcome::UpToDate,
+            (Ok(0), false) => history::Outcome::Success,
+            (_, _) => history::Outcome::Failure,
+        },
+        sig: found.sig,
+        object: found.object,
+        branch: git::branch(&config.build_dir),
+        firstaide: crate_version!().into(),
+    };
+    if let Err(err) = history::record(config, &record) {
+        log::warn!(
+            "Could not record build in {:?}: {}",
+            config.history_file(),
+            err
+        );
+    }
+    settle(config, &record, unchanged);
+    logging::event(
+        module_path!(),
+        log::Level::Info,
+        "build.finish",
+        vec![
+            ("build_dir", build_dir.as_str().into()),
+            ("duration_ms", (elapsed.as_millis() as i64).into()),
+            ("success", result.is_ok().into()),
+        ],
+        format_args!("Finish build in {:.1}s.", elapsed.as_secs_f64()),
+    );
+    result
+}
+
+/// Prompt direnv to reload after a build – unless it changed nothing. Then the
+/// shell already has this environment, albeit perhaps loaded as stale, and
+/// direnv need not be disturbed. After a rebuild that produced the same
+/// environment, the hook says so when it next runs instead.
+fn settle(config: &config::Config, record: &history::Record, unchanged: bool) {
+    let unchanged = match record.outcome {
+        history::Outcome::UpToDate => return,
+        history::Outcome::Success => {
+            let sig = record.sig.as_deref().filter(|_| unchanged);
+            if let Err(err) = history::mark_unchanged(config, sig) {
+                log::warn!("Could not mark environment as unchanged: {}", err);
+            }
+            unchanged
+        }
+        history::Outcome::Failure => false,
+    };
+    if unchanged {
+        log::info!("Environment unchanged; direnv need not reload.");
+    } else if let Err(err) = history::prompt_reload(config) {
+        log::warn!("Could not prompt direnv to reload: {}", err);
+    }
+}
+
+/// Run this build again, in the background, in its own session so that it
+/// survives the terminal going away. Its output goes into the cache dir.
+fn detach(config: config::Config, options: Options) -> Result {
+    fs::create_dir_all(&config.cache_dir)?;
+    let output_file = config.build_output_file();
+    let output = fs::File::create(&output_file)?;
+
+    let mut command = process::Command::new(&config.self_exe);
+    command.arg(NAME).arg(&config.build_dir);
+    if options.force {
+        command.arg("--force");
+    }
+    if !options.wait {
+        command.arg("--no-wait");
+    }
+    if options.show_output {
+        command.arg("--show-output");
     }
+    if let Some(from_dump) = &options.from_dump {
+        command.arg("--from-dump").arg(from_dump);
+    }
+    if let Some(outside_dump) = &options.outside_dump {
+        command.arg("--outside-dump").arg(outside_dump);
+    }
+    let child = spawn_detached(&mut command, output)?;
+
+    log::info!(
+        "Building in the background (PID {}); output in {:?}.",
+        child.id(),
+        output_file
+    );
+    Ok(0)
 }
 
-fn build(config: config::Config) -> Result {
+/// Spawn `command` in its own session, with no input, and sending all output
+/// to `output`.
+pub fn spawn_detached(
+    command: &mut process::Command,
+    output: fs::File,
+) -> io::Result<process::Child> {
+    command
+        .stdin(process::Stdio::null())
+        .stdout(output.try_clone()?)
+        .stderr(output);
+    new_session(command);
+    log::debug!("{:?}", command);
+    command.spawn()
+}
+
+/// In a session of its own, so that it survives the terminal going away.
+#[cfg(unix)]
+fn new_session(command: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+}
+
+/// Without a console, and in a process group of its own, so that Ctrl-C in
+/// the console it came from does not reach it.
+#[cfg(windows)]
+fn new_session(command: &mut process::Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+fn acquire(lock_file: &Path, wait: bool) -> std::result::Result<lock::Lock, Error> {
+    lock::Lock::acquire(lock_file, wait).map_err(|err| {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Error::Locked(lock_file.to_path_buf())
+        } else {
+            Error::Io(err)
+        }
+    })
+}
+
+/// Report what `build` would do. This runs `watch_exe` to find out whether
+/// the environment is stale, but nothing else.
+fn dry_run(config: config::Config, options: Options) -> Result {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    let sums_now = config.checksums()?;
+    let cache_file = config.cache_file(&sums_now);
+    let up_to_date = match config.load_cache(&sums_now) {
+        Ok((cache, _)) => {
+            let changed = sums::changed(&cache.sums, &sums_now);
+            if changed.is_empty() {
+                let message = "Environment is up to date.";
+                writeln!(&mut handle, "{}", term::Style::Okay.mark("✔", message))?;
+            } else {
+                let message = "Environment is stale; changed since last build:";
+                writeln!(&mut handle, "{}", term::Style::Warning.mark("▲", message))?;
+                for subject in changed {
+                    writeln!(&mut handle, "  {}", subject)?;
+                }
+            }
+            sums::equal(&sums_now, &cache.sums)
+        }
+        Err(err) => {
+            let message = format!("Environment not built ({}).", err);
+            writeln!(&mut handle, "{}", term::Style::Error.mark("✘", &message))?;
+            false
+        }
+    };
+
+    if up_to_date && !options.force {
+        writeln!(&mut handle, "Would not build; use --force to build anyway.")?;
+        return Ok(0);
+    }
+
+    let temp_path = config.cache_dir.join(".tmpXXXXXX");
+    let mut commands = vec![config.command_direnv()?, config.command_to_allow_direnv()?];
+    commands[0].arg("version");
+    match &options.outside_dump {
+        Some(outside_dump) => writeln!(&mut handle, "Would read: {}", outside_dump.display())?,
+        None => commands.push(config.command_to_dump_env_outside(temp_path.join("outside"))?),
+    }
+    if let Some(from_dump) = &options.from_dump {
+        writeln!(&mut handle, "Would read: {}", from_dump.display())?;
+    } else if config.layers.is_empty() {
+        commands.push(match config.nix.capture {
+            config::NixCapture::PrintDevEnv => config.command_to_print_dev_env(&[]),
+            config::NixCapture::Shell => {
+                config.command_to_dump_env_inside(temp_path.join("inside"), &[])
+            }
+        });
+    } else {
+        for (index, layer) in config.layers.iter().enumerate() {
+            let dump_path = temp_path.join(format!("inside.{}", index));
+            commands.push(config.command_to_dump_env_inside_layer(layer, dump_path, &[]));
+        }
+    }
+    commands.push(config.command_watch());
+    if let Some(pre_build) = &config.pre_build {
+        commands.insert(2, config.command_build_hook(pre_build));
+    }
+    if let Some(post_build) = &config.post_build {
+        commands.push(config.command_build_hook(post_build));
+    }
+
+    writeln!(&mut handle, "Would run:")?;
+    for command in commands {
+        writeln!(&mut handle, "  {}", describe(&command))?;
+    }
+    writeln!(&mut handle, "Would write: {}", cache_file.display())?;
+
+    Ok(0)
+}
+
+/// A shell-like rendering of `command` for humans.
+fn describe(command: &process::Command) -> String {
+    let mut words = vec![bash::escape(command.get_program())];
+    words.extend(command.get_args().map(bash::escape));
+    String::from_utf8_lossy(&words.join(&b' ')).into_owned()
+}
+
+/// What a build found out along the way, for the history, whether or not it
+/// succeeded.
+#[derive(Default)]
+struct Found {
+    /// The signature of the watched files when the cache was written, or when
+    /// it was found to be up to date.
+    sig: Option<String>,
+    /// With `cache_objects`, the object that the cache is an alias to.
+    object: Option<String>,
+    up_to_date: bool,
+    /// The environment is just as in the most recent cache.
+    unchanged: bool,
+}
+
+/// Link to `cache_file` as the most recent cache on the Git branch that's
+/// checked out, if any, for the `same-branch` fallback. Like the history, this
+/// is a convenience, so failures are reported, not fatal.
+fn link_branch(config: &config::Config, cache_file: &Path) {
+    let branch = match git::branch(&config.build_dir) {
+        Some(branch) => branch,
+        None => return,
+    };
+    let link = config.cache_file_on_branch(&branch);
+    let result = (|| {
+        fs::create_dir_all(config.branches_dir())?;
+        // Relative in relocatable mode, as for the most recent link.
+        let target = match (config.relocatable, cache_file.file_name()) {
+            (true, Some(name)) => Path::new("..").join(name),
+            _ => cache_file.to_path_buf(),
+        };
+        let temp = link.with_file_name(format!(".tmp.{}", process::id()));
+        fs::remove_file(&temp).ok();
+        os::point(&target, &temp)?;
+        fs::rename(&temp, &link)
+    })();
+    if let Err(err) = result {
+        log::warn!("Could not link {:?} to {:?}: {}", link, cache_file, err);
+    }
+}
+
+fn build(config: &config::Config, options: Options, found: &mut Found) -> Result {
     // 0. Check `direnv` is new enough. Older versions have bugs that prevent
     // building from working correctly.
-    check_direnv_version(&config).map_err(Error::DirEnv)?;
+    check_direnv_version(config).map_err(Error::DirEnv)?;
+
+    // 0a. Check that Nix, if it's installed, keeps what's needed to rebuild
+    // the environment. This is advice only.
+    if config.nix.check_conf && Path::new("/nix/store").is_dir() {
+        match nix::Conf::load() {
+            Ok(conf) => {
+                log::debug!("Read Nix configuration from {:?}.", conf.files);
+                for problem in nix::check(&conf) {
+                    log::warn!("{}", problem);
+                }
+            }
+            Err(err) => log::warn!("Could not read Nix configuration: {}", err),
+        }
+    }
+
+    // Show the build's phases as they go by. Build output, when shown, would
+    // make a mess of that, so then they're logged instead.
+    let mut display = progress::Display::start(options.display && !options.show_output);
 
     // 1. Allow `direnv`.
-    log::info!("Allow direnv in {:?}.", &config.build_dir);
-    if !config.command_to_allow_direnv().status()?.success() {
+    display.phase("Allow direnv");
+    log::debug!("Allow direnv in {:?}.", &config.build_dir);
+    if !config.command_to_allow_direnv()?.status()?.success() {
         return Err(Error::DirEnv("could not enable direnv".into()));
     }
 
@@ -98,6 +575,69 @@ fn build(config: config::Config) -> Result {
     log::info!("Create cache dir at {:?}.", &config.cache_dir);
     fs::create_dir_all(&config.cache_dir)?;
 
+    // 2a. Keep the cache dir out of Git, if asked to. Not being able to is
+    // not a reason to fail the build.
+    if let Err(err) =
+        gitignore::ensure(config.gitignore_cache, &config.build_dir, &config.cache_dir)
+    {
+        log::warn!("Could not keep the cache dir out of Git: {}", err);
+    }
+
+    // Only one build at a time. Concurrent builds would race on the cache
+    // dir, build log, and most recent cache file link. The lock is released
+    // when `_lock` is dropped, i.e. when we're done.
+    let lock_file = config.lock_file();
+    let _lock = acquire(&lock_file, options.wait)?;
+
+    // Other tools that build Nix environments on file change may cooperate via
+    // a shared lock dir. We say who we are in the lock file while we hold it.
+    let _shared_lock = match config.shared_lock_file() {
+        Some(shared_lock_file) => {
+            if let Some(parent) = shared_lock_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut lock = acquire(&shared_lock_file, options.wait)?;
+            lock.note(&format!(
+                "firstaide {} {}\n",
+                process::id(),
+                config.build_dir.display()
+            ))?;
+            Some(lock)
+        }
+        None => None,
+    };
+
+    // Skip the (expensive) build when there's a cache entry that matches the
+    // watched files as they are now. We check this only once we hold the lock
+    // because a concurrent build may have just finished.
+    let sums_before = config.checksums()?;
+    let status_before = match config.load_cache(&sums_before) {
+        Ok((cache, _)) if sums::equal(&sums_before, &cache.sums) => EnvironmentStatus::Okay,
+        Ok(_) => EnvironmentStatus::Stale,
+        Err(_) => EnvironmentStatus::Unknown,
+    };
+    if !options.force && matches!(status_before, EnvironmentStatus::Okay) {
+        log::info!("Environment is up to date; use --force to build anyway.");
+        // This may be the first build on a new branch.
+        link_branch(config, &config.cache_file(&sums_before));
+        found.sig = Some(sums_before.sig());
+        found.up_to_date = true;
+        display.succeeded();
+        return Ok(0);
+    }
+
+    // Record progress for the benefit of `status` and `hook`.
+    let mut progress = progress::Tracker::start(config.build_progress_file(), display)?;
+
+    if let Some(pre_build) = &config.pre_build {
+        progress.phase("Run pre-build hook");
+        let mut command = config.command_build_hook(pre_build);
+        command
+            .env("FIRSTAIDE_HOOK", "pre-build")
+            .env("FIRSTAIDE_STATUS", status_before.name());
+        run_build_hook(&mut command).map_err(Error::PreBuild)?;
+    }
+
     // Setting up additional OS pipes for subprocesses to communicate back to us
     // is not well supported in the Rust standard library, so we use files in a
     // temporary directory instead.
@@ -105,91 +645,312 @@ fn build(config: config::Config) -> Result {
     let temp_path = temp_dir.path().to_owned();
 
     // 3a. Capture outside environment.
-    log::info!("Capture outside environment.");
-    let env_outside: env::Env = spin(|| {
+    let env_outside: env::Env = if let Some(outside_dump) = &options.outside_dump {
+        progress.phase("Read outside environment");
+        env::read_dump(outside_dump).map_err(Error::EnvOutside)?
+    } else {
+        progress.phase("Capture outside environment");
         let dump_path = temp_path.join("outside");
-        let mut dump_cmd = config.command_to_dump_env_outside(&dump_path);
-        log::debug!("{:?}", dump_cmd);
-        let mut dump_proc = dump_cmd.spawn()?;
-        if !dump_proc.wait()?.success() {
-            return Err(Error::EnvOutsideCapture);
-        }
-        match bincode::deserialize(&fs::read(dump_path)?) {
-            Ok(env) => Ok(env),
-            Err(err) => Err(Error::EnvOutsideDecode(err)),
-        }
-    })?;
-
-    // 3b. Capture inside environment.
-    log::info!("Capture inside environment (may involve a full build).");
-    let env_inside: env::Env = spin(|| {
-        let dump_path = temp_path.join("inside");
-        let mut dump_cmd = config.command_to_dump_env_inside(&dump_path, &env_outside);
-        log::debug!("{:?}", dump_cmd);
-        let mut dump_proc = dump_cmd.spawn()?;
-        if !dump_proc.wait()?.success() {
-            return Err(Error::EnvInsideCapture);
-        }
-        match bincode::deserialize(&fs::read(dump_path)?) {
-            Ok(env) => Ok(env),
-            Err(err) => Err(Error::EnvInsideDecode(err)),
-        }
-    })?;
-
-    // 4. Calculate environment diff.
-    log::info!("Calculate environment diff.");
-    let env_diff = env::diff(&env_outside, &env_inside);
+        let mut dump_cmd = config.command_to_dump_env_outside(&dump_path)?;
+        capture(config, options.show_output, &mut dump_cmd, &dump_path)
+            .map_err(Error::EnvOutside)?
+    };
+
+    // 3b/4. Capture inside environment and calculate environment diff.
+    let env_diff = if let Some(from_dump) = &options.from_dump {
+        // Someone else – CI, say – has done the expensive part already.
+        progress.phase("Read inside environment");
+        let env_inside = env::read_dump(from_dump).map_err(Error::EnvInside)?;
+        progress.phase("Calculate environment diff");
+        env::diff(&env_outside, &env_inside)
+    } else if config.layers.is_empty() {
+        progress.phase("Capture inside environment (may involve a full build)");
+        let env_inside: env::Env = match config.nix.capture {
+            config::NixCapture::PrintDevEnv => {
+                let mut print_cmd = config.command_to_print_dev_env(&env_outside);
+                capture_dev_env(config, options.show_output, &mut print_cmd, &env_outside)
+            }
+            config::NixCapture::Shell => {
+                let dump_path = temp_path.join("inside");
+                let mut dump_cmd = config.command_to_dump_env_inside(&dump_path, &env_outside);
+                capture(config, options.show_output, &mut dump_cmd, &dump_path)
+            }
+        }
+        .map_err(Error::EnvInside)?;
+        progress.phase("Calculate environment diff");
+        env::diff(&env_outside, &env_inside)
+    } else {
+        // Capture each layer separately, then compose their diffs in order.
+        let mut env_diff = env::Diff::new();
+        for (index, layer) in config.layers.iter().enumerate() {
+            progress.phase(format!(
+                "Capture inside environment from {:?} (may involve a full build)",
+                &layer.build_exe
+            ));
+            let dump_path = temp_path.join(format!("inside.{}", index));
+            let mut dump_cmd =
+                config.command_to_dump_env_inside_layer(layer, &dump_path, &env_outside);
+            let env_inside = capture(config, options.show_output, &mut dump_cmd, &dump_path)
+                .map_err(Error::EnvInside)?;
+            env_diff.extend(layer.exclude(&env::diff(&env_outside, &env_inside)));
+        }
+        progress.phase("Calculate environment diff");
+        env_diff.simplify();
+        env_diff
+    };
 
     // 5. Calculate checksums.
-    log::info!("Calculate file checksums.");
-    let checksums = spin(|| sums::Checksums::from(&config.watch_files()?))?;
+    progress.phase("Calculate file checksums");
+    let checksums = config.checksums()?;
     let cache_file = config.cache_file(&checksums);
+    let cache_is_new = !cache_file.exists();
+    found.sig = Some(checksums.sig());
 
-    // 6. Write out cache.
-    log::info!("Write out cache.");
+    // 6. Write out cache. Excluded variables, like secrets, never make it to
+    // disk.
+    progress.phase("Write out cache");
+    let env_diff = config.exclude_vars(&env_diff);
+    let env_diff = if config.relocatable {
+        let build_dir = config.build_dir.as_os_str().as_bytes();
+        env_diff.replace_in_values(build_dir, cache::BUILD_DIR_PLACEHOLDER)
+    } else {
+        env_diff
+    };
     let cache = cache::Cache {
-        diff: env_diff,
-        sums: checksums,
+        git: git::head(&config.build_dir),
+        ..cache::Cache::new(env_diff, checksums)
     };
-    cache.save(&cache_file).map_err(Error::Cache)?;
+    let most_recent = config.cache_file_most_recent_target();
+    found.unchanged = match most_recent.map(|file| cache::Cache::load(file, &config.cache_keys)) {
+        Some(Ok(previous)) => previous.diff == cache.diff,
+        _ => false,
+    };
+    if config.cache_objects {
+        let (object, existed) = cache
+            .save_object(&cache_file, &config.objects_dir(), &config.cache_keys)
+            .map_err(Error::Cache)?;
+        let name = object.file_name().unwrap_or_default().to_string_lossy();
+        if existed {
+            log::info!(
+                "Environment matches an earlier build; reusing object {}.",
+                name
+            );
+        }
+        found.object = Some(name.into_owned());
+    } else {
+        match dedupe_target(config, &cache) {
+            Some(target) if cache_is_new => {
+                log::info!("Environment is unchanged; aliasing {:?}.", target);
+                cache
+                    .save_alias(&cache_file, &target, &config.cache_keys)
+                    .map_err(Error::Cache)?
+            }
+            _ => cache
+                .save(&cache_file, &config.cache_keys)
+                .map_err(Error::Cache)?,
+        }
+    }
+
+    if let Some(git) = &cache.git {
+        if let Err(err) = git.save(&config.cache_git_file(&cache_file)) {
+            log::warn!("Could not record Git state for {:?}: {}", cache_file, err);
+        }
+    }
 
     // 7. Update the most recent cache file link.
-    log::info!("Update most recent cache file link.");
+    progress.phase("Update most recent cache file link");
     {
-        // Write a new symlink into the temporary directory.
+        // Write a new link into the temporary directory. In relocatable mode
+        // it's relative, i.e. to a sibling.
         let cache_file_link = temp_path.join("cache");
-        unix::fs::symlink(&cache_file, &cache_file_link)?;
-        // Atomically replace any existing symlink with the new one.
-        fs::rename(&cache_file_link, &config.cache_file_most_recent())?
+        let cache_file_target = match (config.relocatable, cache_file.file_name()) {
+            (true, Some(name)) => Path::new(name),
+            _ => cache_file.as_path(),
+        };
+        os::point(cache_file_target, &cache_file_link)?;
+        // Atomically replace any existing link with the new one.
+        fs::rename(&cache_file_link, config.cache_file_most_recent())?
     }
+    link_branch(config, &cache_file);
 
-    // 8. Write to the build log. This may be a useful record, but, since we
-    // also arrange for direnv to watch this log, it's actually here to prompt
-    // direnv to reload. Previously we relied upon getting direnv to watch the
-    // cache file, but the cache file is now named with a checksum suffix, so it
-    // doesn't notice it.
-    {
-        let mut build_log = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&config.build_log_file())?;
-        let build_time = chrono::offset::Local::now();
-        writeln!(
-            &mut build_log,
-            "{}  {}",
-            &build_time.format("%+"),
-            &cache_file.display()
-        )?;
-        build_log.sync_all()?;
+    // 8. The build is recorded in the history once we're done, in
+    // `build_logged`, which then prompts direnv to reload – unless the
+    // environment is unchanged. The cache file is named with a checksum
+    // suffix, so watching it would not do.
+
+    // 8a. Record what this new cache generation exports in the audit log. As
+    // with the history, this is a record only, so failures are reported.
+    if config.audit.enabled && cache_is_new {
+        if let Err(err) = audit::record(config, &cache.sums.sig(), &cache.diff) {
+            log::warn!("Could not write to audit log: {}", err);
+        }
+    }
+
+    // 8b. Protect the store paths that the environment refers to from garbage
+    // collection. Without this, `nix-collect-garbage` can break the
+    // environment, but that's for later, so failures are only reported.
+    if config.nix.gc_roots {
+        progress.phase("Register GC roots");
+        let paths = nix::store_paths(&cache.diff);
+        match nix::add_gc_roots(&config.gc_roots_dir(), &paths) {
+            Ok(roots) => log::info!("Registered {} GC roots.", roots),
+            Err(err) => log::warn!("Could not register GC roots: {}", err),
+        }
+    }
+
+    // 9. Push to the remote cache. The build has succeeded, whatever happens
+    // here, so failures are only reported. Parts already pushed, by another
+    // build say, are skipped.
+    if config.remote.push {
+        progress.phase("Push to remote cache");
+        match remote::push_file(config, &cache_file, true) {
+            Ok(pushed) => log::info!("Pushed to remote cache ({} parts sent).", pushed.sent),
+            Err(err) => log::warn!("Could not push to remote cache: {}", err),
+        }
+    }
+
+    // 10. Run the post-build hook. The cache is written already, so, should
+    // this fail, the environment is still usable.
+    if let Some(post_build) = &config.post_build {
+        progress.phase("Run post-build hook");
+        let mut command = config.command_build_hook(post_build);
+        command
+            .env("FIRSTAIDE_HOOK", "post-build")
+            .env("FIRSTAIDE_STATUS", EnvironmentStatus::Okay.name())
+            .env("FIRSTAIDE_PREVIOUS_STATUS", status_before.name())
+            .env("FIRSTAIDE_CACHE_FILE", &cache_file);
+        run_build_hook(&mut command).map_err(Error::PostBuild)?;
     }
 
     // Done.
+    progress.succeeded();
     Ok(0)
 }
 
+/// With `dedupe_builds`, the most recent cache file – not an alias – when it
+/// has the same diff as `cache`.
+fn dedupe_target(config: &config::Config, cache: &cache::Cache) -> Option<PathBuf> {
+    if !config.dedupe_builds {
+        return None;
+    }
+    let recent = config.cache_file_most_recent_target()?;
+    let target = cache::alias_target(&recent, &config.cache_keys).unwrap_or(recent);
+    match cache::Cache::load(&target, &config.cache_keys) {
+        Ok(previous) if previous.diff == cache.diff => Some(target),
+        _ => None,
+    }
+}
+
+fn run_build_hook(command: &mut process::Command) -> std::result::Result<(), String> {
+    log::debug!("{:?}", command);
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!(
+            "{:?} exited with {}",
+            command.get_program(),
+            status
+        )),
+        Err(err) => Err(format!(
+            "{:?} could not be run: {}",
+            command.get_program(),
+            err
+        )),
+    }
+}
+
+/// Capture an environment with `command`, recording the command's output in
+/// the build log, and showing it too if asked.
+///
+/// The output is appended to the build log only once the command has finished,
+/// in one write, so that output from one build is not interleaved with that
+/// of another.
+fn capture(
+    config: &config::Config,
+    show_output: bool,
+    command: &mut process::Command,
+    dump_path: &Path,
+) -> std::result::Result<env::Env, env::CaptureError> {
+    let mut output = Vec::new();
+    let result = env::capture_with_output(command, dump_path, |line| {
+        if show_output {
+            let stderr = io::stderr();
+            let mut handle = stderr.lock();
+            handle
+                .write_all(line)
+                .and_then(|_| handle.write_all(b"\n"))
+                .ok();
+        }
+        output.push((chrono::Local::now(), line.to_vec()));
+    });
+    if let Err(err) = record_output(config, &output) {
+        log::warn!(
+            "Could not record build output in {:?}: {}",
+            config.build_log_file(),
+            err
+        );
+    }
+    result
+}
+
+/// Like `capture`, but `command` is `nix print-dev-env --json`, which prints
+/// the environment on stdout and its output on stderr. The environment it
+/// prints is composed onto `env_outside`; see `nix::dev_env`.
+fn capture_dev_env(
+    config: &config::Config,
+    show_output: bool,
+    command: &mut process::Command,
+    env_outside: &[env::Item],
+) -> std::result::Result<env::Env, env::CaptureError> {
+    log::debug!("{:?}", command);
+    let result = command.stdin(process::Stdio::null()).output()?;
+    let output: Vec<_> = (result.stderr.lines())
+        .map(|line| (chrono::Local::now(), line.to_vec()))
+        .collect();
+    if show_output {
+        io::stderr().lock().write_all(&result.stderr).ok();
+    }
+    if let Err(err) = record_output(config, &output) {
+        log::warn!(
+            "Could not record build output in {:?}: {}",
+            config.build_log_file(),
+            err
+        );
+    }
+    if !result.status.success() {
+        return Err(env::CaptureError::Failed);
+    }
+    let json = String::from_utf8_lossy(&result.stdout);
+    nix::dev_env(&json, env_outside).map_err(env::CaptureError::Parse)
+}
+
+/// Append timestamped lines of build output to the build log, rotating it
+/// first if it has grown too large. Each line is marked with a leading `>`.
+fn record_output(
+    config: &config::Config,
+    output: &[(chrono::DateTime<chrono::Local>, Vec<u8>)],
+) -> io::Result<()> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    let mut buffer = Vec::new();
+    for (time, line) in output {
+        write!(&mut buffer, "{}  > ", time.format("%+"))?;
+        buffer.extend(line);
+        buffer.push(b'\n');
+    }
+    let path = config.build_log_file();
+    rotate::rotate(&path, history::MAX_SIZE, history::KEEP)?;
+    let mut build_log = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)?;
+    build_log.write_all(&buffer)?;
+    build_log.sync_all()
+}
+
 fn check_direnv_version(config: &config::Config) -> std::result::Result<(), String> {
     let version_min = semver::Version::new(2, 21, 2);
-    let mut command = config.command_direnv();
+    let mut command = config.command_direnv().map_err(|err| err.to_string())?;
     command.arg("version");
     let command_output = command.output().map_err(|err| format!("{}", err))?;
     let version_string = String::from_utf8_lossy(&command_output.stdout);
@@ -211,3 +972,37 @@ fn check_direnv_version(config: &config::Config) -> std::result::Result<(), Stri
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settle_prompts_reload_only_when_something_changed() {
+        let (_dir, config) = config::test_project("");
+        fs::create_dir(&config.cache_dir).unwrap();
+        let now = chrono::Local::now().into();
+        let record = |outcome| history::Record {
+            started: now,
+            finished: now,
+            outcome,
+            sig: Some("abc".into()),
+            object: None,
+            branch: None,
+            firstaide: crate_version!().into(),
+        };
+        let reloaded = || fs::remove_file(config.reload_file()).is_ok();
+
+        settle(&config, &record(history::Outcome::UpToDate), false);
+        assert!(!reloaded());
+        settle(&config, &record(history::Outcome::Success), true);
+        assert!(!reloaded());
+        assert!(history::is_unchanged(&config, "abc"));
+        settle(&config, &record(history::Outcome::Failure), false);
+        assert!(reloaded());
+        assert!(history::is_unchanged(&config, "abc"));
+        settle(&config, &record(history::Outcome::Success), false);
+        assert!(reloaded());
+        assert!(!history::is_unchanged(&config, "abc"));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::cache;
+use crate::cmds;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use crate::git;
+use crate::history;
+use crate::remote;
+use crate::sums;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const NAME: &str = "cache";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Cache(PathBuf, cache::Error),
+    NotFound(String),
+    Remote(remote::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Cache(path, err) => write!(f, "cache {:?} could not be loaded: {}", path, err),
+            NotFound(sig) => write!(f, "no cache entry matching {:?}", sig),
+            Remote(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Cache(_, err) => Some(err),
+            Remote(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Cache(_, err) => err.code(),
+            NotFound(_) => Code::NotFound,
+            Remote(err) => err.code(),
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<remote::Error> for Error {
+    fn from(error: remote::Error) -> Self {
+        Error::Remote(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    let dir = clap::Arg::with_name("dir")
+        .long("dir")
+        .value_name("DIR")
+        .help("The directory in which to build");
+    let sig = clap::Arg::with_name("sig")
+        .value_name("SIG")
+        .help("The entry's signature, or a unique prefix; defaults to the most recent");
+    clap::SubCommand::with_name(NAME)
+        .about("Inspects the cache")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(
+            clap::SubCommand::with_name("ls")
+                .about("Lists cache entries")
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("show")
+                .about("Shows the contents of a cache entry")
+                .arg(sig.clone())
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("push")
+                .about("Pushes a cache entry to the remote cache")
+                .long_about(concat!(
+                    "Pushes a cache entry to the remote cache, configured with url in ",
+                    "[cache.remote]. Entries are pushed in parts; with --resume, parts ",
+                    "that the remote cache already has, from an interrupted push say, ",
+                    "are not pushed again.",
+                ))
+                .arg(
+                    clap::Arg::with_name("resume")
+                        .long("resume")
+                        .help("Skip parts that the remote cache already has"),
+                )
+                .arg(sig)
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("pull")
+                .about("Pulls a cache entry from the remote cache")
+                .long_about(concat!(
+                    "Pulls a cache entry from the remote cache, configured with url in ",
+                    "[cache.remote]: by default, the entry for the watched files as they ",
+                    "are now. Every part is checked against its SHA-256, and the entry ",
+                    "against its signature, before it's written to the cache dir.",
+                ))
+                .arg(
+                    clap::Arg::with_name("sig")
+                        .value_name("SIG")
+                        .help("The entry's signature; defaults to that of the watched files"),
+                )
+                .arg(dir),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    match args.subcommand() {
+        ("ls", Some(subm)) => ls(&cmds::load_config(subm.value_of_os("dir"))?),
+        ("show", Some(subm)) => show(
+            &cmds::load_config(subm.value_of_os("dir"))?,
+            subm.value_of("sig"),
+        ),
+        ("push", Some(subm)) => push(
+            &cmds::load_config(subm.value_of_os("dir"))?,
+            subm.value_of("sig"),
+            subm.is_present("resume"),
+        ),
+        ("pull", Some(subm)) => pull(
+            &cmds::load_config(subm.value_of_os("dir"))?,
+            subm.value_of("sig"),
+        ),
+        // Not reachable while `SubcommandRequired` is in effect.
+        (name, _) => Err(Error::NotFound(name.into())),
+    }
+}
+
+fn sig_of(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.trim_start_matches("cache.").into()
+}
+
+fn ls(config: &config::Config) -> Result {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    let cache_file_now = match config.checksums() {
+        Ok(sums_now) => Some(config.cache_file(&sums_now)),
+        Err(err) => {
+            log::warn!("Could not calculate checksums: {}", err);
+            None
+        }
+    };
+    let cache_file_most_recent = config.cache_file_most_recent_target();
+
+    let mut entries = Vec::new();
+    for file in config.cache_files()? {
+        let metadata = fs::metadata(&file)?;
+        let modified: chrono::DateTime<chrono::Local> = metadata.modified()?.into();
+        entries.push((modified, metadata.len(), file));
+    }
+    entries.sort();
+
+    for (modified, size, file) in entries {
+        let git = git::Head::load(&config.cache_git_file(&file)).map(|git| git.to_string());
+        let mut flags = Vec::new();
+        if Some(&file) == cache_file_now.as_ref() {
+            flags.push("current");
+        }
+        if Some(&file) == cache_file_most_recent.as_ref() {
+            flags.push("most-recent");
+        }
+        let line = format!(
+            "{}  {}  {:>9}  {:<24}  {}",
+            sig_of(&file),
+            modified.format("%Y-%m-%d %H:%M:%S"),
+            size,
+            git.as_deref().unwrap_or("-"),
+            flags.join(","),
+        );
+        writeln!(&mut handle, "{}", line.trim_end())?;
+    }
+
+    Ok(0)
+}
+
+/// The cache file with signature `sig`, or the most recent.
+fn find(config: &config::Config, sig: Option<&str>) -> std::result::Result<PathBuf, Error> {
+    Ok(match sig {
+        None => (config.cache_file_most_recent_target())
+            .unwrap_or_else(|| config.cache_file_most_recent()),
+        Some(sig) => {
+            let mut matches: Vec<PathBuf> = config
+                .cache_files()?
+                .into_iter()
+                .filter(|file| sig_of(file).starts_with(sig))
+                .collect();
+            match matches.len() {
+                1 => matches.remove(0),
+                _ => return Err(Error::NotFound(sig.into())),
+            }
+        }
+    })
+}
+
+fn push(config: &config::Config, sig: Option<&str>, resume: bool) -> Result {
+    let cache_file = find(config, sig)?;
+    let pushed = remote::push_file(config, &cache_file, resume)?;
+    log::info!(
+        "Pushed {} ({} parts sent, {} already there).",
+        sig_of(&cache_file),
+        pushed.sent,
+        pushed.skipped,
+    );
+    Ok(0)
+}
+
+fn pull(config: &config::Config, sig: Option<&str>) -> Result {
+    config.writable()?;
+    let sig = match sig {
+        Some(sig) => sig.to_owned(),
+        None => config.checksums()?.sig(),
+    };
+    if config.cache_dir.join(format!("cache.{}", sig)).exists() {
+        log::info!("Already have {}.", sig);
+        return Ok(0);
+    }
+    match remote::pull_file(config, &sig)? {
+        Some(cache_file) => {
+            log::info!("Pulled {} into {:?}.", sig, cache_file);
+            if let Err(err) = history::prompt_reload(config) {
+                log::warn!("Could not prompt direnv to reload: {}", err);
+            }
+            Ok(0)
+        }
+        None => Err(Error::NotFound(sig)),
+    }
+}
+
+fn show(config: &config::Config, sig: Option<&str>) -> Result {
+    let cache_file = find(config, sig)?;
+    let cache = cache::Cache::load(&cache_file, &config.cache_keys)
+        .map_err(|err| Error::Cache(cache_file, err))?;
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    if let Some(git) = &cache.git {
+        writeln!(&mut handle, "Built from: {}", git)?;
+    }
+    writeln!(&mut handle, "Checksums (signature {}):", cache.sums.sig())?;
+    for sum in cache.sums.iter() {
+        match sum {
+            sums::Checksum::Found(path, sums::Sha1(sha1)) => {
+                writeln!(&mut handle, "  {}  {}", sha1, path.display())?
+            }
+            sums::Checksum::NotFound(path) => {
+                writeln!(&mut handle, "  {:40}  {}", "(not found)", path.display())?
+            }
+            sums::Checksum::Var(_, Some(sums::Sha1(sha1))) => {
+                writeln!(&mut handle, "  {}  {}", sha1, sum.subject())?
+            }
+            sums::Checksum::Var(_, None) => {
+                writeln!(&mut handle, "  {:40}  {}", "(not set)", sum.subject())?
+            }
+        }
+    }
+
+    writeln!(&mut handle, "Diff:")?;
+    for change in &cache.diff {
+        match change {
+            env::Added(name, value) => writeln!(
+                &mut handle,
+                "  + {}={}",
+                name.to_string_lossy(),
+                value.to_string_lossy()
+            )?,
+            env::Changed(name, _, value) => writeln!(
+                &mut handle,
+                "  ~ {}={}",
+                name.to_string_lossy(),
+                value.to_string_lossy()
+            )?,
+            env::Removed(name, _) => writeln!(&mut handle, "  - {}", name.to_string_lossy())?,
+        }
+    }
+
+    Ok(0)
+}
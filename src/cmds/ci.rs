@@ -0,0 +1,246 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::{build, export, hook, status};
+use crate::config;
+use crate::creds;
+use crate::error::Code;
+use crate::remote;
+use crate::status::EnvironmentStatus;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+
+pub const NAME: &str = "ci";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Build(build::Error),
+    Cache(cache::Error),
+    NotOkay(EnvironmentStatus),
+    Export(export::Error),
+    OutUnused(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Build(err) => write!(f, "build failed: {}", err),
+            Cache(err) => write!(f, "{}", err),
+            NotOkay(status) => write!(f, "environment is not ready after building: {}", status),
+            Export(err) => write!(f, "{}", err),
+            OutUnused(format) => write!(f, "--out has no use with --export {}", format),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Build(err) => Some(err),
+            Cache(err) => Some(err),
+            Export(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Build(err) => err.code(),
+            Cache(err) => err.code(),
+            NotOkay(_) => Code::Build,
+            Export(err) => err.code(),
+            OutUnused(_) => Code::Usage,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<build::Error> for Error {
+    fn from(error: build::Error) -> Self {
+        Error::Build(error)
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(error: cache::Error) -> Self {
+        Error::Cache(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Checks config, builds or pulls, checks status, then exports; for CI")
+        .long_about(concat!(
+            "Does what CI usually needs, in one go, without asking anything of anyone: ",
+            "loads and checks the config; pulls the environment from the remote cache, ",
+            "if there is one, or else builds it; checks that it's up to date; then ",
+            "exports it.\n",
+            "With --export github-actions, dotenv, or json, it's written as `export` ",
+            "writes it.",
+        ))
+        .arg(
+            clap::Arg::with_name("export")
+                .long("export")
+                .value_name("FORMAT")
+                .possible_values(&["github-actions", "dotenv", "json", "none"])
+                .default_value("none")
+                .help("How to export the environment"),
+        )
+        .arg(
+            clap::Arg::with_name("out")
+                .short("o")
+                .long("out")
+                .value_name("OUT")
+                .help("Where to export dotenv or json; defaults to stdout"),
+        )
+        .arg(
+            clap::Arg::with_name("no-pull")
+                .long("no-pull")
+                .help("Build even when the remote cache has the environment"),
+        )
+        .arg(
+            clap::Arg::with_name("show-output")
+                .long("show-output")
+                .help("Show the build's output as it happens (it's always in the build log)"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    // Keyrings may prompt for a key or token, and no one is there to answer.
+    creds::non_interactive();
+    let format = args.value_of("export").unwrap_or("none");
+    if args.is_present("out") && !matches!(format, "dotenv" | "json") {
+        return Err(Error::OutUnused(format.into()));
+    }
+
+    // 1. Config. Loading checks it.
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    config.writable()?;
+    log::info!("Config is valid.");
+    // Better to find out that there's nowhere to export before building.
+    let github_actions = match format {
+        "github-actions" => Some(export::GitHubActions::from_env().map_err(Error::Export)?),
+        _ => None,
+    };
+
+    // 2. Pull, when there's a remote cache. Not finding the environment there,
+    // or failing to pull it, is reason to build, not to fail.
+    if config.remote.url.is_some() && !args.is_present("no-pull") {
+        let sig = config.checksums()?.sig();
+        match remote::pull_file(&config, &sig) {
+            Ok(Some(cache_file)) => log::info!("Pulled {} into {:?}.", sig, cache_file),
+            Ok(None) => log::info!("Remote cache does not have {}.", sig),
+            Err(err) => log::warn!("Could not pull from remote cache: {}", err),
+        }
+    }
+
+    // 3. Build, which is quick when the environment was pulled.
+    build::build_logged(&config, build_options(args, &config))?;
+
+    // 4. Check that the environment is what was asked for.
+    let sums_now = config.checksums()?;
+    let status = status::status_of(&config, &sums_now);
+    if status.code() != 0 {
+        return Err(Error::NotOkay(status));
+    }
+    log::info!("{}", status);
+
+    // 5. Export.
+    let (cache, _) = config.load_cache(&sums_now)?;
+    let env_diff = hook::diff_cached(&config, &cache);
+    if let Some(github_actions) = github_actions {
+        github_actions.write(&env_diff)?;
+        return Ok(0);
+    }
+    let output = match format {
+        "dotenv" => export::dotenv(&env_diff),
+        "json" => format!("{}\n", crate::env::to_json(&env_diff)).into_bytes(),
+        _ => return Ok(0),
+    };
+    match args.value_of_os("out") {
+        None => io::stdout().lock().write_all(&output)?,
+        Some(out) => fs::write(out, &output)?,
+    }
+    Ok(0)
+}
+
+/// There's no one to watch the phases go by, and no one to ask to wait.
+fn build_options(args: &clap::ArgMatches, config: &config::Config) -> build::Options {
+    build::Options {
+        wait: true,
+        force: false,
+        show_output: args.is_present("show-output") || config.show_build_output,
+        from_dump: None,
+        outside_dump: None,
+        display: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_is_only_for_dotenv_and_json() {
+        for export in ["none", "github-actions"] {
+            let args = ["ci", "--export", export, "--out", "env"];
+            let args = argspec().get_matches_from(args);
+            match run(&args) {
+                Err(err @ Error::OutUnused(_)) => assert_eq!(Code::Usage, err.code()),
+                other => panic!("expected OutUnused, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn never_asks_anyone() {
+        let (_dir, config) = config::test_project("");
+        let options = build_options(&argspec().get_matches_from(["ci"]), &config);
+        assert!(options.wait);
+        assert!(!options.display);
+
+        // Running at all rules out keyrings, which may prompt.
+        let args = ["ci", "--out", "env"];
+        assert!(run(&argspec().get_matches_from(args)).is_err());
+        let keyring = creds::Auth::Keyring {
+            service: "firstaide-test".into(),
+            account: "ci".into(),
+        };
+        match keyring.token() {
+            Err(creds::Error::NonInteractive(_)) => (),
+            other => panic!("expected NonInteractive, got {:?}", other),
+        }
+    }
+}
@@ -1,15 +1,27 @@
+use crate::cache;
+use crate::cmds;
 use crate::config;
+use crate::error::Code;
+use crate::history;
+use crate::lock;
+use crate::logging;
+use crate::os;
+use crate::rotate;
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub const NAME: &str = "clean";
 
 type Result = std::result::Result<u8, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Config(config::Error),
     Io(io::Error),
+    Locked(PathBuf),
 }
 
 impl fmt::Display for Error {
@@ -18,6 +30,29 @@ impl fmt::Display for Error {
         match self {
             Config(err) => write!(f, "{}", err),
             Io(err) => write!(f, "input/output error: {}", err),
+            Locked(path) => write!(f, "a build is in progress; {:?} is locked", path),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+            Error::Locked(_) => Code::Locked,
         }
     }
 }
@@ -30,25 +65,549 @@ impl From<config::Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
 pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
     clap::SubCommand::with_name(NAME)
         .about("Cleans the development environment")
+        .long_about(concat!(
+            "Cleans the development environment.\n",
+            "By default this removes everything in the cache dir except what the ",
+            "current environment needs: the cache matching the watched files, and ",
+            "the most recent cache. Use --all to remove everything in the cache dir ",
+            "but the lock, ",
+            "--cache-only to remove only caches, or --logs to remove only logs. ",
+            "With --dry-run, nothing is removed; it says what would be, and how much ",
+            "space that would reclaim.",
+        ))
         .arg(
             clap::Arg::with_name("dir")
                 .value_name("DIR")
                 .help("The directory to clean"),
         )
+        .arg(
+            clap::Arg::with_name("all")
+                .long("all")
+                .conflicts_with_all(&["stale", "cache-only", "logs"])
+                .help("Remove everything in the cache dir, including the active cache"),
+        )
+        .arg(
+            clap::Arg::with_name("cache-only")
+                .long("cache-only")
+                .conflicts_with_all(&["stale", "logs"])
+                .help("Remove only caches, keeping the active cache, and logs"),
+        )
+        .arg(
+            clap::Arg::with_name("logs")
+                .long("logs")
+                .conflicts_with("stale")
+                .help("Remove only logs; not the audit log or the build history"),
+        )
+        .arg(
+            clap::Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Say what would be removed, and how much space that would reclaim"),
+        )
+        .arg(
+            clap::Arg::with_name("stale")
+                .long("stale")
+                .help("Remove only stale cache files, keeping the most recent"),
+        )
+        .arg(
+            clap::Arg::with_name("keep")
+                .long("keep")
+                .value_name("N")
+                .validator(super::is_number)
+                .requires("stale")
+                .help("How many recent cache files to keep; see `clean.keep` in config"),
+        )
+        .arg(
+            clap::Arg::with_name("wait")
+                .long("wait")
+                .help("Wait for a build in progress to finish (default)"),
+        )
+        .arg(
+            clap::Arg::with_name("no-wait")
+                .long("no-wait")
+                .conflicts_with("wait")
+                .help("Fail if a build is in progress"),
+        )
 }
 
 pub fn run(args: &clap::ArgMatches) -> Result {
-    let config = config::Config::load(args.value_of_os("dir"))?;
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let dry_run = args.is_present("dry-run");
+    if !dry_run {
+        config.writable()?;
+    }
+
+    // Don't pull the rug out from under a build in progress. A dry run
+    // removes nothing, so it can go ahead.
+    let _lock = if config.cache_dir.is_dir() && !dry_run {
+        let lock_file = config.lock_file();
+        match lock::Lock::acquire(&lock_file, !args.is_present("no-wait")) {
+            Ok(lock) => Some(lock),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(Error::Locked(lock_file))
+            }
+            Err(err) => return Err(Error::Io(err)),
+        }
+    } else {
+        None
+    };
+
+    let stdout = io::stdout();
+    let mut report = Report {
+        out: stdout.lock(),
+        dry_run,
+        reclaimed: 0,
+    };
+
+    let result = if !config.cache_dir.is_dir() {
+        Ok(0)
+    } else if args.is_present("stale") {
+        let keep = if args.is_present("keep") {
+            value_t!(args, "keep", usize).expect("validated by clap")
+        } else {
+            config.clean.keep
+        };
+        clean_stale(&config, keep, &mut report)
+    } else if args.is_present("all") {
+        clean_all(&config, &mut report)
+    } else if args.is_present("cache-only") {
+        clean_caches(&config, &mut report)
+    } else if args.is_present("logs") {
+        clean_logs(&config, &mut report)
+    } else {
+        clean_inactive(&config, &mut report)
+    };
+    report.summary()?;
+    result
+}
+
+/// Tells the user exactly what was kept and what was removed, or, in a dry
+/// run, what would have been.
+struct Report<W: Write> {
+    out: W,
+    dry_run: bool,
+    /// Bytes removed so far.
+    reclaimed: u64,
+}
+
+impl<W: Write> Report<W> {
+    fn kept(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        writeln!(self.out, "Kept {} ({})", path.display(), reason)
+    }
+
+    /// Remove `path`, a file or a directory, unless this is a dry run.
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let size = disk_usage(path)?;
+        self.reclaimed += size;
+        if self.dry_run {
+            writeln!(
+                self.out,
+                "Would remove {} ({})",
+                path.display(),
+                human_size(size)
+            )
+        } else {
+            if fs::symlink_metadata(path)?.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+            writeln!(
+                self.out,
+                "Removed {} ({})",
+                path.display(),
+                human_size(size)
+            )
+        }
+    }
+
+    fn summary(&mut self) -> io::Result<()> {
+        let verb = if self.dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        };
+        writeln!(self.out, "{} {}.", verb, human_size(self.reclaimed))
+    }
+}
+
+/// The size of `path` in bytes, including everything in it if it's a
+/// directory. Symlinks are not followed.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut size = metadata.len();
+        for entry in fs::read_dir(path)? {
+            size += disk_usage(&entry?.path())?;
+        }
+        Ok(size)
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Why some paths must be kept, keyed by path.
+type Keepers = Vec<(PathBuf, &'static str)>;
+
+/// The paths that the environment as it stands depends upon.
+fn active(config: &config::Config) -> Keepers {
+    let mut keepers: Keepers = Vec::new();
+    if let Some(link) = config.cache_file_most_recent_target() {
+        keepers.push((config.cache_file_most_recent(), "most recent link"));
+        keepers.push((link, "most recent"));
+    }
+    match config.checksums() {
+        Ok(sums_now) => keepers.push((config.cache_file(&sums_now), "current")),
+        Err(err) => log::warn!("Could not calculate checksums: {}", err),
+    }
+    with_alias_targets(config, keepers)
+}
+
+/// Aliases are useless without the caches they stand in for, so keep those
+/// too.
+fn with_alias_targets(config: &config::Config, mut keepers: Keepers) -> Keepers {
+    let targets: Vec<PathBuf> = (keepers.iter())
+        .filter_map(|(path, _)| cache::alias_target(path, &config.cache_keys))
+        .collect();
+    keepers.extend(targets.into_iter().map(|target| (target, "aliased")));
+    keepers
+}
+
+/// Remove objects that no cache in `keepers` is an alias to.
+fn clean_objects<W: Write>(
+    config: &config::Config,
+    keepers: &[(PathBuf, &'static str)],
+    report: &mut Report<W>,
+) -> io::Result<()> {
+    let mut objects = config.object_files()?;
+    objects.sort();
+    for object in objects {
+        match reason(keepers, &object) {
+            Some(reason) => report.kept(&object, reason)?,
+            None => report.remove(&object)?,
+        }
+    }
+    Ok(())
+}
+
+/// Remove the Git state beside, and the branch links to, caches that aren't in
+/// `keepers`.
+fn clean_sidecars<W: Write>(
+    config: &config::Config,
+    keepers: &[(PathBuf, &'static str)],
+    report: &mut Report<W>,
+) -> io::Result<()> {
+    for dir in [config.cache_git_dir(), config.branches_dir()] {
+        clean_sidecars_in(config, &dir, keepers, report)?;
+    }
+    Ok(())
+}
+
+fn clean_sidecars_in<W: Write>(
+    config: &config::Config,
+    dir: &Path,
+    keepers: &[(PathBuf, &'static str)],
+    report: &mut Report<W>,
+) -> io::Result<()> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                paths.push(entry?.path());
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    paths.sort();
+    for path in paths {
+        // Git state is named for its cache; a branch link points to it.
+        let name = match os::pointee(&path) {
+            Ok(target) => target.file_name().map(|name| name.to_owned()),
+            Err(_) => path.file_name().map(|name| name.to_owned()),
+        };
+        let cache_file = config.cache_dir.join(name.unwrap_or_default());
+        if reason(keepers, &cache_file).is_none() {
+            report.remove(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn reason(keepers: &[(PathBuf, &'static str)], path: &Path) -> Option<&'static str> {
+    (keepers.iter())
+        .find(|(keeper, _)| keeper == path)
+        .map(|(_, reason)| *reason)
+}
+
+/// Files that this process has open and must not be removed from under it: the
+/// lock, which it holds, and the log file, which it's writing.
+fn in_use(config: &config::Config) -> Keepers {
+    let mut keepers: Keepers = vec![(config.lock_file(), "lock, held")];
+    if let Some(log_file) = &config.log_file {
+        keepers.push((log_file.clone(), "log, in use"));
+    }
+    keepers
+}
+
+/// Remove everything in the cache dir, the active cache included, except what
+/// is in use. Removing the lock while it's held would let another build in.
+fn clean_all<W: Write>(config: &config::Config, report: &mut Report<W>) -> Result {
+    let keepers = in_use(config);
+    let mut entries: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(&config.cache_dir)? {
+        entries.push(entry?.path());
+    }
+    entries.sort();
+    for path in entries {
+        match reason(&keepers, &path) {
+            Some(reason) => report.kept(&path, reason)?,
+            None => report.remove(&path)?,
+        }
+    }
+    Ok(0)
+}
+
+/// Remove everything in the cache dir except the active cache – the one that
+/// matches the watched files, and the most recent – and what's needed to keep
+/// it working, like the lock, the GC roots and, with `nix develop`, the
+/// profile.
+fn clean_inactive<W: Write>(config: &config::Config, report: &mut Report<W>) -> Result {
+    let mut keepers = active(config);
+    keepers.extend(in_use(config));
+    if config.nix.gc_roots {
+        keepers.push((config.gc_roots_dir(), "gc roots"));
+    }
+
+    let profile_name = config.nix_profile().file_name().map(|name| name.to_owned());
+    let mut entries: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(&config.cache_dir)? {
+        entries.push(entry?.path());
+    }
+    entries.sort();
+
+    for path in entries {
+        let is_profile = config.builder == config::Builder::NixDevelop
+            && match (&profile_name, path.file_name()) {
+                // The profile is a link named `profile`, to links named
+                // `profile-${generation}-link`.
+                (Some(profile), Some(name)) => name
+                    .to_string_lossy()
+                    .starts_with(&*profile.to_string_lossy()),
+                _ => false,
+            };
+        if let Some(reason) = reason(&keepers, &path) {
+            report.kept(&path, reason)?;
+        } else if path == config.objects_dir() {
+            clean_objects(config, &keepers, report)?;
+        } else if path == config.cache_git_dir() || path == config.branches_dir() {
+            clean_sidecars_in(config, &path, &keepers, report)?;
+        } else if is_profile {
+            report.kept(&path, "nix profile")?;
+        } else {
+            report.remove(&path)?;
+        }
+    }
 
-    // Just delete the cache directory.
-    fs::remove_dir_all(&config.cache_dir)?;
+    Ok(0)
+}
 
+/// Remove cache files except the active cache, and other caches that
+/// firstaide keeps to save time, like the captured outside environment and the
+/// hook as last rendered. Logs and everything else stay.
+fn clean_caches<W: Write>(config: &config::Config, report: &mut Report<W>) -> Result {
+    let keepers = active(config);
+    let mut files = config.cache_files()?;
+    files.sort();
+    for file in files {
+        if let Some(reason) = reason(&keepers, &file) {
+            report.kept(&file, reason)?;
+        } else {
+            report.remove(&file)?;
+        }
+    }
+    clean_objects(config, &keepers, report)?;
+    clean_sidecars(config, &keepers, report)?;
+    for file in [
+        config.outside_cache_file(),
+        config.stat_cache_file(),
+        config.hook_output_file(),
+    ] {
+        if file.exists() {
+            report.remove(&file)?;
+        }
+    }
     Ok(0)
 }
+
+/// Remove logs, including rotated logs, and the output of detached builds.
+/// The audit log and the build history are records rather than logs, so they
+/// stay, as does the log file that this process is writing.
+fn clean_logs<W: Write>(config: &config::Config, report: &mut Report<W>) -> Result {
+    let keepers = in_use(config);
+    let mut files = vec![
+        config.build_output_file(),
+        config.hook_error_log_file(),
+        config.daemon_log_file(),
+        config.watch_log_file(),
+    ];
+    let build_log_file = config.build_log_file();
+    files.extend((1..=history::KEEP).map(|n| rotate::rotated(&build_log_file, n)));
+    files.push(build_log_file);
+    if let Some(log_file) = &config.log_file {
+        files.extend((1..=logging::LOG_FILE_KEEP).map(|n| rotate::rotated(log_file, n)));
+        files.push(log_file.clone());
+    }
+    for file in files {
+        if fs::symlink_metadata(&file).is_err() {
+            continue;
+        }
+        match reason(&keepers, &file) {
+            Some(reason) => report.kept(&file, reason)?,
+            None => report.remove(&file)?,
+        }
+    }
+    Ok(0)
+}
+
+/// Remove cache files except for the `keep` most recently written, the one
+/// that the most recent cache link points to, and the one matching the current
+/// state of the watched files. Switching back and forth between branches can
+/// then reuse caches rather than always forcing a rebuild.
+fn clean_stale<W: Write>(config: &config::Config, keep: usize, report: &mut Report<W>) -> Result {
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for file in config.cache_files()? {
+        let modified = fs::metadata(&file)?.modified()?;
+        files.push((modified, file));
+    }
+    // Most recent first.
+    files.sort_by(|(ta, _), (tb, _)| tb.cmp(ta));
+
+    let mut keepers = active(config);
+    keepers.extend((files.iter().take(keep)).map(|(_, file)| (file.clone(), "recent")));
+    let keepers = with_alias_targets(config, keepers);
+    for (_, file) in files {
+        if let Some(reason) = reason(&keepers, &file) {
+            report.kept(&file, reason)?;
+        } else {
+            report.remove(&file)?;
+        }
+    }
+    clean_objects(config, &keepers, report)?;
+    clean_sidecars(config, &keepers, report)?;
+
+    // The profile that `nix develop` maintains is a GC root for the current
+    // environment, but older generations also pin their store paths.
+    if config.builder == config::Builder::NixDevelop && config.nix_profile().exists() {
+        if report.dry_run {
+            log::info!(
+                "Would remove old generations of {:?}.",
+                config.nix_profile()
+            );
+            return Ok(0);
+        }
+        log::info!("Remove old generations of {:?}.", config.nix_profile());
+        let mut command = config.command_to_wipe_nix_profile_history();
+        log::debug!("{:?}", command);
+        if !command.status()?.success() {
+            log::warn!(
+                "Could not remove old generations of {:?}.",
+                config.nix_profile()
+            );
+        }
+    }
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_for_humans() {
+        assert_eq!("0 bytes", human_size(0));
+        assert_eq!("1023 bytes", human_size(1023));
+        assert_eq!("1.0 KiB", human_size(1024));
+        assert_eq!("1.5 MiB", human_size(3 * 512 * 1024));
+        assert_eq!("2.0 GiB", human_size(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn sidecars_go_with_their_caches() {
+        let (_dir, config) = config::test_project("");
+        fs::create_dir_all(config.cache_git_dir()).unwrap();
+        fs::create_dir_all(config.branches_dir()).unwrap();
+        let (kept, gone) = (
+            config.cache_dir.join("cache.a"),
+            config.cache_dir.join("cache.b"),
+        );
+        for cache_file in [&kept, &gone] {
+            fs::write(cache_file, "").unwrap();
+            fs::write(config.cache_git_file(cache_file), "").unwrap();
+        }
+        os::point(&kept, &config.cache_file_on_branch("main")).unwrap();
+        os::point("../cache.b".as_ref(), &config.cache_file_on_branch("dev")).unwrap();
+        let mut report = Report {
+            out: Vec::new(),
+            dry_run: false,
+            reclaimed: 0,
+        };
+        clean_sidecars(&config, &[(kept.clone(), "current")], &mut report).unwrap();
+        assert!(config.cache_git_file(&kept).exists());
+        assert!(!config.cache_git_file(&gone).exists());
+        assert!(fs::symlink_metadata(config.cache_file_on_branch("main")).is_ok());
+        assert!(fs::symlink_metadata(config.cache_file_on_branch("dev")).is_err());
+
+        let others = [
+            config.outside_cache_file(),
+            config.stat_cache_file(),
+            config.hook_output_file(),
+        ];
+        for file in &others {
+            fs::write(file, "").unwrap();
+        }
+        clean_caches(&config, &mut report).unwrap();
+        for file in &others {
+            assert!(!file.exists(), "{}", file.display());
+        }
+    }
+
+    #[test]
+    fn dry_run_removes_nothing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("cache.abc");
+        fs::write(&file, vec![0u8; 2048]).unwrap();
+        let mut report = Report {
+            out: Vec::new(),
+            dry_run: true,
+            reclaimed: 0,
+        };
+        report.remove(&file).unwrap();
+        report.summary().unwrap();
+        assert!(file.exists());
+        let out = String::from_utf8(report.out).unwrap();
+        assert!(out.starts_with("Would remove "));
+        assert!(out.ends_with("(2.0 KiB)\nWould reclaim 2.0 KiB.\n"));
+    }
+}
@@ -0,0 +1,331 @@
+use crate::cmds;
+use crate::config::{self, Config};
+use crate::error::Code;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const NAME: &str = "config";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    NotFound(String),
+    UnknownKey(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            NotFound(name) => write!(f, "command not found: {}", name),
+            UnknownKey(key) => write!(f, "unknown configuration key: {}", key),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+            Error::NotFound(_) | Error::UnknownKey(_) => Code::Usage,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    let dir = clap::Arg::with_name("dir")
+        .value_name("DIR")
+        .help("The directory in which to build");
+    clap::SubCommand::with_name(NAME)
+        .about("Manages the configuration file")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(
+            clap::SubCommand::with_name("fix")
+                .about("Rewrites deprecated configuration keys")
+                .arg(
+                    clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Report what would be rewritten without doing it"),
+                )
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("show")
+                .about("Shows the effective configuration")
+                .long_about(concat!(
+                    "Shows the effective configuration: which files it came from, and ",
+                    "every setting that has a value, with paths resolved and defaults ",
+                    "applied. Settings left at their defaults are marked as such.",
+                ))
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("get")
+                .about("Prints one setting from the effective configuration")
+                .long_about(concat!(
+                    "Prints one setting from the effective configuration, e.g. cache_dir ",
+                    "or hook.outside_ttl, for use in scripts. Strings are printed as-is; ",
+                    "other values as TOML.\n",
+                    "- Exits 0 when the setting has a value.\n",
+                    "- Exits 1 when it is not set.\n",
+                    "- Exits 2 when an error occurs, e.g. the key is not known.",
+                ))
+                .arg(
+                    clap::Arg::with_name("key")
+                        .value_name("KEY")
+                        .required(true)
+                        .help("The setting, e.g. cache_dir"),
+                )
+                .arg(dir),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    match args.subcommand() {
+        ("fix", Some(subm)) => fix(
+            &config::Config::find(subm.value_of_os("dir"))?,
+            subm.is_present("dry-run"),
+        ),
+        ("show", Some(subm)) => show(&cmds::load_config(subm.value_of_os("dir"))?),
+        ("get", Some(subm)) => get(
+            &cmds::load_config(subm.value_of_os("dir"))?,
+            subm.value_of("key").unwrap_or_default(),
+        ),
+        // Not reachable while `SubcommandRequired` is in effect.
+        (name, _) => Err(Error::NotFound(name.into())),
+    }
+}
+
+fn fix(config_file: &Path, dry_run: bool) -> Result {
+    let text = fs::read_to_string(config_file)?;
+    let (fixed, renamed) = config::fix_keys(&text);
+    if renamed.is_empty() {
+        log::info!("Nothing to fix in {:?}.", config_file);
+        return Ok(0);
+    }
+    let verb = if dry_run { "Would rename" } else { "Rename" };
+    for renamed in &renamed {
+        log::info!("{} {} to {}.", verb, renamed.old, renamed.new);
+    }
+    if !dry_run {
+        // Write atomically; a half-written config is worse than none.
+        let temp_file = config_file.with_extension("toml.new");
+        fs::write(&temp_file, fixed)?;
+        fs::rename(&temp_file, config_file)?;
+        log::info!("Updated {:?}.", config_file);
+    }
+    Ok(0)
+}
+
+fn show(config: &Config) -> Result {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    writeln!(
+        &mut handle,
+        "# Configuration: {}",
+        config.config_file.display()
+    )?;
+    if let Some(local_file) = &config.local_file {
+        writeln!(&mut handle, "# Merged with: {}", local_file.display())?;
+    }
+    for overlay in &config.overlays {
+        writeln!(&mut handle, "# Overlay: {}", overlay.file.display())?;
+    }
+    writeln!(
+        &mut handle,
+        "# Build directory: {}",
+        config.build_dir.display()
+    )?;
+    for (key, value) in settings(&config.effective) {
+        let default = if is_set(&config.raw, &key) {
+            ""
+        } else {
+            "  # default"
+        };
+        writeln!(&mut handle, "{} = {}{}", key, inline(value), default)?;
+    }
+    Ok(0)
+}
+
+fn get(config: &Config, key: &str) -> Result {
+    if !config.knows_key(key) {
+        return Err(Error::UnknownKey(key.into()));
+    }
+    let value = (key.split('.')).try_fold(&config.effective, |value, name| value.get(name));
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    match value {
+        Some(toml::Value::String(value)) => writeln!(&mut handle, "{}", value)?,
+        Some(value) => writeln!(&mut handle, "{}", inline(value))?,
+        None => {
+            log::info!("{} is not set.", key);
+            return Ok(1);
+        }
+    }
+    Ok(0)
+}
+
+/// Is `key`, dotted, set in `raw`, i.e. in the configuration as written?
+fn is_set(raw: &toml::Value, key: &str) -> bool {
+    let mut value = raw;
+    for name in key.split('.') {
+        value = match value {
+            toml::Value::Table(table) => match table.get(name) {
+                Some(value) => value,
+                None => return false,
+            },
+            // Set as a whole, e.g. `PATH = "prepend"` for `hook.merge.PATH`.
+            _ => return true,
+        };
+    }
+    true
+}
+
+/// Every setting in `effective`, named as in `.firstaide.toml`, i.e. dotted,
+/// with its value. Tables are walked; anything else, arrays included, is a
+/// setting.
+fn settings(effective: &toml::Value) -> Vec<(String, &toml::Value)> {
+    fn walk<'a>(prefix: &str, value: &'a toml::Value, out: &mut Vec<(String, &'a toml::Value)>) {
+        match value {
+            toml::Value::Table(table) => {
+                for (name, value) in table {
+                    let key = match prefix {
+                        "" => key(name),
+                        prefix => format!("{}.{}", prefix, key(name)),
+                    };
+                    walk(&key, value, out);
+                }
+            }
+            value => out.push((prefix.into(), value)),
+        }
+    }
+    let mut settings = Vec::new();
+    walk("", effective, &mut settings);
+    settings
+}
+
+/// `value` on one line, as TOML: arrays and tables inline.
+fn inline(value: &toml::Value) -> String {
+    match value {
+        toml::Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(inline).collect();
+            format!("[{}]", values.join(", "))
+        }
+        toml::Value::Table(table) if table.is_empty() => "{}".into(),
+        toml::Value::Table(table) => {
+            let pairs: Vec<String> = (table.iter())
+                .map(|(name, value)| format!("{} = {}", key(name), inline(value)))
+                .collect();
+            format!("{{ {} }}", pairs.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// `name` as a TOML key: bare when it can be, otherwise quoted.
+fn key(name: &str) -> String {
+    let bare = (name.chars()).all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if bare && !name.is_empty() {
+        name.into()
+    } else {
+        toml::Value::String(name.into()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn finds_dotted_keys() {
+        let raw: toml::Value = toml::from_str("cache_dir = 1\n[hook]\nminimal = true\n").unwrap();
+        assert!(is_set(&raw, "cache_dir"));
+        assert!(is_set(&raw, "hook.minimal"));
+        assert!(!is_set(&raw, "hook.outside_ttl"));
+        assert!(!is_set(&raw, "prompt.format"));
+
+        let raw: toml::Value = toml::from_str("[hook.merge]\nPATH = \"prepend\"\n").unwrap();
+        assert!(is_set(&raw, "hook.merge.PATH.strategy"));
+    }
+
+    #[test]
+    fn settings_are_every_key_in_the_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(
+            root.join(config::FILENAME),
+            concat!(
+                "cache_dir = \".cache\"\nwatch_exe = \"watch.sh\"\nbuilder = \"flake\"\n",
+                "[[environments.compose]]\nbuild_exe = \"layer.sh\"\n",
+                "[hook.merge]\nPATH = \"prepend\"\n",
+                "[hook.scripts]\nstale = \"stale.sh\"\n",
+                "[cache.signing]\ntrusted = [\"ssh-ed25519 AAAA ci\"]\n",
+            ),
+        )
+        .unwrap();
+        let config = Config::load(Some(&root)).ok().unwrap();
+        let settings: BTreeMap<String, String> = (settings(&config.effective).into_iter())
+            .map(|(key, value)| (key, inline(value)))
+            .collect();
+        let setting = |key: &str| settings.get(key).map(String::as_str);
+        assert_eq!(Some("\"prepend\""), setting("hook.merge.PATH.strategy"));
+        assert_eq!(
+            Some(format!("\"{}\"", root.join("stale.sh").display()).as_str()),
+            setting("hook.scripts.stale")
+        );
+        assert_eq!(
+            Some(
+                format!(
+                    "[{{ build_exe = \"{}\", exclude_prefixes = [], exclude_vars = [] }}]",
+                    root.join("layer.sh").display()
+                )
+                .as_str()
+            ),
+            setting("environments.compose")
+        );
+        assert_eq!(
+            Some("[\"ssh-ed25519 AAAA ci\"]"),
+            setting("cache.signing.trusted")
+        );
+        assert_eq!(Some("\"env\""), setting("cache.remote.auth.provider"));
+        assert_eq!(None, setting("nix.installable"));
+
+        assert!(config.knows_key("nix.installable"));
+        assert!(config.knows_key("hook.merge.PYTHONPATH"));
+        assert!(!config.knows_key("nope"));
+        assert!(!config.knows_key("hook.minimal.nope"));
+    }
+}
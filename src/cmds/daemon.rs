@@ -0,0 +1,236 @@
+use crate::cmds;
+use crate::cmds::build;
+use crate::cmds::status;
+use crate::config;
+use crate::error::Code;
+use crate::history;
+use crate::lock;
+use crate::stamps;
+use crate::status::EnvironmentStatus;
+use crate::watcher;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+pub const NAME: &str = "daemon";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Running(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Running(path) => write!(f, "a daemon is already running; {:?} is locked", path),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+            Error::Running(_) => Code::Locked,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Rebuilds the development environment automatically, in the background")
+        .long_about(concat!(
+            "Rebuilds the development environment automatically, in the background.\n",
+            "Once the watched files have been quiet for a while, and the environment is ",
+            "stale, this runs a build, which prompts direnv to reload the shell at the ",
+            "next prompt. Its output goes to ",
+            "daemon.log in the cache dir. Only one daemon runs per cache dir.",
+        ))
+        .arg(
+            clap::Arg::with_name("foreground")
+                .long("foreground")
+                .help("Stay in the foreground"),
+        )
+        .arg(
+            clap::Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .validator(super::is_positive)
+                .default_value("2")
+                .help("How often to check the watched files when none has changed"),
+        )
+        .arg(
+            clap::Arg::with_name("debounce")
+                .long("debounce")
+                .value_name("SECONDS")
+                .validator(super::is_number)
+                .default_value("3")
+                .help("How long the watched files must be unchanged before building"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    config.writable()?;
+    let interval = value_t!(args, "interval", u64).expect("validated by clap");
+    let debounce = value_t!(args, "debounce", u64).expect("validated by clap");
+
+    fs::create_dir_all(&config.cache_dir)?;
+    let lock_file = config.daemon_lock_file();
+    if args.is_present("foreground") {
+        let _lock = match lock::Lock::acquire(&lock_file, false) {
+            Ok(lock) => lock,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(Error::Running(lock_file))
+            }
+            Err(err) => return Err(Error::Io(err)),
+        };
+        daemon(
+            &config,
+            Duration::from_secs(interval),
+            Duration::from_secs(debounce),
+        )
+    } else if lock::is_locked(&lock_file) {
+        Err(Error::Running(lock_file))
+    } else {
+        let output_file = config.daemon_log_file();
+        let output = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&output_file)?;
+        let mut command = process::Command::new(&config.self_exe);
+        command
+            .arg(NAME)
+            .arg(&config.build_dir)
+            .arg("--foreground")
+            .arg("--interval")
+            .arg(interval.to_string())
+            .arg("--debounce")
+            .arg(debounce.to_string());
+        let child = build::spawn_detached(&mut command, output)?;
+        log::info!(
+            "Daemon running (PID {}); output in {:?}.",
+            child.id(),
+            output_file
+        );
+        Ok(0)
+    }
+}
+
+/// Watch the watched files and build once they have been quiet for `debounce`
+/// and the environment is not up to date. A build that fails is not retried
+/// until the watched files change again, and a check that fails is tried
+/// again, later and later, rather than ending the daemon.
+fn daemon(config: &config::Config, interval: Duration, debounce: Duration) -> Result {
+    log::info!("Watching {:?}.", &config.build_dir);
+    let mut watcher = watcher::Watcher::new(config, interval);
+    let mut stamps_last: Option<stamps::Stamps> = None;
+    let mut changed_at = Instant::now();
+    let mut failed_at: Option<stamps::Stamps> = None;
+    loop {
+        let stamps_now = match config.watch_files() {
+            Ok(files) => stamps::Stamps::of(&files),
+            Err(err) => {
+                watcher.retry_after(err);
+                continue;
+            }
+        };
+        if stamps_last.as_ref() != Some(&stamps_now) {
+            changed_at = Instant::now();
+            stamps_last = Some(stamps_now);
+        } else if changed_at.elapsed() >= debounce
+            && failed_at.as_ref() != Some(&stamps_now)
+            // Someone else is building; let them get on with it.
+            && !lock::is_locked(config.lock_file())
+            && !(config.shared_lock_file().iter()).any(lock::is_locked)
+        {
+            let sums_now = match stamps::checksums(config) {
+                Ok(sums_now) => sums_now,
+                Err(err) => {
+                    watcher.retry_after(err);
+                    continue;
+                }
+            };
+            if status::status_of(config, &sums_now) != EnvironmentStatus::Okay {
+                if build(config) {
+                    failed_at = None;
+                } else {
+                    // The build may not have got far enough to do this.
+                    failed_at = Some(stamps_now);
+                    if let Err(err) = history::prompt_reload(config) {
+                        log::warn!("Could not prompt a reload: {}", err);
+                    }
+                }
+            }
+        }
+        // Wake once the files have been quiet for long enough, if sooner.
+        let quiet = debounce.saturating_sub(changed_at.elapsed());
+        watcher.wait(Some(quiet).filter(|quiet| !quiet.is_zero()));
+    }
+}
+
+/// Run a build as a separate process so that, should it crash, the daemon
+/// carries on. Returns true if it succeeded.
+fn build(config: &config::Config) -> bool {
+    log::info!("Environment is not up to date; building.");
+    let mut command = process::Command::new(&config.self_exe);
+    command
+        .arg(build::NAME)
+        .arg(&config.build_dir)
+        .arg("--no-wait");
+    log::debug!("{:?}", command);
+    match command.status() {
+        Ok(status) if status.success() => {
+            log::info!("Build finished.");
+            true
+        }
+        Ok(status) => {
+            log::warn!("Build failed ({}).", status);
+            false
+        }
+        Err(err) => {
+            log::warn!("Build could not be started: {}", err);
+            false
+        }
+    }
+}
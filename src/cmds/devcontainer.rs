@@ -0,0 +1,191 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::{export, hook};
+use crate::config;
+use crate::error::Code;
+use crate::json;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const NAME: &str = "devcontainer";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Cache(cache::Error),
+    Export(export::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Cache(err) => write!(f, "{}", err),
+            Export(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Cache(err) => Some(err),
+            Export(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+            Error::Cache(err) => err.code(),
+            Error::Export(err) => err.code(),
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<export::Error> for Error {
+    fn from(error: export::Error) -> Self {
+        Error::Export(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Writes a devcontainer.json that builds the environment with firstaide")
+        .long_about(concat!(
+            "Writes a devcontainer.json that builds the environment with firstaide.\n",
+            "Its postCreateCommand runs `firstaide build`, and, when the environment has ",
+            "been built here, its remoteEnv is set from it, as `export --format ",
+            "devcontainer` sets it. When OUT already exists, only those properties are ",
+            "replaced.",
+        ))
+        .arg(
+            clap::Arg::with_name("out")
+                .short("o")
+                .long("out")
+                .value_name("OUT")
+                .help("Where to write; defaults to .devcontainer/devcontainer.json"),
+        )
+        .arg(
+            clap::Arg::with_name("strip-paths")
+                .long("strip-paths")
+                .help("Leave out variables that hold paths on this host, like PATH"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let out = match args.value_of_os("out") {
+        Some(out) => PathBuf::from(out),
+        None => config.build_dir.join(".devcontainer/devcontainer.json"),
+    };
+    let existing = Some(out.as_path()).filter(|out| out.exists());
+
+    let mut properties: Vec<(String, json::Value)> = Vec::new();
+    if existing.is_none() {
+        if let Some(name) = config.build_dir.file_name() {
+            properties.push(("name".into(), name.to_string_lossy().into_owned().into()));
+        }
+    }
+    properties.push(("postCreateCommand".into(), "firstaide build".into()));
+    // Not having built here yet is fine; the container builds for itself.
+    // A cache that's there but cannot be read is another matter.
+    let env_diff = match config.load_cache(&config.checksums()?) {
+        Ok((cache, _)) => Some(export::strip_paths_if(
+            args.is_present("strip-paths"),
+            hook::diff_cached(&config, &cache),
+        )),
+        Err(cache::Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            log::info!("Leaving remoteEnv as it is; the environment is not built.");
+            None
+        }
+        Err(err) => return Err(Error::Cache(err)),
+    };
+
+    let output = export::devcontainer(env_diff.as_ref(), existing, properties)?;
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&out, output)?;
+    log::info!("Wrote {:?}.", out);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> (tempfile::TempDir, config::Config) {
+        let (dir, config) = config::test_project("");
+        fs::create_dir(&config.cache_dir).unwrap();
+        fs::write(
+            dir.path().join("watch.sh"),
+            "#!/bin/sh\necho .firstaide.toml\n",
+        )
+        .unwrap();
+        (dir, config)
+    }
+
+    fn run_in(dir: &std::path::Path) -> Result {
+        run(&argspec().get_matches_from(vec!["devcontainer".as_ref(), dir.as_os_str()]))
+    }
+
+    #[test]
+    fn writes_a_devcontainer_without_an_environment() {
+        let (dir, _config) = project();
+        assert_eq!(0, run_in(dir.path()).ok().unwrap());
+        let written = dir.path().join(".devcontainer/devcontainer.json");
+        let written = json::parse(&fs::read_to_string(written).unwrap()).unwrap();
+        assert_eq!(
+            Some("firstaide build"),
+            written
+                .get("postCreateCommand")
+                .and_then(json::Value::as_str)
+        );
+        assert!(written.get("name").is_some());
+        assert_eq!(None, written.get("remoteEnv"));
+    }
+
+    #[test]
+    fn fails_on_a_cache_it_cannot_read() {
+        let (dir, config) = project();
+        let cache_file = config.cache_file(&config.checksums().unwrap());
+        fs::write(cache_file, "not a cache").unwrap();
+        match run_in(dir.path()) {
+            Err(err @ Error::Cache(_)) => assert_eq!(6, err.code().exit_code()),
+            other => panic!("expected a cache error, got {:?}", other),
+        }
+        assert!(!dir.path().join(".devcontainer").exists());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::hook;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use crate::json;
+use crate::sums;
+use crate::term;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const NAME: &str = "diff";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Hook(hook::Error),
+    Cache(cache::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Hook(err) => write!(f, "{}", err),
+            Cache(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Hook(err) => Some(err),
+            Cache(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Hook(err) => err.code(),
+            Cache(err) => err.code(),
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<hook::Error> for Error {
+    fn from(error: hook::Error) -> Self {
+        Error::Hook(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Shows the changes the hook would make to the environment")
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["table", "json"])
+                .default_value("table")
+                .help("The output format"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let env_diff = compose(&config, &config.checksums()?)?;
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    match args.value_of("format") {
+        Some("json") => writeln!(&mut handle, "{}", to_json(&env_diff))?,
+        _ => {
+            for change in &env_diff {
+                let origin =
+                    term::Style::Dim.paint(&format!("{:<8}", origin_of(&env_diff, change)));
+                let name = change.name().to_string_lossy();
+                match change {
+                    env::Added(_, value) => writeln!(
+                        &mut handle,
+                        "{} {} {}={}",
+                        origin,
+                        term::Style::Okay.paint("+"),
+                        term::Style::Bold.paint(&name),
+                        value.to_string_lossy()
+                    )?,
+                    env::Changed(_, _, value) => writeln!(
+                        &mut handle,
+                        "{} {} {}={}",
+                        origin,
+                        term::Style::Warning.paint("~"),
+                        term::Style::Bold.paint(&name),
+                        value.to_string_lossy()
+                    )?,
+                    env::Removed(_, _) => writeln!(
+                        &mut handle,
+                        "{} {} {}",
+                        origin,
+                        term::Style::Error.paint("-"),
+                        term::Style::Bold.paint(&name)
+                    )?,
+                }
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+/// Compose the diff exactly as the hook does.
+pub fn compose(
+    config: &config::Config,
+    sums_now: &sums::Checksums,
+) -> std::result::Result<env::Diff, Error> {
+    let mut env_diff = hook::diff_outside(config)?;
+    let (cache, _) = config.load_cache(sums_now).map_err(Error::Cache)?;
+    env_diff.extend(hook::diff_cached(config, &cache));
+    env_diff.simplify();
+    Ok(env_diff)
+}
+
+fn origin_of(diff: &env::Diff, change: &env::Change) -> String {
+    match diff.origin(change.name()) {
+        Some(origin) => origin.to_string(),
+        None => "unknown".into(),
+    }
+}
+
+pub fn to_json(diff: &env::Diff) -> json::Value {
+    let lossy = |s: &std::ffi::OsString| json::Value::from(s.to_string_lossy().into_owned());
+    json::Value::Array(
+        diff.iter()
+            .map(|change| {
+                let (kind, before, after) = match change {
+                    env::Added(_, vb) => ("added", json::Value::Null, lossy(vb)),
+                    env::Changed(_, va, vb) => ("changed", lossy(va), lossy(vb)),
+                    env::Removed(_, va) => ("removed", lossy(va), json::Value::Null),
+                };
+                json::Value::object(vec![
+                    ("name", lossy(change.name())),
+                    ("change", kind.into()),
+                    ("before", before),
+                    ("after", after),
+                    ("origin", origin_of(diff, change).into()),
+                ])
+            })
+            .collect(),
+    )
+}
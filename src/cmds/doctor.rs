@@ -0,0 +1,274 @@
+use crate::cmds;
+use crate::cmds::status;
+use crate::config;
+use crate::error::Code;
+use crate::i18n;
+use crate::os;
+use crate::status::EnvironmentStatus;
+use crate::term;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const NAME: &str = "doctor";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Io(_) => Code::Io,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Checks that the project and this machine are set up to use firstaide")
+        .long_about(concat!(
+            "Checks that the project and this machine are set up to use firstaide: ",
+            "that the config loads, that direnv and the build and watch executables ",
+            "are there, that `.envrc` uses the hook, that the cache dir is writable, ",
+            "and whether the environment is built.\n",
+            "- Exits 0 when all is well, or there are only warnings.\n",
+            "- Exits 1 when there are problems.\n",
+            "- Exits 2 when an error occurs.",
+        ))
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+/// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Okay,
+    /// Worth knowing, but firstaide works regardless.
+    Warning,
+    /// firstaide will not work until this is put right.
+    Problem,
+}
+
+impl Outcome {
+    fn marked(self, text: &str) -> String {
+        let (style, glyph, label) = match self {
+            Outcome::Okay => (term::Style::Okay, "✔", "doctor.ok"),
+            Outcome::Warning => (term::Style::Warning, "▲", "doctor.warning"),
+            Outcome::Problem => (term::Style::Error, "✘", "doctor.problem"),
+        };
+        if term::enabled() {
+            style.mark(glyph, text)
+        } else {
+            format!("{}: {}", i18n::tr(label), text)
+        }
+    }
+}
+
+type Check = (Outcome, String);
+
+/// The message `key`, with `arg` in place of `{}`.
+fn message(key: &str, arg: impl fmt::Display) -> String {
+    i18n::tr(key).replacen("{}", &arg.to_string(), 1)
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let checks = checks(args.value_of_os("dir").map(Path::new));
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    for (outcome, text) in &checks {
+        writeln!(&mut handle, "{}", outcome.marked(text))?;
+    }
+    let problems = (checks.iter())
+        .filter(|(outcome, _)| *outcome == Outcome::Problem)
+        .count();
+    Ok(if problems == 0 { 0 } else { 1 })
+}
+
+/// Check what firstaide needs, in the order that a user would put things
+/// right. Without config there's nothing more to check.
+fn checks(dir: Option<&Path>) -> Vec<Check> {
+    let config = match cmds::load_config(dir) {
+        Ok(config) => config,
+        Err(err) => return vec![(Outcome::Problem, message("doctor.config-failed", err))],
+    };
+    vec![
+        (
+            Outcome::Okay,
+            message("doctor.config", config.config_file.display()),
+        ),
+        check_direnv(&config),
+        check_build_exe(&config),
+        check_watch_exe(&config),
+        check_envrc(&config),
+        check_cache_dir(&config),
+        check_environment(&config),
+    ]
+}
+
+fn check_direnv(config: &config::Config) -> Check {
+    match config.direnv_exe() {
+        Ok(direnv) => (Outcome::Okay, message("doctor.direnv", direnv.display())),
+        Err(_) => (Outcome::Problem, i18n::tr("doctor.no-direnv")),
+    }
+}
+
+fn check_build_exe(config: &config::Config) -> Check {
+    let build_exe = match &config.build_exe {
+        Some(build_exe) => build_exe,
+        // The Nix builders run `nix`.
+        None => match config::search_path("nix") {
+            Some(nix) => return (Outcome::Okay, message("doctor.nix", nix.display())),
+            None => return (Outcome::Problem, i18n::tr("doctor.no-nix")),
+        },
+    };
+    match fs::metadata(build_exe) {
+        Ok(meta) if os::is_executable(&meta) => (
+            Outcome::Okay,
+            message("doctor.build-exe", build_exe.display()),
+        ),
+        _ => (
+            Outcome::Problem,
+            message("doctor.build-exe-missing", build_exe.display()),
+        ),
+    }
+}
+
+fn check_watch_exe(config: &config::Config) -> Check {
+    match config.watch_files() {
+        Ok(files) => (Outcome::Okay, message("doctor.watch-exe", files.len())),
+        Err(err) => (Outcome::Problem, message("doctor.watch-exe-failed", err)),
+    }
+}
+
+/// Does `.envrc` use the hook? Just mentioning `firstaide` will do; there are
+/// many ways to write it.
+fn check_envrc(config: &config::Config) -> Check {
+    let envrc = config.build_dir.join(".envrc");
+    match fs::read_to_string(&envrc) {
+        Ok(content) if content.contains("firstaide") => {
+            (Outcome::Okay, message("doctor.envrc", envrc.display()))
+        }
+        Ok(_) => (
+            Outcome::Warning,
+            message("doctor.envrc-no-hook", envrc.display()),
+        ),
+        Err(_) => (
+            Outcome::Warning,
+            message("doctor.no-envrc", envrc.display()),
+        ),
+    }
+}
+
+fn check_cache_dir(config: &config::Config) -> Check {
+    if config.observer {
+        return (
+            Outcome::Okay,
+            message("doctor.observer", config.cache_dir.display()),
+        );
+    }
+    // It's created by the first build, from the nearest directory that exists.
+    let dir = (config.cache_dir.ancestors())
+        .find(|dir| dir.exists())
+        .unwrap_or(&config.cache_dir);
+    match tempfile::tempfile_in(dir) {
+        Ok(_) => (
+            Outcome::Okay,
+            message("doctor.cache-dir", config.cache_dir.display()),
+        ),
+        Err(err) => (
+            Outcome::Problem,
+            message(
+                "doctor.cache-dir-unwritable",
+                format!("{} ({})", config.cache_dir.display(), err),
+            ),
+        ),
+    }
+}
+
+fn check_environment(config: &config::Config) -> Check {
+    let sums_now = match config.checksums() {
+        Ok(sums_now) => sums_now,
+        Err(err) => return (Outcome::Problem, message("doctor.watch-exe-failed", err)),
+    };
+    let status = status::status_of(config, &sums_now);
+    let outcome = match status {
+        EnvironmentStatus::Okay => Outcome::Okay,
+        _ => Outcome::Warning,
+    };
+    (outcome, status.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn checks_a_project() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        assert_eq!(Outcome::Problem, checks(Some(root))[0].0);
+
+        fs::write(
+            root.join(".firstaide.toml"),
+            "cache_dir = \".cache\"\nbuild_exe = \"build.sh\"\nwatch_exe = \"watch.sh\"\n",
+        )
+        .unwrap();
+        let watch_exe = root.join("watch.sh");
+        fs::write(&watch_exe, "#!/bin/sh\necho watched\n").unwrap();
+        fs::set_permissions(&watch_exe, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join(".envrc"), "eval \"$(firstaide hook)\"\n").unwrap();
+        let checks = checks(Some(root));
+        let outcomes: Vec<Outcome> = checks.iter().map(|(outcome, _)| *outcome).collect();
+        // Config, direnv (whatever this machine has), build, watch, `.envrc`,
+        // cache dir, and the environment, which is not built.
+        assert_eq!(7, outcomes.len());
+        assert_eq!(
+            [
+                Outcome::Okay,
+                Outcome::Problem,
+                Outcome::Okay,
+                Outcome::Okay
+            ],
+            [outcomes[0], outcomes[2], outcomes[3], outcomes[4]],
+        );
+        assert_eq!(
+            [Outcome::Okay, Outcome::Warning],
+            [outcomes[5], outcomes[6]]
+        );
+        assert!(checks[2].1.contains("build.sh"));
+    }
+}
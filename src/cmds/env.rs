@@ -1,9 +1,16 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::hook;
+use crate::config;
+use crate::crypt;
+use crate::error::Code;
 use bincode;
 use std::env;
 use std::ffi::OsString;
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::path::Path;
 
 pub type Env = Vec<(OsString, OsString)>;
 
@@ -11,9 +18,13 @@ pub const NAME: &str = "env";
 
 type Result = std::result::Result<u8, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Encode(bincode::Error),
+    Config(config::Error),
+    Base(crate::env::CaptureError),
+    Cache(cache::Error),
 }
 
 impl fmt::Display for Error {
@@ -22,13 +33,43 @@ impl fmt::Display for Error {
         match self {
             Io(err) => write!(f, "input/output error: {}", err),
             Encode(err) => write!(f, "could not encode environment: {}", err),
+            Config(err) => write!(f, "{}", err),
+            Base(err) => write!(f, "could not read base environment: {}", err),
+            Cache(err) => write!(f, "could not read diff: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            Encode(err) => Some(err),
+            Config(err) => Some(err),
+            Base(err) => Some(err),
+            Cache(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Io(_) => Code::Io,
+            Encode(_) => Code::Other,
+            Config(err) => err.code(),
+            Base(err) => err.code(),
+            Cache(err) => err.code(),
         }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
@@ -38,6 +79,12 @@ impl From<bincode::Error> for Error {
     }
 }
 
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
 pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
     clap::SubCommand::with_name(NAME)
         .about("Serialize the environment")
@@ -48,16 +95,106 @@ pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
                 .value_name("OUT")
                 .help("Where to dump the environment; defaults to stdout"),
         )
+        .subcommand(
+            clap::SubCommand::with_name("compose")
+                .about("Composes a cached diff onto a base environment")
+                .long_about(concat!(
+                    "Composes a cached diff onto a base environment, as the hook does, ",
+                    "and prints the resulting environment.\n",
+                    "Within a project, or with DIR, the project's policies apply: ",
+                    "exclude_vars, hook.exclude_prefixes and hook.include_vars, and ",
+                    "hook.merge. Elsewhere, cached values replace those in the base.",
+                ))
+                .arg(
+                    clap::Arg::with_name("base")
+                        .long("base")
+                        .value_name("FILE")
+                        .help("A dump from `firstaide env`; defaults to the environment here"),
+                )
+                .arg(
+                    clap::Arg::with_name("diff")
+                        .long("diff")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("A cache file"),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["dump", "json"])
+                        .default_value("json")
+                        .help("The output format"),
+                )
+                .arg(
+                    clap::Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .value_name("OUT")
+                        .help("Where to write; defaults to stdout"),
+                )
+                .arg(
+                    clap::Arg::with_name("dir")
+                        .value_name("DIR")
+                        .help("The directory in which to build"),
+                ),
+        )
 }
 
 pub fn run(args: &clap::ArgMatches) -> Result {
+    if let ("compose", Some(args)) = args.subcommand() {
+        return compose(args);
+    }
     let env: Env = env::vars_os().collect();
-    match args.value_of_os("out") {
-        None => bincode::serialize_into(io::stdout().lock(), &env)?,
-        Some(out) => bincode::serialize_into(
-            fs::OpenOptions::new().write(true).create(true).open(&out)?,
-            &env,
-        )?,
+    let dump = crate::env::encode_dump(&env)?;
+    write(args.value_of_os("out"), &dump)
+}
+
+fn compose(args: &clap::ArgMatches) -> Result {
+    let config = match cmds::load_config(args.value_of_os("dir")) {
+        Ok(config) => Some(config),
+        Err(config::Error::ConfigNotFound(dir)) => {
+            log::debug!("No configuration found from {:?}.", dir);
+            None
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let base: Env = match args.value_of_os("base") {
+        Some(base) => crate::env::read_dump(Path::new(base)).map_err(Error::Base)?,
+        None => env::vars_os().collect(),
+    };
+    let lookup = |name: &std::ffi::OsStr| {
+        (base.iter())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let diff_file = args.value_of_os("diff").unwrap_or_default();
+    let composed = match &config {
+        Some(config) => {
+            let cache = cache::Cache::load(diff_file, &config.cache_keys).map_err(Error::Cache)?;
+            let diff = hook::diff_relocated(config, &cache);
+            crate::env::compose(lookup, &diff, &hook::policy(config))
+        }
+        None => {
+            let keys = crypt::Keys::new(None);
+            let cache = cache::Cache::load(diff_file, &keys).map_err(Error::Cache)?;
+            crate::env::compose(lookup, &cache.diff, &crate::env::Policy::default())
+        }
+    };
+    let env = crate::env::apply(&base, &composed);
+
+    let output = match args.value_of("format") {
+        Some("dump") => crate::env::encode_dump(&env)?,
+        _ => format!("{}\n", crate::env::env_to_json(&env)).into_bytes(),
+    };
+    write(args.value_of_os("out"), &output)
+}
+
+fn write(out: Option<&std::ffi::OsStr>, data: &[u8]) -> Result {
+    match out {
+        None => io::stdout().lock().write_all(data)?,
+        Some(out) => fs::write(out, data)?,
     };
     Ok(0)
 }
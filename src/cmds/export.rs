@@ -0,0 +1,724 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::hook;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use crate::json;
+use crate::os::OsStrExt;
+use std::env::var_os;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const NAME: &str = "export";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Cache(cache::Error),
+    Patch(PathBuf, String),
+    NotGitHubActions(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Cache(err) => write!(f, "{}", err),
+            Patch(path, message) => write!(f, "cannot update {:?}: {}", path, message),
+            NotGitHubActions(name) => write!(f, "{} is not set; is this GitHub Actions?", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Cache(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Cache(err) => err.code(),
+            Patch(_, _) => Code::Config,
+            NotGitHubActions(_) => Code::Usage,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(error: cache::Error) -> Self {
+        Error::Cache(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Exports the cached environment for use by other tools")
+        .long_about(concat!(
+            "Exports the cached environment for use by other tools.\n",
+            "With --format dotenv, each variable that the environment sets is written ",
+            "as KEY='value', or KEY=\"value\" with backslash escapes when the value ",
+            "contains a single quote or a newline. Each variable that the environment ",
+            "removes is written as a comment, # unset KEY.\n",
+            "With --format json, the environment is written as an object mapping ",
+            "names to values, or to null for variables that the environment removes.\n",
+            "With --format null, each variable that the environment sets is written as ",
+            "KEY=value followed by a NUL byte, like `env -0`. Removed variables are left out.\n",
+            "With --format devcontainer, the environment is written as the remoteEnv ",
+            "property of a devcontainer.json; when OUT already exists, only its remoteEnv ",
+            "property is replaced.\n",
+            "With --format dockerfile, each variable that the environment sets is written ",
+            "as an ENV instruction, for inclusion in a Dockerfile.\n",
+            "With --format systemd, each variable that the environment sets is written ",
+            "as KEY=\"value\", for an EnvironmentFile= in a systemd unit.\n",
+            "With --format docker-env, each variable that the environment sets is written ",
+            "as KEY=value, unquoted, for `docker run --env-file`. Values with newlines ",
+            "cannot be written so, and become comments, as do removed variables.\n",
+            "With --strip-paths, variables whose values are paths on this host, like PATH, ",
+            "are left out; they mean little in a container.\n",
+            "With --format github-actions, the environment is appended to the files named ",
+            "by $GITHUB_ENV and, for directories to add to PATH, $GITHUB_PATH, so that the ",
+            "steps that follow in a GitHub Actions job run in it. OUT is not used.",
+        ))
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&[
+                    "dotenv",
+                    "json",
+                    "null",
+                    "devcontainer",
+                    "dockerfile",
+                    "docker-env",
+                    "systemd",
+                    "github-actions",
+                ])
+                .default_value("dotenv")
+                .help("The output format"),
+        )
+        .arg(
+            clap::Arg::with_name("strip-paths")
+                .long("strip-paths")
+                .help("Leave out variables that hold paths on this host, like PATH"),
+        )
+        .arg(
+            clap::Arg::with_name("out")
+                .short("o")
+                .long("out")
+                .value_name("OUT")
+                .help("Where to write; defaults to stdout"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let sums_now = config.checksums()?;
+    let (cache, _) = config.load_cache(&sums_now)?;
+    let env_diff = strip_paths_if(
+        args.is_present("strip-paths"),
+        hook::diff_cached(&config, &cache),
+    );
+
+    if args.value_of("format") == Some("github-actions") {
+        GitHubActions::from_env()?.write(&env_diff)?;
+        return Ok(0);
+    }
+    let output = match args.value_of("format") {
+        Some("json") => format!("{}\n", env::to_json(&env_diff)).into_bytes(),
+        Some("null") => null(&env_diff),
+        Some("devcontainer") => {
+            let existing = match args.value_of_os("out") {
+                Some(out) if Path::new(out).exists() => Some(Path::new(out)),
+                _ => None,
+            };
+            devcontainer(Some(&env_diff), existing, Vec::new())?
+        }
+        Some("dockerfile") => dockerfile(&env_diff),
+        Some("systemd") => systemd(&env_diff),
+        Some("docker-env") => docker_env(&env_diff),
+        _ => dotenv(&env_diff),
+    };
+    match args.value_of_os("out") {
+        None => io::stdout().lock().write_all(&output)?,
+        Some(out) => fs::write(out, &output)?,
+    }
+    Ok(0)
+}
+
+pub fn dotenv(diff: &env::Diff) -> Vec<u8> {
+    let mut output = Vec::new();
+    for change in diff {
+        let name = change.name();
+        if !is_identifier(name) {
+            // Like exported Bash functions, e.g. `BASH_FUNC_foo%%`.
+            log::warn!("Skipping {:?}; it cannot be named in a .env file.", name);
+            continue;
+        }
+        match change {
+            env::Added(_, value) | env::Changed(_, _, value) => {
+                output.extend(name.as_bytes());
+                output.push(b'=');
+                dotenv_quote_into(value.as_bytes(), &mut output);
+                output.push(b'\n');
+            }
+            env::Removed(_, _) => {
+                output.extend(b"# unset ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+    output
+}
+
+/// The files from which GitHub Actions sets up the environment for the steps
+/// that follow in a job.
+pub struct GitHubActions {
+    env: PathBuf,
+    path: PathBuf,
+}
+
+impl GitHubActions {
+    /// From `$GITHUB_ENV` and `$GITHUB_PATH`, which are set in every job.
+    pub fn from_env() -> std::result::Result<Self, Error> {
+        let file = |name| {
+            var_os(name)
+                .map(PathBuf::from)
+                .ok_or(Error::NotGitHubActions(name))
+        };
+        Ok(Self {
+            env: file("GITHUB_ENV")?,
+            path: file("GITHUB_PATH")?,
+        })
+    }
+
+    /// Append the environment: PATH's new directories to `$GITHUB_PATH`,
+    /// everything else to `$GITHUB_ENV`.
+    pub fn write(&self, diff: &env::Diff) -> io::Result<()> {
+        let (diff, paths) = github_path(diff, var_os("PATH").as_deref());
+        append(&self.env, &github_env(&diff))?;
+        append(&self.path, &paths)
+    }
+}
+
+fn append(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?
+        .write_all(data)
+}
+
+/// `diff` without PATH, and, for `$GITHUB_PATH`, the directories in PATH as
+/// `diff` leaves it that are not in `current`. GitHub Actions puts each line
+/// in front of the ones before, so they're written last first. Directories
+/// that `diff` takes out of PATH stay there; `$GITHUB_PATH` can only add.
+fn github_path(diff: &env::Diff, current: Option<&OsStr>) -> (env::Diff, Vec<u8>) {
+    let separator = env::PATH_SEPARATOR.as_bytes()[0];
+    let current: Vec<&[u8]> = match current {
+        Some(current) => current.as_bytes().split(|&b| b == separator).collect(),
+        None => Vec::new(),
+    };
+    let mut paths = Vec::new();
+    for change in diff {
+        match change {
+            env::Added(name, value) | env::Changed(name, _, value) if name == "PATH" => {
+                let dirs = value.as_bytes().split(|&b| b == separator);
+                for dir in dirs.rev() {
+                    if !dir.is_empty() && !current.contains(&dir) {
+                        paths.extend(dir);
+                        paths.push(b'\n');
+                    }
+                }
+            }
+            env::Removed(name, _) if name == "PATH" => {
+                log::warn!("Environment removes PATH; GitHub Actions cannot do that.");
+            }
+            _ => (),
+        }
+    }
+    (diff.exclude_by(|change| change.name() == "PATH"), paths)
+}
+
+/// For GitHub Actions' `$GITHUB_ENV` file, which sets variables for the steps
+/// that follow. Each is written as `KEY<<DELIMITER`, its value, and the
+/// delimiter, which is chosen so that it's not a line of any value. There's
+/// no way to unset a variable there, and empty is not the same as unset, so
+/// removed variables are skipped, with a warning.
+pub fn github_env(diff: &env::Diff) -> Vec<u8> {
+    let mut delimiter = b"FIRSTAIDE_EOF".to_vec();
+    let clashes = |delimiter: &[u8]| {
+        diff.iter().any(|change| match change {
+            env::Added(_, value) | env::Changed(_, _, value) => value
+                .as_bytes()
+                .split(|&b| b == b'\n')
+                .any(|line| line == delimiter),
+            env::Removed(_, _) => false,
+        })
+    };
+    while clashes(&delimiter) {
+        delimiter.push(b'_');
+    }
+    let mut output = Vec::new();
+    for change in diff {
+        let name = change.name();
+        if !is_identifier(name) {
+            log::warn!("Skipping {:?}; GitHub Actions cannot name it.", name);
+            continue;
+        }
+        let value = match change {
+            env::Added(_, value) | env::Changed(_, _, value) => value.as_bytes(),
+            env::Removed(_, _) => {
+                log::warn!("Skipping {:?}; GitHub Actions cannot unset it.", name);
+                continue;
+            }
+        };
+        output.extend(name.as_bytes());
+        output.extend(b"<<");
+        output.extend(&delimiter);
+        output.push(b'\n');
+        output.extend(value);
+        output.push(b'\n');
+        output.extend(&delimiter);
+        output.push(b'\n');
+    }
+    output
+}
+
+/// A `devcontainer.json` with `properties` set, and `remoteEnv` set from the
+/// environment, if there's one; see `patch_json`. The `devcontainer` command
+/// writes one of these too.
+pub fn devcontainer(
+    diff: Option<&env::Diff>,
+    existing: Option<&Path>,
+    mut properties: Vec<(String, json::Value)>,
+) -> std::result::Result<Vec<u8>, Error> {
+    if let Some(diff) = diff {
+        properties.push(("remoteEnv".into(), env::to_json(diff)));
+    }
+    patch_json(existing, properties)
+}
+
+/// A JSON object with `properties` set, or, given an `existing` file, that
+/// file with those properties replaced or added. Comments and trailing commas
+/// in an existing file are tolerated but not preserved.
+fn patch_json(
+    existing: Option<&Path>,
+    properties: Vec<(String, json::Value)>,
+) -> std::result::Result<Vec<u8>, Error> {
+    let mut pairs = match existing {
+        None => Vec::new(),
+        Some(path) => {
+            let text = fs::read_to_string(path)?;
+            match json::parse_jsonc(&text) {
+                Ok(json::Value::Object(pairs)) => pairs,
+                Ok(_) => return Err(Error::Patch(path.into(), "not a JSON object".into())),
+                Err(err) => return Err(Error::Patch(path.into(), err.to_string())),
+            }
+        }
+    };
+    for property in properties {
+        match pairs.iter_mut().find(|(key, _)| *key == property.0) {
+            Some(pair) => *pair = property,
+            None => pairs.push(property),
+        }
+    }
+    Ok(format!("{:#}\n", json::Value::Object(pairs)).into_bytes())
+}
+
+/// `diff`, stripped of paths if `strip`; see `strip_paths`.
+pub fn strip_paths_if(strip: bool, diff: env::Diff) -> env::Diff {
+    if strip {
+        strip_paths(&diff)
+    } else {
+        diff
+    }
+}
+
+/// `diff` without the variables whose values are, or list, absolute paths, as
+/// PATH does. Paths on this host are unlikely to mean anything elsewhere.
+fn strip_paths(diff: &env::Diff) -> env::Diff {
+    let separator = env::PATH_SEPARATOR.as_bytes()[0];
+    diff.exclude_by(|change| match change {
+        env::Added(name, value) | env::Changed(name, _, value) => {
+            let mut entries = value.as_bytes().split(|&b| b == separator);
+            let path_like =
+                entries.any(|entry| entry.starts_with(b"/") && !entry.starts_with(b"//"));
+            if path_like {
+                log::debug!("Leaving out {:?}; it holds paths.", name);
+            }
+            path_like
+        }
+        env::Removed(_, _) => false,
+    })
+}
+
+/// For `docker run --env-file`, which takes everything after the `=` as it
+/// is, so there's no quoting, and no way to write a newline. Nor can Docker
+/// unset a variable. Values with newlines, and removals, become comments. Docker
+/// rejects the whole file if any of it is not UTF-8, so values that are not
+/// become comments too.
+fn docker_env(diff: &env::Diff) -> Vec<u8> {
+    let mut output = Vec::new();
+    for change in diff {
+        let name = change.name();
+        if !is_identifier(name) {
+            log::warn!("Skipping {:?}; it cannot be named in an env file.", name);
+            continue;
+        }
+        match change {
+            env::Added(_, value) | env::Changed(_, _, value) if value.to_str().is_none() => {
+                log::warn!("Skipping {:?}; its value is not UTF-8.", name);
+                output.extend(b"# cannot set ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+            env::Added(_, value) | env::Changed(_, _, value)
+                if value.as_bytes().contains(&b'\n') =>
+            {
+                log::warn!("Skipping {:?}; its value has a newline.", name);
+                output.extend(b"# cannot set ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+            env::Added(_, value) | env::Changed(_, _, value) => {
+                output.extend(name.as_bytes());
+                output.push(b'=');
+                output.extend(value.as_bytes());
+                output.push(b'\n');
+            }
+            env::Removed(_, _) => {
+                output.extend(b"# unset ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+    output
+}
+
+/// For systemd's `EnvironmentFile=`. Values are double-quoted, within which
+/// systemd takes newlines as they are, and needs only `\`, `"`, `$`, and
+/// `` ` `` escaped. It insists on UTF-8, so other values are left out.
+fn systemd(diff: &env::Diff) -> Vec<u8> {
+    let mut output = Vec::new();
+    for change in diff {
+        let name = change.name();
+        if !is_identifier(name) {
+            log::warn!("Skipping {:?}; systemd cannot name it.", name);
+            continue;
+        }
+        match change {
+            env::Added(_, value) | env::Changed(_, _, value) if value.to_str().is_none() => {
+                log::warn!("Skipping {:?}; its value is not UTF-8.", name);
+                output.extend(b"# cannot set ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+            env::Added(_, value) | env::Changed(_, _, value) => {
+                output.extend(name.as_bytes());
+                output.extend(b"=\"");
+                for &byte in value.as_bytes() {
+                    if matches!(byte, b'\\' | b'"' | b'$' | b'`') {
+                        output.push(b'\\');
+                    }
+                    output.push(byte);
+                }
+                output.extend(b"\"\n");
+            }
+            env::Removed(_, _) => {
+                output.extend(b"# unset ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+    output
+}
+
+/// `ENV` instructions. Docker has no way to unset a variable, so removals
+/// become comments, as do values with newlines, which `ENV` cannot express.
+fn dockerfile(diff: &env::Diff) -> Vec<u8> {
+    let mut output = Vec::new();
+    for change in diff {
+        let name = change.name();
+        if !is_identifier(name) {
+            log::warn!("Skipping {:?}; it cannot be named in a Dockerfile.", name);
+            continue;
+        }
+        match change {
+            env::Added(_, value) | env::Changed(_, _, value) if value.to_str().is_none() => {
+                log::warn!("Skipping {:?}; its value is not UTF-8.", name);
+                output.extend(b"# cannot set ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+            env::Added(_, value) | env::Changed(_, _, value)
+                if value.as_bytes().contains(&b'\n') =>
+            {
+                log::warn!("Skipping {:?}; its value has a newline.", name);
+                output.extend(b"# cannot set ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+            env::Added(_, value) | env::Changed(_, _, value) => {
+                output.extend(b"ENV ");
+                output.extend(name.as_bytes());
+                output.extend(b"=\"");
+                for &byte in value.as_bytes() {
+                    match byte {
+                        b'\\' => output.extend(b"\\\\"),
+                        b'"' => output.extend(b"\\\""),
+                        b'$' => output.extend(b"\\$"),
+                        _ => output.push(byte),
+                    }
+                }
+                output.extend(b"\"\n");
+            }
+            env::Removed(_, _) => {
+                output.extend(b"# unset ");
+                output.extend(name.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+    output
+}
+
+fn null(diff: &env::Diff) -> Vec<u8> {
+    let mut output = Vec::new();
+    for change in diff {
+        if let env::Added(name, value) | env::Changed(name, _, value) = change {
+            output.extend(name.as_bytes());
+            output.push(b'=');
+            output.extend(value.as_bytes());
+            output.push(0);
+        }
+    }
+    output
+}
+
+fn is_identifier(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    match name.first() {
+        Some(b'0'..=b'9') | None => false,
+        Some(_) => name.iter().all(|&b| b == b'_' || b.is_ascii_alphanumeric()),
+    }
+}
+
+/// Single quotes are literal in every dialect of .env that we know of, so we
+/// prefer them. They cannot contain single quotes or newlines though, so then
+/// we fall back to double quotes, where backslash escapes are understood.
+fn dotenv_quote_into(value: &[u8], output: &mut Vec<u8>) {
+    if value.contains(&b'\'') || value.contains(&b'\n') {
+        output.push(b'"');
+        for &byte in value {
+            match byte {
+                b'\\' => output.extend(b"\\\\"),
+                b'"' => output.extend(b"\\\""),
+                b'$' => output.extend(b"\\$"),
+                b'\n' => output.extend(b"\\n"),
+                _ => output.push(byte),
+            }
+        }
+        output.push(b'"');
+    } else {
+        output.push(b'\'');
+        output.extend(value);
+        output.push(b'\'');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{Added, Changed, Diff, Removed};
+
+    #[test]
+    fn dotenv_quotes_values_and_comments_removals() {
+        let diff = Diff::from(&[
+            Added("PLAIN".into(), "a b $c".into()),
+            Changed("QUOTED".into(), "".into(), "it's\n\"$x\"".into()),
+            Removed("GONE".into(), "".into()),
+            Added("BASH_FUNC_f%%".into(), "() { :; }".into()),
+        ]);
+        assert_eq!(
+            "PLAIN='a b $c'\nQUOTED=\"it's\\n\\\"\\$x\\\"\"\n# unset GONE\n",
+            String::from_utf8(dotenv(&diff)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn github_env_delimits_values_and_skips_removed() {
+        let diff = Diff::from(&[
+            Added("SET".into(), "a\nFIRSTAIDE_EOF".into()),
+            Removed("GONE".into(), "".into()),
+        ]);
+        assert_eq!(
+            "SET<<FIRSTAIDE_EOF_\na\nFIRSTAIDE_EOF\nFIRSTAIDE_EOF_\n",
+            String::from_utf8(github_env(&diff)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn github_path_adds_new_directories_last_first() {
+        let diff = Diff::from(&[
+            Changed("PATH".into(), "/bin".into(), "/nix/a:/bin:/nix/b".into()),
+            Added("SET".into(), "a".into()),
+        ]);
+        let (diff, paths) = github_path(&diff, Some(OsStr::new("/usr/bin:/bin")));
+        assert_eq!(b"/nix/b\n/nix/a\n", &paths[..]);
+        assert_eq!(
+            vec!["SET"],
+            diff.iter().map(|c| c.name()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn systemd_quotes_values() {
+        let diff = Diff::from(&[
+            Added("SET".into(), "it's \"$x\" `y` \\\nz".into()),
+            Added("BAD".into(), OsStr::from_bytes(b"\xff").into()),
+            Removed("GONE".into(), "".into()),
+        ]);
+        assert_eq!(
+            "SET=\"it's \\\"\\$x\\\" \\`y\\` \\\\\nz\"\n# cannot set BAD\n# unset GONE\n",
+            String::from_utf8(systemd(&diff)).unwrap(),
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn docker_env_writes_values_as_they_are() {
+        let diff = Diff::from(&[
+            Added("SET".into(), "it's \"$x\"".into()),
+            Added("LINES".into(), "a\nb".into()),
+            Added("BYTES".into(), OsStr::from_bytes(b"\xff").into()),
+            Removed("GONE".into(), "".into()),
+        ]);
+        assert_eq!(
+            "SET=it's \"$x\"\n# cannot set LINES\n# cannot set BYTES\n# unset GONE\n",
+            String::from_utf8(docker_env(&diff)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn strip_paths_leaves_out_host_paths() {
+        let diff = Diff::from(&[
+            Added("PATH".into(), "/nix/store/x/bin:/bin".into()),
+            Added("DIRS".into(), "rel:/abs".into()),
+            Added("URL".into(), "https://example.com/".into()),
+            Added("PLAIN".into(), "a".into()),
+            Removed("GONE".into(), "/bin".into()),
+        ]);
+        let names: Vec<_> = strip_paths(&diff)
+            .iter()
+            .map(|c| c.name().to_owned())
+            .collect();
+        assert_eq!(vec!["URL", "PLAIN", "GONE"], names);
+    }
+
+    #[test]
+    fn json_and_null_include_values() {
+        let diff = Diff::from(&[
+            Added("SET".into(), "a\nb".into()),
+            Removed("GONE".into(), "".into()),
+        ]);
+        assert_eq!(
+            r#"{"SET":"a\nb","GONE":null}"#,
+            env::to_json(&diff).to_string()
+        );
+        assert_eq!(b"SET=a\nb\0", &null(&diff)[..]);
+    }
+
+    #[test]
+    fn devcontainer_replaces_only_what_it_sets() {
+        let diff = Diff::from(&[Added("SET".into(), "a".into())]);
+        let devcontainer = |diff, existing, properties| {
+            String::from_utf8(devcontainer(diff, existing, properties).ok().unwrap()).unwrap()
+        };
+        assert_eq!(
+            "{\n  \"remoteEnv\": {\n    \"SET\": \"a\"\n  }\n}\n",
+            devcontainer(Some(&diff), None, Vec::new()),
+        );
+        let dir = tempfile::TempDir::new().unwrap();
+        let existing = dir.path().join("devcontainer.json");
+        fs::write(
+            &existing,
+            concat!(
+                "// Comment.\n{\"name\": \"x\", \"remoteEnv\": {\"OLD\": \"1\"},\n",
+                "  \"hostRequirements\": {\"memory\": 1.5e9, \"cpus\": 2,},\n}",
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            concat!(
+                "{\n  \"name\": \"x\",\n  \"remoteEnv\": {\n    \"SET\": \"a\"\n  },\n",
+                "  \"hostRequirements\": {\n    \"memory\": 1500000000,\n    \"cpus\": 2\n  }\n}\n"
+            ),
+            devcontainer(Some(&diff), Some(&existing), Vec::new()),
+        );
+        // Without an environment, remoteEnv is left as it is.
+        let properties = vec![("postCreateCommand".into(), "firstaide build".into())];
+        assert_eq!(
+            concat!(
+                "{\n  \"name\": \"x\",\n  \"remoteEnv\": {\n    \"OLD\": \"1\"\n  },\n",
+                "  \"hostRequirements\": {\n    \"memory\": 1500000000,\n    \"cpus\": 2\n  },\n",
+                "  \"postCreateCommand\": \"firstaide build\"\n}\n"
+            ),
+            devcontainer(None, Some(&existing), properties),
+        );
+    }
+
+    #[test]
+    fn dockerfile_escapes_values_and_comments_the_rest() {
+        let diff = Diff::from(&[
+            Added("PLAIN".into(), "a \"$b\" \\c".into()),
+            Added("LINES".into(), "a\nb".into()),
+            Removed("GONE".into(), "".into()),
+        ]);
+        assert_eq!(
+            "ENV PLAIN=\"a \\\"\\$b\\\" \\\\c\"\n# cannot set LINES\n# unset GONE\n",
+            String::from_utf8(dockerfile(&diff)).unwrap(),
+        );
+    }
+}
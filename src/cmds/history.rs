@@ -0,0 +1,124 @@
+use crate::cmds;
+use crate::config;
+use crate::error::Code;
+use crate::history;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const NAME: &str = "history";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Limit(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Limit(limit) => write!(f, "not a number of builds: {:?}", limit),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+            Error::Limit(_) => Code::Usage,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Shows recent builds")
+        .long_about(concat!(
+            "Shows recent builds, oldest first, from the build history in the cache ",
+            "dir. Each shows when it started, how long it took, how it went – success, ",
+            "failure, or up-to-date, when there was nothing to do – the signature of ",
+            "the cache it made, and the version of firstaide that made it.",
+        ))
+        .arg(
+            clap::Arg::with_name("limit")
+                .long("limit")
+                .short("n")
+                .value_name("N")
+                .default_value("10")
+                .help("Show only the most recent N builds; 0 for all"),
+        )
+        .arg(
+            clap::Arg::with_name("json")
+                .long("json")
+                .help("Print each build as a line of JSON"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let limit = args.value_of("limit").unwrap_or("10");
+    let limit: usize = limit.parse().map_err(|_| Error::Limit(limit.into()))?;
+
+    let records = history::read(&config)?;
+    let skip = match limit {
+        0 => 0,
+        limit => records.len().saturating_sub(limit),
+    };
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    for record in &records[skip..] {
+        if args.is_present("json") {
+            writeln!(&mut handle, "{}", record.to_json())?;
+        } else {
+            writeln!(
+                &mut handle,
+                "{}  {:>7.1}s  {:<10}  {}  {}",
+                record.started.format("%Y-%m-%d %H:%M:%S"),
+                record.duration().as_secs_f64(),
+                record.outcome.name(),
+                record.sig.as_deref().unwrap_or("-"),
+                record.firstaide,
+            )?;
+        }
+    }
+
+    Ok(0)
+}
@@ -1,25 +1,44 @@
 use crate::cache;
+use crate::cmds;
 use crate::config;
 use crate::env;
+use crate::error::Code;
+use crate::gzenv;
+use crate::history;
+use crate::i18n;
+use crate::json;
+use crate::os::OsStrExt;
+use crate::progress;
+use crate::shells::{self, ShellRenderer};
 use crate::status::EnvironmentStatus;
 use crate::sums;
-use bstr::ByteSlice;
+use crate::template::{self, Template, Value};
+use crate::timings;
+use crypto_hash::{hex_digest, Algorithm};
 use shell_quote::bash;
 use std::env::vars_os;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 use tempfile;
 
 pub const NAME: &str = "hook";
 
+/// Exported by the hook to record which environment has been applied.
+pub const GENERATION_VAR: &str = "FIRSTAIDE_GENERATION";
+
 type Result = std::result::Result<u8, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Config(config::Error),
     Io(io::Error),
-    EnvOutsideCapture,
-    EnvOutsideDecode(bincode::Error),
+    EnvOutside(env::CaptureError),
+    Paranoid(String),
 }
 
 impl fmt::Display for Error {
@@ -28,8 +47,33 @@ impl fmt::Display for Error {
         match self {
             Config(err) => write!(f, "{}", err),
             Io(err) => write!(f, "input/output error: {}", err),
-            EnvOutsideCapture => write!(f, "could not capture outside environment"),
-            EnvOutsideDecode(err) => write!(f, "problem decoding outside environment: {}", err),
+            EnvOutside(err) => write!(f, "could not capture outside environment: {}", err),
+            Paranoid(message) => write!(f, "generated hook does not work: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            EnvOutside(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            EnvOutside(err) => err.code(),
+            Paranoid(_) => Code::Other,
         }
     }
 }
@@ -42,13 +86,45 @@ impl From<config::Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
 pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
     clap::SubCommand::with_name(NAME)
         .about("Hooks the development environment; source the output from .envrc")
+        .arg(
+            clap::Arg::with_name("out")
+                .long("out")
+                .value_name("OUT")
+                .help("Write the hook to OUT, and print only a command to source it"),
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["bash", "direnv-dump"])
+                .default_value("bash")
+                .help("Emit Bash for .envrc, or the environment as a dump for direnv_load"),
+        )
+        .arg(
+            clap::Arg::with_name("shell")
+                .long("shell")
+                .value_name("SHELL")
+                .possible_values(shells::NAMES)
+                .conflicts_with_all(&["out", "format", "minimal"])
+                .help("Emit statements to evaluate in SHELL, as `apply` does, not for .envrc"),
+        )
+        .arg(
+            clap::Arg::with_name("no-cache-outside")
+                .long("no-cache-outside")
+                .help("Capture the outside environment afresh; see `hook.outside_ttl` in config"),
+        )
+        .arg(
+            clap::Arg::with_name("minimal")
+                .long("minimal")
+                .help("Leave out comments and section headers; see `hook.minimal` in config"),
+        )
         .arg(
             clap::Arg::with_name("dir")
                 .value_name("DIR")
@@ -57,163 +133,1399 @@ pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
 }
 
 pub fn run(args: &clap::ArgMatches) -> Result {
-    let config = config::Config::load(args.value_of_os("dir"))?;
+    // A broken config is reported by the hook like any other failure, so that
+    // direnv shows what's wrong rather than leaving the environment unloaded.
+    let config = match cmds::load_config(args.value_of_os("dir")) {
+        Ok(config) => config,
+        Err(err) => {
+            let err = Error::Config(err);
+            io::stdout().lock().write_all(&hook_error(None, &err))?;
+            return Ok(err.code().exit_code());
+        }
+    };
 
-    // Capture the environment here so we can later diff it against the
-    // environment that direnv reports for the configured parent directory.
-    let env_here: env::Env = vars_os().collect();
-    let env_outside: env::Env = {
-        // Setting up additional OS pipes for subprocesses to communicate back
-        // to us is not well supported in the Rust standard library, so we use
-        // files in a temporary directory instead. Here we try to create the
-        // temporary directory in a preexisting cache directory, but fall back
-        // to using the system's temporary directory, since we don't want to
-        // write to the filesystem in the project directory until the user has
-        // specifically requested it (by calling `firstaide build` for example).
-        let temp_dir = tempfile::TempDir::new_in(&config.cache_dir)
-            .or_else(|_err| tempfile::TempDir::new())?;
-        let dump_path = temp_dir.path().join("outside");
-        let mut dump_cmd = config.command_to_dump_env_outside(&dump_path);
-        let mut dump_proc = dump_cmd.spawn()?;
-        if !dump_proc.wait()?.success() {
-            return Err(Error::EnvOutsideCapture);
-        }
-        match bincode::deserialize(&fs::read(dump_path)?) {
-            Ok(env) => Ok(env),
-            Err(err) => Err(Error::EnvOutsideDecode(err)),
-        }
-    }?;
+    let cache_outside = !args.is_present("no-cache-outside");
+    let renderer = args.value_of("shell").and_then(shells::renderer);
+    if let Some(renderer) = renderer {
+        return without_direnv(&config, cache_outside, renderer.as_ref());
+    }
+    if args.value_of("format") == Some("direnv-dump") {
+        return direnv_dump(&config, cache_outside);
+    }
 
-    // However, we prevent the parent environment from removing or wiping
-    // DIRENV_WATCHES. This mirrors the behaviour of direnv's `direnv_load`
-    // function; see `direnv stdlib`. We don't use `direnv_load` because it had
-    // a couple of breaking bugs in direnv 2.20.[01].
-    let mut env_diff = env::diff(&env_here, &env_outside).exclude_by(|change| match change {
-        env::Changed(name, _, value) if name == "DIRENV_WATCHES" && value == "" => true,
-        env::Removed(name, _) if name == "DIRENV_WATCHES" => true,
-        _ => false,
-    });
+    // The hook is rendered in full before anything is written to stdout. When
+    // something goes wrong we can then emit, in its place, a script that
+    // explains the problem, rather than a truncated script or nothing at all.
+    let minimal = args.is_present("minimal") || config.hook.minimal;
+    match render(&config, minimal, cache_outside) {
+        Ok((output, code)) => {
+            match args.value_of_os("out") {
+                // Over a slow connection, echoing a large hook into the shell
+                // takes a noticeable time; sourcing a file is much quicker.
+                // Observers don't write files, so they get the whole hook.
+                Some(out) if config.writable().is_ok() => {
+                    let source =
+                        write_out(&config.resolve_paths.resolve_here(Some(out))?, &output)?;
+                    io::stdout().lock().write_all(&source)?;
+                }
+                _ => io::stdout().lock().write_all(&output)?,
+            }
+            Ok(code)
+        }
+        Err(err) => {
+            io::stdout()
+                .lock()
+                .write_all(&hook_error(Some(&config), &err))?;
+            Ok(err.code().exit_code())
+        }
+    }
+}
 
-    // Prepare to write to stdout.
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
+/// Write `output` to `out`, atomically, and return the command that sources
+/// it, for `--out`.
+fn write_out(out: &Path, output: &[u8]) -> io::Result<Vec<u8>> {
+    let dir = match out.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(output)?;
+    temp.persist(out)?;
+    let mut source = b"source ".to_vec();
+    bash::escape_into(out, &mut source);
+    source.push(b'\n');
+    Ok(source)
+}
 
-    // Wrap everything in { ... } so that it's only evaluated by Bash once
-    // completely written out. This is for correctness, but it might also help
-    // prevent seeing broken pipe errors.
-    writeln!(&mut handle, "{{ # Start.")?;
-    writeln!(&mut handle)?;
-
-    fn chunk(title: &str, chunk: &[u8]) -> Vec<u8> {
-        let mut buf = Vec::new();
-        let comments = title.lines().map(|line| format!("### {}\n", line));
-        buf.extend(comments.map(String::into_bytes).flatten());
-        buf.extend(chunk);
+/// The hook for `.envrc`, and the code with which `hook` exits: non-zero when
+/// the environment is not yet built. It's checked first if `paranoid`.
+pub fn render(
+    config: &config::Config,
+    minimal: bool,
+    cache_outside: bool,
+) -> std::result::Result<(Vec<u8>, u8), Error> {
+    let mut output: Vec<u8> = Vec::new();
+    let code = timings::time("Render hook", || {
+        hook(config, minimal, cache_outside, &mut output)
+    })?;
+    if config.paranoid {
+        check(&output)?;
+    }
+    Ok((output, code))
+}
+
+/// Evaluate the hook in a disposable Bash with a scratch environment, to be
+/// sure that it parses and runs before it goes anywhere near the user's shell.
+/// direnv's functions are stubbed out, and it's as strict as direnv is with
+/// `strict_env`: `set -euo pipefail`.
+fn check(script: &[u8]) -> std::result::Result<(), Error> {
+    let scratch = tempfile::TempDir::new()?;
+    let mut command = process::Command::new("bash");
+    command
+        .arg("--noprofile")
+        .arg("--norc")
+        .arg("-s")
+        .env_clear()
+        .env("HOME", scratch.path())
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .current_dir(scratch.path())
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped());
+    let mut child = command.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(include_bytes!("hook/stubs.sh"))?;
+        stdin.write_all(script)?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(Error::Paranoid(format!(
+            "bash exited with {}: {}",
+            output.status,
+            stderr.trim()
+        )))
+    }
+}
+
+/// Like `chunk`, but in minimal mode without the title. Comments are left
+/// out of the built-in scripts by `script` instead; the content of the chunk
+/// is otherwise left alone, since a line that starts with `#` in a project's
+/// own script might be in a heredoc.
+fn chunk_if(minimal: bool, title: &str, chunk: &[u8]) -> Vec<u8> {
+    if minimal {
+        let mut buf = chunk.to_vec();
         buf.push(b'\n');
         buf
+    } else {
+        self::chunk(title, chunk)
     }
+}
 
-    handle.write_all(&chunk("Helpers.", include_bytes!("hook/helpers.sh")))?;
+/// The translated message `key` as a Bash word, with its `{}`, if any,
+/// replaced by the Bash word `arg` in emphasis. Also returns the width of the
+/// text before the argument.
+fn message(key: &str, arg: &[u8]) -> (Vec<u8>, usize) {
+    let text = i18n::tr(key);
+    let mut parts = text.splitn(2, "{}");
+    let before = parts.next().unwrap_or("");
+    let mut word = bash::escape(before);
+    if let Some(after) = parts.next() {
+        word.extend(b"\"$(em ");
+        word.extend(arg);
+        word.extend(b")\"");
+        word.extend(bash::escape(after));
+    }
+    (word, before.chars().count())
+}
 
-    let sums_now = sums::Checksums::from(&config.watch_files()?)?;
+/// A message from config, like `messages.stale`, as a Bash word, with each
+/// `{name}` in `values` replaced by its value in emphasis. Other braces are
+/// left as they are.
+fn custom_message(text: &str, values: &[(&str, &str)]) -> Vec<u8> {
+    let mut word = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        literal.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = (rest.find('}'))
+            .and_then(|end| values.iter().find(|(name, _)| rest[1..end] == **name))
+            .map(|(name, value)| (name.len() + 2, value));
+        match value {
+            Some((len, value)) => {
+                word.extend(bash::escape(&literal));
+                word.extend(b"\"$(em ");
+                word.extend(bash::escape(value));
+                word.extend(b")\"");
+                literal.clear();
+                rest = &rest[len..];
+            }
+            None => {
+                literal.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    literal.push_str(rest);
+    word.extend(bash::escape(&literal));
+    word
+}
+
+/// The watched files and variables that differ between `then` and `now`,
+/// for `messages.stale`: files relative to the project, and no more than a
+/// handful.
+fn changed(config: &config::Config, then: &sums::Checksums, now: &sums::Checksums) -> String {
+    const MAX: usize = 5;
+    let changed: Vec<String> = (sums::changed(then, now).into_iter())
+        .map(|subject| match subject {
+            sums::Subject::File(path) => {
+                let path = path.strip_prefix(&config.build_dir).unwrap_or(path);
+                path.display().to_string()
+            }
+            subject => subject.to_string(),
+        })
+        .collect();
+    match changed.len() {
+        0 => "nothing".into(),
+        len if len <= MAX => changed.join(", "),
+        len => format!("{} and {} more", changed[..MAX].join(", "), len - MAX),
+    }
+}
+
+fn chunk(title: &str, chunk: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let comments = title.lines().map(|line| format!("### {}\n", line));
+    buf.extend(comments.flat_map(String::into_bytes));
+    buf.extend(chunk);
+    buf.push(b'\n');
+    buf
+}
+
+fn hook(
+    config: &config::Config,
+    minimal: bool,
+    cache_outside: bool,
+    handle: &mut Vec<u8>,
+) -> Result {
+    let chunk = |title: &str, content: &[u8]| chunk_if(minimal, title, content);
+    let script = |name: &str| self::script(config, name, minimal);
+    let (start, end) = if minimal {
+        ("{\n", "}\n")
+    } else {
+        ("{ # Start.\n\n", "} # End.\n")
+    };
+    let sums_now = config.checksums()?;
     let cache_file = config.cache_file(&sums_now);
-    let cache_file_fallback = config.cache_file_most_recent();
+    let (cache, fallback) = match config.load_cache(&sums_now) {
+        Ok((cache, used)) => (Ok(cache), Some(used).filter(|used| *used != cache_file)),
+        Err(err) => (Err(err), None),
+    };
+
+    // When the environment is up to date and this shell already carries it,
+    // there's little to do. This is typical of repeat evaluations triggered
+    // by unrelated watches, so we skip the (relatively) expensive work below.
+    // Note that direnv reverts its own changes before evaluating `.envrc`, so
+    // under direnv this only kicks in when a parent environment – not this
+    // hook – has exported the generation.
+    let status = match &cache {
+        Ok(cache) => config.staleness(&sums_now, &cache.sums),
+        Err(_) => EnvironmentStatus::Unknown,
+    };
+    // A soft stale environment is the one that was cached, so it's the same
+    // generation as when it was up to date.
+    let generation = match (&cache, status) {
+        (Ok(cache), EnvironmentStatus::Okay | EnvironmentStatus::SoftStale) => {
+            Some(generation(config, cache)?)
+        }
+        _ => None,
+    };
+    // The cached environment is still exported, with the watches, since
+    // direnv keeps only what the hook leaves in the environment, and watches
+    // only what it's told to.
+    if let (Ok(cache), Some(generation)) = (&cache, &generation) {
+        if std::env::var_os(GENERATION_VAR).as_deref() == Some(OsStr::new(generation)) {
+            let mut env_diff = env::Diff::new();
+            extend_with_cached(config, &mut env_diff, cache, Some(generation));
+            handle.write_all(start.as_bytes())?;
+            handle.write_all(&chunk(
+                &format!("Generation {} is already applied.", generation),
+                &shells::Bash.dump(&env_diff),
+            ))?;
+            handle.write_all(&chunk(
+                "Watch dependencies.",
+                &watch_files(minimal, watched(config, &cache.sums, Some(&cache_file))),
+            ))?;
+            handle.write_all(end.as_bytes())?;
+            return Ok(0);
+        }
+    }
+    if let Ok(cache) = &cache {
+        cache.warn_if_foreign();
+    }
+
+    // Without direnv there's no parent environment to start from, and it's
+    // unlikely that anything will evaluate the hook properly, so we say so
+    // rather than fail.
+    if config.direnv_exe().is_err() {
+        handle.write_all(start.as_bytes())?;
+        handle.write_all(&chunk("Helpers.", &script("helpers")?.render(&[])))?;
+        handle.write_all(&chunk(
+            &EnvironmentStatus::Unknown.display(),
+            &script("no-direnv")?.render(&[("NO_DIRENV", Value::Text(i18n::tr("hook.no-direnv")))]),
+        ))?;
+        handle.write_all(end.as_bytes())?;
+        return Ok(0);
+    }
+
+    // When nothing that goes into the hook has changed since we last rendered
+    // it, we can skip capturing the outside environment, diffing, and so on,
+    // and emit what we rendered then.
+    let fast_key = if cache_outside {
+        fast_key(config, minimal, &sums_now, &cache)?
+    } else {
+        None
+    };
+    if let Some(key) = &fast_key {
+        if let Some(output) = fast_load(config, key) {
+            handle.write_all(&output)?;
+            return Ok(0);
+        }
+    }
+
+    let mut env_diff = diff_outside_cached(config, cache_outside)?;
+
+    // Wrap everything in { ... } so that it's only evaluated by Bash once
+    // completely written out. This is for correctness, but it might also help
+    // prevent seeing broken pipe errors.
+    handle.write_all(start.as_bytes())?;
+
+    handle.write_all(&chunk("Helpers.", &script("helpers")?.render(&[])))?;
 
-    match cache::Cache::load_with_fallback(&cache_file, &cache_file_fallback) {
+    // If a build is already underway, say so rather than suggesting that the
+    // user start another.
+    let (building, started) = match progress::current(config) {
+        Some(progress) => ("true", bash::escape(progress.started_ago())),
+        None => ("false", b"''".to_vec()),
+    };
+    let building = || Value::Bash(building.into());
+    let in_progress = || Value::Bash(message("hook.in-progress", &started).0);
+    let build_cmd = bash::escape("firstaide build");
+
+    match cache {
         Ok(cache) => {
-            // Filter out DIRENV_ and SSH_ vars from cached diff, then use it to
-            // extend the parent's environment diff.
-            env_diff.extend(
-                cache
-                    .diff
-                    .exclude_by_prefix(b"DIRENV_")
-                    .exclude_by_prefix(b"SSH_"),
-            );
-            env_diff.simplify();
-            if sums::equal(&sums_now, &cache.sums) {
-                let chunk_message = bash::escape(&config.messages.getting_started);
-                let chunk_content =
-                    include_bytes!("hook/active.sh").replace(b"__MESSAGE__", chunk_message);
-                handle.write_all(&chunk(&EnvironmentStatus::Okay.display(), &chunk_content))?;
+            extend_with_cached(config, &mut env_diff, &cache, generation.as_deref());
+            if generation.is_some() {
+                // Soft stale environments are used as if up to date, but
+                // with a reminder to rebuild.
+                let chunk_content = if status == EnvironmentStatus::SoftStale {
+                    script("soft-stale")?.render(&[
+                        ("SOFT_STALE", Value::Text(i18n::tr("hook.soft-stale"))),
+                        ("BUILDING", building()),
+                        ("IN_PROGRESS", in_progress()),
+                        (
+                            "REBUILD",
+                            Value::Bash(message("hook.rebuild", &build_cmd).0),
+                        ),
+                    ])
+                } else {
+                    let chunk_message = bash::escape(&config.messages.getting_started);
+                    let (next, pad) = message("hook.next", &chunk_message);
+                    // After a rebuild that made no difference, say so, once;
+                    // there's no new environment to look for.
+                    let okay = if history::take_unchanged(config, &cache.sums.sig()) {
+                        i18n::tr("hook.unchanged")
+                    } else {
+                        i18n::tr("hook.okay")
+                    };
+                    script("active")?.render(&[
+                        ("OKAY", Value::Text(okay)),
+                        ("MINIMAL", Value::Bash(message("hook.minimal", b"").0)),
+                        ("NEXT", Value::Bash(next)),
+                        ("PAD", Value::Bash(" ".repeat(pad).into())),
+                        ("MESSAGE", Value::Bash(chunk_message)),
+                    ])
+                };
+                handle.write_all(&chunk(&status.display(), &chunk_content))?;
+                if let Some(fallback) = &fallback {
+                    handle.write_all(&chunk(
+                        "Fallback.",
+                        &fallback_chunk(config, fallback, minimal)?,
+                    ))?;
+                }
+                if let Some(content) = overrides_chunk(config, &env_diff, minimal)? {
+                    handle.write_all(&chunk("Overrides.", &content))?;
+                }
                 handle.write_all(&chunk(
                     "Computed environment follows (includes parent environment):",
-                    &env_diff_dump(&env_diff),
+                    &shells::Bash.dump(&env_diff),
                 ))?;
             } else {
                 handle.write_all(&chunk(
                     &EnvironmentStatus::Stale.display(),
-                    include_bytes!("hook/stale.sh"),
+                    &script("stale")?.render(&[
+                        ("STALE", Value::Text(i18n::tr("hook.stale"))),
+                        ("BUILDING", building()),
+                        ("IN_PROGRESS", in_progress()),
+                        (
+                            "REBUILD",
+                            Value::Bash(match &config.messages.stale {
+                                Some(text) => custom_message(
+                                    text,
+                                    &[
+                                        ("build", "firstaide build"),
+                                        ("changed", &changed(config, &cache.sums, &sums_now)),
+                                    ],
+                                ),
+                                None => message("hook.rebuild", &build_cmd).0,
+                            }),
+                        ),
+                        (
+                            "LOADING_STALE",
+                            Value::Bash(message("hook.loading-stale", b"STALE").0),
+                        ),
+                    ]),
                 ))?;
+                if let Some(fallback) = &fallback {
+                    handle.write_all(&chunk(
+                        "Fallback.",
+                        &fallback_chunk(config, fallback, minimal)?,
+                    ))?;
+                }
+                if let Some(content) = overrides_chunk(config, &env_diff, minimal)? {
+                    handle.write_all(&chunk("Overrides.", &content))?;
+                }
                 handle.write_all(&chunk(
                     "Computed environment follows (includes parent environment):",
-                    &env_diff_dump(&env_diff),
+                    &shells::Bash.dump(&env_diff),
                 ))?;
             }
-            // We want direnv to watch every file for which we calculate a
-            // checksum, AND we want it to watch the firstaide cache file.
-            {
-                let mut watches = Vec::with_capacity(8192); // 8kB enough?
-                watches.extend(b"watch_file \\\n  ");
-                for watch in cache.sums.into_iter() {
-                    bash::escape_into(watch.path(), &mut watches);
-                    watches.extend(b" \\\n  ");
-                }
-                // Also watch the cache file, the build log, the build
-                // executable, and the watch executable.
-                bash::escape_into(&cache_file, &mut watches);
-                watches.extend(b" \\\n  ");
-                bash::escape_into(&config.build_log_file(), &mut watches);
-                watches.extend(b" \\\n  ");
-                bash::escape_into(&config.build_exe, &mut watches);
-                watches.extend(b" \\\n  ");
-                bash::escape_into(&config.watch_exe, &mut watches);
-                watches.push(b'\n');
-
-                handle.write_all(&chunk("Watch dependencies.", &watches))?;
-            }
+            let in_use = generation.as_ref().map(|_| cache_file.as_path());
+            handle.write_all(&chunk(
+                "Watch dependencies.",
+                &watch_files(minimal, watched(config, &cache.sums, in_use)),
+            ))?;
         }
-        Err(_) => {
+        Err(err) => {
+            log::debug!("{}", err);
             handle.write_all(&chunk(
                 &EnvironmentStatus::Unknown.display(),
-                include_bytes!("hook/inactive.sh"),
+                &script("inactive")?.render(&[
+                    ("UNBUILT", Value::Text(i18n::tr("hook.unbuilt"))),
+                    ("BUILDING", building()),
+                    ("IN_PROGRESS", in_progress()),
+                    (
+                        "BUILD",
+                        Value::Bash(match &config.messages.unbuilt {
+                            Some(text) => custom_message(text, &[("build", "firstaide build")]),
+                            None => message("hook.build", &build_cmd).0,
+                        }),
+                    ),
+                ]),
             ))?;
             handle.write_all(&chunk(
                 "Parent environment follows:",
-                &env_diff_dump(&env_diff),
+                &shells::Bash.dump(&env_diff),
             ))?;
         }
     };
 
-    writeln!(&mut handle, "}} # End.")?;
+    handle.write_all(end.as_bytes())?;
+
+    if let Some(key) = &fast_key {
+        fast_save(config, key, handle);
+    }
 
     Ok(0)
 }
 
-fn env_diff_dump(diff: &env::Diff) -> Vec<u8> {
-    use bash::escape as esc;
-    use env::Change::*;
+/// The hook script called `name`: the project's own, per `hook.scripts`, or
+/// the built-in one, without comments if `minimal`.
+fn script(config: &config::Config, name: &str, minimal: bool) -> io::Result<Template> {
+    let script = Template::load(name, &config.hook.scripts).map_err(|err| {
+        let path = &config.hook.scripts[name];
+        io::Error::new(err.kind(), format!("{:?}: {}", path, err))
+    })?;
+    Ok(if minimal { script.minimal() } else { script })
+}
 
-    let mut output: Vec<u8> = Vec::new();
-    for change in diff {
-        match change {
-            Added(k, vb) => {
-                output.extend(b"export ");
-                output.extend(esc(k));
-                output.extend(b"=");
-                output.extend(esc(vb));
+/// Say which cache was loaded in place of one for the watched files as they
+/// are now, and how old it is, so that it's clear what's in use.
+fn fallback_chunk(config: &config::Config, fallback: &Path, minimal: bool) -> io::Result<Vec<u8>> {
+    let name = fallback.file_name().unwrap_or_default().to_string_lossy();
+    let age = match fs::metadata(fallback).and_then(|meta| meta.modified()) {
+        Ok(modified) => {
+            let modified: chrono::DateTime<chrono::Local> = modified.into();
+            progress::ago(chrono::Local::now().signed_duration_since(modified))
+        }
+        Err(_) => "at an unknown time".into(),
+    };
+    let used = bash::escape(format!("{} (built {})", name, age));
+    Ok(script(config, "fallback", minimal)?
+        .render(&[("FALLBACK", Value::Bash(message("hook.fallback", &used).0))]))
+}
+
+/// Identifies everything that goes into the hook: the options, the config,
+/// the environment here, the parent `.envrc` files, the signature of the
+/// watched files now, and the generation of the cache in use, and whether it's
+/// marked unchanged. Returns `None`
+/// when the hook must be rendered afresh: for observers, while a build is in
+/// progress, and when reusing the outside environment is disabled, since the
+/// fast path is bound by `hook.outside_ttl` too.
+fn fast_key<E>(
+    config: &config::Config,
+    minimal: bool,
+    sums_now: &sums::Checksums,
+    cache: &std::result::Result<cache::Cache, E>,
+) -> io::Result<Option<String>> {
+    if config.writable().is_err() || config.hook.outside_ttl == 0 || !config.cache_dir.is_dir() {
+        return Ok(None);
+    }
+    if progress::current(config).is_some() {
+        return Ok(None);
+    }
+    let mut env_here: env::Env = vars_os().collect();
+    env_here.sort();
+    let (generation, unchanged) = match cache {
+        Ok(cache) => (
+            Some(generation(config, cache)?),
+            history::is_unchanged(config, &cache.sums.sig()),
+        ),
+        Err(_) => (None, false),
+    };
+    let key = (
+        crate_version!(),
+        minimal,
+        format!("{:?}", config),
+        sums_now.sig(),
+        generation,
+        // The hook says so only once.
+        unchanged,
+        sums::Checksums::from(&config.parent_envrc_files())?.sig(),
+        template::contents(&config.hook.scripts),
+        env_here,
+    );
+    let key = bincode::serialize(&key).map_err(io::Error::other)?;
+    Ok(Some(hex_digest(Algorithm::SHA256, &key)))
+}
+
+/// The hook saved with `key`, if it's no older than `hook.outside_ttl`.
+fn fast_load(config: &config::Config, key: &str) -> Option<Vec<u8>> {
+    let file = config.hook_output_file();
+    let ttl = Duration::from_secs(config.hook.outside_ttl);
+    let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok()?;
+    if !modified.elapsed().is_ok_and(|age| age < ttl) {
+        return None;
+    }
+    let data = load_private(config, &file)?;
+    match bincode::deserialize::<(String, Vec<u8>)>(&data) {
+        Ok((saved_key, output)) if saved_key == key => {
+            log::debug!("Reusing hook from {:?}.", file);
+            Some(output)
+        }
+        _ => None,
+    }
+}
+
+/// Save the hook with `key` for `fast_load`. Failing to do so is not fatal.
+fn fast_save(config: &config::Config, key: &str, output: &[u8]) {
+    let file = config.hook_output_file();
+    let saved = bincode::serialize(&(key, output))
+        .map_err(io::Error::other)
+        .and_then(|data| save_private(config, &file, data));
+    if let Err(err) = saved {
+        log::warn!("Could not save hook: {}", err);
+    }
+}
+
+/// The contents of `file`, one of the hook's own files in the cache dir,
+/// decrypted if need be; `None` if it's missing or won't decrypt.
+fn load_private(config: &config::Config, file: &Path) -> Option<Vec<u8>> {
+    let data = fs::read(file).ok()?;
+    match config.cache_keys.decrypt(data) {
+        Ok(data) => Some(data),
+        Err(err) => {
+            log::debug!("Could not decrypt {:?}: {}", file, err);
+            None
+        }
+    }
+}
+
+/// Save `data` to `file` for `load_private`: encrypted, like the caches, if
+/// they're encrypted, and through a temporary file that only we can read.
+fn save_private(config: &config::Config, file: &Path, data: Vec<u8>) -> io::Result<()> {
+    let data = (config.cache_keys.encrypt(data)).map_err(io::Error::other)?;
+    let dir = file
+        .parent()
+        .expect("the hook's files are in the cache dir");
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(&data)?;
+    temp.persist(file)?;
+    Ok(())
+}
+
+/// Report, per `hook.report_overrides`, variables that the shell has set
+/// and that the environment will replace or unset.
+fn overrides_chunk(
+    config: &config::Config,
+    env_diff: &env::Diff,
+    minimal: bool,
+) -> io::Result<Option<Vec<u8>>> {
+    let names = overrides(&config.hook, env_diff, |name| std::env::var_os(name));
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let names = (names.iter())
+        .map(|name| name.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let overrides = message("hook.overrides", &bash::escape(names)).0;
+    let script = script(config, "overrides", minimal)?;
+    Ok(Some(
+        script.render(&[("OVERRIDES", Value::Bash(overrides))]),
+    ))
+}
+
+/// Variables whose values `now` – in the shell, before the hook – are lost
+/// to the cache or config. A value whose entries all survive a merge, like
+/// those of the old `PATH` in the new, is not lost.
+fn overrides<F>(hook: &config::Hook, env_diff: &env::Diff, now: F) -> Vec<OsString>
+where
+    F: Fn(&OsStr) -> Option<OsString>,
+{
+    (env_diff.iter())
+        .filter(|change| env_diff.origin(change.name()) != Some(env::Origin::Outside))
+        .filter(|change| hook.reports_override(change.name().as_bytes()))
+        .filter(|change| match (now(change.name()), change) {
+            (None, _) => false,
+            (Some(_), env::Removed(_, _)) => true,
+            (Some(before), env::Added(name, after) | env::Changed(name, _, after)) => {
+                !hook.merge_for(name).keeps(&before, after)
             }
-            Changed(k, _va, vb) => {
-                output.extend(b"export ");
-                output.extend(esc(k));
-                output.extend(b"=");
-                output.extend(esc(vb));
+        })
+        .map(|change| change.name().clone())
+        .collect()
+}
+
+/// Use the cached diff to extend the parent's environment diff. Changes to
+/// list variables, like PATH, are merged with their values now, after the
+/// parent's changes. Overlays for the directory we're in go on top.
+fn extend_with_cached(
+    config: &config::Config,
+    env_diff: &mut env::Diff,
+    cache: &cache::Cache,
+    generation: Option<&str>,
+) {
+    let cached = env::compose(
+        |name| match env_diff.value(name) {
+            Some(value) => value.cloned(),
+            None => std::env::var_os(name),
+        },
+        &diff_relocated(config, cache),
+        &policy(config),
+    );
+    env_diff.extend(config.overlay(cached));
+    if let Some(generation) = generation {
+        let name = OsString::from(GENERATION_VAR);
+        let change = env::Added(name, generation.into());
+        env_diff.extend(env::Diff::from(&[change]).with_origin(env::Origin::Config));
+    }
+    env_diff.simplify();
+}
+
+/// A `watch_file` command for direnv to watch `paths`.
+fn watch_files(minimal: bool, paths: Vec<PathBuf>) -> Vec<u8> {
+    let mut watches = Vec::with_capacity(8192); // 8kB enough?
+    let sep: &[u8] = if minimal { b" " } else { b" \\\n  " };
+    watches.extend(b"watch_file");
+    for path in paths {
+        watches.extend(sep);
+        bash::escape_into(path, &mut watches);
+    }
+    watches.push(b'\n');
+    watches
+}
+
+/// Every file for which we calculate a checksum, for direnv to watch, and then
+/// the cache file, if it's in use, the reload file, the build executable (if
+/// there is one), and the watch executable. A stale environment's cache file
+/// does not exist yet; not watching it lets a build that changes nothing
+/// leave direnv be. In relocatable mode these are relative to the build dir,
+/// as are the paths in the checksums.
+fn watched(
+    config: &config::Config,
+    sums: &sums::Checksums,
+    cache_file: Option<&Path>,
+) -> Vec<PathBuf> {
+    let path = |path: &Path| config.relocatable_path(path).to_path_buf();
+    // Watched variables are not files; direnv cannot watch them.
+    let mut watches: Vec<PathBuf> = (sums.iter())
+        .filter_map(sums::Checksum::path)
+        .map(Path::to_path_buf)
+        .collect();
+    watches.extend(cache_file.map(path));
+    watches.push(path(&config.reload_file()));
+    watches.extend(config.build_exe.as_deref().map(path));
+    watches.extend(config.layers.iter().map(|layer| path(&layer.build_exe)));
+    watches.extend(config.overlays.iter().map(|overlay| path(&overlay.file)));
+    watches.extend(config.hook.scripts.values().map(|script| path(script)));
+    watches.push(path(&config.watch_exe));
+    watches
+}
+
+/// Write the whole environment that the hook would apply as a dump in the
+/// format of `direnv dump gzenv`, for `direnv_load` in `.envrc`. direnv
+/// applies a dump as it is, so the files to watch go into its
+/// `DIRENV_WATCHES`, alongside those it's watching already. There's no room
+/// for messages, so the status is logged instead. The dump goes to the file
+/// that `direnv_load` names in `DIRENV_DUMP_FILE_PATH`, else to stdout.
+fn direnv_dump(config: &config::Config, cache_outside: bool) -> Result {
+    let (status, env_diff, watches) = environment_watched(config, cache_outside)?;
+    match status {
+        EnvironmentStatus::Okay => log::info!("{}", status),
+        _ => log::warn!("{}", status),
+    }
+    let env_here: env::Env = vars_os().collect();
+    let env = env::apply(&env_here, &env_diff);
+    let watches = watches.iter().map(|path| config.build_dir.join(path));
+    let dump = dump(&env, watches);
+    match std::env::var_os("DIRENV_DUMP_FILE_PATH") {
+        Some(path) => fs::write(path, dump)?,
+        None => writeln!(io::stdout().lock(), "{}", dump)?,
+    }
+    Ok(0)
+}
+
+/// `env` as direnv dumps it: gzenv-encoded JSON, mapping names to values,
+/// with `watches` added to those in its `DIRENV_WATCHES`. direnv's JSON has
+/// room only for UTF-8, so variables that are not are left out, with a
+/// warning, rather than mangled.
+fn dump(env: &[env::Item], watches: impl IntoIterator<Item = PathBuf>) -> String {
+    let watches_name = OsStr::new("DIRENV_WATCHES");
+    let mut watching = (env.iter())
+        .find(|(name, _)| name == watches_name)
+        .and_then(|(_, value)| value.to_str())
+        .and_then(|value| gzenv::decode(value).ok())
+        .and_then(|json| json::parse(&json).ok())
+        .and_then(|json| match json {
+            json::Value::Array(watching) => Some(watching),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let watched = outside_watches_in(&watching);
+    for path in watches {
+        if watched.contains(&path) {
+            continue;
+        }
+        match path.to_str() {
+            Some(path_str) => {
+                let meta = fs::metadata(&path).ok();
+                let modtime = (meta.as_ref())
+                    .and_then(|meta| meta.modified().ok())
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs() as i64);
+                watching.push(json::Value::object(vec![
+                    ("Path", path_str.into()),
+                    ("Modtime", modtime.into()),
+                    ("Exists", meta.is_some().into()),
+                ]));
             }
-            Removed(k, _va) => {
-                output.extend(b"unset ");
-                output.extend(esc(k));
+            None => log::warn!("Cannot watch {:?}: not UTF-8.", path),
+        }
+    }
+    let watching = gzenv::encode(&json::Value::Array(watching).to_string());
+
+    let mut vars = Vec::with_capacity(env.len());
+    for (name, value) in env {
+        if name == watches_name {
+            continue;
+        }
+        match (name.to_str(), value.to_str()) {
+            (Some(name), Some(value)) => vars.push((name, value.into())),
+            _ => log::warn!("Leaving {:?} out of the dump: not UTF-8.", name),
+        }
+    }
+    vars.push(("DIRENV_WATCHES", watching.into()));
+    gzenv::encode(&json::Value::object(vars).to_string())
+}
+
+/// Like `direnv_dump`, there's no direnv to lean on, so this applies the whole
+/// environment, and reports its status, in the shell that `renderer` speaks.
+/// It's for the user to evaluate it from a prompt hook.
+fn without_direnv(
+    config: &config::Config,
+    cache_outside: bool,
+    renderer: &dyn shells::ShellRenderer,
+) -> Result {
+    let (status, env_diff) = environment(config, cache_outside)?;
+    let mut output = report(config, status, renderer);
+    output.extend(renderer.dump(&env_diff));
+    io::stdout().lock().write_all(&output)?;
+    Ok(0)
+}
+
+/// The status, and what to do next, as the Bash hook's scripts say it, but
+/// in the shell that `renderer` speaks, without emphasis.
+fn report(
+    config: &config::Config,
+    status: EnvironmentStatus,
+    renderer: &dyn shells::ShellRenderer,
+) -> Vec<u8> {
+    let plain = |key: &str, arg: &str| i18n::tr(key).replacen("{}", arg, 1);
+    let next = |key: &str| match progress::current(config) {
+        Some(progress) => plain("hook.in-progress", &progress.started_ago()),
+        None => plain(key, "firstaide build"),
+    };
+    let (label, color, text, next) = match status {
+        EnvironmentStatus::Okay => (
+            "OKAY",
+            "32",
+            i18n::tr("hook.okay"),
+            plain("hook.next", &config.messages.getting_started),
+        ),
+        EnvironmentStatus::SoftStale => (
+            "NOTE",
+            "33",
+            i18n::tr("hook.soft-stale"),
+            next("hook.rebuild"),
+        ),
+        EnvironmentStatus::Stale => (
+            "WARNING",
+            "33",
+            i18n::tr("hook.stale"),
+            next("hook.rebuild"),
+        ),
+        EnvironmentStatus::Unknown => ("ERROR", "31", i18n::tr("hook.unbuilt"), next("hook.build")),
+    };
+    renderer.report(label, color, &text, &next)
+}
+
+/// The status of the environment, and the diff from the environment here to
+/// the environment that the hook would apply: the parent environment, plus
+/// the cached environment if there is one, stale or not.
+pub fn environment(
+    config: &config::Config,
+    cache_outside: bool,
+) -> std::result::Result<(EnvironmentStatus, env::Diff), Error> {
+    let (status, env_diff, _) = environment_watched(config, cache_outside)?;
+    Ok((status, env_diff))
+}
+
+/// Like `environment`, but also returns the files that the hook would have
+/// direnv watch; see `watched`.
+fn environment_watched(
+    config: &config::Config,
+    cache_outside: bool,
+) -> std::result::Result<(EnvironmentStatus, env::Diff, Vec<PathBuf>), Error> {
+    let sums_now = config.checksums()?;
+    let cache = config.load_cache(&sums_now).map(|(cache, _)| cache);
+    let mut env_diff = diff_outside_cached(config, cache_outside)?;
+    let (status, watches) = match &cache {
+        Ok(cache) => {
+            cache.warn_if_foreign();
+            match config.staleness(&sums_now, &cache.sums) {
+                status @ (EnvironmentStatus::Okay | EnvironmentStatus::SoftStale) => {
+                    let generation = generation(config, cache)?;
+                    extend_with_cached(config, &mut env_diff, cache, Some(&generation));
+                    let cache_file = config.cache_file(&sums_now);
+                    (status, watched(config, &cache.sums, Some(&cache_file)))
+                }
+                status => {
+                    extend_with_cached(config, &mut env_diff, cache, None);
+                    (status, watched(config, &cache.sums, None))
+                }
             }
         }
-        output.push(b'\n');
+        Err(err) => {
+            log::debug!("{}", err);
+            env_diff.simplify();
+            (EnvironmentStatus::Unknown, Vec::new())
+        }
+    };
+    Ok((status, env_diff, watches))
+}
+
+/// Identifies the environment that the hook exports: the signature of the
+/// watched files, and a digest of the cached environment, plus a digest of the
+/// overlays, if any. A rebuild that changes the environment changes this, as
+/// does one that does not, and nothing else does.
+fn generation(config: &config::Config, cache: &cache::Cache) -> io::Result<String> {
+    let diff = bincode::serialize(&cache.diff).map_err(io::Error::other)?;
+    let diff = hex_digest(Algorithm::SHA1, &diff);
+    if config.overlays.is_empty() {
+        Ok(format!("{}:{}", cache.sums.sig(), diff))
+    } else {
+        let mut overlays = Vec::new();
+        for overlay in &config.overlays {
+            overlays.extend(fs::read(&overlay.file)?);
+        }
+        let overlays = hex_digest(Algorithm::SHA1, &overlays);
+        Ok(format!("{}:{}:{}", cache.sums.sig(), diff, overlays))
+    }
+}
+
+/// Like `diff_outside`, but reuses a previous capture when it's no older than
+/// `hook.outside_ttl` and nothing it depends on has changed; see `outside_key`.
+/// Capturing is the slowest part of the hook, and these rarely change.
+/// Observers neither read nor write the saved capture, since it's in the
+/// cache dir.
+fn diff_outside_cached(
+    config: &config::Config,
+    use_cache: bool,
+) -> std::result::Result<env::Diff, Error> {
+    if !use_cache
+        || config.writable().is_err()
+        || config.hook.outside_ttl == 0
+        || config.direnv_exe().is_err()
+    {
+        return diff_outside(config);
+    }
+    let cache_file = config.outside_cache_file();
+    let ttl = Duration::from_secs(config.hook.outside_ttl);
+    let fresh = match fs::metadata(&cache_file).and_then(|meta| meta.modified()) {
+        Ok(modified) => modified.elapsed().is_ok_and(|age| age < ttl),
+        Err(_) => false,
+    };
+    if fresh {
+        let cached: Option<bincode::Result<(String, Vec<PathBuf>, env::Diff)>> =
+            load_private(config, &cache_file).map(|data| bincode::deserialize(&data));
+        match cached {
+            Some(Ok((cached_key, watches, diff)))
+                if cached_key == outside_key(config, &watches)? =>
+            {
+                log::debug!("Reusing outside environment from {:?}.", cache_file);
+                return Ok(diff.with_origin(env::Origin::Outside));
+            }
+            _ => log::debug!("Outside environment in {:?} is not usable.", cache_file),
+        }
+    }
+    let diff = diff_outside(config)?;
+    // Only into a cache dir that exists; see `diff_outside`.
+    if config.cache_dir.is_dir() {
+        let watches = outside_watches(&diff);
+        let saved = outside_key(config, &watches)
+            .and_then(|key| bincode::serialize(&(&key, &watches, &diff)).map_err(io::Error::other))
+            .and_then(|data| save_private(config, &cache_file, data));
+        if let Err(err) = saved {
+            log::warn!("Could not save outside environment: {}", err);
+        }
+    }
+    Ok(diff)
+}
+
+/// What the outside environment depends on: the parent `.envrc` files, the
+/// files that direnv watched as it loaded them, and the environment here, in
+/// which they're evaluated – less direnv's own variables, which differ inside
+/// the project and out.
+fn outside_key(config: &config::Config, watches: &[PathBuf]) -> io::Result<String> {
+    let mut files = config.parent_envrc_files();
+    files.extend(watches.iter().cloned());
+    let sums = sums::Checksums::from(&files)?;
+    let mut env_here: env::Env = vars_os()
+        .filter(|(name, _)| !name.as_bytes().starts_with(b"DIRENV_"))
+        .collect();
+    env_here.sort();
+    let env_here = bincode::serialize(&env_here).map_err(io::Error::other)?;
+    Ok(format!(
+        "{}:{}",
+        sums.sig(),
+        hex_digest(Algorithm::SHA256, &env_here)
+    ))
+}
+
+/// The files that direnv watched as it loaded the outside environment – those
+/// that `.envrc` files sourced, or asked it to watch – from the
+/// `DIRENV_WATCHES` in `diff`, if it's there and makes sense.
+fn outside_watches(diff: &env::Diff) -> Vec<PathBuf> {
+    let watches = match diff.value(OsStr::new("DIRENV_WATCHES")) {
+        Some(Some(watches)) => watches.to_string_lossy().into_owned(),
+        _ => return Vec::new(),
+    };
+    let watches = match gzenv::decode(&watches).map(|json| json::parse(&json)) {
+        Ok(Ok(json::Value::Array(watches))) => watches,
+        _ => {
+            log::debug!("Could not make sense of DIRENV_WATCHES: {:?}", watches);
+            return Vec::new();
+        }
+    };
+    outside_watches_in(&watches)
+}
+
+/// The paths in direnv's list of watches. Its keys have been spelt both
+/// `path` and `Path`.
+fn outside_watches_in(watches: &[json::Value]) -> Vec<PathBuf> {
+    (watches.iter())
+        .filter_map(|watch| watch.get("path").or_else(|| watch.get("Path")))
+        .filter_map(json::Value::as_str)
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Diff between the environment here and the environment that direnv reports
+/// for the configured parent directory. Without direnv there are no parent
+/// `.envrc` files to speak of, so there's no difference: `apply`, `hook
+/// --shell`, and the like then start from the environment here.
+pub fn diff_outside(config: &config::Config) -> std::result::Result<env::Diff, Error> {
+    if config.direnv_exe().is_err() {
+        log::debug!("direnv is not on PATH; the outside environment is the one here.");
+        return Ok(env::Diff::new());
+    }
+    let env_here: env::Env = vars_os().collect();
+    let capture = || -> std::result::Result<env::Env, Error> {
+        Ok(if config.writable().is_err() {
+            // Observers don't write anything, not even temporary files, so the
+            // dump comes back on stdout instead.
+            let mut dump_cmd = config.command_to_dump_env_outside_to_stdout()?;
+            env::capture_stdout(&mut dump_cmd).map_err(Error::EnvOutside)?
+        } else {
+            // Setting up additional OS pipes for subprocesses to communicate back
+            // to us is not well supported in the Rust standard library, so we use
+            // files in a temporary directory instead. Here we try to create the
+            // temporary directory in a preexisting cache directory, but fall back
+            // to using the system's temporary directory, since we don't want to
+            // write to the filesystem in the project directory until the user has
+            // specifically requested it (by calling `firstaide build` for example).
+            let temp_dir = tempfile::TempDir::new_in(&config.cache_dir)
+                .or_else(|_err| tempfile::TempDir::new())?;
+            let dump_path = temp_dir.path().join("outside");
+            let mut dump_cmd = config.command_to_dump_env_outside(&dump_path)?;
+            env::capture(&mut dump_cmd, &dump_path).map_err(Error::EnvOutside)?
+        })
+    };
+    let env_outside = timings::time("Capture outside environment", capture)?;
+
+    // However, we prevent the parent environment from removing or wiping
+    // DIRENV_WATCHES. This mirrors the behaviour of direnv's `direnv_load`
+    // function; see `direnv stdlib`. We don't use `direnv_load` because it had
+    // a couple of breaking bugs in direnv 2.20.[01].
+    let env_diff = timings::time("Diff environments", || env::diff(&env_here, &env_outside));
+    let env_diff = env_diff.exclude_by(|change| match change {
+        env::Changed(name, _, value) if name == "DIRENV_WATCHES" && value.is_empty() => true,
+        env::Removed(name, _) if name == "DIRENV_WATCHES" => true,
+        _ => false,
+    });
+
+    // Nor may it touch variables that belong to this shell session, like PS1.
+    let env_diff =
+        env_diff.exclude_by(|change| config.hook.is_session_var(change.name().as_bytes()));
+
+    Ok(config
+        .exclude_vars(&env_diff)
+        .with_origin(env::Origin::Outside))
+}
+
+/// The cached diff, less the variables that `policy` excludes, and with paths
+/// made by a relocatable build resolved against the build directory as it is
+/// now.
+pub fn diff_cached(config: &config::Config, cache: &cache::Cache) -> env::Diff {
+    let policy = policy(config);
+    diff_relocated(config, cache).exclude_by(|change| (policy.exclude)(change.name()))
+}
+
+/// The cached diff with paths made by a relocatable build resolved against
+/// the build directory as it is now.
+pub fn diff_relocated(config: &config::Config, cache: &cache::Cache) -> env::Diff {
+    let build_dir = config.build_dir.as_os_str().as_bytes();
+    (cache.diff)
+        .replace_in_values(cache::BUILD_DIR_PLACEHOLDER, build_dir)
+        .with_origin(env::Origin::Cache)
+}
+
+/// How to compose the cached diff onto the environment: leaving out DIRENV_
+/// vars, `hook.exclude_prefixes` (but not `hook.include_vars`),
+/// `hook.session_vars`, and `exclude_vars` – caches written before a variable
+/// was excluded may still have it – and merging per `hook.merge`.
+pub fn policy(config: &config::Config) -> env::Policy<'_> {
+    env::Policy {
+        exclude: Box::new(move |name| {
+            let name = name.as_bytes();
+            config.excludes_var(name)
+                || config.hook.excludes(name)
+                || config.hook.is_session_var(name)
+        }),
+        merge: Box::new(move |name| config.hook.merge_for(name)),
+    }
+}
+
+/// Render a script that reports `err` concisely, pointing at a log file that
+/// has the details. With `--verbose` the details are also reported inline.
+/// Without `config`, because it could not be loaded, the built-in scripts are
+/// used.
+fn hook_error(config: Option<&config::Config>, err: &Error) -> Vec<u8> {
+    let message = format!("{}", err);
+    let mut details = format!(
+        "{}  firstaide {} hook failed\n",
+        chrono::Local::now().format("%+"),
+        crate_version!(),
+    );
+    if let Some(config) = config {
+        details.push_str(&format!(
+            "  directory: {:?}\n  cache dir: {:?}\n",
+            config.build_dir, config.cache_dir,
+        ));
+    }
+    details.push_str(&format!("  error: {}\n", message));
+
+    // Prefer to write the log into the cache directory, but only if it exists;
+    // as elsewhere, we don't want to write into the project directory until
+    // the user has specifically requested it. Otherwise it's a new temporary
+    // file, never one with a name that another user could predict.
+    let log_file = match config {
+        Some(config) if config.writable().is_err() => None,
+        Some(config) if config.cache_dir.is_dir() => {
+            let log_file = config.hook_error_log_file();
+            fs::write(&log_file, &details)
+                .map_err(|err| log::warn!("Could not write {:?}: {}", log_file, err))
+                .ok()
+                .map(|_| log_file)
+        }
+        _ => write_temp_log(&details)
+            .map_err(|err| log::warn!("Could not write hook error log: {}", err))
+            .ok(),
+    };
+
+    let mut output: Vec<u8> = Vec::new();
+    output.extend(b"{ # Start.\n\n");
+    // The hook may have failed for want of a project's own script, so fall
+    // back to the built-in ones here.
+    let script = |name| match config {
+        Some(config) => script(config, name, false).unwrap_or_else(|err| {
+            log::warn!("Could not read {} hook script: {}", name, err);
+            Template::builtin(name)
+        }),
+        None => Template::builtin(name),
+    };
+    output.extend(chunk("Helpers.", &script("helpers").render(&[])));
+    let log = match log_file {
+        Some(log_file) => bash::escape(log_file),
+        None => bash::escape("(could not be written)"),
+    };
+    let chunk_content = script("error").render(&[
+        ("FAILED", Value::Text(i18n::tr("hook.failed"))),
+        (
+            "DETAILS",
+            Value::Bash(self::message("hook.details", &log).0),
+        ),
+        ("MESSAGE", Value::Text(message)),
+    ]);
+    output.extend(chunk("Hook failed!", &chunk_content));
+    if log::log_enabled!(log::Level::Debug) {
+        let mut verbose = Vec::new();
+        for line in details.lines() {
+            verbose.extend(b"log_error ");
+            verbose.extend(bash::escape(line));
+            verbose.extend(b" >&2\n");
+        }
+        output.extend(chunk("Details.", &verbose));
     }
+    output.extend(b"} # End.\n");
     output
 }
+
+/// Write `details` to a new file in the temporary directory, readable only by
+/// its owner, and keep it.
+fn write_temp_log(details: &str) -> io::Result<PathBuf> {
+    let mut file = tempfile::Builder::new()
+        .prefix("firstaide-hook-error.")
+        .suffix(".log")
+        .tempfile()?;
+    file.write_all(details.as_bytes())?;
+    let (_, path) = file.keep().map_err(|err| err.error)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bstr::ByteSlice;
+
+    #[test]
+    fn config_errors_render_as_error_script() {
+        let err = Error::Config(config::Error::DirenvNotFound);
+        let output = String::from_utf8(hook_error(None, &err)).unwrap();
+        assert!(output.starts_with("{ # Start.\n"));
+        assert!(output.contains("### Hook failed!\n"));
+        assert!(output.contains(&bash::escape(err.to_string()).to_str_lossy().into_owned()));
+    }
+
+    #[test]
+    fn checks_are_as_strict_as_strict_env() {
+        assert!(check(b"log_status ok\n").is_ok());
+        assert!(check(b"false\n").is_err());
+        assert!(check(b"echo \"$FIRSTAIDE_NOT_SET\"\n").is_err());
+        assert!(check(b"false | true\n").is_err());
+    }
+
+    #[test]
+    fn builtin_scripts_run_under_strict_env() {
+        let helpers = Template::builtin("helpers").render(&[]);
+        for (name, _, placeholders) in template::SCRIPTS {
+            for building in ["true", "false"] {
+                let values: Vec<(&str, Value)> = (placeholders.iter())
+                    .map(|&placeholder| match placeholder {
+                        "BUILDING" => (placeholder, Value::Bash(building.into())),
+                        "PAD" => (placeholder, Value::Bash(b"' '".to_vec())),
+                        _ => (placeholder, Value::Text(format!("{} text", placeholder))),
+                    })
+                    .collect();
+                let mut script = helpers.clone();
+                script.extend(Template::builtin(name).render(&values));
+                if let Err(err) = check(&script) {
+                    panic!("{} (building: {}): {}", name, building, err);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn temp_logs_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = write_temp_log("details").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(0o600, mode & 0o777);
+        assert_ne!(std::env::temp_dir().join("firstaide-hook-error.log"), path);
+    }
+
+    #[test]
+    fn private_files_are_private_and_encrypted() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_dir, config) = config::test_project(
+            "encrypt_cache = true\n[cache.key]\nprovider = \"file\"\npath = \"key\"\n",
+        );
+        fs::create_dir(&config.cache_dir).unwrap();
+        fs::write(config.build_dir.join("key"), "sesame").unwrap();
+        let file = config.outside_cache_file();
+        save_private(&config, &file, b"ALICE=alice".to_vec()).unwrap();
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(!fs::read(&file).unwrap().windows(5).any(|w| w == b"alice"));
+        assert_eq!(Some(b"ALICE=alice".to_vec()), load_private(&config, &file));
+    }
+
+    #[test]
+    fn outside_key_covers_what_direnv_watched() {
+        let (_dir, config) = config::test_project("");
+        let sourced = config.build_dir.join("sourced.sh");
+        let watches = format!(r#"[{{"path":{:?},"modtime":0,"exists":true}}]"#, sourced);
+        let diff = env::Diff::from(&[env::Added(
+            "DIRENV_WATCHES".into(),
+            gzenv::encode(&watches).into(),
+        )]);
+        let watches = outside_watches(&diff);
+        assert_eq!(vec![sourced.clone()], watches);
+        assert_eq!(Vec::<PathBuf>::new(), outside_watches(&env::Diff::new()));
+
+        let before = outside_key(&config, &watches).unwrap();
+        fs::write(&sourced, "export A=a\n").unwrap();
+        let after = outside_key(&config, &watches).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn generations_follow_the_cached_environment_not_the_history() {
+        let (_dir, config) = config::test_project("");
+        let sums = sums::Checksums::from::<&str>(&[]).unwrap();
+        let cache = |value: &str| {
+            let diff = env::Diff::from(&[env::Added("A".into(), value.into())]);
+            cache::Cache::new(diff, sums.clone())
+        };
+        let before = generation(&config, &cache("a")).unwrap();
+        assert_eq!(before, generation(&config, &cache("a")).unwrap());
+        assert_ne!(before, generation(&config, &cache("b")).unwrap());
+    }
+
+    #[test]
+    fn every_shell_is_evaluated_directly_and_only_on_request() {
+        let parse = |args: &[&str]| argspec().get_matches_from_safe(args);
+        let shell = |args: &[&str]| parse(args).unwrap().value_of("shell").map(str::to_owned);
+        assert_eq!(None, shell(&["hook"]));
+        assert_eq!(Some("bash".into()), shell(&["hook", "--shell", "bash"]));
+        assert!(parse(&["hook", "--shell", "zsh", "--out", "hook.sh"]).is_err());
+        assert!(parse(&["hook", "--shell", "zsh", "--minimal"]).is_err());
+        assert!(parse(&["hook", "--shell", "zsh", "--format", "direnv-dump"]).is_err());
+    }
+
+    #[test]
+    fn minimal_hooks_leave_out_titles_and_builtin_comments_only() {
+        let (_dir, config) = config::test_project("[hook.scripts]\nstale = \"stale.sh\"\n");
+        let heredoc = "cat <<EOF\n# Not a comment.\nEOF\n";
+        fs::write(config.build_dir.join("stale.sh"), heredoc).unwrap();
+        let stale = script(&config, "stale", true).unwrap().render(&[]);
+        assert_eq!(heredoc, stale.to_str_lossy());
+        assert_eq!(
+            format!("{}\n", heredoc),
+            chunk_if(true, "Stale.", &stale).to_str_lossy()
+        );
+        assert!(chunk_if(false, "Stale.", &stale).starts_with(b"### Stale.\n"));
+        for name in ["helpers", "active", "no-direnv"] {
+            let minimal = script(&config, name, true).unwrap().render(&[]);
+            let full = script(&config, name, false).unwrap().render(&[]);
+            assert!(minimal
+                .lines()
+                .all(|line| !line.trim_start().starts_with(b"#")));
+            assert!(minimal.len() < full.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn out_writes_the_hook_and_echoes_a_source_command() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("the hook.sh");
+        let source = write_out(&out, b"export A=a\n").unwrap();
+        assert_eq!(b"export A=a\n".to_vec(), fs::read(&out).unwrap());
+        let mut expected = b"source ".to_vec();
+        bash::escape_into(&out, &mut expected);
+        expected.push(b'\n');
+        assert_eq!(expected, source);
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+        // Not clobbered by a temporary file of the same name.
+        let out = dir.path().join("hook.new");
+        write_out(&out, b"export B=b\n").unwrap();
+        assert_eq!(b"export B=b\n".to_vec(), fs::read(&out).unwrap());
+    }
+
+    #[test]
+    fn watch_files_are_escaped_and_separated() {
+        let paths = vec![PathBuf::from("/a b"), PathBuf::from("/c")];
+        assert_eq!(
+            b"watch_file $'/a b' /c\n".to_vec(),
+            watch_files(true, paths.clone())
+        );
+        assert_eq!(
+            b"watch_file \\\n  $'/a b' \\\n  /c\n".to_vec(),
+            watch_files(false, paths)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dumps_are_what_direnv_loads() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let watched = dir.path().join("default.nix");
+        fs::write(&watched, "{}").unwrap();
+        let missing = dir.path().join("missing");
+        let envrc = json::Value::object(vec![
+            ("Path", "/project/.envrc".into()),
+            ("Modtime", 1700000000.into()),
+            ("Exists", true.into()),
+        ]);
+        let env: env::Env = vec![
+            ("A".into(), "a".into()),
+            (
+                "DIRENV_WATCHES".into(),
+                gzenv::encode(&json::Value::Array(vec![envrc]).to_string()).into(),
+            ),
+            ("NOT_UTF8".into(), OsStr::from_bytes(b"\xff").into()),
+        ];
+        let dump = dump(&env, vec![watched.clone(), missing.clone()]);
+        let dump = json::parse(&gzenv::decode(&dump).unwrap()).unwrap();
+        assert_eq!(Some("a"), dump.get("A").and_then(json::Value::as_str));
+        assert_eq!(None, dump.get("NOT_UTF8"));
+        let watches = (dump.get("DIRENV_WATCHES"))
+            .and_then(json::Value::as_str)
+            .map(|watches| json::parse(&gzenv::decode(watches).unwrap()).unwrap());
+        let watches = match watches {
+            Some(json::Value::Array(watches)) => watches,
+            watches => panic!("no watches: {:?}", watches),
+        };
+        assert_eq!(
+            vec![PathBuf::from("/project/.envrc"), watched, missing],
+            outside_watches_in(&watches)
+        );
+        assert_eq!(Some(&json::Value::Bool(true)), watches[1].get("Exists"));
+        assert_ne!(Some(&json::Value::Number(0)), watches[1].get("Modtime"));
+        assert_eq!(Some(&json::Value::Bool(false)), watches[2].get("Exists"));
+    }
+
+    #[test]
+    fn custom_messages_emphasise_placeholders() {
+        let word = custom_message(
+            "Run {build} ({changed}), not {other} or {",
+            &[("build", "make env"), ("changed", "a.txt")],
+        );
+        assert_eq!(
+            concat!(
+                "$'Run '\"$(em $'make env')\"",
+                "$' ('\"$(em a.txt)\"",
+                "$'), not {other} or {'",
+            ),
+            String::from_utf8(word).unwrap()
+        );
+    }
+
+    #[test]
+    fn overrides_are_values_from_the_shell_that_are_lost() {
+        let hook = config::Hook {
+            report_overrides: vec!["*PATH".into(), "GONE".into()],
+            ..config::Hook::default()
+        };
+        let diff = env::Diff::from(&[
+            env::Added("PYTHONPATH".into(), "/nix/site".into()),
+            env::Changed("PATH".into(), "/bin".into(), "/nix/bin:/bin".into()),
+            env::Removed("GONE".into(), "x".into()),
+            env::Added("MANPATH".into(), "/nix/man".into()),
+            env::Added("LD_PATH".into(), "/abc/lib".into()),
+            env::Added("EMPTY_PATH".into(), "/nix/empty".into()),
+        ])
+        .with_origin(env::Origin::Cache);
+        let now = |name: &OsStr| match name.to_str() {
+            Some("PYTHONPATH") => Some("/home/site".into()),
+            Some("PATH") => Some("/bin".into()),
+            Some("GONE") => Some("x".into()),
+            // Part of a value is not the value.
+            Some("LD_PATH") => Some("/a".into()),
+            Some("EMPTY_PATH") => Some("".into()),
+            _ => None,
+        };
+        let names: Vec<OsString> = vec![
+            "PYTHONPATH".into(),
+            "GONE".into(),
+            "LD_PATH".into(),
+            "EMPTY_PATH".into(),
+        ];
+        assert_eq!(names, overrides(&hook, &diff, now));
+    }
+}
@@ -1,5 +1,5 @@
 # shellcheck shell=bash
-log_status "$(okay OKAY): $(em 'Nix environment is up to date!')" >&2
-log_status "This is a $(em minimal environment); subprojects may not be built." >&2
-log_status "--> Use $(em __MESSAGE__) to find out what to do next." >&2
-log_status "        $(m=__MESSAGE__ && em "${m//?/^}")" >&2
+log_status "$(okay OKAY): $(em __OKAY__)" >&2
+log_status __MINIMAL__ >&2
+log_status "--> "__NEXT__ >&2
+log_status "    __PAD__$(m=__MESSAGE__ && em "${m//?/^}")" >&2
@@ -0,0 +1,3 @@
+# shellcheck shell=bash
+log_status "$(error ERROR): $(em __FAILED__) "__MESSAGE__ >&2
+log_status "--> "__DETAILS__ >&2
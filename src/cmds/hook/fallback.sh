@@ -0,0 +1,2 @@
+# shellcheck shell=bash
+log_status "--> "__FALLBACK__ >&2
@@ -1,3 +1,7 @@
 # shellcheck shell=bash
-log_status "$(error ERROR): $(em 'Nix environment is not yet built!')" >&2
-log_status "--> Use $(em firstaide build) to build it." >&2
+log_status "$(error ERROR): $(em __UNBUILT__)" >&2
+if __BUILDING__; then
+    log_status "--> "__IN_PROGRESS__ >&2
+else
+    log_status "--> "__BUILD__ >&2
+fi
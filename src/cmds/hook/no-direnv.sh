@@ -0,0 +1,3 @@
+# shellcheck shell=bash
+# direnv's `log_status` is not available without direnv.
+printf 'firstaide: %s: %s\n' "$(error ERROR)" "$(em __NO_DIRENV__)" >&2
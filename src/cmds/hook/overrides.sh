@@ -0,0 +1,2 @@
+# shellcheck shell=bash
+log_status "$(warning NOTE): "__OVERRIDES__ >&2
@@ -0,0 +1,7 @@
+# shellcheck shell=bash
+log_status "$(warning NOTE): $(em __SOFT_STALE__)" >&2
+if __BUILDING__; then
+    log_status "--> "__IN_PROGRESS__ >&2
+else
+    log_status "--> "__REBUILD__ >&2
+fi
@@ -1,4 +1,8 @@
 # shellcheck shell=bash
-log_status "$(warning WARNING): $(em 'Nix environment is out of date!') " >&2
-log_status "--> Use $(em firstaide build) to rebuild it." >&2
-log_status "$(warning WARNING): Loading $(em STALE) environment ;-(" >&2
+log_status "$(warning WARNING): $(em __STALE__) " >&2
+if __BUILDING__; then
+    log_status "--> "__IN_PROGRESS__ >&2
+else
+    log_status "--> "__REBUILD__ >&2
+fi
+log_status "$(warning WARNING): "__LOADING_STALE__ >&2
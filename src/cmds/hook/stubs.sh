@@ -0,0 +1,7 @@
+# shellcheck shell=bash
+# Stand-ins for direnv's stdlib, used when checking a hook. The hook must run
+# under direnv's `strict_env` too, so check it as that would run it.
+set -euo pipefail
+log_status() { :; }
+log_error() { :; }
+watch_file() { :; }
@@ -0,0 +1,117 @@
+use crate::cmds;
+use crate::config;
+use crate::creds;
+use crate::error::Code;
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+pub const NAME: &str = "login";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Creds(creds::Error),
+    Io(io::Error),
+    NoToken,
+    Mismatch(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Creds(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            NoToken => write!(f, "no token given"),
+            Mismatch(place) => write!(f, "token read back from {} does not match", place),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Creds(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Creds(_) => Code::Config,
+            Io(_) => Code::Io,
+            NoToken => Code::Usage,
+            Mismatch(_) => Code::Config,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<creds::Error> for Error {
+    fn from(error: creds::Error) -> Self {
+        Error::Creds(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Stores a token for the remote cache")
+        .long_about(concat!(
+            "Stores a token for the remote cache.\n",
+            "The token is read from stdin and stored using the provider ",
+            "configured in the [cache.remote.auth] section of .firstaide.toml.",
+        ))
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+
+    // Read the token from stdin rather than from the command line so that it
+    // does not end up in shell history or the process table.
+    if io::stdin().is_terminal() {
+        eprint!("Token: ");
+        io::stderr().flush()?;
+    }
+    let mut token = String::new();
+    io::stdin().lock().read_line(&mut token)?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(Error::NoToken);
+    }
+
+    config.remote_auth.store(token)?;
+    // Read it back to be sure that it can be found later on.
+    if config.remote_auth.token()? != token {
+        return Err(Error::Mismatch(config.remote_auth.describe()));
+    }
+    log::info!("Token stored in {}.", config.remote_auth.describe());
+
+    Ok(0)
+}
@@ -0,0 +1,260 @@
+use crate::cmds;
+use crate::cmds::diff;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use crate::json;
+use crate::stamps;
+use crate::status::EnvironmentStatus;
+use crate::sums;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+pub const NAME: &str = "serve";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Answers JSON-RPC requests, e.g. from editors")
+        .long_about(concat!(
+            "Answers JSON-RPC 2.0 requests, e.g. from editors.\n",
+            "Requests and responses are one per line. Methods: status, env, diff, ",
+            "watches, build, and shutdown.",
+        ))
+        .arg(
+            clap::Arg::with_name("stdio")
+                .long("stdio")
+                .required(true)
+                .help("Serve on stdin and stdout (the only transport for now)"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let mut server = Server { config, memo: None };
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, shutdown) = server.handle(&line);
+        if let Some(response) = response {
+            let mut handle = stdout.lock();
+            writeln!(&mut handle, "{}", response)?;
+            handle.flush()?;
+        }
+        if shutdown {
+            break;
+        }
+    }
+    Ok(0)
+}
+
+// Error codes from the JSON-RPC 2.0 specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_ERROR: i64 = -32000;
+
+type Reply = std::result::Result<json::Value, (i64, String)>;
+
+struct Server {
+    config: config::Config,
+    /// Checksums from last time, and the stamps of the files they came from.
+    /// Only when a stamp changes do we pay to calculate checksums again.
+    memo: Option<(stamps::Stamps, sums::Checksums)>,
+}
+
+impl Server {
+    /// Returns the response, if any – notifications get none – and whether
+    /// the server should shut down.
+    fn handle(&mut self, line: &str) -> (Option<json::Value>, bool) {
+        let request = match json::parse(line) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = (PARSE_ERROR, err.to_string());
+                return (Some(response(json::Value::Null, Err(error))), false);
+            }
+        };
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(json::Value::as_str);
+        let (reply, shutdown) = match method {
+            None => (Err((INVALID_REQUEST, "method missing".into())), false),
+            Some("shutdown") => (Ok(json::Value::Null), true),
+            Some(method) => (self.call(method), false),
+        };
+        if let Err((code, message)) = &reply {
+            log::debug!("{:?} failed ({}): {}", method, code, message);
+        }
+        (id.map(|id| response(id, reply)), shutdown)
+    }
+
+    fn call(&mut self, method: &str) -> Reply {
+        match method {
+            "status" => self.status(),
+            "env" => self.env(),
+            "diff" => self.diff(),
+            "watches" => self.watches(),
+            "build" => self.build(),
+            _ => Err((METHOD_NOT_FOUND, format!("no method {:?}", method))),
+        }
+    }
+
+    fn checksums(&mut self) -> std::result::Result<sums::Checksums, (i64, String)> {
+        let files = self.config.watch_files().map_err(server_error)?;
+        let stamps = stamps::Stamps::of(&files);
+        if let Some((memo_stamps, memo_sums)) = &self.memo {
+            if memo_stamps == &stamps {
+                return Ok(memo_sums.clone());
+            }
+        }
+        let sums_now = self.config.checksums_of(&files).map_err(server_error)?;
+        self.memo = Some((stamps, sums_now.clone()));
+        Ok(sums_now)
+    }
+
+    fn status(&mut self) -> Reply {
+        let sums_now = self.checksums()?;
+        let status = match self.config.load_cache(&sums_now) {
+            Ok((cache, _)) => self.config.staleness(&sums_now, &cache.sums),
+            Err(_) => EnvironmentStatus::Unknown,
+        };
+        Ok(json::Value::object(vec![
+            ("status", status.name().into()),
+            ("message", status.display().into()),
+        ]))
+    }
+
+    /// The variables that the hook would set or unset, with their values.
+    fn env(&mut self) -> Reply {
+        let sums_now = self.checksums()?;
+        let env_diff = diff::compose(&self.config, &sums_now).map_err(server_error)?;
+        Ok(env::to_json(&env_diff))
+    }
+
+    fn diff(&mut self) -> Reply {
+        let sums_now = self.checksums()?;
+        let env_diff = diff::compose(&self.config, &sums_now).map_err(server_error)?;
+        Ok(diff::to_json(&env_diff))
+    }
+
+    fn watches(&mut self) -> Reply {
+        let sums_now = self.checksums()?;
+        Ok(json::Value::Array(
+            (sums_now.iter())
+                .filter_map(sums::Checksum::path)
+                .map(|path| path.to_string_lossy().into_owned().into())
+                .collect(),
+        ))
+    }
+
+    /// Start a build in the background; see `status` for its progress.
+    fn build(&mut self) -> Reply {
+        let status = process::Command::new(&self.config.self_exe)
+            .arg("build")
+            .arg(&self.config.build_dir)
+            .arg("--detach")
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .status()
+            .map_err(server_error)?;
+        if status.success() {
+            Ok(json::Value::object(vec![("started", true.into())]))
+        } else {
+            Err((
+                SERVER_ERROR,
+                format!("build could not be started: {}", status),
+            ))
+        }
+    }
+}
+
+fn server_error<E: fmt::Display>(err: E) -> (i64, String) {
+    (SERVER_ERROR, err.to_string())
+}
+
+fn response(id: json::Value, reply: Reply) -> json::Value {
+    let outcome = match reply {
+        Ok(result) => ("result", result),
+        Err((code, message)) => (
+            "error",
+            json::Value::object(vec![("code", code.into()), ("message", message.into())]),
+        ),
+    };
+    json::Value::object(vec![("jsonrpc", "2.0".into()), ("id", id), outcome])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_carries_id_and_result_or_error() {
+        assert_eq!(
+            r#"{"jsonrpc":"2.0","id":1,"result":true}"#,
+            response(1.into(), Ok(true.into())).to_string()
+        );
+        assert_eq!(
+            r#"{"jsonrpc":"2.0","id":"a","error":{"code":-32601,"message":"nope"}}"#,
+            response("a".into(), Err((METHOD_NOT_FOUND, "nope".into()))).to_string()
+        );
+    }
+}
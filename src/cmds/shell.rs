@@ -0,0 +1,156 @@
+use crate::cache;
+use crate::cmds;
+use crate::cmds::hook;
+use crate::config;
+use crate::env;
+use crate::error::Code;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::process;
+
+pub const NAME: &str = "shell";
+
+/// Exported into the subshell so that it, and tools within, can tell that
+/// they're in one. Its value is the build directory.
+pub const SHELL_VAR: &str = "FIRSTAIDE_SHELL";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Cache(cache::Error),
+    Exec(OsString, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Cache(err) => write!(f, "no environment; run `firstaide build` first: {}", err),
+            Exec(shell, err) => write!(f, "could not run {:?}: {}", shell, err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Cache(err) => Some(err),
+            Exec(_, err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) | Exec(_, _) => Code::Io,
+            Cache(err) => err.code(),
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(error: cache::Error) -> Self {
+        Error::Cache(error)
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Starts a shell in the development environment")
+        .long_about(concat!(
+            "Starts a shell in the development environment, without direnv.\n",
+            "The shell is $SHELL, or /bin/sh when that's not set. With --command, ",
+            "the shell runs COMMAND and exits. When the environment is stale, the ",
+            "most recent build is used; run `firstaide build` to bring it up to date. ",
+            "Exit the shell to leave the environment.",
+        ))
+        .arg(
+            clap::Arg::with_name("command")
+                .short("c")
+                .long("command")
+                .value_name("COMMAND")
+                .help("Run COMMAND in the shell instead of starting it interactively"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let sums_now = config.checksums()?;
+    let (cache, _) = config.load_cache(&sums_now)?;
+    let status = config.staleness(&sums_now, &cache.sums);
+
+    // As in the hook, list variables like PATH are merged with their values
+    // now, and overlays for the directory we're in go on top.
+    let mut env_diff = config.overlay(env::compose(
+        |name| std::env::var_os(name),
+        &hook::diff_relocated(&config, &cache),
+        &hook::policy(&config),
+    ));
+    env_diff.simplify();
+    let env_here: env::Env = std::env::vars_os().collect();
+
+    let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+    let mut command = process::Command::new(&shell);
+    if let Some(script) = args.value_of_os("command") {
+        command.arg("-c").arg(script);
+    } else {
+        if std::env::var_os(SHELL_VAR).is_some() {
+            log::warn!("Already in a firstaide shell; starting another within it.");
+        }
+        log::info!("{}", status);
+        log::info!("Starting {:?}; exit to leave the environment.", shell);
+    }
+    command
+        .env_clear()
+        .envs(env::apply(&env_here, &env_diff))
+        .env(SHELL_VAR, &config.build_dir);
+
+    log::debug!("{:?}", command);
+    exec(shell, command)
+}
+
+/// Replace this process with `command`. Only returns on failure.
+#[cfg(unix)]
+fn exec(shell: OsString, mut command: process::Command) -> Result {
+    use std::os::unix::process::CommandExt;
+    Err(Error::Exec(shell, command.exec()))
+}
+
+/// Windows cannot replace a process, so run `command` and exit as it did.
+#[cfg(windows)]
+fn exec(shell: OsString, mut command: process::Command) -> Result {
+    match command.status() {
+        Ok(status) => Ok(status.code().unwrap_or(1) as u8),
+        Err(err) => Err(Error::Exec(shell, err)),
+    }
+}
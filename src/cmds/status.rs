@@ -1,17 +1,30 @@
 use crate::cache;
+use crate::cmds;
 use crate::config;
+use crate::error::Code;
+use crate::progress;
+use crate::stamps;
 use crate::status::EnvironmentStatus;
 use crate::sums;
+use crate::term;
+use crate::watcher;
+use crate::workspace;
+use chrono::Local;
 use std::fmt;
 use std::io::{self, Write};
+use std::process;
+use std::time::{Duration, Instant};
 
 pub const NAME: &str = "status";
 
 type Result = std::result::Result<u8, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Config(config::Error),
     Io(io::Error),
+    Workspace(workspace::Error),
+    TimedOut(String, EnvironmentStatus),
 }
 
 impl fmt::Display for Error {
@@ -20,6 +33,38 @@ impl fmt::Display for Error {
         match self {
             Config(err) => write!(f, "{}", err),
             Io(err) => write!(f, "input/output error: {}", err),
+            Workspace(err) => write!(f, "{}", err),
+            TimedOut(target, status) => write!(
+                f,
+                "gave up waiting for the environment to be {}; it is {}",
+                target,
+                status.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            Workspace(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Workspace(err) => err.code(),
+            TimedOut(..) => Code::Timeout,
         }
     }
 }
@@ -32,7 +77,7 @@ impl From<config::Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
@@ -41,10 +86,67 @@ pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
         .about("Reports the status of the development environment")
         .long_about(concat!(
             "Reports the status of the development environment.\n",
-            "- Exits 0 when the environment is up to date.\n",
+            "- Exits 0 when the environment is up to date, or only soft stale.\n",
             "- Exits 1 when the environment is stale.\n",
-            "- Exits 2 when the environment is unbuilt, or when an error occurs.",
+            "- Exits 2 when the environment is unbuilt, or when an error occurs.\n",
+            "- Exits 7 when --wait-for gives up after --timeout.\n",
+            "With --all, reports on every member of the workspace, and exits with the ",
+            "worst of their codes. With --silent, prints nothing; there's only the code.",
         ))
+        .arg(
+            clap::Arg::with_name("prompt").long("prompt").help(
+                "Print only a short status for use in a shell prompt; see `prompt` in config",
+            ),
+        )
+        .arg(
+            clap::Arg::with_name("watch")
+                .long("watch")
+                .conflicts_with("prompt")
+                .help("Keep watching, printing the status whenever it changes"),
+        )
+        .arg(
+            clap::Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .validator(super::is_positive)
+                .default_value("2")
+                .help("How often to check the watched files when none has changed"),
+        )
+        .arg(
+            clap::Arg::with_name("exec")
+                .long("exec")
+                .value_name("COMMAND")
+                .requires("watch")
+                .help("Run COMMAND with Bash whenever the environment becomes stale"),
+        )
+        .arg(
+            clap::Arg::with_name("wait-for")
+                .long("wait-for")
+                .value_name("STATUS")
+                .possible_values(&["ok", "soft-stale", "stale", "none"])
+                .conflicts_with_all(&["prompt", "watch"])
+                .help("Wait until the environment has STATUS; ok includes soft-stale"),
+        )
+        .arg(
+            clap::Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("DURATION")
+                .requires("wait-for")
+                .validator(is_duration)
+                .help("Give up waiting after DURATION, e.g. 90s, 30m, or 1h30m"),
+        )
+        .arg(
+            clap::Arg::with_name("all")
+                .long("all")
+                .conflicts_with_all(&["prompt", "watch", "wait-for"])
+                .help("Report on every member of the workspace that DIR is in"),
+        )
+        .arg(
+            clap::Arg::with_name("silent")
+                .long("silent")
+                .conflicts_with_all(&["prompt", "watch"])
+                .help("Print nothing; the exit code is the status"),
+        )
         .arg(
             clap::Arg::with_name("dir")
                 .value_name("DIR")
@@ -53,25 +155,313 @@ pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
 }
 
 pub fn run(args: &clap::ArgMatches) -> Result {
-    let config = config::Config::load(args.value_of_os("dir"))?;
+    if args.is_present("all") {
+        return status_all(args);
+    }
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    if args.is_present("watch") {
+        let interval = value_t!(args, "interval", u64).expect("validated by clap");
+        return watch(
+            &config,
+            Duration::from_secs(interval),
+            args.value_of("exec"),
+        );
+    }
+
+    let silent = args.is_present("silent");
+    if let Some(target) = args.value_of("wait-for") {
+        let interval = value_t!(args, "interval", u64).expect("validated by clap");
+        let timeout =
+            (args.value_of("timeout")).map(|timeout| duration(timeout).expect("validated by clap"));
+        let status = wait_for(&config, target, Duration::from_secs(interval), timeout)?;
+        if !silent {
+            println!("{}", status.marked());
+        }
+        return Ok(status.code());
+    }
+
     let stdout = io::stdout();
     let mut handle = stdout.lock();
 
-    let sums_now = sums::Checksums::from(&config.watch_files()?)?;
-    let cache_file = config.cache_file(&sums_now);
-    let cache_file_fallback = config.cache_file_most_recent();
+    // Prompts must be fast, so they make do with the stat cache.
+    let sums_now = if args.is_present("prompt") {
+        stamps::checksums(&config)?
+    } else {
+        config.checksums()?
+    };
+    let cache = load(&config, &sums_now);
 
-    let status = match cache::Cache::load_with_fallback(&cache_file, &cache_file_fallback) {
-        Ok(cache) => {
-            if sums::equal(&sums_now, &cache.sums) {
-                EnvironmentStatus::Okay
-            } else {
-                EnvironmentStatus::Stale
+    if args.is_present("prompt") {
+        let status = status_of_cache(&config, &sums_now, &cache);
+        // The prompt is captured by the shell, so it's never a terminal; it's
+        // colored on request only, and `NO_COLOR` has the last word.
+        let color = match term::choice() {
+            term::Choice::Always => true,
+            term::Choice::Never => false,
+            term::Choice::Auto => config.prompt.color && !term::no_color(),
+        };
+        writeln!(
+            &mut handle,
+            "{}",
+            prompt(&config.prompt.format, &status, color)
+        )?;
+        return Ok(status.code());
+    }
+
+    // Not for prompts: they'd warn every time.
+    if let Ok(cache) = &cache {
+        cache.warn_if_foreign();
+    }
+    let status = status_of_cache(&config, &sums_now, &cache);
+    if silent {
+        return Ok(status.code());
+    }
+    writeln!(&mut handle, "{}", status.marked())?;
+    if let Some(git) = cache.as_ref().ok().and_then(|cache| cache.git.as_ref()) {
+        let git = term::Style::Dim.paint(&git.to_string());
+        writeln!(&mut handle, "Built from {}.", git)?;
+    }
+    if let Some(progress) = progress::current(&config) {
+        writeln!(
+            &mut handle,
+            "Build in progress (PID {}, started {}): {}.",
+            progress.pid,
+            progress.started_ago(),
+            progress.phase,
+        )?;
+    }
+    Ok(status.code())
+}
+
+/// A line per member of the workspace: its status, or what went wrong.
+fn status_all(args: &clap::ArgMatches) -> Result {
+    let silent = args.is_present("silent");
+    let workspace =
+        workspace::Workspace::find(args.value_of_os("dir")).map_err(Error::Workspace)?;
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    let mut code = 0;
+    for member in &workspace.members {
+        let name = workspace.name(member).display();
+        let status = config::Config::load(Some(member))
+            .map_err(Error::from)
+            .and_then(|config| Ok(status_of(&config, &config.checksums()?)));
+        match status {
+            Ok(status) => {
+                code = code.max(status.code());
+                if !silent {
+                    writeln!(&mut handle, "{}: {}", name, status.marked())?;
+                }
             }
+            Err(err) => {
+                code = code.max(err.code().exit_code());
+                if !silent {
+                    let err = term::Style::Error.paint(&err.to_string());
+                    writeln!(&mut handle, "{}: {}", name, err)?;
+                }
+            }
+        }
+    }
+    Ok(code)
+}
+
+pub fn status_of(config: &config::Config, sums_now: &sums::Checksums) -> EnvironmentStatus {
+    status_of_cache(config, sums_now, &load(config, sums_now))
+}
+
+fn load(config: &config::Config, sums_now: &sums::Checksums) -> cache::Result<cache::Cache> {
+    config.load_cache(sums_now).map(|(cache, _)| cache)
+}
+
+fn status_of_cache(
+    config: &config::Config,
+    sums_now: &sums::Checksums,
+    cache: &cache::Result<cache::Cache>,
+) -> EnvironmentStatus {
+    match cache {
+        Ok(cache) => config.staleness(sums_now, &cache.sums),
+        Err(err @ cache::Error::Outdated(_))
+        | Err(err @ cache::Error::TooNew(_))
+        | Err(err @ cache::Error::Decode(_))
+        | Err(err @ cache::Error::Decompress(_))
+        | Err(err @ cache::Error::Crypt(_)) => {
+            log::warn!("{}", err);
+            EnvironmentStatus::Unknown
         }
-        Err(_) => EnvironmentStatus::Unknown,
+        Err(err) => {
+            log::debug!("{}", err);
+            EnvironmentStatus::Unknown
+        }
+    }
+}
+
+/// Print the status, then again each time it changes. Checksums are
+/// calculated only when a watched file's size or modification time changes, so
+/// the checks are cheap, and a check that fails is tried again, later and
+/// later, rather than ending the watch.
+fn watch(config: &config::Config, interval: Duration, exec: Option<&str>) -> Result {
+    let mut watcher = watcher::Watcher::new(config, interval);
+    let mut status_last: Option<EnvironmentStatus> = None;
+    loop {
+        let sums_now = match stamps::checksums(config) {
+            Ok(sums_now) => sums_now,
+            Err(err) => {
+                watcher.retry_after(err);
+                continue;
+            }
+        };
+        let status = status_of(config, &sums_now);
+        if status_last != Some(status) {
+            let now = Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
+            println!("{} {}", term::Style::Dim.paint(&now), status.marked());
+            if status == EnvironmentStatus::Stale {
+                if let Some(exec) = exec {
+                    let mut command = process::Command::new("bash");
+                    command.arg("-c").arg(exec).current_dir(&config.build_dir);
+                    log::debug!("{:?}", command);
+                    // Keep watching either way; the next change may fix it.
+                    match command.status() {
+                        Ok(exit) if exit.success() => (),
+                        Ok(exit) => log::warn!("{:?} failed ({}).", exec, exit),
+                        Err(err) => log::warn!("{:?} could not be started: {}", exec, err),
+                    }
+                }
+            }
+            status_last = Some(status);
+        }
+        watcher.wait(None);
+    }
+}
+
+/// Wait until the environment has the `target` status, and return it, or
+/// fail once `timeout` passes. A target of `ok` is met by soft
+/// stale too, as `status` exits 0 for both.
+fn wait_for(
+    config: &config::Config,
+    target: &str,
+    interval: Duration,
+    timeout: Option<Duration>,
+) -> std::result::Result<EnvironmentStatus, Error> {
+    let mut watcher = watcher::Watcher::new(config, interval);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let status = status_of(config, &stamps::checksums(config)?);
+        let reached = match target {
+            "ok" => status.code() == 0,
+            target => status.name() == target,
+        };
+        if reached {
+            return Ok(status);
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            return Err(Error::TimedOut(target.into(), status));
+        }
+        log::debug!(
+            "Waiting for {}; the environment is {}.",
+            target,
+            status.name()
+        );
+        watcher.wait(remaining);
+    }
+}
+
+/// For clap: is `value` a duration that `duration` can parse?
+fn is_duration(value: String) -> std::result::Result<(), String> {
+    match duration(&value) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "{:?} is not a duration, e.g. 90s, 30m, or 1h30m",
+            value
+        )),
+    }
+}
+
+/// Parse a duration like `90s`, `30m`, `1h30m`, or `45`, which is seconds.
+fn duration(text: &str) -> Option<Duration> {
+    if let Ok(seconds) = text.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let (mut seconds, mut number) = (0u64, None::<u64>);
+    for ch in text.chars() {
+        match ch {
+            '0'..='9' => {
+                let digit = ch.to_digit(10)? as u64;
+                number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            }
+            'h' | 'm' | 's' => {
+                let unit = match ch {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                seconds = seconds.checked_add(number.take()?.checked_mul(unit)?)?;
+            }
+            _ => return None,
+        }
+    }
+    match number {
+        None if !text.is_empty() => Some(Duration::from_secs(seconds)),
+        _ => None,
+    }
+}
+
+fn prompt(format: &str, status: &EnvironmentStatus, color: bool) -> String {
+    let style = match status {
+        EnvironmentStatus::Okay => term::Style::Okay,
+        EnvironmentStatus::SoftStale | EnvironmentStatus::Stale => term::Style::Warning,
+        EnvironmentStatus::Unknown => term::Style::Error,
     };
+    format.replace("{status}", &style.paint_if(color, status.name()))
+}
 
-    writeln!(&mut handle, "{}", status)?;
-    Ok(status.code())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(Some(Duration::from_secs(45)), duration("45"));
+        assert_eq!(Some(Duration::from_secs(90)), duration("90s"));
+        assert_eq!(Some(Duration::from_secs(1800)), duration("30m"));
+        assert_eq!(Some(Duration::from_secs(5400)), duration("1h30m"));
+        assert_eq!(None, duration(""));
+        assert_eq!(None, duration("m"));
+        assert_eq!(None, duration("1h30"));
+        assert_eq!(None, duration("30 min"));
+    }
+
+    #[test]
+    fn arguments_that_cannot_work_are_usage_errors() {
+        let parse = |args: &[&str]| argspec().get_matches_from_safe(args);
+        assert!(parse(&["status", "--wait-for", "ok", "--timeout", "30m"]).is_ok());
+        assert!(parse(&["status", "--wait-for", "ok", "--timeout", "soon"]).is_err());
+        assert!(parse(&["status", "--all", "--wait-for", "ok"]).is_err());
+        assert!(parse(&["status", "--silent", "--prompt"]).is_err());
+        assert!(parse(&["status", "--watch", "--interval", "1"]).is_ok());
+        assert!(parse(&["status", "--watch", "--interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn timeouts_have_their_own_code() {
+        let err = Error::TimedOut("ok".into(), EnvironmentStatus::Stale);
+        assert_eq!(Code::Timeout, err.code());
+        assert_eq!(7, err.code().exit_code());
+        assert_eq!(
+            "gave up waiting for the environment to be ok; it is stale",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn prompt_fills_in_status() {
+        assert_eq!(
+            "nix:stale",
+            prompt("nix:{status}", &EnvironmentStatus::Stale, false)
+        );
+        assert_eq!(
+            "\x1b[1;32mok\x1b[0m",
+            prompt("{status}", &EnvironmentStatus::Okay, true)
+        );
+    }
 }
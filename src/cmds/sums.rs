@@ -0,0 +1,152 @@
+use crate::cmds;
+use crate::config;
+use crate::error::Code;
+use crate::stamps;
+use crate::sums;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub const NAME: &str = "sums";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(err) => err.code(),
+            Error::Io(_) => Code::Io,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Shows checksums of the watched files")
+        .long_about(concat!(
+            "Shows checksums of the watched files.\n",
+            "With --slowest, shows instead the files that took longest to hash. ",
+            "Timings are recorded in the stat cache whenever checksums are ",
+            "calculated, and are reused while no watched file has changed.",
+        ))
+        .arg(
+            clap::Arg::with_name("slowest")
+                .long("slowest")
+                .value_name("N")
+                .validator(super::is_number)
+                .help("Show the N files that took longest to hash"),
+        )
+        .arg(
+            clap::Arg::with_name("dir")
+                .value_name("DIR")
+                .help("The directory in which to build"),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    let config = cmds::load_config(args.value_of_os("dir"))?;
+    let (sums, timings) = stamps::checksums_timed(&config)?;
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    if args.is_present("slowest") {
+        let count = value_t!(args, "slowest", usize).expect("validated by clap");
+        for (timing, sum) in slowest(&sums, &timings, count) {
+            writeln!(
+                &mut handle,
+                "{:>10.3}ms  {}",
+                timing.as_secs_f64() * 1000.0,
+                sum.subject()
+            )?;
+        }
+    } else {
+        for sum in sums.iter() {
+            match sum {
+                sums::Checksum::Found(path, sha1) => {
+                    writeln!(&mut handle, "{}  {}", sha1.0, path.display())?
+                }
+                sums::Checksum::NotFound(path) => {
+                    writeln!(&mut handle, "{:<40}  {}", "(not found)", path.display())?
+                }
+                sums::Checksum::Var(_, Some(sha1)) => {
+                    writeln!(&mut handle, "{}  {}", sha1.0, sum.subject())?
+                }
+                sums::Checksum::Var(_, None) => {
+                    writeln!(&mut handle, "{:<40}  {}", "(not set)", sum.subject())?
+                }
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+/// The `count` checksums that took longest to calculate, slowest first.
+fn slowest<'a>(
+    sums: &'a sums::Checksums,
+    timings: &[Duration],
+    count: usize,
+) -> Vec<(Duration, &'a sums::Checksum)> {
+    let mut timed: Vec<_> = timings.iter().copied().zip(sums.iter()).collect();
+    timed.sort_by(|(ta, _), (tb, _)| tb.cmp(ta));
+    timed.truncate(count);
+    timed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn slowest_are_listed_first() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (fast, slow) = (dir.path().join("fast"), dir.path().join("slow"));
+        let sums = sums::Checksums::from(&[&fast, &slow]).unwrap();
+        let timings = [Duration::from_millis(1), Duration::from_millis(5)];
+        let paths: Vec<_> = (slowest(&sums, &timings, 1).into_iter())
+            .filter_map(|(_, sum)| sum.path().map(Path::to_path_buf))
+            .collect();
+        assert_eq!(vec![slow], paths);
+    }
+}
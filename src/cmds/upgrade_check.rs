@@ -0,0 +1,141 @@
+use crate::error::Code;
+use crate::json;
+use std::fmt;
+use std::io;
+use std::process::{Command, Stdio};
+
+pub const NAME: &str = "upgrade-check";
+
+/// Where to ask for the latest release, instead of GitHub.
+const URL_VAR: &str = "FIRSTAIDE_RELEASES_URL";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Fetch(String, String),
+    Parse(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Fetch(url, message) => write!(f, "could not fetch {:?}: {}", url, message),
+            Parse(message) => write!(f, "could not understand latest release: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Io(_) => Code::Io,
+            Error::Fetch(_, _) | Error::Parse(_) => Code::Network,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(NAME)
+        .about("Checks for a newer release of firstaide")
+        .long_about(concat!(
+            "Checks for a newer release of firstaide by asking GitHub for the latest ",
+            "release. Nothing else in firstaide does this; only this command goes to ",
+            "the network.\n",
+            "- Exits 0 when this is the latest release, or newer.\n",
+            "- Exits 1 when there's a newer release.\n",
+            "- Exits 2 when an error occurs.",
+        ))
+}
+
+pub fn run(_args: &clap::ArgMatches) -> Result {
+    let ours = semver::Version::parse(crate_version!()).expect("our version is valid");
+    let latest = latest()?;
+    if latest > ours {
+        log::warn!(
+            "firstaide {} is available; this is firstaide {}.",
+            latest,
+            ours
+        );
+        Ok(1)
+    } else {
+        log::info!("firstaide {} is the latest release.", ours);
+        Ok(0)
+    }
+}
+
+fn releases_url() -> String {
+    match std::env::var(URL_VAR) {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            let repository = env!("CARGO_PKG_REPOSITORY");
+            let repository = repository.trim_start_matches("https://github.com/");
+            format!(
+                "https://api.github.com/repos/{}/releases/latest",
+                repository
+            )
+        }
+    }
+}
+
+/// The version of the latest release. We have no HTTP client in our
+/// dependencies, so we drive `curl`, as for the remote cache.
+fn latest() -> std::result::Result<semver::Version, Error> {
+    let url = releases_url();
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--fail", "--location"])
+        .args(["--header", "Accept: application/vnd.github+json"])
+        .arg(&url)
+        .stdin(Stdio::null());
+    log::debug!("{:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Fetch(url, stderr.trim().into()));
+    }
+    parse_release(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_release(body: &str) -> std::result::Result<semver::Version, Error> {
+    let release = json::parse(body).map_err(|err| Error::Parse(err.to_string()))?;
+    let tag = (release.get("tag_name"))
+        .and_then(json::Value::as_str)
+        .ok_or_else(|| Error::Parse("no tag_name".into()))?;
+    semver::Version::parse(tag.trim_start_matches('v'))
+        .map_err(|err| Error::Parse(format!("tag {:?}: {}", tag, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_release() {
+        let body = r#"{"id": 1, "tag_name": "v1.2.3", "draft": false}"#;
+        let version = parse_release(body).ok().unwrap();
+        assert_eq!(semver::Version::new(1, 2, 3), version);
+        assert!(parse_release(r#"{"id": 1}"#).is_err());
+        assert!(parse_release(r#"{"tag_name": "latest"}"#).is_err());
+    }
+}
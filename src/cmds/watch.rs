@@ -0,0 +1,235 @@
+use crate::cmds;
+use crate::cmds::status;
+use crate::config;
+use crate::error::Code;
+use crate::service;
+use crate::stamps;
+use crate::status::EnvironmentStatus;
+use crate::watcher;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+pub const NAME: &str = "watch";
+
+type Result = std::result::Result<u8, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Io(io::Error),
+    Command(String),
+    NotInstalled(PathBuf),
+    NotFound(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Command(message) => write!(f, "{}", message),
+            NotInstalled(path) => write!(f, "service is not installed; {:?} not found", path),
+            NotFound(name) => write!(f, "command not found: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Config(err) => Some(err),
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Config(err) => err.code(),
+            Io(_) => Code::Io,
+            Command(_) => Code::Other,
+            NotInstalled(_) => Code::NotFound,
+            NotFound(_) => Code::Usage,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub fn argspec<'a, 'b>() -> clap::App<'a, 'b> {
+    let dir = clap::Arg::with_name("dir")
+        .value_name("DIR")
+        .help("The directory in which to build");
+    clap::SubCommand::with_name(NAME)
+        .about("Rebuilds the development environment when it becomes stale")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(
+            clap::SubCommand::with_name("run")
+                .about("Watches in the foreground")
+                .arg(dir.clone())
+                .arg(
+                    clap::Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .validator(super::is_positive)
+                        .default_value("5")
+                        .help("How often to check for staleness when no watched file has changed"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("install-service")
+                .about("Installs and starts a user-level service that watches")
+                .long_about(concat!(
+                    "Installs and starts a user-level service that watches.\n",
+                    "This is a launchd agent on macOS, or a systemd user unit elsewhere. ",
+                    "Its output goes to watch.log in the cache dir.",
+                ))
+                .arg(dir.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("uninstall-service")
+                .about("Stops and removes the service installed by install-service")
+                .arg(dir),
+        )
+}
+
+pub fn run(args: &clap::ArgMatches) -> Result {
+    match args.subcommand() {
+        ("run", Some(subm)) => {
+            let interval = value_t!(subm, "interval", u64).expect("validated by clap");
+            watch(
+                &cmds::load_config(subm.value_of_os("dir"))?,
+                Duration::from_secs(interval),
+            )
+        }
+        ("install-service", Some(subm)) => {
+            install_service(&cmds::load_config(subm.value_of_os("dir"))?)
+        }
+        ("uninstall-service", Some(subm)) => {
+            uninstall_service(&cmds::load_config(subm.value_of_os("dir"))?)
+        }
+        // Not reachable while `SubcommandRequired` is in effect.
+        (name, _) => Err(Error::NotFound(name.into())),
+    }
+}
+
+/// Watch the watched files and run a build whenever the environment is
+/// stale. Builds run as a separate process so that a failing build does not
+/// take the watcher down with it, and a check that fails is tried again, later
+/// and later.
+fn watch(config: &config::Config, interval: Duration) -> Result {
+    config.writable()?;
+    log::info!("Watching {:?}.", &config.build_dir);
+    let mut watcher = watcher::Watcher::new(config, interval);
+    loop {
+        match is_stale(config) {
+            Ok(true) => {
+                log::info!("Environment is stale; building.");
+                let mut command = process::Command::new(&config.self_exe);
+                command.arg("build").arg(&config.build_dir).arg("--no-wait");
+                log::debug!("{:?}", command);
+                match command.status() {
+                    Ok(status) if status.success() => log::info!("Build finished."),
+                    Ok(status) => log::warn!("Build failed ({}).", status),
+                    Err(err) => log::warn!("Build could not be started: {}", err),
+                }
+            }
+            Ok(false) => (),
+            Err(err) => {
+                watcher.retry_after(err);
+                continue;
+            }
+        }
+        watcher.wait(None);
+    }
+}
+
+/// Is the environment anything but up to date? This is what `daemon` asks
+/// too; a cache that cannot be read counts as stale.
+fn is_stale(config: &config::Config) -> io::Result<bool> {
+    let sums_now = stamps::checksums(config)?;
+    Ok(status::status_of(config, &sums_now) != EnvironmentStatus::Okay)
+}
+
+fn service(config: &config::Config) -> service::Service {
+    service::Service::new(
+        &config.build_dir,
+        vec![
+            config.self_exe.clone(),
+            NAME.into(),
+            "run".into(),
+            config.build_dir.clone(),
+        ],
+        config.watch_log_file(),
+    )
+}
+
+fn install_service(config: &config::Config) -> Result {
+    config.writable()?;
+    let service = service(config);
+    let unit_file = service.unit_file()?;
+
+    // The service logs into the cache dir, so it must exist first.
+    fs::create_dir_all(&config.cache_dir)?;
+    if let Some(parent) = unit_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    log::info!("Write service definition to {:?}.", &unit_file);
+    fs::write(&unit_file, service.unit())?;
+
+    for mut command in service.commands_to_load(&unit_file) {
+        execute(&mut command)?;
+    }
+    log::info!("Service {} is running.", &service.name);
+    Ok(0)
+}
+
+fn uninstall_service(config: &config::Config) -> Result {
+    let service = service(config);
+    let unit_file = service.unit_file()?;
+    if !unit_file.exists() {
+        return Err(Error::NotInstalled(unit_file));
+    }
+
+    for mut command in service.commands_to_unload(&unit_file) {
+        // Press on regardless; the service may already have been stopped.
+        if let Err(err) = execute(&mut command) {
+            log::warn!("{}", err);
+        }
+    }
+    log::info!("Remove service definition {:?}.", &unit_file);
+    fs::remove_file(&unit_file)?;
+    for mut command in service.commands_after_unload() {
+        execute(&mut command)?;
+    }
+    Ok(0)
+}
+
+fn execute(command: &mut process::Command) -> std::result::Result<(), Error> {
+    log::debug!("{:?}", command);
+    if command.status()?.success() {
+        Ok(())
+    } else {
+        Err(Error::Command(format!("{:?} failed", command)))
+    }
+}
@@ -1,25 +1,46 @@
+use crate::error::Code;
+use crate::os::{self, OsStrExt};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
 use std::io;
-use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use path_absolutize::Absolutize;
-use serde::Deserialize;
+use crypto_hash::{hex_digest, Algorithm};
+use serde::{Deserialize, Serialize};
 use toml;
 
+use crate::cache;
+use crate::creds;
+use crate::crypt;
+use crate::git;
+use crate::gitignore;
+use crate::glob;
+use crate::i18n;
+use crate::paths;
+use crate::sign;
+use crate::status::EnvironmentStatus;
 use crate::sums;
+use crate::template;
+use crate::timings;
+use crate::validate;
 
 type Result = std::result::Result<Config, Error>;
 
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     ConfigNotFound(PathBuf),
     DirenvNotFound,
-    Invalid(toml::de::Error),
+    Invalid(validate::Invalid),
+    Renamed(&'static Renamed),
+    Observer,
+    Overlay(PathBuf, toml::de::Error),
     Other(String),
 }
 
@@ -31,47 +52,595 @@ impl fmt::Display for Error {
             ConfigNotFound(path) => write!(f, "config file not found; started from {:?}", path),
             DirenvNotFound => write!(f, "direnv not found on PATH"),
             Invalid(err) => write!(f, "configuration file not valid: {}", err),
+            Renamed(renamed) => write!(
+                f,
+                "configuration key {} was renamed to {} and is no longer accepted; \
+                 use `firstaide config fix` to update it",
+                renamed.old, renamed.new,
+            ),
+            Observer => write!(f, "observers cannot write to the cache; ask its owner"),
+            Overlay(path, err) => write!(f, "overlay {:?} not valid: {}", path, err),
             Other(message) => write!(f, "could not use configuration: {}", message),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            Invalid(err) => Some(err),
+            Overlay(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Io(_) => Code::Io,
+            Error::DirenvNotFound => Code::Direnv,
+            _ => Code::Config,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::Io(error)
+        Error::Io(crate::error::traced(error))
     }
 }
 
-impl From<toml::de::Error> for Error {
-    fn from(error: toml::de::Error) -> Self {
+impl From<validate::Invalid> for Error {
+    fn from(error: validate::Invalid) -> Self {
         Error::Invalid(error)
     }
 }
 
+/// A configuration key that has been renamed within its table. Keys are
+/// dotted paths from the top of the configuration file.
+#[derive(Debug)]
+pub struct Renamed {
+    pub old: &'static str,
+    pub new: &'static str,
+    /// The release from which `old` is an error rather than a warning.
+    pub until: Option<&'static str>,
+}
+
+/// Renamed keys, and spellings common enough to be worth correcting. Without
+/// these, serde would ignore the old key and complain – if at all – that the
+/// new one is missing.
+pub const RENAMED: &[Renamed] = &[
+    Renamed {
+        old: "cache-dir",
+        new: "cache_dir",
+        until: None,
+    },
+    Renamed {
+        old: "build-exe",
+        new: "build_exe",
+        until: None,
+    },
+    Renamed {
+        old: "watch-exe",
+        new: "watch_exe",
+        until: None,
+    },
+    Renamed {
+        old: "parent-dir",
+        new: "parent_dir",
+        until: None,
+    },
+];
+
+/// The configuration file, found in the project's directory or above.
+pub const FILENAME: &str = ".firstaide.toml";
+
+/// Alongside `.firstaide.toml`, and merged over it; for settings that are
+/// personal, so it should not be checked in.
+pub const LOCAL_FILENAME: &str = ".firstaide.local.toml";
+
+/// Merge `over` into `base`, field by field: tables are merged recursively,
+/// key by key, and anything else in `over` – strings, numbers, arrays, and so
+/// on – replaces what's in `base`.
+fn merge(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Move renamed keys in `config` to their new names, warning as we go. Once a
+/// rename's deprecation window has closed, i.e. `version` is at or beyond its
+/// `until`, fail instead.
+fn rename_keys(
+    renames: &'static [Renamed],
+    config: &mut toml::Value,
+    version: &str,
+) -> std::result::Result<(), Error> {
+    for renamed in renames {
+        let (parent, old) = split_key(renamed.old);
+        let (_, new) = split_key(renamed.new);
+        let table = parent
+            .iter()
+            .try_fold(&mut *config, |value, name| value.get_mut(name))
+            .and_then(toml::Value::as_table_mut);
+        if let Some(table) = table {
+            if let Some(value) = table.remove(old) {
+                if (renamed.until.iter()).any(|until| !older(version, until)) {
+                    return Err(Error::Renamed(renamed));
+                }
+                log::warn!(
+                    "Configuration key {} is deprecated; use {} instead, \
+                     or run `firstaide config fix`.",
+                    renamed.old,
+                    renamed.new,
+                );
+                table.entry(new).or_insert(value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite renamed keys in the text of a configuration file, keeping its
+/// layout and comments. Returns the new text and the renames made.
+pub fn fix_keys(text: &str) -> (String, Vec<&'static Renamed>) {
+    let mut fixed = String::with_capacity(text.len());
+    let mut renamed_keys = Vec::new();
+    let mut table: Vec<String> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[');
+            let header = header.split(']').next().unwrap_or("");
+            table = header
+                .split('.')
+                .map(|name| name.trim().to_owned())
+                .collect();
+            fixed.push_str(line);
+            continue;
+        }
+        let key = trimmed.split('=').next().unwrap_or("").trim();
+        let renamed = RENAMED.iter().find(|renamed| {
+            let (parent, old) = split_key(renamed.old);
+            parent == table && old == key && trimmed.contains('=')
+        });
+        match renamed {
+            Some(renamed) => {
+                let (_, new) = split_key(renamed.new);
+                let indent = &line[..line.len() - trimmed.len()];
+                fixed.push_str(indent);
+                fixed.push_str(new);
+                fixed.push_str(&trimmed[key.len()..]);
+                renamed_keys.push(renamed);
+            }
+            None => fixed.push_str(line),
+        }
+    }
+    (fixed, renamed_keys)
+}
+
+fn split_key(key: &str) -> (Vec<&str>, &str) {
+    let mut names: Vec<&str> = key.split('.').collect();
+    let last = names.pop().unwrap_or("");
+    (names, last)
+}
+
+/// Is version `a` older than version `b`? Both are dotted numbers, like
+/// "0.1.12"; anything non-numeric counts as zero.
+fn older(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> { v.split('.').map(|n| n.parse().unwrap_or(0)).collect() };
+    parse(a) < parse(b)
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub build_dir: PathBuf,
     pub cache_dir: PathBuf,
-    pub build_exe: PathBuf,
+    pub cache_location: CacheLocation,
+    /// Whether `build` keeps the cache dir out of Git, and how.
+    pub gitignore_cache: gitignore::Policy,
+    pub builder: Builder,
+    pub build_exe: Option<PathBuf>,
+    pub layers: Vec<Layer>,
     pub watch_exe: PathBuf,
-    pub direnv_exe: PathBuf,
+    pub watch_output: WatchOutput,
+    /// Files named by `watch_exe` to disregard, as glob patterns relative to
+    /// the build dir.
+    pub watch_ignore: Vec<String>,
+    /// Environment variables whose values, like the watched files, make the
+    /// environment stale when they change.
+    pub watch_env: Vec<String>,
+    /// Found on `PATH` when first needed; see `direnv_exe`.
+    direnv_exe: OnceLock<Option<PathBuf>>,
     pub parent_dir: PathBuf,
     pub self_exe: PathBuf,
     pub messages: Messages,
+    pub remote: Remote,
+    pub remote_auth: creds::Auth,
+    /// How to encrypt caches, if at all; see `encrypt_cache`.
+    pub cache_keys: crypt::Keys,
+    /// See `cache_file_fallback`.
+    pub cache_fallback: Fallback,
+    pub clean: Clean,
+    pub paranoid: bool,
+    pub show_build_output: bool,
+    pub pre_build: Option<BuildHook>,
+    pub post_build: Option<BuildHook>,
+    pub relocatable: bool,
+    /// When a build's environment is the same as the most recent, save an
+    /// alias to that cache rather than another copy of it.
+    pub dedupe_builds: bool,
+    /// Store each distinct environment once, as an object named for its
+    /// content, with cache files as aliases to objects. Implies
+    /// `dedupe_builds`, and more: any earlier environment is shared, not
+    /// just the most recent.
+    pub cache_objects: bool,
+    pub resolve_paths: paths::Policy,
+    pub shared_lock_dir: Option<PathBuf>,
+    /// Variables, as glob patterns, to keep out of the cache and the hook.
+    pub exclude_vars: Vec<String>,
+    pub hook: Hook,
+    pub staleness: Staleness,
+    pub prompt: Prompt,
+    pub audit: Audit,
+    pub nix: Nix,
+    /// Where to log, as well as to stderr, or `None` to not; by default,
+    /// `firstaide.log` in the cache dir.
+    pub log_file: Option<PathBuf>,
+    /// Overlays that apply to the directory in which we started, outermost
+    /// first.
+    pub overlays: Vec<Overlay>,
+    /// Read-only: use a cache that someone else manages, without ever
+    /// writing to it or anywhere else.
+    pub observer: bool,
+    /// Where this configuration came from: `.firstaide.toml`, and
+    /// `.firstaide.local.toml` when there is one.
+    pub config_file: PathBuf,
+    pub local_file: Option<PathBuf>,
+    /// The configuration as written, merged from `config_file` and
+    /// `local_file`, before defaults are applied and paths resolved.
+    pub raw: toml::Value,
+    /// The configuration in effect: `raw` with defaults applied and paths
+    /// resolved. Settings that are not set are absent.
+    pub effective: toml::Value,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct ConfigData {
-    cache_dir: PathBuf,
-    build_exe: PathBuf,
+    /// Required unless `cache_location` is `xdg`.
+    cache_dir: Option<PathBuf>,
+    #[serde(default)]
+    cache_location: CacheLocation,
+    #[serde(default)]
+    gitignore_cache: gitignore::Policy,
+    #[serde(default)]
+    builder: Builder,
+    build_exe: Option<PathBuf>,
     watch_exe: PathBuf,
     #[serde(default)]
+    watch_output: WatchOutput,
+    #[serde(default)]
+    watch_ignore: Vec<String>,
+    #[serde(default)]
+    watch_env: Vec<String>,
+    #[serde(default)]
     parent_dir: ParentDir,
     #[serde(default)]
     messages: Messages,
+    #[serde(default)]
+    cache: CacheData,
+    #[serde(default)]
+    encrypt_cache: bool,
+    #[serde(default)]
+    clean: Clean,
+    #[serde(default)]
+    environments: EnvironmentsData,
+    #[serde(default)]
+    paranoid: bool,
+    #[serde(default)]
+    show_build_output: bool,
+    pre_build_exe: Option<PathBuf>,
+    pre_build_command: Option<String>,
+    post_build_exe: Option<PathBuf>,
+    post_build_command: Option<String>,
+    #[serde(default)]
+    relocatable: bool,
+    #[serde(default)]
+    dedupe_builds: bool,
+    #[serde(default)]
+    cache_objects: bool,
+    #[serde(default)]
+    resolve_paths: paths::Policy,
+    shared_lock_dir: Option<PathBuf>,
+    language: Option<String>,
+    #[serde(default)]
+    exclude_vars: Vec<String>,
+    #[serde(default)]
+    hook: Hook,
+    #[serde(default)]
+    staleness: Staleness,
+    #[serde(default)]
+    prompt: Prompt,
+    #[serde(default)]
+    observer: ObserverData,
+    #[serde(default)]
+    audit: Audit,
+    #[serde(default)]
+    nix: Nix,
+    /// An empty path means no log file.
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentsData {
+    #[serde(default)]
+    compose: Vec<Layer>,
+}
+
+/// One of several environments that are captured separately and composed, in
+/// order, into one. Configured with `[[environments.compose]]` tables.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub build_exe: PathBuf,
+    /// Exclude changes to variables with these prefixes.
+    #[serde(default)]
+    pub exclude_prefixes: Vec<String>,
+    /// Exclude changes to variables matching these patterns, e.g. `PS1`.
+    #[serde(default)]
+    pub exclude_vars: Vec<String>,
+}
+
+impl Layer {
+    pub fn exclude(&self, diff: &crate::env::Diff) -> crate::env::Diff {
+        diff.exclude_by(|change| {
+            let name = change.name().as_bytes();
+            matches_any(&self.exclude_vars, name)
+                || (self.exclude_prefixes.iter()).any(|prefix| name.starts_with(prefix.as_bytes()))
+        })
+    }
 }
 
+/// Extra variables for a subdirectory of a project, from a
+/// `.firstaide.overlay.toml` file. The hook applies them on top of the
+/// project's environment when run from that subdirectory, or from below it,
+/// without another build.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overlay {
+    #[serde(skip)]
+    pub file: PathBuf,
+    /// Set these variables.
+    #[serde(default)]
+    pub exports: BTreeMap<String, String>,
+    /// Leave out changes to variables matching these patterns.
+    #[serde(default)]
+    pub exclude_vars: Vec<String>,
+}
+
+pub const OVERLAY_FILE: &str = ".firstaide.overlay.toml";
+
+impl Overlay {
+    fn load(file: PathBuf) -> std::result::Result<Self, Error> {
+        let bytes = fs::read(&file)?;
+        match toml::from_slice::<Self>(&bytes) {
+            Ok(overlay) => Ok(Self { file, ..overlay }),
+            Err(err) => Err(Error::Overlay(file, err)),
+        }
+    }
+
+    /// Overlays in `dir` and its ancestors, but not in `root` or above,
+    /// outermost first.
+    fn find(dir: &Path, root: &Path) -> std::result::Result<Vec<Self>, Error> {
+        let mut overlays = (dir.ancestors())
+            .take_while(|path| *path != root && path.starts_with(root))
+            .map(|path| path.join(OVERLAY_FILE))
+            .filter(|file| file.is_file())
+            .map(Self::load)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        overlays.reverse();
+        Ok(overlays)
+    }
+
+    pub fn apply(&self, diff: &crate::env::Diff) -> crate::env::Diff {
+        let mut diff =
+            diff.exclude_by(|change| matches_any(&self.exclude_vars, change.name().as_bytes()));
+        let exports: Vec<_> = (self.exports.iter())
+            .map(|(name, value)| crate::env::Added(name.into(), value.into()))
+            .collect();
+        diff.extend(crate::env::Diff::from(&exports).with_origin(crate::env::Origin::Config));
+        diff
+    }
+}
+
+fn matches_any(patterns: &[String], name: &[u8]) -> bool {
+    (patterns.iter()).any(|pattern| glob::matches(pattern.as_bytes(), name))
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct CacheData {
+    #[serde(default)]
+    remote: RemoteData,
+    /// Where to keep the key for `encrypt_cache`. By default it's in the
+    /// keychain, and is generated on first use.
+    key: Option<creds::Auth>,
+    #[serde(default)]
+    signing: sign::Signing,
+    /// Which cache to use when there's none for the watched files as they are
+    /// now.
+    #[serde(default)]
+    fallback: Fallback,
+}
+
+/// Unknown keys in `[cache.remote]` are ignored: serde cannot deny them in a
+/// struct with flattened fields.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RemoteData {
+    #[serde(default)]
+    auth: creds::Auth,
+    #[serde(flatten)]
+    remote: Remote,
+}
+
+/// For `cache push`; configured in `[cache.remote]`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Remote {
+    /// A directory, or a `file://`, `http://`, or `https://` URL.
+    pub url: Option<String>,
+    /// Caches are pushed in parts of this many bytes.
+    #[serde(default = "Remote::default_chunk_size")]
+    pub chunk_size: usize,
+    /// Push after every build. Failures are reported but do not fail the
+    /// build.
+    #[serde(default)]
+    pub push: bool,
+}
+
+impl Remote {
+    fn default_chunk_size() -> usize {
+        4 * 1024 * 1024
+    }
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self {
+            url: None,
+            chunk_size: Self::default_chunk_size(),
+            push: false,
+        }
+    }
+}
+
+/// How to build the environment and capture it from the inside.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Builder {
+    /// Run `build_exe`, passing it the command to run inside the environment.
+    #[default]
+    Exe,
+    /// Run `nix develop` with a profile in `cache_dir`. Later builds can reuse
+    /// the profile, and it doubles as a GC root for the environment.
+    NixDevelop,
+    /// Run `nix develop` on the flake in the build dir, or `nix.installable`,
+    /// without a profile.
+    Flake,
+}
+
+impl Builder {
+    /// Whether this builder evaluates a flake, and so depends on `flake.nix`
+    /// and `flake.lock`.
+    pub fn uses_flake(self) -> bool {
+        match self {
+            Builder::Exe => false,
+            Builder::NixDevelop | Builder::Flake => true,
+        }
+    }
+}
+
+/// Where to keep the cache.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheLocation {
+    /// In `cache_dir`, usually within the project.
+    #[default]
+    Project,
+    /// In `$XDG_CACHE_HOME/firstaide/<project-hash>/`, by default under
+    /// `~/.cache`, out of the work tree; `cache_dir` is ignored.
+    Xdg,
+}
+
+/// How the names that `watch_exe` prints are delimited.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchOutput {
+    /// NUL-delimited if there are any NULs, otherwise newline-delimited.
+    #[default]
+    Auto,
+    Nul,
+    Newline,
+}
+
+impl WatchOutput {
+    /// Split the output of `watch_exe` into names, warning when the output
+    /// looks like it uses a different delimiter.
+    pub fn split(self, output: &[u8]) -> Vec<&[u8]> {
+        let has_nul = output.contains(&0);
+        let delimiter = match self {
+            WatchOutput::Auto if has_nul => 0,
+            WatchOutput::Auto => b'\n',
+            WatchOutput::Nul => {
+                if !has_nul && output.iter().filter(|&&byte| byte == b'\n').count() > 1 {
+                    log::warn!(
+                        "Output from watch_exe contains no NULs but several newlines; \
+                         consider watch_output = \"newline\"."
+                    );
+                }
+                0
+            }
+            WatchOutput::Newline => {
+                if has_nul {
+                    log::warn!(
+                        "Output from watch_exe contains NULs; names containing newlines \
+                         will be split; consider watch_output = \"nul\"."
+                    );
+                }
+                b'\n'
+            }
+        };
+        output
+            .split(|&byte| byte == delimiter)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+/// Something to run before or after a build: an executable, or an inline
+/// command for Bash.
+#[derive(Debug)]
+pub enum BuildHook {
+    Exe(PathBuf),
+    Command(String),
+}
+
+impl BuildHook {
+    fn from(
+        name: &str,
+        exe: Option<PathBuf>,
+        command: Option<String>,
+        datum_dir: &Path,
+        resolve_paths: paths::Policy,
+    ) -> std::result::Result<Option<Self>, Error> {
+        match (exe, command) {
+            (None, None) => Ok(None),
+            (Some(exe), None) => Ok(Some(BuildHook::Exe(resolve_paths.resolve(datum_dir, exe)?))),
+            (None, Some(command)) => Ok(Some(BuildHook::Command(command))),
+            (Some(_), Some(_)) => Err(Error::Other(format!(
+                "set only one of {}_exe and {}_command",
+                name, name
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ParentDir(pub PathBuf);
 
 impl Default for ParentDir {
@@ -86,56 +655,613 @@ impl AsRef<Path> for ParentDir {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Messages {
     pub getting_started: String,
+    /// Said by the hook, in place of how to rebuild, when the environment is
+    /// stale. `{build}` is replaced with the build command, and `{changed}`
+    /// with the watched files that have changed.
+    pub stale: Option<String>,
+    /// Said by the hook, in place of how to build, when there's no
+    /// environment yet. `{build}` is replaced with the build command.
+    pub unbuilt: Option<String>,
 }
 
 impl Default for Messages {
     fn default() -> Self {
         Self {
             getting_started: "aide --help".into(),
+            stale: None,
+            unbuilt: None,
+        }
+    }
+}
+
+/// For shared machines where one user builds and others only look. Observers
+/// can also be enabled by setting `FIRSTAIDE_OBSERVER=1`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ObserverData {
+    #[serde(default)]
+    enabled: bool,
+    /// The shared cache directory, in place of `cache_dir`.
+    cache_dir: Option<PathBuf>,
+}
+
+impl ObserverData {
+    fn enabled(&self) -> bool {
+        self.enabled
+            || matches!(env::var_os("FIRSTAIDE_OBSERVER"), Some(v) if !v.is_empty() && v != "0")
+    }
+}
+
+/// For `hook`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Leave out comments and section headers.
+    #[serde(default)]
+    pub minimal: bool,
+    /// Leave out cached changes to variables with these prefixes. Changes to
+    /// `DIRENV_` variables are always left out; they belong to direnv.
+    #[serde(default = "Hook::default_exclude_prefixes")]
+    pub exclude_prefixes: Vec<String>,
+    /// Keep cached changes to variables matching these patterns, even when
+    /// their prefix is in `exclude_prefixes`, e.g. `SSH_AUTH_SOCK`.
+    #[serde(default)]
+    pub include_vars: Vec<String>,
+    /// How to apply cached changes to list variables; see `merge_for`.
+    #[serde(default)]
+    pub merge: HashMap<String, crate::env::Merge>,
+    /// For how long, in seconds, to reuse the captured outside environment
+    /// while the parent `.envrc` files are unchanged. Zero disables.
+    #[serde(default = "Hook::default_outside_ttl")]
+    pub outside_ttl: u64,
+    /// Say when the environment overrides the shell's own values of variables
+    /// matching these patterns, e.g. `PYTHONPATH`.
+    #[serde(default)]
+    pub report_overrides: Vec<String>,
+    /// Variables, as glob patterns, that belong to the shell session, like
+    /// `PS1` and `SHLVL`. The hook never exports or unsets them, whatever the
+    /// outside or cached environments say.
+    #[serde(default = "Hook::default_session_vars")]
+    pub session_vars: Vec<String>,
+    /// The project's own versions of the scripts that make up the hook, by
+    /// name, e.g. `stale`; see `template::SCRIPTS`. Relative paths are
+    /// relative to the project.
+    #[serde(default)]
+    pub scripts: BTreeMap<String, PathBuf>,
+}
+
+/// Which cache to fall back to, as stale, when there's none for the watched
+/// files as they are now.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fallback {
+    /// The most recently built.
+    #[default]
+    MostRecent,
+    /// None; the environment is not loaded until it's rebuilt.
+    Never,
+    /// The most recently built on the Git branch that's checked out, if any.
+    SameBranch,
+}
+
+impl Hook {
+    fn default_exclude_prefixes() -> Vec<String> {
+        vec!["SSH_".into()]
+    }
+
+    fn default_outside_ttl() -> u64 {
+        60
+    }
+
+    /// Those that direnv itself ignores, and `TERM`.
+    fn default_session_vars() -> Vec<String> {
+        [
+            "BASH_FUNC_*",
+            "COMP_WORDBREAKS",
+            "OLDPWD",
+            "PS1",
+            "PWD",
+            "SHELL",
+            "SHELLOPTS",
+            "SHLVL",
+            "TERM",
+            "_",
+            "__fish*",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+    }
+
+    /// Is `name` a variable that belongs to the shell session?
+    pub fn is_session_var(&self, name: &[u8]) -> bool {
+        matches_any(&self.session_vars, name)
+    }
+
+    /// How to apply cached changes to the variable `name`. Unless configured
+    /// otherwise, the cached value replaces the value when the hook runs.
+    pub fn merge_for(&self, name: &OsStr) -> crate::env::Merge {
+        use crate::env::{Merge, Strategy};
+        match name.to_str().and_then(|name| self.merge.get(name)) {
+            Some(merge) => merge.clone(),
+            None => Merge::new(Strategy::Replace),
+        }
+    }
+
+    pub fn reports_override(&self, name: &[u8]) -> bool {
+        matches_any(&self.report_overrides, name)
+    }
+
+    /// Should the hook leave out cached changes to the variable `name`?
+    pub fn excludes(&self, name: &[u8]) -> bool {
+        name.starts_with(b"DIRENV_")
+            || ((self.exclude_prefixes.iter()).any(|prefix| name.starts_with(prefix.as_bytes()))
+                && !matches_any(&self.include_vars, name))
+    }
+}
+
+impl Default for Hook {
+    fn default() -> Self {
+        Self {
+            minimal: false,
+            exclude_prefixes: Self::default_exclude_prefixes(),
+            include_vars: Vec::new(),
+            merge: HashMap::new(),
+            outside_ttl: Self::default_outside_ttl(),
+            report_overrides: Vec::new(),
+            session_vars: Self::default_session_vars(),
+            scripts: BTreeMap::new(),
+        }
+    }
+}
+
+/// Which changes to the watched files make the environment stale.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Staleness {
+    /// When only files matching these patterns, relative to the build dir,
+    /// have changed, e.g. `docs/*`, the environment is soft stale: it's still
+    /// used, with a reminder to rebuild.
+    #[serde(default)]
+    pub soft: Vec<String>,
+}
+
+/// For `status --prompt`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Prompt {
+    /// `{status}` is replaced with `ok`, `stale`, or `none`.
+    #[serde(default = "Prompt::default_format")]
+    pub format: String,
+    #[serde(default)]
+    pub color: bool,
+}
+
+impl Prompt {
+    fn default_format() -> String {
+        "{status}".into()
+    }
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Self {
+            format: Self::default_format(),
+            color: false,
+        }
+    }
+}
+
+/// For the audit log of variables exported by each build.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Audit {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where to keep the log; by default, `audit.log` in the cache dir.
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub values: AuditValues,
+    /// Never record values of variables matching these patterns, not even
+    /// hashed.
+    #[serde(default)]
+    pub redact: Vec<String>,
+    /// Rotate the log when it grows beyond this many bytes.
+    #[serde(default = "Audit::default_max_size")]
+    pub max_size: u64,
+    /// How many rotated logs to keep.
+    #[serde(default = "Audit::default_keep")]
+    pub keep: usize,
+}
+
+impl Audit {
+    fn default_max_size() -> u64 {
+        1024 * 1024
+    }
+
+    fn default_keep() -> usize {
+        5
+    }
+
+    pub fn redacts(&self, name: &[u8]) -> bool {
+        matches_any(&self.redact, name)
+    }
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: None,
+            values: AuditValues::default(),
+            redact: Vec::new(),
+            max_size: Self::default_max_size(),
+            keep: Self::default_keep(),
+        }
+    }
+}
+
+/// For working with Nix.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Nix {
+    /// Register the store paths that the environment refers to as GC roots,
+    /// so that garbage collection doesn't break the environment.
+    #[serde(default)]
+    pub gc_roots: bool,
+    /// Warn when building if `nix.conf` lets garbage collection delete what's
+    /// needed to rebuild the environment. Off by default: that's a choice for
+    /// each machine, not each build, to make.
+    #[serde(default)]
+    pub check_conf: bool,
+    /// What `nix develop` builds for the Nix builders, e.g. `.#ci`; by
+    /// default, the flake in the build dir.
+    pub installable: Option<String>,
+    #[serde(default)]
+    pub capture: NixCapture,
+}
+
+/// How the Nix builders capture the environment.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NixCapture {
+    /// Run `firstaide env` in the shell that `nix develop` starts.
+    #[default]
+    Shell,
+    /// Read the environment from `nix print-dev-env --json`. This skips the
+    /// shell, and so `shellHook`, which is quicker and more reproducible.
+    PrintDevEnv,
+}
+
+/// What the audit log records of each variable's value.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditValues {
+    /// Nothing; names only.
+    #[default]
+    None,
+    /// A SHA-256 of the value.
+    Hash,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Clean {
+    /// How many of the most recent cache files to keep when cleaning stale
+    /// cache files.
+    #[serde(default = "Clean::default_keep")]
+    pub keep: usize,
+}
+
+impl Clean {
+    fn default_keep() -> usize {
+        3
+    }
+}
+
+impl Default for Clean {
+    fn default() -> Self {
+        Self {
+            keep: Self::default_keep(),
         }
     }
 }
 
 impl Config {
+    /// Find the configuration file that applies to `dir`, or to the current
+    /// directory, searching upwards.
+    pub fn find<T: Into<PathBuf>>(dir: Option<T>) -> std::result::Result<PathBuf, Error> {
+        // Until the config is loaded its `resolve_paths` is not known, so
+        // this is the default.
+        let dir = paths::Policy::default().resolve_here(dir.map(Into::<PathBuf>::into))?;
+        dir.ancestors()
+            .map(|path| path.join(FILENAME))
+            .find(|path| path.is_file())
+            .ok_or(Error::ConfigNotFound(dir))
+    }
+
     pub fn load<T: Into<PathBuf>>(dir: Option<T>) -> Result {
-        let dir = match dir {
-            Some(d) => d.into().absolutize()?,
-            None => PathBuf::new().absolutize()?,
-        };
+        timings::time("Load config", || Self::load_untimed(dir))
+    }
 
+    fn load_untimed<T: Into<PathBuf>>(dir: Option<T>) -> Result {
         // Find and load a configuration file.
-        let config_file: PathBuf = dir
-            .ancestors()
-            .map(|path| path.join(".firstaide.toml"))
-            .find(|path| path.is_file())
-            .ok_or(Error::ConfigNotFound(dir))?;
-        let config_bytes: Vec<u8> = fs::read(&config_file)?;
-        let config_data: ConfigData = toml::from_slice(&config_bytes)?;
+        let start_dir = paths::Policy::default().resolve_here(dir.map(Into::<PathBuf>::into))?;
+        let config_file = Self::find(Some(&start_dir))?;
+        let config_source = fs::read_to_string(&config_file)?;
+        let mut config_value: toml::Value =
+            toml::from_str(&config_source).map_err(|err| validate::syntax(&config_file, &err))?;
+        rename_keys(RENAMED, &mut config_value, crate_version!())?;
+        let local_file = config_file.with_file_name(LOCAL_FILENAME);
+        let mut local_source = None;
+        if local_file.is_file() {
+            log::debug!("Merging {:?} over {:?}.", local_file, config_file);
+            let source = fs::read_to_string(&local_file)?;
+            let mut local_value: toml::Value =
+                toml::from_str(&source).map_err(|err| validate::syntax(&local_file, &err))?;
+            rename_keys(RENAMED, &mut local_value, crate_version!())?;
+            merge(&mut config_value, local_value);
+            local_source = Some(source);
+        }
+        let raw = config_value.clone();
+        let config_data: ConfigData = config_value.try_into().map_err(|err| {
+            let mut sources = Vec::with_capacity(2);
+            if let Some(source) = &local_source {
+                sources.push((local_file.as_path(), source.as_str()));
+            }
+            sources.push((config_file.as_path(), config_source.as_str()));
+            validate::explain(&err, &sources)
+        })?;
+        i18n::select(config_data.language.as_deref());
 
         // All paths are resolved relative to the directory where we found the
-        // configuration file.
+        // configuration file, and all in the same way.
+        let resolve_paths = config_data.resolve_paths;
         let datum_dir = (config_file.parent())
             .ok_or_else(|| Error::Other("could not get directory of configuration file".into()))?;
+        let datum_dir = &resolve_paths.resolve(datum_dir, ".")?;
+        let resolve = |path: &Path| resolve_paths.resolve(datum_dir, path);
+        let start_dir = resolve_paths.resolve(&start_dir, ".")?;
+
+        // Paths are resolved in place, so that `effective` shows them as
+        // they're used.
+        let mut config_data = config_data;
+        for layer in config_data.environments.compose.iter_mut() {
+            layer.build_exe = resolve(&layer.build_exe)?;
+        }
+
+        for (name, script) in config_data.hook.scripts.iter_mut() {
+            if !template::is_script(name) {
+                let message = format!("hook.scripts: unknown script {:?}", name);
+                return Err(Error::Other(message));
+            }
+            *script = resolve(script)?;
+        }
+
+        config_data.build_exe = match (config_data.builder, config_data.build_exe.take()) {
+            (_, Some(build_exe)) => Some(resolve(&build_exe)?),
+            (Builder::Exe, None) if config_data.environments.compose.is_empty() => {
+                return Err(Error::Other("build_exe must be set".into()))
+            }
+            (_, None) => None,
+        };
+        if config_data.nix.capture == NixCapture::PrintDevEnv && !config_data.builder.uses_flake() {
+            return Err(Error::Other(
+                "nix.capture = \"print-dev-env\" needs a Nix builder".into(),
+            ));
+        }
+
+        let pre_build = BuildHook::from(
+            "pre_build",
+            config_data.pre_build_exe.take(),
+            config_data.pre_build_command.clone(),
+            datum_dir,
+            resolve_paths,
+        )?;
+        if let Some(BuildHook::Exe(exe)) = &pre_build {
+            config_data.pre_build_exe = Some(exe.clone());
+        }
+        let post_build = BuildHook::from(
+            "post_build",
+            config_data.post_build_exe.take(),
+            config_data.post_build_command.clone(),
+            datum_dir,
+            resolve_paths,
+        )?;
+        if let Some(BuildHook::Exe(exe)) = &post_build {
+            config_data.post_build_exe = Some(exe.clone());
+        }
+
+        let signing = &mut config_data.cache.signing;
+        signing.check().map_err(Error::Other)?;
+        if let Some(key_file) = signing.key_file.take() {
+            signing.key_file = Some(resolve(&expand_path(key_file, &home_dir()))?);
+        }
+        if let Some(key) = config_data.cache.key.take() {
+            config_data.cache.key = Some(key.resolve(datum_dir));
+        } else if config_data.encrypt_cache {
+            config_data.cache.key = Some(creds::Auth::Keyring {
+                service: "firstaide".into(),
+                account: "cache-key".into(),
+            });
+        }
+        let remote_auth = std::mem::take(&mut config_data.cache.remote.auth);
+        config_data.cache.remote.auth = remote_auth.resolve(datum_dir);
+
+        let observer = config_data.observer.enabled();
+        config_data.observer.enabled = observer;
+        if let Some(cache_dir) = config_data.observer.cache_dir.take() {
+            config_data.observer.cache_dir = Some(resolve(&expand_path(cache_dir, &home_dir()))?);
+        }
+        let cache_location = config_data.cache_location;
+        let cache_dir = match (observer, &config_data.observer.cache_dir) {
+            (true, Some(cache_dir)) => cache_dir.clone(),
+            (_, _) => match (cache_location, config_data.cache_dir.take()) {
+                (CacheLocation::Xdg, _) => xdg_cache_dir(
+                    env::var_os("XDG_CACHE_HOME").map(PathBuf::from),
+                    &home_dir(),
+                    datum_dir,
+                ),
+                (CacheLocation::Project, Some(cache_dir)) => resolve(&cache_dir)?,
+                (CacheLocation::Project, None) => {
+                    return Err(Error::Other("cache_dir must be set".into()))
+                }
+            },
+        };
+        config_data.cache_dir = Some(cache_dir.clone());
+        config_data.log_file = match config_data.log_file.take() {
+            Some(log_file) if log_file.as_os_str().is_empty() => None,
+            Some(log_file) => Some(resolve(&expand_path(log_file, &home_dir()))?),
+            None => Some(cache_dir.join("firstaide.log")),
+        };
+        if let Some(dir) = config_data.shared_lock_dir.take() {
+            config_data.shared_lock_dir = Some(resolve(&expand_path(dir, &home_dir()))?);
+        }
+        if let Some(file) = config_data.audit.file.take() {
+            config_data.audit.file = Some(resolve(&expand_path(file, &home_dir()))?);
+        }
+        config_data.watch_exe = resolve(&config_data.watch_exe)?;
+        config_data.parent_dir = ParentDir(resolve(config_data.parent_dir.as_ref())?);
+
+        let effective = toml::Value::try_from(&config_data)
+            .map_err(|err| Error::Other(format!("could not show configuration: {}", err)))?;
 
         Ok(Config {
             build_dir: datum_dir.to_path_buf(),
-            cache_dir: datum_dir.join(config_data.cache_dir).absolutize()?,
-            build_exe: datum_dir.join(config_data.build_exe).absolutize()?,
-            watch_exe: datum_dir.join(config_data.watch_exe).absolutize()?,
-            direnv_exe: search_path("direnv").ok_or(Error::DirenvNotFound)?,
-            parent_dir: datum_dir.join(config_data.parent_dir).absolutize()?,
+            cache_dir,
+            cache_location,
+            gitignore_cache: config_data.gitignore_cache,
+            builder: config_data.builder,
+            build_exe: config_data.build_exe,
+            layers: config_data.environments.compose,
+            watch_exe: config_data.watch_exe,
+            watch_output: config_data.watch_output,
+            watch_ignore: config_data.watch_ignore,
+            watch_env: config_data.watch_env,
+            direnv_exe: OnceLock::new(),
+            parent_dir: config_data.parent_dir.0,
             self_exe: env::current_exe()?,
             messages: config_data.messages,
+            remote: config_data.cache.remote.remote,
+            remote_auth: config_data.cache.remote.auth,
+            cache_keys: crypt::Keys::new(if config_data.encrypt_cache {
+                config_data.cache.key
+            } else {
+                None
+            })
+            .with_signing(config_data.cache.signing)
+            // An observer builds nothing, so every cache it loads is another's.
+            .with_verify_on_open(observer),
+            cache_fallback: config_data.cache.fallback,
+            clean: config_data.clean,
+            paranoid: config_data.paranoid,
+            show_build_output: config_data.show_build_output,
+            pre_build,
+            post_build,
+            relocatable: config_data.relocatable,
+            dedupe_builds: config_data.dedupe_builds,
+            cache_objects: config_data.cache_objects,
+            resolve_paths,
+            shared_lock_dir: config_data.shared_lock_dir,
+            exclude_vars: config_data.exclude_vars,
+            hook: config_data.hook,
+            staleness: config_data.staleness,
+            prompt: config_data.prompt,
+            audit: config_data.audit,
+            nix: config_data.nix,
+            log_file: config_data.log_file,
+            overlays: Overlay::find(&start_dir, datum_dir)?,
+            observer,
+            local_file: local_source.map(|_| local_file),
+            config_file,
+            raw,
+            effective,
         })
     }
 
-    pub fn command_to_allow_direnv(&self) -> Command {
-        let mut command = self.command_direnv();
+    /// Is `key`, dotted, one that `.firstaide.toml` can set, whether or not
+    /// it's set? `ConfigData` itself is asked, by setting `key` and seeing
+    /// whether it's refused as unknown.
+    pub fn knows_key(&self, key: &str) -> bool {
+        let names: Vec<&str> = key.split('.').collect();
+        // Below a setting that isn't a table there are no more settings.
+        let mut effective = Some(&self.effective);
+        for name in &names {
+            effective = match effective {
+                Some(toml::Value::Table(table)) => table.get(*name),
+                Some(_) => return false,
+                None => break,
+            };
+        }
+        if effective.is_some() {
+            return true;
+        }
+        let mut probe = self.raw.clone();
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        let mut table = match &mut probe {
+            toml::Value::Table(table) => table,
+            _ => return false,
+        };
+        for name in parents {
+            let value = (table.entry(name.to_string()))
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+            table = match value {
+                toml::Value::Table(table) => table,
+                _ => return false,
+            };
+        }
+        table.insert(last.to_string(), toml::Value::Boolean(true));
+        match probe.try_into::<ConfigData>() {
+            Ok(_) => true,
+            Err(err) => !matches!(
+                validate::explain(&err, &[]).problem,
+                validate::Problem::UnknownKey { .. }
+            ),
+        }
+    }
+
+    /// Apply `overlays` to `diff`, outermost first.
+    pub fn overlay(&self, diff: crate::env::Diff) -> crate::env::Diff {
+        (self.overlays.iter()).fold(diff, |diff, overlay| overlay.apply(&diff))
+    }
+
+    /// Commands that write to the cache, or build, call this first.
+    pub fn writable(&self) -> std::result::Result<(), Error> {
+        if self.observer {
+            Err(Error::Observer)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Leave out changes to variables matching `exclude_vars`, like secrets.
+    pub fn exclude_vars(&self, diff: &crate::env::Diff) -> crate::env::Diff {
+        diff.exclude_by(|change| self.excludes_var(change.name().as_bytes()))
+    }
+
+    pub fn excludes_var(&self, name: &[u8]) -> bool {
+        matches_any(&self.exclude_vars, name)
+    }
+
+    /// Where to find direnv. We look only when it's first needed, so that
+    /// commands that do not need it work without it.
+    pub fn direnv_exe(&self) -> std::result::Result<&Path, Error> {
+        (self.direnv_exe.get_or_init(|| search_path("direnv")))
+            .as_deref()
+            .ok_or(Error::DirenvNotFound)
+    }
+
+    pub fn command_to_allow_direnv(&self) -> std::result::Result<Command, Error> {
+        let mut command = self.command_direnv()?;
         command.arg("allow").arg("--").arg(&self.build_dir);
-        command
+        Ok(command)
     }
 
     /// Capture the environment from outside of the Nix environment.
@@ -145,17 +1271,25 @@ impl Config {
     /// want the `.envrc` in the build directory itself from influencing the
     /// build: *it* is a consumer of *this*, not the other way around or some
     /// weird ouroboros of both.
-    pub fn command_to_dump_env_outside<T: Into<PathBuf>>(&self, out: T) -> Command {
-        let mut command = self.command_direnv();
+    pub fn command_to_dump_env_outside<T: Into<PathBuf>>(
+        &self,
+        out: T,
+    ) -> std::result::Result<Command, Error> {
+        let mut command = self.command_to_dump_env_outside_to_stdout()?;
+        command.arg("--out").arg(out.into());
+        Ok(command)
+    }
+
+    /// Like `command_to_dump_env_outside`, but the dump is written to stdout.
+    pub fn command_to_dump_env_outside_to_stdout(&self) -> std::result::Result<Command, Error> {
+        let mut command = self.command_direnv()?;
         command
             .current_dir(&self.build_dir)
             .arg("exec")
             .arg(&self.parent_dir)
             .arg(&self.self_exe)
-            .arg("env")
-            .arg("--out")
-            .arg(out.into());
-        command
+            .arg("env");
+        Ok(command)
     }
 
     /// Capture the environment from inside the Nix environment.
@@ -167,7 +1301,66 @@ impl Config {
         out: T,
         env: &[crate::env::Item],
     ) -> Command {
-        let mut command = Command::new(&self.build_exe);
+        let command = match (self.builder, &self.build_exe) {
+            (Builder::Exe, Some(build_exe)) => Command::new(build_exe),
+            // `Config::load` ensures that `build_exe` is set for `Builder::Exe`
+            // unless there are layers, in which case the build uses
+            // `command_to_dump_env_inside_layer` instead.
+            (Builder::Exe, None) => unreachable!(),
+            (Builder::NixDevelop, _) => {
+                let mut command = Command::new("nix");
+                command
+                    .arg("develop")
+                    .arg(self.nix_installable())
+                    .arg("--profile")
+                    .arg(self.nix_profile())
+                    .arg("--command");
+                command
+            }
+            (Builder::Flake, _) => {
+                let mut command = Command::new("nix");
+                command
+                    .arg("develop")
+                    .arg(self.nix_installable())
+                    .arg("--command");
+                command
+            }
+        };
+        self.command_to_dump_env(command, out, env)
+    }
+
+    /// Print, as JSON, the environment that `nix develop` would set up. Like
+    /// `command_to_dump_env_inside`, it runs with exactly `env`.
+    pub fn command_to_print_dev_env(&self, env: &[crate::env::Item]) -> Command {
+        let mut command = Command::new("nix");
+        command
+            .current_dir(&self.build_dir)
+            .arg("print-dev-env")
+            .arg(self.nix_installable())
+            .arg("--json");
+        if self.builder == Builder::NixDevelop {
+            command.arg("--profile").arg(self.nix_profile());
+        }
+        command.env_clear().envs(env.iter().cloned());
+        command
+    }
+
+    /// Capture the environment from inside one of the composed environments.
+    pub fn command_to_dump_env_inside_layer<T: Into<PathBuf>>(
+        &self,
+        layer: &Layer,
+        out: T,
+        env: &[crate::env::Item],
+    ) -> Command {
+        self.command_to_dump_env(Command::new(&layer.build_exe), out, env)
+    }
+
+    fn command_to_dump_env<T: Into<PathBuf>>(
+        &self,
+        mut command: Command,
+        out: T,
+        env: &[crate::env::Item],
+    ) -> Command {
         command
             .current_dir(&self.build_dir)
             .arg(&self.self_exe)
@@ -179,30 +1372,138 @@ impl Config {
         command
     }
 
-    pub fn watch_files(&self) -> io::Result<Vec<PathBuf>> {
+    pub fn command_build_hook(&self, hook: &BuildHook) -> Command {
+        let mut command = match hook {
+            BuildHook::Exe(exe) => Command::new(exe),
+            BuildHook::Command(script) => {
+                let mut command = Command::new("bash");
+                command.arg("-c").arg(script);
+                command
+            }
+        };
+        command
+            .current_dir(&self.build_dir)
+            .env("FIRSTAIDE_BUILD_DIR", &self.build_dir)
+            .env("FIRSTAIDE_CACHE_DIR", &self.cache_dir);
+        command
+    }
+
+    pub fn command_watch(&self) -> Command {
         let mut command = Command::new(&self.watch_exe);
         command.current_dir(&self.build_dir);
-        let output = command.output()?;
-        let names = output
-            .stdout
-            .split(|&byte| byte == 0)
-            .filter(|name| !name.is_empty());
-        let paths = names.map(|name| OsStr::from_bytes(name));
-        Ok(paths.map(|path| self.abspath(path)).collect())
-    }
-
-    /// Return an absolute path, resolved relative to `self.build_dir`.
-    fn abspath<T: AsRef<Path>>(&self, path: T) -> PathBuf {
-        let p = path.as_ref();
-        if p.is_relative() {
-            self.build_dir.join(p)
+        command
+    }
+
+    /// The files that `watch_exe` names and, with a builder that uses a
+    /// flake, `flake.nix` and `flake.lock` in the build dir, whether they
+    /// exist or not – less those matching `watch_ignore`.
+    pub fn watch_files(&self) -> io::Result<Vec<PathBuf>> {
+        let output = self.command_watch().output()?;
+        let names = self.watch_output.split(&output.stdout);
+        let paths = names.into_iter().map(os::from_bytes);
+        let mut files = paths
+            .map(|path| self.abspath(&path))
+            .collect::<io::Result<Vec<_>>>()?;
+        if self.builder.uses_flake() {
+            for name in &["flake.nix", "flake.lock"] {
+                let file = self.build_dir.join(name);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        files.retain(|file| !self.watch_ignored(file));
+        Ok(files)
+    }
+
+    /// Whether `file` matches a pattern in `watch_ignore`.
+    fn watch_ignored(&self, file: &Path) -> bool {
+        let path = file.strip_prefix(&self.build_dir).unwrap_or(file);
+        let ignored = matches_any(&self.watch_ignore, path.as_os_str().as_bytes());
+        if ignored {
+            log::debug!("Not watching {:?}; it matches watch_ignore.", file);
+        }
+        ignored
+    }
+
+    /// Checksums of the watched files and variables as they are now. In
+    /// relocatable mode the files' paths are relative to `self.build_dir`.
+    pub fn checksums(&self) -> io::Result<sums::Checksums> {
+        let files = timings::time("Find watched files", || self.watch_files())?;
+        timings::time("Calculate checksums", || self.checksums_of(&files))
+    }
+
+    /// Checksums of `files`, which should come from `watch_files`, and of the
+    /// variables in `watch_env`.
+    pub fn checksums_of(&self, files: &[PathBuf]) -> io::Result<sums::Checksums> {
+        let sums = sums::Checksums::from(files)?;
+        Ok(self
+            .relocate(sums)
+            .with_vars(&self.watch_env, crate::env::var_before_direnv))
+    }
+
+    /// Like `checksums_of`, but also report how long each file took to hash.
+    pub fn checksums_of_timed(
+        &self,
+        files: &[PathBuf],
+    ) -> io::Result<(sums::Checksums, Vec<Duration>)> {
+        let (sums, timings) = sums::Checksums::from_timed(files)?;
+        Ok((
+            self.relocate(sums)
+                .with_vars(&self.watch_env, crate::env::var_before_direnv),
+            timings,
+        ))
+    }
+
+    /// The status of an environment built when the watched files were as in
+    /// `cached`, now that they're as in `now`: okay if nothing has changed,
+    /// soft stale if only files matching `staleness.soft` have, and otherwise
+    /// stale. `build` does not use this; it always rebuilds after changes.
+    pub fn staleness(&self, now: &sums::Checksums, cached: &sums::Checksums) -> EnvironmentStatus {
+        if sums::equal(now, cached) {
+            return EnvironmentStatus::Okay;
+        }
+        // A change to a watched variable is never soft.
+        let soft = |subject: sums::Subject| match subject {
+            sums::Subject::File(path) => {
+                let path = path.strip_prefix(&self.build_dir).unwrap_or(path);
+                matches_any(&self.staleness.soft, path.as_os_str().as_bytes())
+            }
+            sums::Subject::Var(_) => false,
+        };
+        if !self.staleness.soft.is_empty() && sums::changed(now, cached).into_iter().all(soft) {
+            EnvironmentStatus::SoftStale
+        } else {
+            EnvironmentStatus::Stale
+        }
+    }
+
+    fn relocate(&self, sums: sums::Checksums) -> sums::Checksums {
+        if self.relocatable {
+            sums.relative_to(&self.build_dir)
         } else {
-            p.to_path_buf()
+            sums
         }
     }
 
-    pub fn command_direnv(&self) -> Command {
-        Command::new(&self.direnv_exe)
+    /// In relocatable mode, `path` relative to `self.build_dir` if it's within
+    /// the build dir; otherwise `path` unchanged.
+    pub fn relocatable_path<'a>(&self, path: &'a Path) -> &'a Path {
+        if self.relocatable {
+            path.strip_prefix(&self.build_dir).unwrap_or(path)
+        } else {
+            path
+        }
+    }
+
+    /// Return an absolute path, resolved relative to `self.build_dir`
+    /// according to the `resolve_paths` policy.
+    fn abspath<T: AsRef<Path>>(&self, path: T) -> io::Result<PathBuf> {
+        self.resolve_paths.resolve(&self.build_dir, path)
+    }
+
+    pub fn command_direnv(&self) -> std::result::Result<Command, Error> {
+        Ok(Command::new(self.direnv_exe()?))
     }
 
     pub fn cache_file(&self, sums: &sums::Checksums) -> PathBuf {
@@ -213,14 +1514,249 @@ impl Config {
         self.cache_dir.join("cache")
     }
 
+    /// The cache file to fall back to, according to `cache.fallback`, when
+    /// there's none for the watched files as they are now.
+    pub fn cache_file_fallback(&self) -> Option<PathBuf> {
+        match self.cache_fallback {
+            Fallback::MostRecent => self.cache_file_most_recent_target(),
+            Fallback::Never => None,
+            Fallback::SameBranch => {
+                let branch = git::branch(&self.build_dir)?;
+                // Like the most recent link, this is relative in relocatable
+                // mode.
+                let target = os::pointee(&self.cache_file_on_branch(&branch)).ok()?;
+                Some(self.branches_dir().join(target))
+            }
+        }
+    }
+
+    /// Where each cache's Git state is kept beside it; see `cache_git_file`.
+    pub fn cache_git_dir(&self) -> PathBuf {
+        self.cache_dir.join("git")
+    }
+
+    /// The Git state that `cache_file` was built from, as `git::Head::save`
+    /// writes it. It's in the cache too, but reading it from there means
+    /// loading the whole cache.
+    pub fn cache_git_file(&self, cache_file: &Path) -> PathBuf {
+        self.cache_git_dir()
+            .join(cache_file.file_name().unwrap_or_default())
+    }
+
+    /// Links to the most recent cache built on each Git branch, for the
+    /// `same-branch` fallback.
+    pub fn branches_dir(&self) -> PathBuf {
+        self.cache_dir.join("branches")
+    }
+
+    /// The link to the most recent cache built on `branch`. Branch names may
+    /// have slashes, so those – and percent signs, to keep names distinct –
+    /// are percent-encoded.
+    pub fn cache_file_on_branch(&self, branch: &str) -> PathBuf {
+        let name = branch.replace('%', "%25").replace('/', "%2F");
+        self.branches_dir().join(name)
+    }
+
+    /// Load the cache for `sums` or, failing that, the fallback. Returns the
+    /// cache and the file that it came from. A cache that exists but cannot
+    /// be loaded is reported, not skipped over quietly.
+    pub fn load_cache(&self, sums: &sums::Checksums) -> cache::Result<(cache::Cache, PathBuf)> {
+        let cache_file = self.cache_file(sums);
+        let err = match cache::Cache::load(&cache_file, &self.cache_keys) {
+            Ok(cache) => return Ok((cache, cache_file)),
+            Err(cache::Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                cache::Error::Io(err)
+            }
+            Err(err) => {
+                log::warn!("Could not load {:?}: {}", cache_file, err);
+                err
+            }
+        };
+        let fallback = match self.cache_file_fallback() {
+            Some(fallback) => fallback,
+            None => return Err(err),
+        };
+        log::debug!("Loading fallback, {}", fallback.display());
+        let cache = cache::Cache::load(&fallback, &self.cache_keys)?;
+        Ok((cache, fallback))
+    }
+
+    /// The cache file that the most recent cache file link points to.
+    pub fn cache_file_most_recent_target(&self) -> Option<PathBuf> {
+        // The link is relative in relocatable mode.
+        let target = os::pointee(&self.cache_file_most_recent()).ok()?;
+        Some(self.cache_dir.join(target))
+    }
+
+    /// All cache files, i.e. those named `cache.${sig}`, in `cache_dir`.
+    pub fn cache_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let entries = match fs::read_dir(&self.cache_dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.as_bytes().starts_with(b"cache.") && entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Where `cache_objects` are stored.
+    pub fn objects_dir(&self) -> PathBuf {
+        self.cache_dir.join(cache::OBJECTS_DIR)
+    }
+
+    /// All objects in `objects_dir`.
+    pub fn object_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let entries = match fs::read_dir(self.objects_dir()) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            files.push(entry?.path());
+        }
+        Ok(files)
+    }
+
+    /// Output from builds, with timestamps.
     pub fn build_log_file(&self) -> PathBuf {
         self.cache_dir.join("build.log")
     }
+
+    /// A record of each build; see `history`.
+    pub fn history_file(&self) -> PathBuf {
+        self.cache_dir.join("history.jsonl")
+    }
+
+    /// Touched to prompt direnv to reload; see `history::prompt_reload`.
+    pub fn reload_file(&self) -> PathBuf {
+        self.cache_dir.join("reload")
+    }
+
+    /// Marks a rebuild that made no difference; see `history::mark_unchanged`.
+    pub fn unchanged_file(&self) -> PathBuf {
+        self.cache_dir.join("unchanged")
+    }
+
+    /// The profile that `nix develop` maintains for `Builder::NixDevelop`.
+    pub fn nix_profile(&self) -> PathBuf {
+        self.cache_dir.join("profile")
+    }
+
+    /// Links to store paths, registered as GC roots, with `nix.gc_roots`.
+    pub fn gc_roots_dir(&self) -> PathBuf {
+        self.cache_dir.join("gc-roots")
+    }
+
+    /// What `nix develop` builds: `nix.installable`, or the flake in the
+    /// build dir, since that's where it runs.
+    pub fn nix_installable(&self) -> &str {
+        self.nix.installable.as_deref().unwrap_or(".")
+    }
+
+    /// Delete all but the current generation of `nix_profile`.
+    pub fn command_to_wipe_nix_profile_history(&self) -> Command {
+        let mut command = Command::new("nix-env");
+        command
+            .arg("--profile")
+            .arg(self.nix_profile())
+            .arg("--delete-generations")
+            .arg("old");
+        command
+    }
+
+    pub fn build_progress_file(&self) -> PathBuf {
+        self.cache_dir.join("build.progress")
+    }
+
+    pub fn build_output_file(&self) -> PathBuf {
+        self.cache_dir.join("build.out")
+    }
+
+    pub fn lock_file(&self) -> PathBuf {
+        self.cache_dir.join("lock")
+    }
+
+    pub fn hook_error_log_file(&self) -> PathBuf {
+        self.cache_dir.join("hook-error.log")
+    }
+
+    /// The hook as last rendered; see `hook::fast_key`.
+    pub fn hook_output_file(&self) -> PathBuf {
+        self.cache_dir.join("hook-output")
+    }
+
+    pub fn outside_cache_file(&self) -> PathBuf {
+        self.cache_dir.join("outside-cache")
+    }
+
+    /// The `.envrc` files that direnv may load for `parent_dir`, whether they
+    /// exist or not.
+    pub fn parent_envrc_files(&self) -> Vec<PathBuf> {
+        (self.parent_dir.ancestors())
+            .map(|dir| dir.join(".envrc"))
+            .collect()
+    }
+
+    pub fn stat_cache_file(&self) -> PathBuf {
+        self.cache_dir.join("stat-cache")
+    }
+
+    /// The lock in `shared_lock_dir` for this project, if configured. It's
+    /// named after the SHA-1 of the build dir so that other tools can find it.
+    pub fn shared_lock_file(&self) -> Option<PathBuf> {
+        self.shared_lock_dir.as_ref().map(|dir| {
+            let digest = hex_digest(Algorithm::SHA1, self.build_dir.as_os_str().as_bytes());
+            dir.join(format!("{}.lock", digest))
+        })
+    }
+
+    pub fn audit_log_file(&self) -> PathBuf {
+        match &self.audit.file {
+            Some(file) => file.clone(),
+            None => self.cache_dir.join("audit.log"),
+        }
+    }
+
+    pub fn daemon_lock_file(&self) -> PathBuf {
+        self.cache_dir.join("daemon.lock")
+    }
+
+    pub fn daemon_log_file(&self) -> PathBuf {
+        self.cache_dir.join("daemon.log")
+    }
+
+    pub fn watch_log_file(&self) -> PathBuf {
+        self.cache_dir.join("watch.log")
+    }
+}
+
+fn home_dir() -> PathBuf {
+    dirs::home_dir().unwrap_or_else(|| "/home/not/found".into())
 }
 
-fn search_path<T: Into<PathBuf>>(name: T) -> Option<PathBuf> {
+/// The cache dir for the project in `build_dir` when `cache_location` is
+/// `xdg`. A relative `XDG_CACHE_HOME` is ignored, as the spec says it should
+/// be.
+fn xdg_cache_dir(xdg_cache_home: Option<PathBuf>, home: &Path, build_dir: &Path) -> PathBuf {
+    let cache_home = match xdg_cache_home {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => home.join(".cache"),
+    };
+    let digest = hex_digest(Algorithm::SHA1, build_dir.as_os_str().as_bytes());
+    cache_home.join("firstaide").join(digest)
+}
+
+pub(crate) fn search_path<T: Into<PathBuf>>(name: T) -> Option<PathBuf> {
     let name = name.into();
-    let home = dirs::home_dir().unwrap_or_else(|| "/home/not/found".into());
+    let home = home_dir();
     let path = env::var_os("PATH").unwrap_or_default();
     env::split_paths(&path)
         .map(|path| expand_path(path, &home))
@@ -240,10 +1776,223 @@ fn expand_path<T: Into<PathBuf>>(path: T, home: &Path) -> PathBuf {
     }
 }
 
+/// A project for tests, in a new temporary directory: it builds with `true`
+/// into `.cache`, and watches what `watch.sh` prints, which is nothing until a
+/// test says otherwise. `extra` is appended to its `.firstaide.toml`.
+#[cfg(test)]
+pub(crate) fn test_project(extra: &str) -> (tempfile::TempDir, Config) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root = dir.path();
+    fs::write(
+        root.join(FILENAME),
+        format!(
+            "cache_dir = \".cache\"\nbuild_exe = \"true\"\nwatch_exe = \"watch.sh\"\n{}",
+            extra
+        ),
+    )
+    .unwrap();
+    let watch_exe = root.join("watch.sh");
+    fs::write(&watch_exe, "#!/bin/sh\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&watch_exe, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let config = Config::load(Some(root)).ok().unwrap();
+    (dir, config)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn staleness_is_soft_when_only_soft_files_change() {
+        let (_dir, config) = test_project("[staleness]\nsoft = [\"docs/*\"]\n");
+        let dir = &config.build_dir;
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.md"), "one").unwrap();
+        fs::write(dir.join("default.nix"), "one").unwrap();
+        let files = [dir.join("docs/index.md"), dir.join("default.nix")];
+        let before = config.checksums_of(&files).unwrap();
+        assert!(config.staleness(&before, &before) == EnvironmentStatus::Okay);
+
+        fs::write(dir.join("docs/index.md"), "two").unwrap();
+        let after = config.checksums_of(&files).unwrap();
+        assert!(config.staleness(&after, &before) == EnvironmentStatus::SoftStale);
+
+        fs::write(dir.join("default.nix"), "two").unwrap();
+        let after = config.checksums_of(&files).unwrap();
+        assert!(config.staleness(&after, &before) == EnvironmentStatus::Stale);
+    }
+
+    /// A project in Git, on `main`, that watches `default.nix` and falls back
+    /// according to `fallback`.
+    fn fallback_project(fallback: &str) -> (tempfile::TempDir, Config) {
+        let (dir, config) = test_project(&format!("[cache]\nfallback = \"{}\"\n", fallback));
+        let root = dir.path();
+        fs::write(root.join("watch.sh"), "#!/bin/sh\necho default.nix\n").unwrap();
+        fs::write(root.join("default.nix"), "0").unwrap();
+        fs::create_dir(root.join(".git")).unwrap();
+        checkout(root, "main");
+        fs::create_dir_all(&config.cache_dir).unwrap();
+        (dir, config)
+    }
+
+    fn checkout(root: &Path, branch: &str) {
+        fs::write(
+            root.join(".git/HEAD"),
+            format!("ref: refs/heads/{}\n", branch),
+        )
+        .unwrap();
+    }
+
+    /// Save a cache for the watched files as they are now, and link to it as
+    /// `build` does.
+    fn save_cache(config: &Config) -> PathBuf {
+        let sums = config.checksums().unwrap();
+        let cache_file = config.cache_file(&sums);
+        cache::Cache::new(crate::env::Diff::new(), sums)
+            .save(&cache_file, &config.cache_keys)
+            .unwrap();
+        let most_recent = config.cache_file_most_recent();
+        fs::remove_file(&most_recent).ok();
+        os::point(&cache_file, &most_recent).unwrap();
+        if let Some(branch) = git::branch(&config.build_dir) {
+            let link = config.cache_file_on_branch(&branch);
+            fs::create_dir_all(config.branches_dir()).unwrap();
+            fs::remove_file(&link).ok();
+            os::point(&cache_file, &link).unwrap();
+        }
+        cache_file
+    }
+
+    fn loaded(config: &Config) -> Option<PathBuf> {
+        let sums = config.checksums().unwrap();
+        config.load_cache(&sums).ok().map(|(_, file)| file)
+    }
+
+    #[test]
+    fn load_cache_prefers_the_cache_for_the_files_as_they_are() {
+        for fallback in ["most-recent", "never", "same-branch"] {
+            let (dir, config) = fallback_project(fallback);
+            assert_eq!(None, loaded(&config), "{}", fallback);
+            let zero = save_cache(&config);
+            fs::write(dir.path().join("default.nix"), "1").unwrap();
+            let one = save_cache(&config);
+            fs::write(dir.path().join("default.nix"), "0").unwrap();
+            assert_eq!(Some(zero), loaded(&config), "{}", fallback);
+            fs::write(dir.path().join("default.nix"), "1").unwrap();
+            assert_eq!(Some(one), loaded(&config), "{}", fallback);
+        }
+    }
+
+    #[test]
+    fn load_cache_falls_back_to_the_most_recent() {
+        let (dir, config) = fallback_project("most-recent");
+        save_cache(&config);
+        fs::write(dir.path().join("default.nix"), "1").unwrap();
+        let one = save_cache(&config);
+        fs::write(dir.path().join("default.nix"), "2").unwrap();
+        assert_eq!(Some(one), loaded(&config));
+    }
+
+    #[test]
+    fn observers_reject_tampered_caches_when_keys_are_trusted() {
+        let (dir, unsigned) = fallback_project("never");
+        let (key_file, trusted) = crate::sign::generate(dir.path(), "ci");
+        let config_file = dir.path().join(".firstaide.toml");
+        let base = fs::read_to_string(&config_file).unwrap();
+        fs::write(
+            &config_file,
+            format!("{}[cache.signing]\nkey_file = {:?}\n", base, key_file),
+        )
+        .unwrap();
+        let owner = Config::load(Some(dir.path())).ok().unwrap();
+        let cache_file = save_cache(&owner);
+
+        fs::write(
+            &config_file,
+            format!(
+                "{}[cache.signing]\ntrusted = [{:?}]\n[observer]\nenabled = true\ncache_dir = {:?}\n",
+                base, trusted, owner.cache_dir,
+            ),
+        )
+        .unwrap();
+        let observer = Config::load(Some(dir.path())).ok().unwrap();
+        assert!(observer.observer);
+        assert_eq!(Some(cache_file.clone()), loaded(&observer));
+
+        // Whole, but not as signed.
+        assert_eq!(cache_file, save_cache(&unsigned));
+        assert_eq!(None, loaded(&observer));
+    }
+
+    #[test]
+    fn load_cache_never_falls_back() {
+        let (dir, config) = fallback_project("never");
+        save_cache(&config);
+        fs::write(dir.path().join("default.nix"), "1").unwrap();
+        let sums = config.checksums().unwrap();
+        match config.load_cache(&sums) {
+            Err(cache::Error::Io(err)) => assert_eq!(io::ErrorKind::NotFound, err.kind()),
+            other => panic!("expected not found, got {:?}", other.map(|(_, file)| file)),
+        }
+    }
+
+    #[test]
+    fn load_cache_falls_back_to_the_same_branch() {
+        let (dir, config) = fallback_project("same-branch");
+        let root = dir.path();
+        let on_main = save_cache(&config);
+        checkout(root, "feature/x");
+        fs::write(root.join("default.nix"), "1").unwrap();
+        let on_feature = save_cache(&config);
+        assert!(config.branches_dir().join("feature%2Fx").exists());
+
+        fs::write(root.join("default.nix"), "2").unwrap();
+        assert_eq!(Some(on_feature), loaded(&config));
+        checkout(root, "main");
+        assert_eq!(Some(on_main), loaded(&config));
+        // Nothing was built on this branch, and nothing when detached.
+        checkout(root, "other");
+        assert_eq!(None, loaded(&config));
+        fs::write(root.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(None, loaded(&config));
+    }
+
+    #[test]
+    fn branch_links_are_distinct() {
+        let (_dir, config) = fallback_project("same-branch");
+        let a = config.cache_file_on_branch("a/b");
+        let b = config.cache_file_on_branch("a%2Fb");
+        assert_ne!(a, b);
+        assert_eq!(config.branches_dir(), a.parent().unwrap());
+    }
+
+    #[test]
+    fn watch_ignore_drops_matching_files() {
+        let (_dir, config) = test_project("watch_ignore = [\"result\", \"*.lock.bak\"]\n");
+        let dir = &config.build_dir;
+        assert!(config.watch_ignored(&dir.join("result")));
+        assert!(config.watch_ignored(&dir.join("sub/Cargo.lock.bak")));
+        assert!(!config.watch_ignored(&dir.join("sub/result")));
+        assert!(!config.watch_ignored(&dir.join("Cargo.lock")));
+    }
+
+    #[test]
+    fn xdg_cache_dir_is_per_project() {
+        let home = pb("/home/dir");
+        let a = xdg_cache_dir(None, &home, Path::new("/src/a"));
+        let b = xdg_cache_dir(None, &home, Path::new("/src/b"));
+        assert!(a.starts_with("/home/dir/.cache/firstaide"));
+        assert_ne!(a, b);
+        let c = xdg_cache_dir(Some(pb("/xdg")), &home, Path::new("/src/a"));
+        assert_eq!(pb("/xdg/firstaide"), c.parent().unwrap());
+        let d = xdg_cache_dir(Some(pb("relative")), &home, Path::new("/src/a"));
+        assert_eq!(a, d);
+    }
+
     #[test]
     fn expands_path_with_leading_tilde() {
         assert_eq!(pb("/home/dir/baz"), expand_path("~/baz", &pb("/home/dir")),);
@@ -259,7 +2008,243 @@ mod tests {
         assert_eq!(pb("sum/were"), expand_path("sum/were", &pb("/home/dir")),);
     }
 
+    #[test]
+    fn watch_output_auto_detects_delimiter() {
+        let nul: &[&[u8]] = &[b"a b", b"c\nd"];
+        assert_eq!(nul, &WatchOutput::Auto.split(b"a b\0c\nd\0")[..]);
+        let newline: &[&[u8]] = &[b"a b", b"c"];
+        assert_eq!(newline, &WatchOutput::Auto.split(b"a b\nc\n")[..]);
+        assert_eq!(newline, &WatchOutput::Newline.split(b"a b\nc\n")[..]);
+    }
+
+    #[test]
+    fn hook_excludes_prefixes_except_included_vars() {
+        let hook = Hook {
+            exclude_prefixes: vec!["SSH_".into(), "NIX_".into()],
+            include_vars: vec!["SSH_AUTH_*".into()],
+            ..Hook::default()
+        };
+        assert!(hook.excludes(b"NIX_STORE"));
+        assert!(hook.excludes(b"SSH_AGENT_PID"));
+        assert!(!hook.excludes(b"SSH_AUTH_SOCK"));
+        assert!(!hook.excludes(b"PATH"));
+        // direnv's own variables are always left out.
+        let hook = Hook {
+            include_vars: vec!["*".into()],
+            ..Hook::default()
+        };
+        assert!(hook.excludes(b"DIRENV_DIFF"));
+    }
+
+    #[test]
+    fn hook_knows_session_vars() {
+        let hook = Hook::default();
+        assert!(hook.is_session_var(b"PS1"));
+        assert!(hook.is_session_var(b"SHLVL"));
+        assert!(hook.is_session_var(b"BASH_FUNC_foo%%"));
+        assert!(!hook.is_session_var(b"PATH"));
+        assert!(!hook.is_session_var(b"PS2"));
+    }
+
+    #[test]
+    fn overlays_apply_outermost_first_below_root() {
+        let root = tempfile::TempDir::new().unwrap();
+        let api = root.path().join("services/api");
+        fs::create_dir_all(&api).unwrap();
+        let write = |dir: &Path, text: &str| fs::write(dir.join(OVERLAY_FILE), text).unwrap();
+        write(root.path(), "[exports]\nIGNORED = \"root\"\n");
+        write(
+            &root.path().join("services"),
+            "exclude_vars = [\"SECRET\"]\n[exports]\nPORT = \"80\"\nSERVICE = \"x\"\n",
+        );
+        write(&api, "[exports]\nPORT = \"8080\"\n");
+
+        let overlays = Overlay::find(&api, root.path()).ok().unwrap();
+        assert_eq!(2, overlays.len());
+        let mut diff = (overlays.iter()).fold(
+            crate::env::Diff::from(&[
+                crate::env::Added("SECRET".into(), "s".into()),
+                crate::env::Added("KEEP".into(), "k".into()),
+            ]),
+            |diff, overlay| overlay.apply(&diff),
+        );
+        diff.simplify();
+        let vars: Vec<_> = (diff.iter())
+            .map(|change| match change {
+                crate::env::Added(name, value) => (name.clone(), value.clone()),
+                _ => panic!("expected only additions"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("KEEP".into(), "k".into()),
+                ("PORT".into(), "8080".into()),
+                ("SERVICE".into(), "x".into()),
+            ],
+            vars
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn load_resolves_start_dir_by_policy() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let real = root.join("real");
+        fs::create_dir_all(real.join("sub")).unwrap();
+        std::os::unix::fs::symlink(&real, root.join("link")).unwrap();
+        fs::write(
+            real.join("sub").join(OVERLAY_FILE),
+            "[exports]\nA = \"a\"\n",
+        )
+        .unwrap();
+        fs::write(
+            real.join(FILENAME),
+            "cache_dir = \".cache\"\nbuild_exe = \"true\"\nwatch_exe = \"true\"\nresolve_paths = \"physical\"\n",
+        )
+        .unwrap();
+        let config = Config::load(Some(root.join("link/sub"))).ok().unwrap();
+        assert_eq!(real, config.build_dir);
+        assert_eq!(1, config.overlays.len());
+    }
+
+    const RENAMED_FOR_TESTS: &[Renamed] = &[
+        Renamed {
+            old: "prompt.colour",
+            new: "prompt.color",
+            until: None,
+        },
+        Renamed {
+            old: "kept",
+            new: "keep",
+            until: Some("1.0.0"),
+        },
+    ];
+
+    #[test]
+    fn merge_is_field_wise() {
+        let mut config: toml::Value = toml::from_str(concat!(
+            "cache_dir = \".cache\"\n",
+            "exclude_vars = [\"A\", \"B\"]\n",
+            "[messages]\n",
+            "getting_started = \"Hello\"\n",
+            "[hook]\n",
+            "minimal = false\n",
+            "outside_ttl = 5\n",
+        ))
+        .unwrap();
+        let local: toml::Value = toml::from_str(concat!(
+            "cache_dir = \"/tmp/mine\"\n",
+            "exclude_vars = [\"C\"]\n",
+            "[hook]\n",
+            "minimal = true\n",
+        ))
+        .unwrap();
+        merge(&mut config, local);
+        assert_eq!(Some("/tmp/mine"), config["cache_dir"].as_str());
+        assert_eq!(
+            Some(1),
+            config["exclude_vars"].as_array().map(|items| items.len())
+        );
+        assert_eq!(
+            Some("Hello"),
+            config["messages"]["getting_started"].as_str()
+        );
+        assert_eq!(Some(true), config["hook"]["minimal"].as_bool());
+        assert_eq!(Some(5), config["hook"]["outside_ttl"].as_integer());
+    }
+
+    #[test]
+    fn rename_keys_moves_deprecated_keys_within_tables() {
+        let mut config: toml::Value = toml::from_str("[prompt]\ncolour = true\n").unwrap();
+        rename_keys(RENAMED_FOR_TESTS, &mut config, "0.1.0")
+            .ok()
+            .unwrap();
+        assert_eq!(Some(true), config["prompt"]["color"].as_bool());
+        assert!(config["prompt"].get("colour").is_none());
+    }
+
+    #[test]
+    fn rename_keys_fails_once_window_closes() {
+        let mut config: toml::Value = toml::from_str("kept = 1\n").unwrap();
+        assert!(rename_keys(RENAMED_FOR_TESTS, &mut config.clone(), "0.9.9").is_ok());
+        match rename_keys(RENAMED_FOR_TESTS, &mut config, "1.0.0") {
+            Err(Error::Renamed(renamed)) => assert_eq!("kept", renamed.old),
+            _ => panic!("expected Renamed"),
+        }
+    }
+
+    #[test]
+    fn fix_keys_rewrites_only_renamed_keys() {
+        let text =
+            "# cache-dir = \"x\"\n  cache-dir = \".cache\" # here\n[messages]\ncache-dir = 1\n";
+        let (fixed, renamed) = fix_keys(text);
+        assert_eq!(
+            "# cache-dir = \"x\"\n  cache_dir = \".cache\" # here\n[messages]\ncache-dir = 1\n",
+            fixed
+        );
+        assert_eq!(1, renamed.len());
+    }
+
+    #[test]
+    fn layer_excludes_prefixes_and_vars() {
+        use crate::env::{Added, Diff};
+        let layer = Layer {
+            build_exe: pb("build"),
+            exclude_prefixes: vec!["NIX_".into()],
+            exclude_vars: vec!["PS1".into()],
+        };
+        let diff = Diff::from(&[
+            Added("NIX_CFLAGS".into(), "-O".into()),
+            Added("PS1".into(), "$ ".into()),
+            Added("PS2".into(), "> ".into()),
+        ]);
+        assert_eq!(
+            Diff::from(&[Added("PS2".into(), "> ".into())]),
+            layer.exclude(&diff)
+        );
+    }
+
     fn pb<T: Into<PathBuf>>(path: T) -> PathBuf {
         path.into()
     }
+
+    #[test]
+    fn build_hook_is_exe_or_command_but_not_both() {
+        let datum_dir = Path::new("/datum");
+        match BuildHook::from(
+            "pre_build",
+            Some("hook.sh".into()),
+            None,
+            datum_dir,
+            paths::Policy::Logical,
+        ) {
+            Ok(Some(BuildHook::Exe(exe))) => assert_eq!(Path::new("/datum/hook.sh"), exe),
+            _ => panic!("expected Exe"),
+        }
+        match BuildHook::from(
+            "pre_build",
+            None,
+            Some("true".into()),
+            datum_dir,
+            paths::Policy::Logical,
+        ) {
+            Ok(Some(BuildHook::Command(command))) => assert_eq!("true", command),
+            _ => panic!("expected Command"),
+        }
+        match BuildHook::from("pre_build", None, None, datum_dir, paths::Policy::Logical) {
+            Ok(None) => (),
+            _ => panic!("expected None"),
+        }
+        match BuildHook::from(
+            "pre_build",
+            Some("a".into()),
+            Some("b".into()),
+            datum_dir,
+            paths::Policy::Logical,
+        ) {
+            Err(Error::Other(message)) => assert!(message.contains("pre_build_exe")),
+            _ => panic!("expected Other"),
+        }
+    }
 }
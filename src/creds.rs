@@ -0,0 +1,366 @@
+use crate::os;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Stop asking keyrings for credentials, from now on: they may prompt, and
+/// there may be no one to answer, as in CI. Credentials from the environment
+/// and from files are still found.
+pub fn non_interactive() {
+    INTERACTIVE.store(false, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    NotFound(String),
+    ReadOnly(String),
+    Keyring(String),
+    NonInteractive(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            NotFound(message) => write!(f, "credentials not found: {}", message),
+            ReadOnly(message) => write!(f, "credentials cannot be stored: {}", message),
+            Keyring(message) => write!(f, "keyring broke: {}", message),
+            NonInteractive(what) => write!(
+                f,
+                "not asking {}, which may prompt, when non-interactive; use an env or file provider",
+                what
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+/// Where to find credentials for the remote cache.
+///
+/// Selected in `.firstaide.toml` with a `[cache.remote.auth]` table, e.g.:
+///
+/// ```toml
+/// [cache.remote.auth]
+/// provider = "keyring"
+/// service = "firstaide"
+/// account = "ci@example.com"
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "provider", rename_all = "kebab-case")]
+pub enum Auth {
+    Env {
+        #[serde(default = "default_env_var")]
+        var: String,
+    },
+    File {
+        #[serde(default = "default_file_path")]
+        path: PathBuf,
+    },
+    Keyring {
+        #[serde(default = "default_keyring_service")]
+        service: String,
+        #[serde(default = "default_keyring_account")]
+        account: String,
+    },
+}
+
+fn default_env_var() -> String {
+    "FIRSTAIDE_CACHE_TOKEN".into()
+}
+
+fn default_file_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| "/config/not/found".into())
+        .join("firstaide")
+        .join("credentials")
+}
+
+fn default_keyring_service() -> String {
+    "firstaide".into()
+}
+
+fn default_keyring_account() -> String {
+    "remote-cache".into()
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth::Env {
+            var: default_env_var(),
+        }
+    }
+}
+
+impl Auth {
+    /// Resolve relative paths against `dir`.
+    pub fn resolve(self, dir: &Path) -> Self {
+        match self {
+            Auth::File { path } => Auth::File {
+                path: dir.join(path),
+            },
+            auth => auth,
+        }
+    }
+
+    /// Fetch the token from wherever it's kept.
+    pub fn token(&self) -> Result<String, Error> {
+        match self {
+            Auth::Env { var } => match env::var(var) {
+                Ok(token) if !token.is_empty() => Ok(token),
+                _ => Err(Error::NotFound(format!("${} is not set", var))),
+            },
+            Auth::File { path } => match fs::read_to_string(path) {
+                Ok(token) if !token.trim().is_empty() => Ok(token.trim().into()),
+                Ok(_) => Err(Error::NotFound(format!("{:?} is empty", path))),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    Err(Error::NotFound(format!("{:?} does not exist", path)))
+                }
+                Err(err) => Err(Error::Io(err)),
+            },
+            Auth::Keyring { service, account } => {
+                self.interactively(|| keyring_lookup(service, account))
+            }
+        }
+    }
+
+    /// Run `f`, which asks a keyring, unless that's been ruled out.
+    fn interactively<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        if INTERACTIVE.load(Ordering::Relaxed) {
+            f()
+        } else {
+            Err(Error::NonInteractive(self.describe()))
+        }
+    }
+
+    /// Store the token so that `token` can later find it.
+    pub fn store(&self, token: &str) -> Result<(), Error> {
+        match self {
+            Auth::Env { var } => Err(Error::ReadOnly(format!(
+                "set ${} in your environment instead",
+                var
+            ))),
+            Auth::File { path } => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // Only the owner should be able to read the token.
+                let mut file = os::private_file(
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true),
+                )
+                .open(path)?;
+                writeln!(&mut file, "{}", token)?;
+                Ok(())
+            }
+            Auth::Keyring { service, account } => {
+                self.interactively(|| keyring_store(service, account, token))
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Auth::Env { var } => format!("environment variable ${}", var),
+            Auth::File { path } => format!("file {:?}", path),
+            Auth::Keyring { service, account } => {
+                format!("keyring (service {:?}, account {:?})", service, account)
+            }
+        }
+    }
+}
+
+// There are no keyring bindings in our dependencies, so we drive the platform
+// tools instead: `security` on macOS, and `secret-tool` (from libsecret) for
+// the Secret Service API elsewhere.
+
+#[cfg(target_os = "macos")]
+fn keyring_lookup(service: &str, account: &str) -> Result<String, Error> {
+    let mut command = Command::new("security");
+    command
+        .arg("find-generic-password")
+        .arg("-s")
+        .arg(service)
+        .arg("-a")
+        .arg(account)
+        .arg("-w");
+    keyring_output(command)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn keyring_lookup(service: &str, account: &str) -> Result<String, Error> {
+    let mut command = Command::new("secret-tool");
+    command
+        .arg("lookup")
+        .arg("service")
+        .arg(service)
+        .arg("account")
+        .arg(account);
+    keyring_output(command)
+}
+
+fn keyring_output(mut command: Command) -> Result<String, Error> {
+    let output = command
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| Error::Keyring(format!("{:?}: {}", command, err)))?;
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !token.is_empty() {
+        Ok(token)
+    } else {
+        Err(Error::NotFound("no matching entry in keyring".into()))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn keyring_store(service: &str, account: &str, token: &str) -> Result<(), Error> {
+    // `security -w <token>` would put the token in the process table, so the
+    // command goes to `security -i` on stdin instead. `-U` updates any
+    // existing entry.
+    let mut command = Command::new("security");
+    command.arg("-i");
+    let input = format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        security_quote(service),
+        security_quote(account),
+        security_quote(token),
+    );
+    keyring_input(command, &input)
+}
+
+/// Quote `word` for `security -i`, which splits its input like a shell.
+#[cfg(any(target_os = "macos", test))]
+fn security_quote(word: &str) -> String {
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn keyring_store(service: &str, account: &str, token: &str) -> Result<(), Error> {
+    // `secret-tool store` reads the secret from stdin.
+    let mut command = Command::new("secret-tool");
+    command
+        .arg("store")
+        .arg(format!("--label=firstaide ({})", service))
+        .arg("service")
+        .arg(service)
+        .arg("account")
+        .arg(account);
+    keyring_input(command, token)
+}
+
+/// Run `command` with `input` on its stdin, and nothing else.
+fn keyring_input(mut command: Command, input: &str) -> Result<(), Error> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| Error::Keyring(format!("{:?}: {}", command, err)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Keyring(format!(
+            "{:?} exited with {}",
+            command, status
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_provider_needs_the_var_to_be_set() {
+        let auth = Auth::Env {
+            var: "FIRSTAIDE_TEST_TOKEN_THAT_IS_NEVER_SET".into(),
+        };
+        assert!(matches!(auth.token(), Err(Error::NotFound(_))));
+        assert!(matches!(auth.store("abc"), Err(Error::ReadOnly(_))));
+    }
+
+    #[test]
+    fn file_provider_stores_then_reads() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let auth = Auth::File {
+            path: "creds/token".into(),
+        }
+        .resolve(dir.path());
+        assert!(matches!(auth.token(), Err(Error::NotFound(_))));
+        auth.store("abc").ok().unwrap();
+        assert_eq!("abc", auth.token().ok().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join("creds/token"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+    }
+
+    #[test]
+    fn file_provider_rejects_empty_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "\n").unwrap();
+        let auth = Auth::File { path };
+        assert!(matches!(auth.token(), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn keyring_output_needs_a_token() {
+        let mut command = Command::new("printf");
+        command.arg("abc\n");
+        assert_eq!("abc", keyring_output(command).ok().unwrap());
+        let command = Command::new("true");
+        assert!(matches!(keyring_output(command), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn keyring_input_writes_to_stdin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("input");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("cat > \"$0\"").arg(&path);
+        keyring_input(command, "abc").ok().unwrap();
+        assert_eq!("abc", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn security_quote_escapes() {
+        assert_eq!(r#""a\"b\\c""#, security_quote("a\"b\\c"));
+    }
+}
@@ -0,0 +1,385 @@
+//! Encryption at rest for cache files.
+//!
+//! A sealed file is `MAGIC`, the format version, a random salt, a random
+//! nonce, then the ciphertext and its tag from ChaCha20-Poly1305. The cipher's
+//! key is derived from the secret and the salt with HKDF-SHA256, so a fresh
+//! salt for every file means that keys are never reused. Everything before the
+//! ciphertext is authenticated too.
+//!
+//! Sealed or not, caches can also be signed; see `sign`.
+
+use crate::creds;
+use crate::error::Code;
+use crate::os;
+use crate::sign;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use crypto_hash::{hex_digest, Algorithm};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Every sealed file starts with this, followed by the format version.
+const MAGIC: &[u8] = b"firstaide-crypt\0";
+/// Format 1 used a hand-rolled construction; its files are refused, and the
+/// caches that used it are rebuilt.
+const VERSION: u8 = 2;
+
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+#[derive(Debug)]
+pub enum Error {
+    Unavailable(String),
+    NoKey,
+    Corrupt,
+    Version(u8),
+    Sign(sign::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Unavailable(message) => write!(f, "cache key not available: {}", message),
+            NoKey => write!(f, "cache is encrypted but encrypt_cache is not set"),
+            Corrupt => write!(
+                f,
+                "cache could not be decrypted; it was modified, or the key is wrong"
+            ),
+            Version(version) => write!(f, "cache encryption (format {}) not recognised", version),
+            Sign(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Sign(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Unavailable(_) | Error::NoKey => Code::Config,
+            _ => Code::CacheCorrupt,
+        }
+    }
+}
+
+impl From<sign::Error> for Error {
+    fn from(error: sign::Error) -> Self {
+        Error::Sign(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Key(Vec<u8>);
+
+impl Key {
+    /// A key from a secret of any length, like a passphrase.
+    pub fn from_secret(secret: &str) -> Self {
+        Key(secret.as_bytes().to_vec())
+    }
+
+    /// The cipher for one file, given its salt.
+    fn cipher(&self, salt: &[u8]) -> ChaCha20Poly1305 {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &self.0)
+            .expand(b"firstaide cache", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        ChaCha20Poly1305::new(&key.into())
+    }
+}
+
+/// Where to find the key with which to encrypt caches, if at all. The key is
+/// fetched once, and only when it's first needed, since asking a keychain can
+/// be slow, and may even prompt; a key from a keychain is also remembered for
+/// the rest of the login session; see `remembered`. Also how to sign caches,
+/// and whom to trust.
+pub struct Keys {
+    auth: Option<creds::Auth>,
+    key: OnceLock<std::result::Result<Key, String>>,
+    signing: sign::Signing,
+    verify_on_open: bool,
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never the key itself.
+        f.debug_struct("Keys")
+            .field("auth", &self.auth)
+            .field("signing", &self.signing)
+            .field("verify_on_open", &self.verify_on_open)
+            .finish()
+    }
+}
+
+impl Keys {
+    pub fn new(auth: Option<creds::Auth>) -> Self {
+        Self {
+            auth,
+            key: OnceLock::new(),
+            signing: sign::Signing::default(),
+            verify_on_open: false,
+        }
+    }
+
+    pub fn with_signing(self, signing: sign::Signing) -> Self {
+        Self { signing, ..self }
+    }
+
+    /// Check signatures in `open` too, for when no cache that's opened was
+    /// built by this process, as for an observer of a shared cache dir.
+    pub fn with_verify_on_open(self, verify_on_open: bool) -> Self {
+        Self {
+            verify_on_open,
+            ..self
+        }
+    }
+
+    /// Encrypt `data` if encryption is enabled, then sign it if there's a
+    /// signing key. A key is generated and stored if there's not one already.
+    pub fn seal(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(self.signing.sign(self.encrypt(data)?)?)
+    }
+
+    /// Strip the signature from `data`, if it's signed, then decrypt it if
+    /// it's sealed; otherwise return it unchanged, so that caches written
+    /// before encryption was enabled can still be read. Signatures are not
+    /// checked here, but by `verify`, on caches from elsewhere – unless
+    /// `with_verify_on_open` says otherwise.
+    pub fn open(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = if self.verify_on_open {
+            self.signing.verify(data)?
+        } else {
+            sign::Signing::strip(data)?
+        };
+        self.decrypt(data)
+    }
+
+    /// Check the signature on `data`, from a remote cache, say, if there are
+    /// trusted keys.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        self.signing.verify(data.to_vec())?;
+        Ok(())
+    }
+
+    /// Encrypt `data` if encryption is enabled, but don't sign it: for files
+    /// that never leave this machine, like those the hook keeps to itself.
+    pub fn encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.auth {
+            None => Ok(data),
+            Some(auth) => Ok(seal(self.key(auth, true)?, &data)),
+        }
+    }
+
+    /// Decrypt `data` if it's sealed; otherwise return it unchanged.
+    pub fn decrypt(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if !is_sealed(&data) {
+            return Ok(data);
+        }
+        match &self.auth {
+            None => Err(Error::NoKey),
+            Some(auth) => open(self.key(auth, false)?, &data),
+        }
+    }
+
+    fn key(&self, auth: &creds::Auth, create: bool) -> Result<&Key> {
+        let key = self.key.get_or_init(|| {
+            if let Some(secret) = remembered(auth) {
+                return Ok(Key::from_secret(&secret));
+            }
+            let secret = match auth.token() {
+                Ok(secret) => secret,
+                Err(creds::Error::NotFound(_)) if create => {
+                    let mut secret = [0u8; 32];
+                    OsRng.fill_bytes(&mut secret);
+                    let secret = hex(&secret);
+                    log::info!("Storing new cache key in {}.", auth.describe());
+                    auth.store(&secret).map_err(|err| err.to_string())?;
+                    secret
+                }
+                Err(err) => return Err(err.to_string()),
+            };
+            remember(auth, &secret);
+            Ok(Key::from_secret(&secret))
+        });
+        key.as_ref()
+            .map_err(|message| Error::Unavailable(message.clone()))
+    }
+}
+
+/// Where a key from a keychain is remembered: in the per-user runtime dir,
+/// which is private, lives in memory, and goes at logout. Hooks run at every
+/// prompt, so this spares the keychain – and the user, should it prompt –
+/// from being asked every time. Keys from anywhere else are cheap to fetch,
+/// and on platforms without a runtime dir, like macOS, they're not remembered.
+fn remembered_file(auth: &creds::Auth) -> Option<PathBuf> {
+    match auth {
+        creds::Auth::Keyring { .. } => Some(
+            dirs::runtime_dir()?
+                .join("firstaide")
+                .join(hex_digest(Algorithm::SHA256, auth.describe().as_bytes())),
+        ),
+        _ => None,
+    }
+}
+
+fn remembered(auth: &creds::Auth) -> Option<String> {
+    let secret = fs::read_to_string(remembered_file(auth)?).ok()?;
+    Some(secret).filter(|secret| !secret.is_empty())
+}
+
+/// Remember `secret` for `remembered`. Failing to do so is not fatal.
+fn remember(auth: &creds::Auth, secret: &str) {
+    let file = match remembered_file(auth) {
+        Some(file) => file,
+        None => return,
+    };
+    let saved = (|| -> io::Result<()> {
+        let dir = file.parent().expect("remembered keys are in a directory");
+        os::private_dir(fs::DirBuilder::new().recursive(true)).create(dir)?;
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(secret.as_bytes())?;
+        temp.persist(&file)?;
+        Ok(())
+    })();
+    if let Err(err) = saved {
+        log::debug!("Could not remember cache key in {:?}: {}", file, err);
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn seal(key: &Key, plain: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    seal_with(key, &salt, &nonce, plain)
+}
+
+fn seal_with(key: &Key, salt: &[u8; SALT_LEN], nonce: &[u8; NONCE_LEN], plain: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(HEADER_LEN + plain.len() + TAG_LEN);
+    sealed.extend(MAGIC);
+    sealed.push(VERSION);
+    sealed.extend(salt);
+    sealed.extend(nonce);
+    let payload = Payload {
+        msg: plain,
+        aad: &sealed,
+    };
+    let ciphertext = (key.cipher(salt))
+        .encrypt(Nonce::from_slice(nonce), payload)
+        .expect("could not encrypt in memory");
+    sealed.extend(ciphertext);
+    sealed
+}
+
+pub fn open(key: &Key, sealed: &[u8]) -> Result<Vec<u8>> {
+    if !is_sealed(sealed) || sealed.len() < MAGIC.len() + 1 {
+        return Err(Error::Corrupt);
+    }
+    match sealed[MAGIC.len()] {
+        VERSION => (),
+        version => return Err(Error::Version(version)),
+    }
+    if sealed.len() < HEADER_LEN + TAG_LEN {
+        return Err(Error::Corrupt);
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = &header[MAGIC.len() + 1 + SALT_LEN..];
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    (key.cipher(salt))
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| Error::Corrupt)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let key = Key::from_secret("sesame");
+        let sealed = seal(&key, b"ALICE=alice");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(5).any(|window| window == b"alice"));
+        assert_eq!(b"ALICE=alice".to_vec(), open(&key, &sealed).ok().unwrap());
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_tampering() {
+        let key = Key::from_secret("sesame");
+        let sealed = seal_with(&key, &[7u8; SALT_LEN], &[9u8; NONCE_LEN], b"ALICE=alice");
+        assert!(open(&Key::from_secret("open"), &sealed).is_err());
+        // The ciphertext, the tag, and the header are all authenticated.
+        for index in [sealed.len() - TAG_LEN - 1, sealed.len() - 1, HEADER_LEN - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            match open(&key, &tampered) {
+                Err(Error::Corrupt) => (),
+                _ => panic!("expected Corrupt"),
+            }
+        }
+    }
+
+    #[test]
+    fn salts_and_nonces_differ() {
+        let key = Key::from_secret("sesame");
+        assert_ne!(seal(&key, b"ALICE=alice"), seal(&key, b"ALICE=alice"));
+    }
+
+    #[test]
+    fn refuses_the_old_format() {
+        let mut sealed = seal(&Key::from_secret("sesame"), b"ALICE=alice");
+        sealed[MAGIC.len()] = 1;
+        match open(&Key::from_secret("sesame"), &sealed) {
+            Err(Error::Version(1)) => (),
+            _ => panic!("expected Version(1)"),
+        }
+    }
+
+    #[test]
+    fn leaves_unsealed_data_alone() {
+        let keys = Keys::new(None);
+        assert_eq!(
+            b"plain".to_vec(),
+            keys.open(b"plain".to_vec()).ok().unwrap()
+        );
+        assert_eq!(
+            b"plain".to_vec(),
+            keys.seal(b"plain".to_vec()).ok().unwrap()
+        );
+        match keys.open(seal(&Key::from_secret("sesame"), b"plain")) {
+            Err(Error::NoKey) => (),
+            _ => panic!("expected NoKey"),
+        }
+    }
+}
@@ -1,9 +1,23 @@
+use crate::error::Code;
+use crate::gzenv;
+use crate::json;
+use crate::os::{self, OsStrExt};
 use bstr::ByteSlice;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::hash_map::HashMap;
-use std::ffi::OsString;
-use std::os::unix::ffi::OsStrExt;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+
+/// What separates entries in `PATH` and its kin on this platform.
+pub const PATH_SEPARATOR: &str = if cfg!(windows) { ";" } else { ":" };
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum Change {
@@ -24,32 +38,219 @@ impl Change {
 
 pub use Change::*;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Diff(Vec<Change>);
+/// How the hook applies a cached change to a list variable, like `PATH` or
+/// `PYTHONPATH`, whose entries are joined with a separator.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Use the value captured by the build as it is.
+    Replace,
+    /// Prepend the entries that the build added to the variable's value now,
+    /// keeping entries added since the build, by other tools say.
+    Prepend,
+    /// Like `Prepend`, but append.
+    Append,
+}
+
+/// A `Strategy` and the separator between entries. In configuration, either
+/// just the strategy, e.g. `"prepend"`, or a table, e.g. `{ strategy =
+/// "append", separator = " " }`; the separator is a colon by default.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(from = "MergeData")]
+pub struct Merge {
+    pub strategy: Strategy,
+    pub separator: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MergeData {
+    Strategy(Strategy),
+    Table {
+        strategy: Strategy,
+        #[serde(default = "Merge::default_separator")]
+        separator: String,
+    },
+}
+
+impl From<MergeData> for Merge {
+    fn from(data: MergeData) -> Self {
+        match data {
+            MergeData::Strategy(strategy) => Merge::new(strategy),
+            MergeData::Table {
+                strategy,
+                separator,
+            } => Merge {
+                strategy,
+                separator,
+            },
+        }
+    }
+}
+
+impl Merge {
+    /// A merge with entries separated by `PATH_SEPARATOR`.
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            separator: Self::default_separator(),
+        }
+    }
+
+    fn default_separator() -> String {
+        PATH_SEPARATOR.into()
+    }
+
+    /// Rewrite `change`, captured by the build, for a variable that has the
+    /// value `now`.
+    pub fn apply(&self, change: &Change, now: Option<&OsStr>) -> Change {
+        let (name, before, after) = match (self.strategy, change) {
+            (Strategy::Replace, change) | (_, change @ Removed(_, _)) => return change.clone(),
+            // Without a separator there are no entries to speak of.
+            (_, change) if self.separator.is_empty() => return change.clone(),
+            (_, Added(name, after)) => (name, None, after),
+            (_, Changed(name, before, after)) => (name, Some(before), after),
+        };
+        let before = self.entries(before.map(OsString::as_os_str));
+        let mut added: Vec<&[u8]> = Vec::new();
+        for entry in self.entries(Some(after)) {
+            if !before.contains(&entry) && !added.contains(&entry) {
+                added.push(entry);
+            }
+        }
+        let kept = (self.entries(now).into_iter()).filter(|entry| !added.contains(entry));
+        let merged: Vec<&[u8]> = match self.strategy {
+            Strategy::Append => kept.chain(added.iter().cloned()).collect(),
+            _ => added.iter().cloned().chain(kept).collect(),
+        };
+        let merged = os::from_vec(merged.join(self.separator.as_bytes()));
+        match now {
+            Some(now) => Changed(name.clone(), now.into(), merged),
+            None => Added(name.clone(), merged),
+        }
+    }
+
+    /// The non-empty entries in `value`.
+    /// Does `after` keep what's in `before`, i.e. every one of its entries?
+    /// An empty `before` has no entries, but is kept only by an empty `after`.
+    pub fn keeps(&self, before: &OsStr, after: &OsStr) -> bool {
+        let before_entries = self.entries(Some(before));
+        if before_entries.is_empty() {
+            return after.is_empty();
+        }
+        let after_entries = self.entries(Some(after));
+        (before_entries.iter()).all(|entry| after_entries.contains(entry))
+    }
+
+    fn entries<'a>(&'a self, value: Option<&'a OsStr>) -> Vec<&'a [u8]> {
+        match value {
+            Some(value) => (value.as_bytes().split_str(&self.separator))
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Where a change came from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Origin {
+    /// The environment outside of the build, i.e. from parent `.envrc`s.
+    Outside,
+    /// The cached environment captured from inside the build.
+    Cache,
+    /// Injected by firstaide's configuration.
+    Config,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Outside => write!(f, "outside"),
+            Origin::Cache => write!(f, "cache"),
+            Origin::Config => write!(f, "config"),
+        }
+    }
+}
+
+/// A list of changes, plus – in parallel – where each change came from. The
+/// origins are keyed by variable name and are not serialized, so the on-disk
+/// form of a `Diff` remains just its list of changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diff(Vec<Change>, #[serde(skip)] HashMap<OsString, Origin>);
 
 impl Diff {
     pub fn new() -> Self {
-        Self(Vec::new())
+        Self(Vec::new(), HashMap::new())
     }
 
     pub fn from(changes: &[Change]) -> Self {
-        Self(changes.into())
+        Self(changes.into(), HashMap::new())
     }
 
     pub fn push(&mut self, change: Change) {
+        self.1.remove(change.name());
         self.0.push(change);
     }
 
     pub fn extend(&mut self, diff: Diff) {
+        // Origins of the incoming changes supersede those we already have.
+        for change in &diff.0 {
+            self.1.remove(change.name());
+        }
+        self.1.extend(diff.1);
         self.0.extend(diff.0);
     }
 
-    pub fn iter(&self) -> DiffIter {
+    /// Record `origin` as the origin of every change in this diff.
+    pub fn with_origin(mut self, origin: Origin) -> Self {
+        self.1 = self.0.iter().map(|c| (c.name().clone(), origin)).collect();
+        self
+    }
+
+    /// Where the change to `name` came from, if known.
+    pub fn origin(&self, name: &OsString) -> Option<Origin> {
+        self.1.get(name).cloned()
+    }
+
+    /// The value that this diff leaves `name` with: `Some(Some(value))` when
+    /// set, `Some(None)` when removed, or `None` when it's left alone.
+    pub fn value(&self, name: &OsStr) -> Option<Option<&OsString>> {
+        self.0
+            .iter()
+            .rev()
+            .find(|change| change.name() == name)
+            .map(|change| match change {
+                Added(_, value) | Changed(_, _, value) => Some(value),
+                Removed(_, _) => None,
+            })
+    }
+
+    /// Rewrite each change according to its variable's `Merge` strategy, given
+    /// the variable's value now.
+    pub fn merge_by<F, G>(&self, merge: F, now: G) -> Self
+    where
+        F: Fn(&OsStr) -> Merge,
+        G: Fn(&OsStr) -> Option<OsString>,
+    {
+        Self(
+            self.0
+                .iter()
+                .map(|change| {
+                    let name = change.name();
+                    merge(name).apply(change, now(name).as_deref())
+                })
+                .collect(),
+            self.1.clone(),
+        )
+    }
+
+    pub fn iter(&self) -> DiffIter<'_> {
         DiffIter(self.0.iter())
     }
 
     pub fn exclude_by_prefix(&self, prefix: &[u8]) -> Self {
-        self.exclude_by(|change| change.name().as_bytes().starts_with_str(&prefix))
+        self.exclude_by(|change| change.name().as_bytes().starts_with_str(prefix))
     }
 
     pub fn exclude_by<F>(&self, func: F) -> Self
@@ -62,6 +263,24 @@ impl Diff {
                 .filter(|change| !func(change))
                 .cloned()
                 .collect(),
+            self.1.clone(),
+        )
+    }
+
+    /// Replace every occurrence of `from` with `to` in the values of this
+    /// diff's changes.
+    pub fn replace_in_values(&self, from: &[u8], to: &[u8]) -> Self {
+        let replace = |value: &OsString| os::from_vec(value.as_bytes().replace(from, to));
+        Self(
+            self.0
+                .iter()
+                .map(|change| match change {
+                    Added(name, vb) => Added(name.clone(), replace(vb)),
+                    Changed(name, va, vb) => Changed(name.clone(), replace(va), replace(vb)),
+                    Removed(name, va) => Removed(name.clone(), replace(va)),
+                })
+                .collect(),
+            self.1.clone(),
         )
     }
 
@@ -154,6 +373,235 @@ pub type Item = (OsString, OsString);
 
 pub type Env = Vec<Item>;
 
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    Failed,
+    Decode(bincode::Error),
+    /// Output from `nix print-dev-env --json` that we don't understand.
+    Parse(String),
+    TooLarge(u64),
+    /// The dump came from a different firstaide, with the given version – if
+    /// known; dumps from before versioning do not say – and dump format.
+    Mismatch(Option<(String, u32)>),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CaptureError::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Failed => write!(f, "command failed"),
+            Decode(err) => write!(f, "problem decoding environment: {}", err),
+            Parse(message) => write!(f, "problem parsing environment: {}", message),
+            TooLarge(size) => write!(
+                f,
+                "environment is too large ({} bytes; the limit is {} bytes)",
+                size, MAX_DUMP_SIZE
+            ),
+            Mismatch(inside) => {
+                write!(f, "version mismatch between inner and outer firstaide: ")?;
+                match inside {
+                    Some((version, format)) => {
+                        write!(f, "inner is {} (dump format {}), ", version, format)?
+                    }
+                    None => write!(f, "inner is older and does not version its dumps, ")?,
+                }
+                write!(
+                    f,
+                    "outer is {} (dump format {}); make sure the same firstaide \
+                     is on PATH inside the environment",
+                    VERSION, DUMP_FORMAT
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use CaptureError::*;
+        match self {
+            Io(err) => Some(err),
+            Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl CaptureError {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            CaptureError::Io(_) => Code::Io,
+            _ => Code::Build,
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(error: io::Error) -> Self {
+        CaptureError::Io(crate::error::traced(error))
+    }
+}
+
+/// No environment is this large; a dump that is must be broken.
+const MAX_DUMP_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Read an environment dumped by `firstaide env`.
+pub fn read_dump(dump_path: &Path) -> Result<Env, CaptureError> {
+    let mut data = Vec::new();
+    fs::File::open(dump_path)?
+        .take(MAX_DUMP_SIZE + 1)
+        .read_to_end(&mut data)?;
+    decode_dump(&data)
+}
+
+/// Dumps start with this, then a header, then the environment. Without it the
+/// dump was written by a firstaide from before dumps were versioned.
+const DUMP_MAGIC: &[u8] = b"\x00firstaide-env\n";
+
+/// Bump this whenever the layout of the header or environment changes.
+const DUMP_FORMAT: u32 = 1;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize, Deserialize)]
+struct DumpHeader {
+    format: u32,
+    version: String,
+}
+
+/// Encode an environment for `decode_dump`.
+pub fn encode_dump(env: &[Item]) -> bincode::Result<Vec<u8>> {
+    let header = DumpHeader {
+        format: DUMP_FORMAT,
+        version: VERSION.into(),
+    };
+    let mut data = DUMP_MAGIC.to_vec();
+    bincode::serialize_into(&mut data, &header)?;
+    bincode::serialize_into(&mut data, env)?;
+    Ok(data)
+}
+
+/// Decode an environment dumped by `firstaide env`. This must not panic, nor
+/// allocate without bound, no matter what `data` contains; see the fuzz
+/// targets in `fuzz`.
+pub fn decode_dump(data: &[u8]) -> Result<Env, CaptureError> {
+    if data.len() as u64 > MAX_DUMP_SIZE {
+        return Err(CaptureError::TooLarge(data.len() as u64));
+    }
+    if !data.starts_with(DUMP_MAGIC) {
+        return Err(CaptureError::Mismatch(None));
+    }
+    let mut data = &data[DUMP_MAGIC.len()..];
+    let limit = data.len() as u64;
+    let header: DumpHeader = bincode::config()
+        .limit(limit)
+        .deserialize_from(&mut data)
+        .map_err(CaptureError::Decode)?;
+    if header.format != DUMP_FORMAT {
+        return Err(CaptureError::Mismatch(Some((
+            header.version,
+            header.format,
+        ))));
+    }
+    bincode::config()
+        .limit(limit)
+        .deserialize(data)
+        .map_err(CaptureError::Decode)
+}
+
+/// Run `command`, which is expected to call `firstaide env --out $dump_path`,
+/// then read back the environment it dumped.
+pub fn capture(command: &mut Command, dump_path: &Path) -> Result<Env, CaptureError> {
+    log::debug!("{:?}", command);
+    if !command.spawn()?.wait()?.success() {
+        return Err(CaptureError::Failed);
+    }
+    read_dump(dump_path)
+}
+
+/// Like `capture`, but `command` is expected to call `firstaide env` without
+/// `--out`, so the dump arrives on stdout. Anything printed before the dump –
+/// by an `.envrc`, say – is skipped.
+pub fn capture_stdout(command: &mut Command) -> Result<Env, CaptureError> {
+    log::debug!("{:?}", command);
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let mut data = Vec::new();
+    let read = (child.stdout.take().expect("stdout is piped"))
+        .take(MAX_DUMP_SIZE + 1)
+        .read_to_end(&mut data);
+    // Past the limit, or on error, we stop listening, but a child with more to
+    // say may block on the full pipe and never exit, so stop it first.
+    if read.is_err() || data.len() as u64 > MAX_DUMP_SIZE {
+        child.kill().ok();
+        child.wait()?;
+        read?;
+        return Err(CaptureError::TooLarge(data.len() as u64));
+    }
+    if !child.wait()?.success() {
+        return Err(CaptureError::Failed);
+    }
+    match data.find(DUMP_MAGIC) {
+        Some(start) => decode_dump(&data[start..]),
+        None => decode_dump(&data),
+    }
+}
+
+/// Like `capture`, but `command`'s stdout and stderr are collected, and each
+/// line is passed to `output` as it arrives (without its trailing newline).
+pub fn capture_with_output<F>(
+    command: &mut Command,
+    dump_path: &Path,
+    mut output: F,
+) -> Result<Env, CaptureError>
+where
+    F: FnMut(&[u8]),
+{
+    log::debug!("{:?}", command);
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read stdout and stderr in their own threads so that neither can block
+    // the other; lines from both are funnelled back here in arrival order.
+    let (sender, receiver) = mpsc::channel();
+    let streams: Vec<Box<dyn Read + Send>> = vec![
+        Box::new(child.stdout.take().expect("stdout is piped")),
+        Box::new(child.stderr.take().expect("stderr is piped")),
+    ];
+    let readers: Vec<_> = streams
+        .into_iter()
+        .map(|stream| {
+            let sender = sender.clone();
+            thread::spawn(move || -> io::Result<()> {
+                for line in io::BufReader::new(stream).split(b'\n') {
+                    // The receiver outlives the readers, so this cannot fail.
+                    sender.send(line?).ok();
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    drop(sender);
+
+    for line in receiver {
+        output(&line);
+    }
+    for reader in readers {
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("output reader panicked")))?;
+    }
+
+    if !child.wait()?.success() {
+        return Err(CaptureError::Failed);
+    }
+    read_dump(dump_path)
+}
+
 pub fn diff(a: &[Item], b: &[Item]) -> Diff {
     let mut diff = Diff::new();
 
@@ -202,6 +650,118 @@ pub fn diff(a: &[Item], b: &[Item]) -> Diff {
     diff
 }
 
+/// Policies for `compose`.
+pub struct Policy<'a> {
+    /// Leave out changes to variables for which this is true.
+    pub exclude: Box<dyn Fn(&OsStr) -> bool + 'a>,
+    /// How to merge a change to a variable with its value in the base.
+    pub merge: Box<dyn Fn(&OsStr) -> Merge + 'a>,
+}
+
+impl Default for Policy<'_> {
+    /// Exclude nothing, and replace every value.
+    fn default() -> Self {
+        Self {
+            exclude: Box::new(|_| false),
+            merge: Box::new(|_| Merge::new(Strategy::Replace)),
+        }
+    }
+}
+
+/// Compose `diff` onto a base environment in which `base` gives the value of
+/// each variable. Changes to variables that `policy` excludes are left out,
+/// and the rest are merged with their values in the base. The result is a
+/// diff from the base, for `apply` or to emit as a script.
+///
+/// This is how the hook, `shell`, and `env compose` all apply a cached
+/// environment.
+pub fn compose<B>(base: B, diff: &Diff, policy: &Policy) -> Diff
+where
+    B: Fn(&OsStr) -> Option<OsString>,
+{
+    diff.exclude_by(|change| (policy.exclude)(change.name()))
+        .merge_by(|name| (policy.merge)(name), base)
+}
+
+/// Apply `diff` to `base`, returning the resulting environment sorted by name.
+pub fn apply(base: &[Item], diff: &Diff) -> Env {
+    let mut env: BTreeMap<OsString, OsString> = base.iter().cloned().collect();
+    for change in diff {
+        match change {
+            Added(name, value) | Changed(name, _, value) => {
+                env.insert(name.clone(), value.clone());
+            }
+            Removed(name, _) => {
+                env.remove(name);
+            }
+        }
+    }
+    env.into_iter().collect()
+}
+
+/// `diff` as JSON: an object mapping each name to its new value, or to `null`
+/// for a variable that it removes. Names and values that are not UTF-8 are
+/// converted lossily. This is what `export`, `apply`, `env compose`, and
+/// `serve` all print.
+pub fn to_json(diff: &Diff) -> json::Value {
+    json::Value::object(
+        diff.iter()
+            .map(|change| {
+                let name = change.name().to_string_lossy();
+                let value = match change {
+                    Added(_, value) | Changed(_, _, value) => {
+                        value.to_string_lossy().into_owned().into()
+                    }
+                    Removed(_, _) => json::Value::Null,
+                };
+                (name, value)
+            })
+            .collect(),
+    )
+}
+
+/// The whole of `env` as JSON, as `to_json` would write it.
+pub fn env_to_json(env: &[Item]) -> json::Value {
+    let added: Vec<Change> = (env.iter())
+        .map(|(name, value)| Added(name.clone(), value.clone()))
+        .collect();
+    to_json(&Diff::from(&added))
+}
+
+/// The value of `name` before direnv applied the environment it loaded, going
+/// by its record of what it changed in `DIRENV_DIFF`. Outside of direnv, and
+/// while it evaluates `.envrc` – having reverted its changes already – that's
+/// the value now. The hook and `build` then agree on variables that the
+/// environment itself sets.
+pub fn var_before_direnv(name: &str) -> Option<OsString> {
+    let direnv_diff = std::env::var("DIRENV_DIFF").ok();
+    before_direnv(direnv_diff.as_deref(), name, || std::env::var_os(name))
+}
+
+fn before_direnv<F>(direnv_diff: Option<&str>, name: &str, now: F) -> Option<OsString>
+where
+    F: FnOnce() -> Option<OsString>,
+{
+    let direnv_diff = match direnv_diff.map(gzenv::decode) {
+        Some(Ok(json)) => json::parse(&json).ok(),
+        _ => None,
+    };
+    let (prev, next) = match &direnv_diff {
+        Some(diff) => (diff.get("p"), diff.get("n")),
+        None => (None, None),
+    };
+    match (
+        prev.and_then(|prev| prev.get(name)),
+        next.and_then(|next| next.get(name)),
+    ) {
+        // It was set, and direnv changed or removed it.
+        (Some(value), _) => value.as_str().map(OsString::from),
+        // It wasn't set; direnv added it.
+        (None, Some(_)) => None,
+        (None, None) => now(),
+    }
+}
+
 fn sorted<T: Ord + Clone>(v: &[T]) -> Vec<T> {
     let mut result = v.to_vec();
     result.sort();
@@ -212,6 +772,25 @@ fn sorted<T: Ord + Clone>(v: &[T]) -> Vec<T> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn vars_are_as_they_were_before_direnv() {
+        let direnv_diff = gzenv::encode(concat!(
+            r#"{"p":{"CHANGED":"outside","REMOVED":"outside"},"#,
+            r#""n":{"CHANGED":"inside","ADDED":"inside"}}"#,
+        ));
+        let now = || Some(OsString::from("now"));
+        let before = |name| before_direnv(Some(&direnv_diff), name, now);
+        assert_eq!(Some("outside".into()), before("CHANGED"));
+        assert_eq!(Some("outside".into()), before("REMOVED"));
+        assert_eq!(None, before("ADDED"));
+        assert_eq!(Some("now".into()), before("UNTOUCHED"));
+        assert_eq!(Some("now".into()), before_direnv(None, "CHANGED", now));
+        assert_eq!(
+            Some("now".into()),
+            before_direnv(Some("junk"), "CHANGED", now)
+        );
+    }
+
     #[test]
     fn can_diff_empty_envs() {
         assert_eq!(Diff::new(), diff(&[], &[]));
@@ -312,16 +891,17 @@ mod tests {
             added("ALICE2", "alice2"),
             added("ALICE3", "alice3"),
         ]);
-        let mut env_before = eval_diff(&da);
         let db = Diff::from(&[
             added("ALICE1", "Alice1"),
             changed("ALICE2", "Alice2-before", "Alice2"),
             removed("ALICE3", "Alice3"),
         ]);
-        eval_diff_into(&db, &mut env_before);
+        // Variables that are there to begin with, so that removing one shows.
+        let base = env(&[("ALICE1", "?"), ("ALICE2", "?"), ("ALICE3", "?")]);
+        let env_before = apply(&apply(&base, &da), &db);
         da.extend(db);
         da.simplify();
-        let env_after = eval_diff(&da);
+        let env_after = apply(&base, &da);
         assert_eq!(
             Diff::from(&[
                 added("ALICE1", "Alice1"),
@@ -341,16 +921,17 @@ mod tests {
             changed("CAROL2", "carol2-before", "carol2"),
             changed("CAROL3", "carol3-before", "carol3"),
         ]);
-        let mut env_before = eval_diff(&da);
         let db = Diff::from(&[
             added("CAROL1", "Carol1"),
             changed("CAROL2", "Carol2-before", "Carol2"),
             removed("CAROL3", "Carol3"),
         ]);
-        eval_diff_into(&db, &mut env_before);
+        // Variables that are there to begin with, so that removing one shows.
+        let base = env(&[("CAROL1", "?"), ("CAROL2", "?"), ("CAROL3", "?")]);
+        let env_before = apply(&apply(&base, &da), &db);
         da.extend(db);
         da.simplify();
-        let env_after = eval_diff(&da);
+        let env_after = apply(&base, &da);
         assert_eq!(
             Diff::from(&[
                 changed("CAROL1", "carol1-before", "Carol1"),
@@ -370,16 +951,17 @@ mod tests {
             removed("ROGER2", "roger2"),
             removed("ROGER3", "roger3"),
         ]);
-        let mut env_before = eval_diff(&da);
         let db = Diff::from(&[
             added("ROGER1", "Roger1"),
             changed("ROGER2", "Roger2-before", "Roger2"),
             removed("ROGER3", "Roger3"),
         ]);
-        eval_diff_into(&db, &mut env_before);
+        // Variables that are there to begin with, so that removing one shows.
+        let base = env(&[("ROGER1", "?"), ("ROGER2", "?"), ("ROGER3", "?")]);
+        let env_before = apply(&apply(&base, &da), &db);
         da.extend(db);
         da.simplify();
-        let env_after = eval_diff(&da);
+        let env_after = apply(&base, &da);
         assert_eq!(
             Diff::from(&[
                 added("ROGER1", "Roger1"),
@@ -392,6 +974,203 @@ mod tests {
         assert_eq!(env_before, env_after);
     }
 
+    #[test]
+    fn origins_follow_changes() {
+        let da = Diff::from(&[added("ALICE", "a"), added("BOB", "b")]).with_origin(Origin::Outside);
+        let db = Diff::from(&[changed("BOB", "b", "B")]).with_origin(Origin::Cache);
+        let mut d = da.exclude_by_prefix(b"CAROL");
+        d.extend(db);
+        d.simplify();
+        assert_eq!(Some(Origin::Outside), d.origin(&"ALICE".into()));
+        assert_eq!(Some(Origin::Cache), d.origin(&"BOB".into()));
+        assert_eq!(None, d.origin(&"CAROL".into()));
+    }
+
+    #[test]
+    fn origins_are_not_serialized() {
+        let d = Diff::from(&[added("ALICE", "a")]);
+        let data = bincode::serialize(&d.with_origin(Origin::Config)).unwrap();
+        assert_eq!(
+            bincode::serialize(&vec![added("ALICE", "a")]).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn replace_in_values_replaces_in_all_values() {
+        let d = Diff::from(&[
+            added("A", "/x/a"),
+            changed("B", "/x/b", "/x/c:/x/d"),
+            removed("C", "/y"),
+        ]);
+        assert_eq!(
+            Diff::from(&[
+                added("A", "@/a"),
+                changed("B", "@/b", "@/c:@/d"),
+                removed("C", "/y"),
+            ]),
+            d.replace_in_values(b"/x", b"@"),
+        );
+    }
+
+    #[test]
+    fn capture_with_output_collects_stdout_and_stderr() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dump_path = dir.path().join("dump");
+        let env: Env = vec![("ALICE".into(), "a".into())];
+        fs::write(&dump_path, encode_dump(&env).unwrap()).unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        let mut lines = Vec::new();
+        let captured =
+            capture_with_output(&mut command, &dump_path, |line| lines.push(line.to_vec()))
+                .ok()
+                .unwrap();
+
+        assert_eq!(env, captured);
+        lines.sort();
+        assert_eq!(vec![b"err".to_vec(), b"out".to_vec()], lines);
+    }
+
+    #[test]
+    fn capture_stdout_stops_children_that_say_too_much() {
+        // Ignoring SIGPIPE, this would go on forever once we stop listening.
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("trap '' PIPE; while :; do yes; done 2>/dev/null");
+        match capture_stdout(&mut command) {
+            Err(CaptureError::TooLarge(size)) if size == MAX_DUMP_SIZE + 1 => (),
+            _ => panic!("expected TooLarge"),
+        }
+    }
+
+    #[test]
+    fn dumps_round_trip() {
+        let env: Env = vec![("ALICE".into(), "a".into())];
+        let decoded = decode_dump(&encode_dump(&env).unwrap()).ok().unwrap();
+        assert_eq!(env, decoded);
+    }
+
+    #[test]
+    fn unversioned_and_other_format_dumps_are_mismatches() {
+        let env: Env = vec![("ALICE".into(), "a".into())];
+        match decode_dump(&bincode::serialize(&env).unwrap()) {
+            Err(CaptureError::Mismatch(None)) => (),
+            _ => panic!("expected Mismatch(None)"),
+        }
+        let header = DumpHeader {
+            format: DUMP_FORMAT + 1,
+            version: "9.9.9".into(),
+        };
+        let mut data = DUMP_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, &header).unwrap();
+        match decode_dump(&data) {
+            Err(CaptureError::Mismatch(Some((version, _)))) => assert_eq!("9.9.9", version),
+            _ => panic!("expected Mismatch(Some(..))"),
+        }
+    }
+
+    #[test]
+    fn prepend_merges_added_entries_into_value_now() {
+        let prepend = Merge::new(Strategy::Prepend);
+        let change = changed("PATH", "/usr/bin:/bin", "/nix/a:/usr/bin:/nix/b:/bin");
+        assert_eq!(
+            changed(
+                "PATH",
+                "/usr/bin:/opt/bin",
+                "/nix/a:/nix/b:/usr/bin:/opt/bin"
+            ),
+            prepend.apply(&change, Some(OsStr::new("/usr/bin:/opt/bin")))
+        );
+        // Entries are not repeated, even when the value now already has them.
+        assert_eq!(
+            changed("PATH", "/nix/b:/bin", "/nix/a:/nix/b:/bin"),
+            prepend.apply(&change, Some(OsStr::new("/nix/b:/bin")))
+        );
+        assert_eq!(added("PATH", "/nix/a:/nix/b"), prepend.apply(&change, None));
+        let replace = Merge::new(Strategy::Replace);
+        assert_eq!(change, replace.apply(&change, Some(OsStr::new("/x"))));
+        let removal = removed("PATH", "/bin");
+        assert_eq!(removal, prepend.apply(&removal, Some(OsStr::new("/x"))));
+    }
+
+    #[test]
+    fn append_merges_with_any_separator() {
+        let append = Merge {
+            strategy: Strategy::Append,
+            separator: " ".into(),
+        };
+        let change = changed("FLAGS", "-a", "-a -b -c");
+        assert_eq!(
+            changed("FLAGS", "-c -x", "-x -b -c"),
+            append.apply(&change, Some(OsStr::new("-c -x")))
+        );
+    }
+
+    #[test]
+    fn merges_are_configured_with_a_strategy_or_a_table() {
+        #[derive(Deserialize)]
+        struct Merges {
+            a: Merge,
+            b: Merge,
+        }
+        let merges: Merges =
+            toml::from_str("a = \"append\"\nb = { strategy = \"prepend\", separator = \";\" }")
+                .unwrap();
+        assert_eq!(Merge::new(Strategy::Append), merges.a);
+        assert_eq!(";", merges.b.separator);
+    }
+
+    #[test]
+    fn compose_excludes_then_merges_with_base() {
+        let base = env(&[("PATH", "/bin"), ("HOME", "/home")]);
+        let lookup = |name: &OsStr| {
+            (base.iter())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        let policy = Policy {
+            exclude: Box::new(|name| name == "SECRET"),
+            merge: Box::new(|name| match name.to_str() {
+                Some("PATH") => Merge::new(Strategy::Prepend),
+                _ => Merge::new(Strategy::Replace),
+            }),
+        };
+        let cached = Diff::from(&[
+            changed("PATH", "/usr/bin", "/nix/bin:/usr/bin"),
+            added("SECRET", "s"),
+            removed("HOME", "/root"),
+        ]);
+        let composed = compose(lookup, &cached, &policy);
+        assert_eq!(
+            Diff::from(&[
+                changed("PATH", "/bin", "/nix/bin:/bin"),
+                removed("HOME", "/root"),
+            ]),
+            composed
+        );
+        assert_eq!(env(&[("PATH", "/nix/bin:/bin")]), apply(&base, &composed));
+    }
+
+    #[test]
+    fn json_maps_names_to_values_or_null() {
+        let diff = Diff::from(&[
+            added("SET", "a\nb"),
+            changed("CHANGED", "a", "b"),
+            removed("GONE", "c"),
+        ]);
+        assert_eq!(
+            r#"{"SET":"a\nb","CHANGED":"b","GONE":null}"#,
+            to_json(&diff).to_string()
+        );
+        assert_eq!(
+            r#"{"A":"a","B":"b"}"#,
+            env_to_json(&env(&[("A", "a"), ("B", "b")])).to_string()
+        );
+    }
+
     fn added(key: &str, vb: &str) -> Change {
         Added(key.into(), vb.into())
     }
@@ -411,20 +1190,4 @@ mod tests {
             .map(|(k, v)| (k.into(), v.into()))
             .collect()
     }
-
-    fn eval_diff(diff: &Diff) -> HashMap<OsString, Option<OsString>> {
-        let mut env = HashMap::new();
-        eval_diff_into(diff, &mut env);
-        env
-    }
-
-    fn eval_diff_into(diff: &Diff, env: &mut HashMap<OsString, Option<OsString>>) {
-        for change in diff {
-            match change {
-                Added(name, value) => env.insert(name.clone(), Some(value.clone())),
-                Changed(name, _, value) => env.insert(name.clone(), Some(value.clone())),
-                Removed(name, _) => env.insert(name.clone(), None),
-            };
-        }
-    }
 }
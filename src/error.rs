@@ -1,26 +1,390 @@
 use crate::cmds;
+use crate::json;
+use crate::term;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 
 pub use Error::*;
 
 pub enum Error {
     CommandNotFound(String),
+    ApplyError(cmds::apply::Error),
     BuildError(cmds::build::Error),
     StatusError(cmds::status::Error),
+    SumsError(cmds::sums::Error),
     CleanError(cmds::clean::Error),
+    ConfigError(cmds::config::Error),
+    DaemonError(cmds::daemon::Error),
+    DevcontainerError(cmds::devcontainer::Error),
     HookError(cmds::hook::Error),
     EnvError(cmds::env::Error),
+    ExportError(cmds::export::Error),
+    CacheError(cmds::cache::Error),
+    CiError(cmds::ci::Error),
+    DiffError(cmds::diff::Error),
+    LoginError(cmds::login::Error),
+    WatchError(cmds::watch::Error),
+    ServeError(cmds::serve::Error),
+    AuditLogError(cmds::audit_log::Error),
+    HistoryError(cmds::history::Error),
+    ShellError(cmds::shell::Error),
+    DoctorError(cmds::doctor::Error),
+    UpgradeCheckError(cmds::upgrade_check::Error),
+}
+
+/// What kind of failure, for tools that wrap firstaide to branch on. The
+/// names are stable: more may be added, but these will not change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    /// Bad arguments, or a command, key, or the like that does not exist.
+    Usage,
+    /// Config that is missing or invalid.
+    Config,
+    /// direnv is missing, or too old.
+    Direnv,
+    /// The build failed, or a hook around it.
+    Build,
+    /// A cache that cannot be read, or is not what it should be.
+    CacheCorrupt,
+    /// A cache written by a firstaide too old or too new to read it.
+    CacheVersion,
+    /// Another build, or a daemon, is already running.
+    Locked,
+    /// Waiting gave up before what was awaited happened.
+    Timeout,
+    NotFound,
+    Network,
+    Io,
+    Other,
+}
+
+impl Code {
+    pub fn name(self) -> &'static str {
+        use Code::*;
+        match self {
+            Usage => "usage",
+            Config => "config",
+            Direnv => "direnv",
+            Build => "build",
+            CacheCorrupt => "cache-corrupt",
+            CacheVersion => "cache-version",
+            Locked => "locked",
+            Timeout => "timeout",
+            NotFound => "not-found",
+            Network => "network",
+            Io => "io",
+            Other => "other",
+        }
+    }
+
+    /// The code with which firstaide exits. `status` uses 0 to 2 to report
+    /// on the environment, so 2 also stands for failures in general.
+    pub fn exit_code(self) -> u8 {
+        use Code::*;
+        match self {
+            Config => 3,
+            Build => 4,
+            Direnv => 5,
+            CacheCorrupt | CacheVersion => 6,
+            Timeout => 7,
+            Usage | Locked | NotFound | Network | Io | Other => 2,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is. Each command's errors know that for
+    /// themselves.
+    pub fn code(&self) -> Code {
+        match self {
+            CommandNotFound(_) => Code::Usage,
+            ApplyError(err) => err.code(),
+            BuildError(err) => err.code(),
+            StatusError(err) => err.code(),
+            SumsError(err) => err.code(),
+            CleanError(err) => err.code(),
+            ConfigError(err) => err.code(),
+            DaemonError(err) => err.code(),
+            DevcontainerError(err) => err.code(),
+            HookError(err) => err.code(),
+            EnvError(err) => err.code(),
+            ExportError(err) => err.code(),
+            CacheError(err) => err.code(),
+            CiError(err) => err.code(),
+            DiffError(err) => err.code(),
+            LoginError(err) => err.code(),
+            WatchError(err) => err.code(),
+            ServeError(err) => err.code(),
+            AuditLogError(err) => err.code(),
+            HistoryError(err) => err.code(),
+            ShellError(err) => err.code(),
+            DoctorError(err) => err.code(),
+            UpgradeCheckError(err) => err.code(),
+        }
+    }
+
+    /// For `--format json`, in place of the output that there isn't.
+    pub fn to_json(&self) -> json::Value {
+        let mut chain = chain(self).into_iter();
+        let message = chain.next().unwrap_or_default();
+        json::Value::object(vec![(
+            "error",
+            json::Value::object(vec![
+                ("code", self.code().name().into()),
+                ("message", message.into()),
+                (
+                    "causes",
+                    json::Value::Array(chain.map(Into::into).collect()),
+                ),
+            ]),
+        )])
+    }
+
+    /// What failed, and why.
+    fn parts(&self) -> (&'static str, &dyn fmt::Display) {
+        match self {
+            CommandNotFound(message) => ("command not found", message),
+            ApplyError(err) => ("apply failed", err),
+            BuildError(err) => ("build failed", err),
+            StatusError(err) => ("status failed", err),
+            SumsError(err) => ("sums failed", err),
+            CleanError(err) => ("clean failed", err),
+            ConfigError(err) => ("config failed", err),
+            DaemonError(err) => ("daemon failed", err),
+            DevcontainerError(err) => ("devcontainer failed", err),
+            HookError(err) => ("hook failed", err),
+            EnvError(err) => ("env failed", err),
+            ExportError(err) => ("export failed", err),
+            CacheError(err) => ("cache failed", err),
+            CiError(err) => ("ci failed", err),
+            DiffError(err) => ("diff failed", err),
+            LoginError(err) => ("login failed", err),
+            WatchError(err) => ("watch failed", err),
+            ServeError(err) => ("serve failed", err),
+            AuditLogError(err) => ("audit-log failed", err),
+            HistoryError(err) => ("history failed", err),
+            ShellError(err) => ("shell failed", err),
+            DoctorError(err) => ("doctor failed", err),
+            UpgradeCheckError(err) => ("upgrade-check failed", err),
+        }
+    }
+
+    /// Render for humans: what failed on the first line, then the chain of
+    /// causes, one per line and indented, innermost last. With `verbose`, and
+    /// when `RUST_BACKTRACE` is set, a backtrace of where the error happened
+    /// follows. With `color`, it's styled as `term` would.
+    pub fn report(&self, verbose: bool, color: bool) -> String {
+        let mut chain = chain(self).into_iter();
+        let mut report = term::Style::Error.paint_if(color, &chain.next().unwrap_or_default());
+        let caused_by = term::Style::Dim.paint_if(color, "caused by:");
+        for cause in chain {
+            report.push_str(&format!("\n  {} {}", caused_by, cause));
+        }
+        if verbose {
+            if let Some(backtrace) = backtrace(self) {
+                let heading = term::Style::Dim.paint_if(color, "backtrace:");
+                report.push_str(&format!("\n  {}\n{}", heading, backtrace));
+            }
+        }
+        report
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, why) = self.parts();
+        write!(f, "{}: {}", what, why)
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandNotFound(name) => f.debug_tuple("CommandNotFound").field(name).finish(),
+            _ => f.debug_tuple("Error").field(&self.source()).finish(),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
-            CommandNotFound(message) => write!(f, "command not found: {}", message),
-            BuildError(err) => write!(f, "build failed: {}", err),
-            StatusError(err) => write!(f, "status failed: {}", err),
-            CleanError(err) => write!(f, "clean failed: {}", err),
-            HookError(err) => write!(f, "hook failed: {}", err),
-            EnvError(err) => write!(f, "env failed: {}", err),
+            CommandNotFound(_) => None,
+            ApplyError(err) => Some(err),
+            BuildError(err) => Some(err),
+            StatusError(err) => Some(err),
+            SumsError(err) => Some(err),
+            CleanError(err) => Some(err),
+            ConfigError(err) => Some(err),
+            DaemonError(err) => Some(err),
+            DevcontainerError(err) => Some(err),
+            HookError(err) => Some(err),
+            EnvError(err) => Some(err),
+            ExportError(err) => Some(err),
+            CacheError(err) => Some(err),
+            CiError(err) => Some(err),
+            DiffError(err) => Some(err),
+            LoginError(err) => Some(err),
+            WatchError(err) => Some(err),
+            ServeError(err) => Some(err),
+            AuditLogError(err) => Some(err),
+            HistoryError(err) => Some(err),
+            ShellError(err) => Some(err),
+            DoctorError(err) => Some(err),
+            UpgradeCheckError(err) => Some(err),
         }
     }
 }
+
+/// The messages of `err` and its sources, outermost first. Messages in this
+/// crate say what went wrong then, after ": ", why – their source's message –
+/// so each is trimmed to what it adds. Those that add nothing, because they
+/// wrap their source as it is, are left out.
+fn chain(err: &(dyn StdError + 'static)) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut next = Some(err);
+    while let Some(err) = next {
+        next = err.source();
+        let message = err.to_string();
+        let message = match next.map(ToString::to_string) {
+            Some(source) if source == message => continue,
+            Some(source) => match message.strip_suffix(&format!(": {}", source)) {
+                Some(what) => what.to_owned(),
+                None => message,
+            },
+            None => message,
+        };
+        messages.push(message);
+    }
+    messages
+}
+
+/// An I/O error with a backtrace of where it became one of ours; see `traced`.
+#[derive(Debug)]
+struct Traced {
+    error: io::Error,
+    backtrace: Backtrace,
+}
+
+impl fmt::Display for Traced {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl StdError for Traced {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.error.source()
+    }
+}
+
+/// When `RUST_BACKTRACE` asks for them, capture a backtrace for `error`, here,
+/// where it's converted into one of this crate's errors – that's close to
+/// where it happened – and keep it in the error, for `report`. The error has
+/// the same kind and message either way.
+pub fn traced(error: io::Error) -> io::Error {
+    let backtrace = Backtrace::capture();
+    match backtrace.status() {
+        BacktraceStatus::Captured => io::Error::new(error.kind(), Traced { error, backtrace }),
+        _ => error,
+    }
+}
+
+/// The backtrace kept by `traced` in `err` or one of its sources, if any.
+fn backtrace<'a>(err: &'a (dyn StdError + 'static)) -> Option<&'a Backtrace> {
+    let mut next = Some(err);
+    while let Some(err) = next {
+        let traced = (err.downcast_ref::<io::Error>())
+            .and_then(io::Error::get_ref)
+            .and_then(|inner| inner.downcast_ref::<Traced>());
+        if let Some(traced) = traced {
+            return Some(&traced.backtrace);
+        }
+        next = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache, config};
+
+    /// A build that failed for want of a file, wrapped as errors are.
+    fn build_error() -> Error {
+        let err = io::Error::new(io::ErrorKind::NotFound, "gone: \"a: b\"");
+        BuildError(cmds::build::Error::Cache(cache::Error::Io(err)))
+    }
+
+    #[test]
+    fn chain_walks_sources() {
+        assert_eq!(
+            vec![
+                "build failed",
+                "cache could not be saved",
+                "input/output error",
+                "gone: \"a: b\"",
+            ],
+            chain(&build_error())
+        );
+        // Without a source, a message is not split, whatever it says.
+        assert_eq!(
+            vec!["command not found: frob: not a command"],
+            chain(&CommandNotFound("frob: not a command".into()))
+        );
+    }
+
+    #[test]
+    fn chain_skips_transparent_wrappers() {
+        let err = BuildError(cmds::build::Error::Config(config::Error::DirenvNotFound));
+        assert_eq!(
+            vec!["build failed", "direnv not found on PATH"],
+            chain(&err)
+        );
+    }
+
+    #[test]
+    fn report_snapshot() {
+        let err = build_error();
+        assert_eq!(
+            include_str!("snapshots/report.txt").trim_end(),
+            err.report(false, false)
+        );
+        assert_eq!(
+            include_str!("snapshots/report-color.txt").trim_end(),
+            err.report(false, true)
+        );
+    }
+
+    #[test]
+    fn json_snapshot() {
+        assert_eq!(
+            include_str!("snapshots/report.json").trim_end(),
+            build_error().to_json().to_string()
+        );
+    }
+
+    #[test]
+    fn traced_keeps_kind_and_message() {
+        let err = traced(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert_eq!("gone", err.to_string());
+    }
+
+    #[test]
+    fn codes_classify_failures() {
+        let err = BuildError(cmds::build::Error::Cache(cache::Error::Decompress(
+            io::Error::new(io::ErrorKind::InvalidData, "bad magic"),
+        )));
+        assert_eq!("cache-corrupt", err.code().name());
+        let err = BuildError(cmds::build::Error::Config(config::Error::DirenvNotFound));
+        assert_eq!(Code::Direnv, err.code());
+        assert_eq!(5, err.code().exit_code());
+        assert_eq!(
+            r#"{"error":{"code":"usage","message":"command not found: frob","causes":[]}}"#,
+            CommandNotFound("frob".into()).to_json().to_string()
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! What Git says about the project, for those features that care which
+//! branch it's on. Projects need not be in Git, and Git need not be
+//! installed, so every question here may go unanswered.
+
+use crate::json;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Where the project's work tree was, in Git terms.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Head {
+    /// `None` when HEAD is detached.
+    pub branch: Option<String>,
+    pub commit: String,
+}
+
+impl Head {
+    /// Save to `path`, which `cache ls` can read without loading – perhaps
+    /// decrypting and verifying – the cache this describes.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let value = json::Value::object(vec![
+            ("branch", self.branch.clone().into()),
+            ("commit", self.commit.as_str().into()),
+        ]);
+        fs::write(path, format!("{}\n", value))
+    }
+
+    /// Load from `path`, as written by `save`, or `None` if it cannot be read.
+    pub fn load(path: &Path) -> Option<Self> {
+        let value = json::parse(fs::read_to_string(path).ok()?.trim_end()).ok()?;
+        Some(Self {
+            branch: value
+                .get("branch")
+                .and_then(json::Value::as_str)
+                .map(String::from),
+            commit: value.get("commit")?.as_str()?.into(),
+        })
+    }
+}
+
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let commit = self.commit.get(..12).unwrap_or(&self.commit);
+        match &self.branch {
+            Some(branch) => write!(f, "{}@{}", branch, commit),
+            None => write!(f, "{}", commit),
+        }
+    }
+}
+
+/// The branch and commit checked out in `dir`, or `None` if it's not in a Git
+/// work tree, or there are no commits yet.
+pub fn head(dir: &Path) -> Option<Head> {
+    let commit = git(dir, &["rev-parse", "--verify", "--quiet", "HEAD"])?;
+    Some(Head {
+        branch: branch(dir),
+        commit,
+    })
+}
+
+/// The branch checked out in `dir`, or `None` if it's not in a Git work
+/// tree, or HEAD is detached. The hook asks at every prompt, so this reads
+/// `HEAD` rather than running `git`.
+pub fn branch(dir: &Path) -> Option<String> {
+    let head = fs::read_to_string(git_dir(dir)?.join("HEAD")).ok()?;
+    let branch = head.trim_end().strip_prefix("ref: refs/heads/")?;
+    Some(branch.to_owned()).filter(|branch| !branch.is_empty())
+}
+
+/// The Git dir for `dir`: `.git` in it or its nearest ancestor that has one.
+/// In linked work trees and submodules `.git` is a file that says where the
+/// Git dir is.
+fn git_dir(dir: &Path) -> Option<PathBuf> {
+    let dot_git = dir
+        .ancestors()
+        .map(|dir| dir.join(".git"))
+        .find(|dot_git| dot_git.exists())?;
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let link = fs::read_to_string(&dot_git).ok()?;
+    let git_dir = Path::new(link.trim_end().strip_prefix("gitdir: ")?);
+    // A relative path is relative to the `.git` file.
+    Some(dot_git.parent()?.join(git_dir))
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        let out = String::from_utf8(output.stdout).ok()?;
+        Some(out.trim().to_owned()).filter(|out| !out.is_empty())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_branch_outside_git() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(None, branch(dir.path()));
+        assert_eq!(None, head(dir.path()));
+    }
+
+    #[test]
+    fn branch_from_head() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let git_dir = dir.path().join(".git");
+        fs::create_dir(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        assert_eq!(Some("feature/x".into()), branch(dir.path()));
+        assert_eq!(Some("feature/x".into()), branch(&sub));
+        // Detached.
+        fs::write(git_dir.join("HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(None, branch(dir.path()));
+    }
+
+    #[test]
+    fn branch_in_linked_work_tree() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let git_dir = dir.path().join("repo.git/worktrees/tree");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join(".git"), "gitdir: ../repo.git/worktrees/tree\n").unwrap();
+        assert_eq!(Some("main".into()), branch(&tree));
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("git/cache.abc");
+        for branch in [Some("main".into()), None] {
+            let head = Head {
+                branch,
+                commit: "0123456789abcdef".into(),
+            };
+            head.save(&path).unwrap();
+            assert_eq!(Some(head), Head::load(&path));
+        }
+        assert_eq!(None, Head::load(&dir.path().join("missing")));
+    }
+
+    #[test]
+    fn displays_short_commit() {
+        let head = Head {
+            branch: Some("main".into()),
+            commit: "0123456789abcdef0123".into(),
+        };
+        assert_eq!("main@0123456789ab", head.to_string());
+        let head = Head {
+            branch: None,
+            commit: "abc".into(),
+        };
+        assert_eq!("abc", head.to_string());
+    }
+}
@@ -0,0 +1,117 @@
+//! Keeping the cache dir out of version control, for `gitignore_cache`.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// How to keep the cache dir out of Git when it's within the project.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Leave it to the user.
+    #[default]
+    Off,
+    /// Put a `.gitignore` that ignores everything into the cache dir.
+    CacheDir,
+    /// Add the cache dir to the project's `.gitignore`.
+    Project,
+}
+
+/// Make sure that Git ignores `cache_dir`, according to `policy`. Nothing is
+/// done when `cache_dir` is not within `build_dir`.
+pub fn ensure(policy: Policy, build_dir: &Path, cache_dir: &Path) -> io::Result<()> {
+    let relative = match cache_dir.strip_prefix(build_dir) {
+        Ok(relative) if relative.as_os_str().is_empty() => return Ok(()),
+        Ok(relative) => relative,
+        Err(_) => return Ok(()),
+    };
+    match policy {
+        Policy::Off => Ok(()),
+        Policy::CacheDir => {
+            let gitignore = cache_dir.join(".gitignore");
+            if !gitignore.exists() {
+                log::info!("Create {:?}.", gitignore);
+                fs::write(&gitignore, "*\n")?;
+            }
+            Ok(())
+        }
+        Policy::Project => {
+            let gitignore = build_dir.join(".gitignore");
+            let existing = match fs::read_to_string(&gitignore) {
+                Ok(existing) => existing,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err),
+            };
+            let entry = format!("/{}/", relative.to_string_lossy());
+            if let Some(addition) = addition(&existing, &entry) {
+                log::info!("Add {} to {:?}.", entry, gitignore);
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&gitignore)?;
+                file.write_all(addition.as_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What to append to a `.gitignore` containing `existing` so that it has
+/// `entry`, like `/.cache/`, or `None` if it already has it, with or without
+/// the leading or trailing slash.
+fn addition(existing: &str, entry: &str) -> Option<String> {
+    let name = entry.trim_matches('/');
+    let present = (existing.lines())
+        .map(str::trim)
+        .any(|line| line.trim_start_matches('/').trim_end_matches('/') == name);
+    match (present, existing.is_empty() || existing.ends_with('\n')) {
+        (true, _) => None,
+        (false, true) => Some(format!("{}\n", entry)),
+        (false, false) => Some(format!("\n{}\n", entry)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_entry_only_when_missing() {
+        assert_eq!(Some("/.cache/\n".into()), addition("", "/.cache/"));
+        assert_eq!(Some("\n/.cache/\n".into()), addition("target", "/.cache/"));
+        assert_eq!(None, addition("target\n.cache\n", "/.cache/"));
+        assert_eq!(None, addition("/.cache/\n", "/.cache/"));
+        assert_eq!(
+            Some("/.cache/\n".into()),
+            addition("/.cache/more\n", "/.cache/")
+        );
+    }
+
+    #[test]
+    fn ignores_cache_dir() {
+        let build_dir = tempfile::TempDir::new().unwrap();
+        let build_dir = build_dir.path();
+        let cache_dir = build_dir.join(".cache");
+        fs::create_dir(&cache_dir).unwrap();
+
+        ensure(Policy::CacheDir, build_dir, &cache_dir).unwrap();
+        assert_eq!(
+            "*\n",
+            fs::read_to_string(cache_dir.join(".gitignore")).unwrap()
+        );
+
+        fs::write(build_dir.join(".gitignore"), "target\n").unwrap();
+        ensure(Policy::Project, build_dir, &cache_dir).unwrap();
+        ensure(Policy::Project, build_dir, &cache_dir).unwrap();
+        assert_eq!(
+            "target\n/.cache/\n",
+            fs::read_to_string(build_dir.join(".gitignore")).unwrap()
+        );
+
+        // Not within the project, so not our business.
+        let elsewhere = tempfile::TempDir::new().unwrap();
+        ensure(Policy::CacheDir, build_dir, elsewhere.path()).unwrap();
+        assert!(!elsewhere.path().join(".gitignore").exists());
+    }
+}
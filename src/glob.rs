@@ -0,0 +1,50 @@
+//! Shell-style patterns for matching names, where `*` matches any run of
+//! bytes, `?` matches any one byte, and everything else matches itself.
+
+pub fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    // Where to resume after the most recent `*`, if any: the index in the
+    // pattern after the `*`, and the index in the name it has matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&b) if b == b'?' || b == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the `*` swallow one more byte and try again.
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_literals_and_wildcards() {
+        assert!(matches(b"GITHUB_TOKEN", b"GITHUB_TOKEN"));
+        assert!(!matches(b"GITHUB_TOKEN", b"GITHUB_TOKENS"));
+        assert!(matches(b"AWS_SECRET*", b"AWS_SECRET_ACCESS_KEY"));
+        assert!(matches(b"AWS_SECRET*", b"AWS_SECRET"));
+        assert!(!matches(b"AWS_SECRET*", b"MY_AWS_SECRET"));
+        assert!(matches(b"*_TOKEN", b"NPM_TOKEN"));
+        assert!(matches(b"*TOK*N*", b"A_TOKEN_B"));
+        assert!(matches(b"NPM_?OKEN", b"NPM_TOKEN"));
+        assert!(!matches(b"", b"X"));
+        assert!(matches(b"*", b""));
+    }
+}
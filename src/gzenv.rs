@@ -0,0 +1,108 @@
+//! direnv's "gzenv" encoding, in which it keeps its own state, like
+//! `DIRENV_WATCHES`, and in which `direnv dump` writes environments: JSON,
+//! compressed with zlib, then encoded as URL-safe base64, with padding.
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt;
+use std::io::{Read, Write};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// No state that direnv keeps is this large; a value that is must be broken.
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not gzenv: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn encode(json: &str) -> String {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // Writing into memory does not fail.
+    let compressed = (encoder.write_all(json.as_bytes()))
+        .and_then(|()| encoder.finish())
+        .expect("could not compress in memory");
+    let mut encoded = String::with_capacity(compressed.len().div_ceil(3) * 4);
+    for chunk in compressed.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for n in 0..4 {
+            if n <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * n) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Result<String, Error> {
+    let encoded = encoded.trim().trim_end_matches('=');
+    let mut compressed = Vec::with_capacity(encoded.len() / 4 * 3 + 2);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in encoded.bytes() {
+        let value = match ALPHABET.iter().position(|&b| b == byte) {
+            Some(value) => value as u32,
+            None => return Err(Error(format!("unexpected {:?}", byte as char))),
+        };
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            compressed.push((bits >> count) as u8);
+        }
+    }
+    let mut json = String::new();
+    (ZlibDecoder::new(&compressed[..]).take(MAX_SIZE + 1))
+        .read_to_string(&mut json)
+        .map_err(|err| Error(err.to_string()))?;
+    if json.len() as u64 > MAX_SIZE {
+        return Err(Error("too large".into()));
+    }
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for json in ["", "[]", "{\"A\":\"a\"}", "[{\"path\":\"/a/.envrc\"}]"] {
+            let encoded = encode(json);
+            assert!(encoded.len() % 4 == 0);
+            assert_eq!(json, decode(&encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn decodes_zlib_and_padded_base64() {
+        // Made independently, with Python's `zlib` and `base64.urlsafe_b64encode`.
+        let encoded =
+            "eJyLrlYKSCzJULJS0i_JLdDXS80rK0pW0lHyzU8pycxNVbIyNDeAAh0l14rM4pJiJauSotLU2lgAHcISRg==";
+        assert_eq!(
+            "[{\"Path\":\"/tmp/.envrc\",\"Modtime\":1700000000,\"Exists\":true}]",
+            decode(encoded).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode("not base64!").is_err());
+        assert!(decode("AAAA").is_err());
+    }
+}
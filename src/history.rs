@@ -0,0 +1,262 @@
+//! A history of builds, in `history.jsonl` in the cache dir: one JSON object
+//! per line, per build, saying when it started and finished, how it went,
+//! the signature of the cache it made, and which firstaide made it.
+//!
+//! The hook does not watch it, though, but the reload file, which builds touch
+//! – except those that change nothing – to prompt direnv to reload.
+
+use crate::config;
+use crate::json;
+use crate::rotate;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Rotate the history, and the build log, when they grow beyond this size.
+pub const MAX_SIZE: u64 = 1024 * 1024;
+/// How many rotated files to keep.
+pub const KEEP: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// A new environment was captured, or an existing cache aliased.
+    Success,
+    /// The environment was already up to date, so nothing was built.
+    UpToDate,
+    Failure,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::UpToDate => "up-to-date",
+            Outcome::Failure => "failure",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "success" => Some(Outcome::Success),
+            "up-to-date" => Some(Outcome::UpToDate),
+            "failure" => Some(Outcome::Failure),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub started: chrono::DateTime<chrono::FixedOffset>,
+    pub finished: chrono::DateTime<chrono::FixedOffset>,
+    pub outcome: Outcome,
+    /// The signature of the cache that the build made or found, if it got
+    /// that far.
+    pub sig: Option<String>,
+    /// With `cache_objects`, the object holding the environment.
+    pub object: Option<String>,
+    /// The Git branch that the project was on, if any.
+    pub branch: Option<String>,
+    pub firstaide: String,
+}
+
+impl Record {
+    pub fn duration(&self) -> Duration {
+        (self.finished - self.started).to_std().unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        json::Value::object(vec![
+            ("started", self.started.to_rfc3339().into()),
+            ("finished", self.finished.to_rfc3339().into()),
+            ("duration_ms", (self.duration().as_millis() as i64).into()),
+            ("outcome", self.outcome.name().into()),
+            ("sig", self.sig.clone().into()),
+            ("object", self.object.clone().into()),
+            ("branch", self.branch.clone().into()),
+            ("firstaide", self.firstaide.as_str().into()),
+        ])
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let value = json::parse(line).ok()?;
+        let time = |key| chrono::DateTime::parse_from_rfc3339(value.get(key)?.as_str()?).ok();
+        Some(Self {
+            started: time("started")?,
+            finished: time("finished")?,
+            outcome: Outcome::from_name(value.get("outcome")?.as_str()?)?,
+            sig: value
+                .get("sig")
+                .and_then(json::Value::as_str)
+                .map(String::from),
+            object: value
+                .get("object")
+                .and_then(json::Value::as_str)
+                .map(String::from),
+            branch: value
+                .get("branch")
+                .and_then(json::Value::as_str)
+                .map(String::from),
+            firstaide: value.get("firstaide")?.as_str()?.into(),
+        })
+    }
+}
+
+/// Append `record` to the history, rotating it first if it has grown too
+/// large.
+pub fn record(config: &config::Config, record: &Record) -> io::Result<()> {
+    let path = config.history_file();
+    rotate::rotate(&path, MAX_SIZE, KEEP)?;
+    let mut history = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)?;
+    // One write, so that records from concurrent builds do not interleave.
+    history.write_all(format!("{}\n", record.to_json()).as_bytes())?;
+    history.sync_all()
+}
+
+/// Read all records, oldest first, from rotated files too.
+pub fn read(config: &config::Config) -> io::Result<Vec<Record>> {
+    let path = config.history_file();
+    let mut paths: Vec<PathBuf> = (1..=KEEP)
+        .rev()
+        .map(|n| rotate::rotated(&path, n))
+        .collect();
+    paths.push(path);
+    let mut records = Vec::new();
+    for path in paths {
+        records.extend(read_file(&path)?);
+    }
+    Ok(records)
+}
+
+/// Record that the rebuild that made the cache with signature `sig` produced
+/// the same environment as the build before it, or, with `None`, that the
+/// most recent rebuild did not. direnv is not prompted to reload after such a
+/// rebuild, so the hook says so the next time it runs; see `take_unchanged`.
+pub fn mark_unchanged(config: &config::Config, sig: Option<&str>) -> io::Result<()> {
+    let file = config.unchanged_file();
+    match sig {
+        Some(sig) => fs::write(file, sig),
+        None => match fs::remove_file(file) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Is the cache with signature `sig` marked as unchanged?
+pub fn is_unchanged(config: &config::Config, sig: &str) -> bool {
+    fs::read_to_string(config.unchanged_file()).is_ok_and(|marked| marked == sig)
+}
+
+/// Like `is_unchanged`, but clears the mark, so that the hook says so once.
+/// Where it cannot be cleared, as for observers, this is never so, lest the
+/// hook say so at every prompt.
+pub fn take_unchanged(config: &config::Config, sig: &str) -> bool {
+    is_unchanged(config, sig) && config.writable().is_ok() && mark_unchanged(config, None).is_ok()
+}
+
+/// Prompt direnv to reload, at the next prompt, by touching the reload file,
+/// which the hook asks it to watch.
+pub fn prompt_reload(config: &config::Config) -> io::Result<()> {
+    touch(&config.reload_file())
+}
+
+/// Set the modification time of `path` to now, creating it if necessary.
+fn touch(path: &Path) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    set_modified_now(path)
+}
+
+#[cfg(unix)]
+fn set_modified_now(path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::utimes(path.as_ptr(), std::ptr::null()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn set_modified_now(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    file.set_modified(std::time::SystemTime::now())
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<Record>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut records = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        match Record::parse(&line?) {
+            Some(record) => records.push(record),
+            None => log::warn!("Malformed line in build history {:?}.", path),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_records() {
+        let started = chrono::DateTime::parse_from_rfc3339("2020-04-01T12:00:00+01:00").unwrap();
+        let record = Record {
+            started,
+            finished: started + chrono::Duration::milliseconds(1500),
+            outcome: Outcome::Success,
+            sig: Some("abc123".into()),
+            object: Some("def456".into()),
+            branch: Some("main".into()),
+            firstaide: "0.1.0".into(),
+        };
+        let line = record.to_json().to_string();
+        assert!(line.contains("\"duration_ms\":1500"));
+        assert_eq!(Some(record.clone()), Record::parse(&line));
+
+        let record = Record {
+            outcome: Outcome::Failure,
+            sig: None,
+            object: None,
+            branch: None,
+            ..record
+        };
+        assert_eq!(
+            Some(record.clone()),
+            Record::parse(&record.to_json().to_string())
+        );
+        assert_eq!(
+            None,
+            Record::parse("2020-04-01T12:00:00+01:00  /some/cache")
+        );
+    }
+
+    #[test]
+    fn unchanged_is_taken_once() {
+        let (_dir, config) = config::test_project("");
+        fs::create_dir(&config.cache_dir).unwrap();
+        mark_unchanged(&config, Some("abc")).unwrap();
+        assert!(!is_unchanged(&config, "def"));
+        assert!(!take_unchanged(&config, "def"));
+        assert!(is_unchanged(&config, "abc"));
+        assert!(take_unchanged(&config, "abc"));
+        assert!(!take_unchanged(&config, "abc"));
+        mark_unchanged(&config, Some("abc")).unwrap();
+        mark_unchanged(&config, None).unwrap();
+        mark_unchanged(&config, None).unwrap();
+        assert!(!is_unchanged(&config, "abc"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Translations of user-facing messages. Catalogs are TOML files in `i18n`,
+//! one per language, embedded at build time. To add a language, add its
+//! catalog to `CATALOGS`; messages it lacks fall back to English.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+
+type Catalog = HashMap<String, String>;
+
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("i18n/en.toml")),
+    ("de", include_str!("i18n/de.toml")),
+];
+
+const FALLBACK: &str = "en";
+
+/// The catalogs to consult, most preferred first.
+static SELECTED: OnceLock<Vec<Catalog>> = OnceLock::new();
+
+/// Choose the language: `language` if given, e.g. from config, otherwise from
+/// the environment, as gettext does. Only the first call has any effect, and
+/// only if no message has been translated yet.
+pub fn select(language: Option<&str>) {
+    SELECTED.get_or_init(|| catalogs(&language.map_or_else(from_env, str::to_owned)));
+}
+
+/// Translate the message identified by `key`. Missing messages come out as
+/// the key itself, which is ugly but at least identifiable.
+pub fn tr(key: &str) -> String {
+    let catalogs = SELECTED.get_or_init(|| catalogs(&from_env()));
+    (catalogs.iter())
+        .find_map(|catalog| catalog.get(key))
+        .cloned()
+        .unwrap_or_else(|| key.to_owned())
+}
+
+/// From `LC_ALL`, `LC_MESSAGES`, or `LANG`, in that order of preference.
+fn from_env() -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default()
+}
+
+/// Catalogs for `language`, like `de_CH.UTF-8`: first `de_CH`, then `de`, then
+/// the fallback.
+fn catalogs(language: &str) -> Vec<Catalog> {
+    let language = language.split(['.', '@']).next().unwrap_or("");
+    let base = language.split(['_', '-']).next().unwrap_or("");
+    let mut names = vec![language, base, FALLBACK];
+    names.dedup();
+    (names.into_iter())
+        .filter_map(|name| CATALOGS.iter().find(|(lang, _)| *lang == name))
+        .filter_map(|(lang, text)| match toml::from_str(text) {
+            Ok(catalog) => Some(catalog),
+            Err(err) => {
+                log::warn!("Could not load {} messages: {}", lang, err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_are_valid() {
+        for (lang, text) in CATALOGS {
+            let catalog: Result<Catalog, _> = toml::from_str(text);
+            assert!(catalog.is_ok(), "{} catalog is not valid", lang);
+        }
+    }
+
+    #[test]
+    fn catalogs_translate_only_english_messages_with_the_same_arguments() {
+        let english: Catalog = toml::from_str(CATALOGS[0].1).unwrap();
+        for (lang, text) in &CATALOGS[1..] {
+            let catalog: Catalog = toml::from_str(text).unwrap();
+            for (key, message) in &catalog {
+                let original = english.get(key);
+                assert!(original.is_some(), "{} has {}, English does not", lang, key);
+                assert_eq!(
+                    original.unwrap().matches("{}").count(),
+                    message.matches("{}").count(),
+                    "{} {} has different arguments",
+                    lang,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn catalogs_are_chosen_by_language_then_english() {
+        let catalogs = catalogs("de_CH.UTF-8");
+        assert_eq!(2, catalogs.len());
+        assert_eq!("Umgebung ist aktuell!", catalogs[0]["status.okay"]);
+        assert_eq!("Environment is up to date!", catalogs[1]["status.okay"]);
+    }
+
+    #[test]
+    fn catalogs_fall_back_to_english() {
+        let catalogs = catalogs("xx_YY.UTF-8");
+        assert_eq!(1, catalogs.len());
+        assert!(catalogs[0].contains_key("status.okay"));
+    }
+}
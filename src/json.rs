@@ -0,0 +1,512 @@
+//! Just enough JSON to emit machine-readable output, to read simple
+//! requests, and to patch files like `devcontainer.json`. Numbers are integers
+//! unless they have a fraction or an exponent, or are too large.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Build an object from `(key, value)` pairs, preserving their order.
+    pub fn object<K: Into<String>>(pairs: Vec<(K, Value)>) -> Self {
+        Value::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+}
+
+impl Value {
+    /// The value for `key` when this is an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String, usize);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.0, self.1)
+    }
+}
+
+/// Parse a complete JSON document.
+pub fn parse(input: &str) -> Result<Value, ParseError> {
+    parse_document(input, false)
+}
+
+/// Like `parse`, but allow `//` and `/* */` comments, and trailing commas, as
+/// VS Code does in its settings and `devcontainer.json`. The comments are
+/// discarded.
+pub fn parse_jsonc(input: &str) -> Result<Value, ParseError> {
+    parse_document(input, true)
+}
+
+fn parse_document(input: &str, jsonc: bool) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        jsonc,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos < input.len() {
+        Err(parser.error("trailing characters"))
+    } else {
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    jsonc: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError(message.into(), self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        match self.next() {
+            Some(n) if n == c => Ok(()),
+            _ => Err(self.error(&format!("expected {:?}", c))),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value, ParseError> {
+        if self.input[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected characters"))
+        }
+    }
+
+    fn whitespace(&mut self) {
+        loop {
+            while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+                self.pos += 1;
+            }
+            let rest = &self.input[self.pos..];
+            let end = if !self.jsonc {
+                None
+            } else if rest.starts_with("//") {
+                Some(rest.find('\n').unwrap_or(rest.len()))
+            } else if rest.starts_with("/*") {
+                Some(rest.find("*/").map_or(rest.len(), |end| end + 2))
+            } else {
+                None
+            };
+            match end {
+                Some(end) => self.pos += end,
+                None => break,
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some('-') | Some('0'..='9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        let digits = |parser: &mut Self| {
+            let start = parser.pos;
+            while let Some('0'..='9') = parser.peek() {
+                parser.pos += 1;
+            }
+            parser.pos > start
+        };
+        let mut valid = digits(self);
+        let mut integer = true;
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            valid &= digits(self);
+            integer = false;
+        }
+        if let Some('e') | Some('E') = self.peek() {
+            self.pos += 1;
+            if let Some('+') | Some('-') = self.peek() {
+                self.pos += 1;
+            }
+            valid &= digits(self);
+            integer = false;
+        }
+        let text = &self.input[start..self.pos];
+        let invalid = || ParseError("invalid number".into(), start);
+        if !valid {
+            return Err(invalid());
+        }
+        // Integers too large for `i64` fall back to `f64`, as JavaScript has it.
+        match text.parse() {
+            Ok(n) if integer => Ok(Value::Number(n)),
+            _ => text.parse().map(Value::Float).map_err(|_| invalid()),
+        }
+    }
+
+    /// After a comma, is this the end of the array or object, as JSONC allows?
+    fn trailing(&mut self, end: char) -> bool {
+        self.whitespace();
+        if self.jsonc && self.peek() == Some(end) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.unicode_escape()?),
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(self.error("control character in string"))
+                }
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.input.get(self.pos..self.pos + 4);
+        match digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()) {
+            Some(n) => {
+                self.pos += 4;
+                Ok(n)
+            }
+            None => Err(self.error("invalid unicode escape")),
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            // A surrogate pair.
+            self.expect('\\')?;
+            self.expect('u')?;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("invalid surrogate pair"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.next() {
+                Some(',') if self.trailing(']') => return Ok(Value::Array(items)),
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut pairs = Vec::new();
+        self.whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(pairs));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            pairs.push((key, self.value()?));
+            self.whitespace();
+            match self.next() {
+                Some(',') if self.trailing('}') => return Ok(Value::Object(pairs)),
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(pairs)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        match o {
+            Some(v) => v.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Compact by default; with `{:#}`, objects and arrays are indented, one item
+/// per line, for files that people also edit.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            return write_pretty(f, self, 0);
+        }
+        use Value::*;
+        match self {
+            Null => write!(f, "null"),
+            Bool(b) => write!(f, "{}", b),
+            Number(n) => write!(f, "{}", n),
+            // Parsed floats are finite, and Rust writes them without exponents.
+            Float(n) => write!(f, "{}", n),
+            String(s) => write_string(f, s),
+            Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Object(pairs) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in pairs.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_pretty(f: &mut fmt::Formatter, value: &Value, depth: usize) -> fmt::Result {
+    let indent = |f: &mut fmt::Formatter, depth: usize| write!(f, "{:1$}", "", depth * 2);
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            writeln!(f, "[")?;
+            for (index, item) in items.iter().enumerate() {
+                indent(f, depth + 1)?;
+                write_pretty(f, item, depth + 1)?;
+                writeln!(f, "{}", if index + 1 < items.len() { "," } else { "" })?;
+            }
+            indent(f, depth)?;
+            write!(f, "]")
+        }
+        Value::Object(pairs) if !pairs.is_empty() => {
+            writeln!(f, "{{")?;
+            for (index, (key, value)) in pairs.iter().enumerate() {
+                indent(f, depth + 1)?;
+                write_string(f, key)?;
+                write!(f, ": ")?;
+                write_pretty(f, value, depth + 1)?;
+                writeln!(f, "{}", if index + 1 < pairs.len() { "," } else { "" })?;
+            }
+            indent(f, depth)?;
+            write!(f, "}}")
+        }
+        value => write!(f, "{}", value),
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_scalars() {
+        assert_eq!("null", Value::Null.to_string());
+        assert_eq!("true", Value::Bool(true).to_string());
+        assert_eq!("-12", Value::Number(-12).to_string());
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(
+            r#""a\"b\\c\nd\u0001""#,
+            Value::from("a\"b\\c\nd\u{1}").to_string()
+        );
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        let value = Value::object(vec![
+            (
+                "a",
+                Value::Array(vec![Value::Number(-1), Value::Null, true.into()]),
+            ),
+            ("b", Value::from("x\"y\\z\n\u{1}é")),
+            ("c", Value::object::<&str>(vec![])),
+        ]);
+        assert_eq!(Ok(value.clone()), parse(&value.to_string()));
+    }
+
+    #[test]
+    fn parses_whitespace_and_escapes() {
+        assert_eq!(
+            Ok(Value::Array(vec!["\u{1F600}/".into(), Value::Bool(false)])),
+            parse(" [ \"\\ud83d\\ude00\\/\" ,\n false ] ")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for input in &[
+            "",
+            "[1,",
+            "{\"a\" 1}",
+            "nul",
+            "1.",
+            "-",
+            "1e",
+            "\"\\x\"",
+            "[] []",
+            "[1,]",
+        ] {
+            assert!(parse(input).is_err(), "{:?} should not parse", input);
+        }
+    }
+
+    #[test]
+    fn formats_containers() {
+        let value = Value::object(vec![
+            ("a", Value::Array(vec![Value::Number(1), Value::Null])),
+            ("b", Value::object::<&str>(vec![])),
+        ]);
+        assert_eq!(r#"{"a":[1,null],"b":{}}"#, value.to_string());
+        assert_eq!(
+            "{\n  \"a\": [\n    1,\n    null\n  ],\n  \"b\": {}\n}",
+            format!("{:#}", value)
+        );
+    }
+
+    #[test]
+    fn parses_comments_and_trailing_commas_only_when_asked() {
+        let input = "// Top.\n{ /* a */ \"a\": [1, 2, /* b */ ], // c\n }";
+        assert!(parse(input).is_err());
+        let numbers = Value::Array(vec![Value::Number(1), Value::Number(2)]);
+        assert_eq!(Ok(Value::object(vec![("a", numbers)])), parse_jsonc(input));
+        assert!(parse_jsonc("[,]").is_err());
+        assert!(parse_jsonc("{\"a\": 1,,}").is_err());
+    }
+
+    #[test]
+    fn parses_floats_and_writes_them_back() {
+        assert_eq!(Ok(Value::Float(1.5)), parse("1.5"));
+        assert_eq!(Ok(Value::Float(-250.0)), parse("-2.5e2"));
+        assert_eq!(Ok(Value::Float(1e20)), parse("1E+20"));
+        assert_eq!(
+            Ok(Value::Float(9223372036854775808.0)),
+            parse("9223372036854775808")
+        );
+        assert_eq!("[1.5,-0.25]", parse("[1.5, -25e-2]").unwrap().to_string());
+    }
+}
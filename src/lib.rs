@@ -0,0 +1,262 @@
+//! firstaide as a library, for tools that want to know about a project's
+//! environment without running `firstaide` and scraping its output: load the
+//! project, ask for the status, read the cache, compose the diff that the
+//! hook would apply, or render the hook itself.
+//!
+//! [`Project`] and the types its methods return are the stable API, and change
+//! only with the major version. The modules are public only so that the
+//! `firstaide` command, in the `firstaide-cli` package, can be built on this
+//! crate; they are hidden from the docs, and may change in any release.
+//!
+//! ```no_run
+//! let project = firstaide::Project::load(Some("/path/to/project"))?;
+//! println!("{}", project.status()?.name());
+//! # Ok::<(), firstaide::Error>(())
+//! ```
+
+#[macro_use]
+extern crate clap;
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[doc(hidden)]
+pub mod audit;
+#[doc(hidden)]
+pub mod cache;
+#[doc(hidden)]
+pub mod cmds;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod creds;
+#[doc(hidden)]
+pub mod crypt;
+#[doc(hidden)]
+pub mod env;
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod git;
+#[doc(hidden)]
+pub mod gitignore;
+#[doc(hidden)]
+pub mod glob;
+#[doc(hidden)]
+pub mod gzenv;
+#[doc(hidden)]
+pub mod history;
+#[doc(hidden)]
+pub mod i18n;
+#[doc(hidden)]
+pub mod json;
+#[doc(hidden)]
+pub mod lock;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod nix;
+#[doc(hidden)]
+pub mod os;
+#[doc(hidden)]
+pub mod paths;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod rotate;
+#[doc(hidden)]
+pub mod service;
+#[doc(hidden)]
+pub mod shells;
+#[doc(hidden)]
+pub mod sign;
+#[doc(hidden)]
+pub mod stamps;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod sums;
+#[doc(hidden)]
+pub mod template;
+#[doc(hidden)]
+pub mod term;
+#[doc(hidden)]
+pub mod timings;
+#[doc(hidden)]
+pub mod validate;
+#[doc(hidden)]
+pub mod version;
+#[doc(hidden)]
+pub mod watcher;
+#[doc(hidden)]
+pub mod workspace;
+
+pub use env::Change;
+pub use status::EnvironmentStatus;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A project, found and configured as the `firstaide` command would find it.
+pub struct Project(config::Config);
+
+impl Project {
+    /// The project in `dir`, or in the current directory.
+    pub fn load<T: AsRef<Path>>(dir: Option<T>) -> Result<Self> {
+        let config = config::Config::load(dir.map(|dir| dir.as_ref().to_path_buf()))?;
+        Ok(Project(config))
+    }
+
+    /// The directory in which the environment is built.
+    pub fn build_dir(&self) -> &Path {
+        &self.0.build_dir
+    }
+
+    /// The directory in which built environments are cached.
+    pub fn cache_dir(&self) -> &Path {
+        &self.0.cache_dir
+    }
+
+    /// The config file, `.firstaide.toml`.
+    pub fn config_file(&self) -> &Path {
+        &self.0.config_file
+    }
+
+    /// The status of the environment, as `firstaide status` reports it.
+    pub fn status(&self) -> Result<EnvironmentStatus> {
+        Ok(cmds::status::status_of(&self.0, &self.0.checksums()?))
+    }
+
+    /// The cached environment that matches the watched files as they are now,
+    /// or else the most recent one, stale or not.
+    pub fn read_cache(&self) -> Result<CachedEnvironment> {
+        let (cache, _) = self.0.load_cache(&self.0.checksums()?)?;
+        let (branch, commit) = match cache.git {
+            Some(head) => (head.branch, Some(head.commit)),
+            None => (None, None),
+        };
+        Ok(CachedEnvironment {
+            changes: cache.diff.iter().cloned().collect(),
+            firstaide: cache.firstaide,
+            branch,
+            commit,
+        })
+    }
+
+    /// The changes that the hook would make to the environment of this
+    /// process, as `firstaide diff` shows them. This captures the outside
+    /// environment, so it's not quick.
+    pub fn diff(&self) -> Result<Vec<Change>> {
+        let diff = cmds::diff::compose(&self.0, &self.0.checksums()?)?;
+        Ok(diff.iter().cloned().collect())
+    }
+
+    /// The hook, in Bash for `.envrc`, as `firstaide hook` emits it, and the
+    /// code with which `firstaide hook` would exit.
+    pub fn hook(&self) -> Result<(Vec<u8>, u8)> {
+        Ok(cmds::hook::render(&self.0, self.0.hook.minimal, true)?)
+    }
+}
+
+/// A built environment, as read from the cache.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CachedEnvironment {
+    /// What the environment changes.
+    pub changes: Vec<Change>,
+    /// The version of firstaide that built it, or empty if it was built
+    /// before caches recorded it.
+    pub firstaide: String,
+    /// The Git branch of the project when it was built; `None` when HEAD was
+    /// detached, or the project is not in Git.
+    pub branch: Option<String>,
+    /// The Git commit of the project when it was built, if it's in Git.
+    pub commit: Option<String>,
+}
+
+/// What went wrong; its `Display` says so for humans, and `source` says why.
+#[derive(Debug)]
+pub struct Error(Inner);
+
+#[derive(Debug)]
+enum Inner {
+    Config(config::Error),
+    Io(io::Error),
+    Cache(cache::Error),
+    Diff(cmds::diff::Error),
+    Hook(cmds::hook::Error),
+}
+
+impl Error {
+    /// The kind of failure, e.g. `config` or `cache-corrupt`: the same stable
+    /// names that `firstaide --log-format json` reports as `code`.
+    pub fn code(&self) -> &'static str {
+        use Inner::*;
+        match &self.0 {
+            Config(err) => err.code(),
+            Io(_) => error::Code::Io,
+            Cache(err) => err.code(),
+            Diff(err) => err.code(),
+            Hook(err) => err.code(),
+        }
+        .name()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Inner::*;
+        match &self.0 {
+            Config(err) => write!(f, "{}", err),
+            Io(err) => write!(f, "input/output error: {}", err),
+            Cache(err) => write!(f, "{}", err),
+            Diff(err) => write!(f, "{}", err),
+            Hook(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Inner::*;
+        match &self.0 {
+            Config(err) => err.source(),
+            Io(err) => Some(err),
+            Cache(err) => err.source(),
+            Diff(err) => err.source(),
+            Hook(err) => err.source(),
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Self {
+        Error(Inner::Config(error))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error(Inner::Io(error))
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(error: cache::Error) -> Self {
+        Error(Inner::Cache(error))
+    }
+}
+
+impl From<cmds::diff::Error> for Error {
+    fn from(error: cmds::diff::Error) -> Self {
+        Error(Inner::Diff(error))
+    }
+}
+
+impl From<cmds::hook::Error> for Error {
+    fn from(error: cmds::hook::Error) -> Self {
+        Error(Inner::Hook(error))
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::Path;
+
+/// An advisory, exclusive lock on a file, held until dropped.
+///
+/// This uses `flock(2)` or, on Windows, `LockFileEx`, so the lock is released
+/// when the file is closed, even if this process is killed.
+pub struct Lock {
+    file: fs::File,
+}
+
+impl Lock {
+    /// Acquire the lock on `path`, creating the file if necessary.
+    ///
+    /// When `wait` is true this blocks until the lock can be acquired,
+    /// otherwise it fails immediately with `io::ErrorKind::WouldBlock`.
+    pub fn acquire<T: AsRef<Path>>(path: T, wait: bool) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        match lock(&file, Mode::Exclusive, false) {
+            Ok(()) => Ok(Self { file }),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && wait => {
+                log::info!("Waiting for lock on {:?}.", path.as_ref());
+                lock(&file, Mode::Exclusive, true)?;
+                Ok(Self { file })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replace the contents of the lock file with `note`, e.g. to say who
+    /// holds the lock.
+    pub fn note(&mut self, note: &str) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        self.file.write_all(note.as_bytes())
+    }
+}
+
+/// Is someone holding the lock on `path`? This does not create `path`.
+pub fn is_locked<T: AsRef<Path>>(path: T) -> bool {
+    match fs::File::open(path) {
+        Ok(file) => match lock(&file, Mode::Shared, false) {
+            Ok(()) => false,
+            Err(ref err) => err.kind() == io::ErrorKind::WouldBlock,
+        },
+        Err(_) => false,
+    }
+}
+
+enum Mode {
+    Shared,
+    Exclusive,
+}
+
+/// Lock `file`, waiting if `wait` is true, otherwise failing with
+/// `io::ErrorKind::WouldBlock` when someone else holds a conflicting lock.
+#[cfg(unix)]
+fn lock(file: &fs::File, mode: Mode, wait: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut operation = match mode {
+        Mode::Shared => libc::LOCK_SH,
+        Mode::Exclusive => libc::LOCK_EX,
+    };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock(file: &fs::File, mode: Mode, wait: bool) -> io::Result<()> {
+    let locked = match (mode, wait) {
+        (Mode::Shared, true) => return file.lock_shared(),
+        (Mode::Exclusive, true) => return file.lock(),
+        (Mode::Shared, false) => file.try_lock_shared(),
+        (Mode::Exclusive, false) => file.try_lock(),
+    };
+    match locked {
+        Ok(()) => Ok(()),
+        Err(fs::TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+        Err(fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_does_not_wait() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        let _lock = Lock::acquire(&path, false).unwrap();
+        match Lock::acquire(&path, false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            _ => panic!("expected WouldBlock"),
+        }
+    }
+
+    #[test]
+    fn lock_is_released_when_dropped() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        drop(Lock::acquire(&path, false).unwrap());
+        assert!(Lock::acquire(&path, false).is_ok());
+    }
+
+    #[test]
+    fn note_replaces_contents() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        let mut lock = Lock::acquire(&path, false).unwrap();
+        lock.note("a long note\n").unwrap();
+        lock.note("short\n").unwrap();
+        assert_eq!("short\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn is_locked_reports_lock_state() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        assert!(!is_locked(&path));
+        let lock = Lock::acquire(&path, false).unwrap();
+        assert!(is_locked(&path));
+        drop(lock);
+        assert!(!is_locked(&path));
+    }
+}
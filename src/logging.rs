@@ -0,0 +1,347 @@
+use crate::json;
+use std::cell::RefCell;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Rotate the log file when it grows beyond this many bytes.
+const LOG_FILE_MAX_SIZE: u64 = 1024 * 1024;
+
+/// How many rotated log files to keep.
+pub const LOG_FILE_KEEP: usize = 3;
+
+/// Filter directives, like `RUST_LOG` for `env_logger`: a comma-separated
+/// list of `level`, `target=level`, or `target`, e.g. `info,firstaide::env=trace`.
+pub const FILTER_VAR: &str = "FIRSTAIDE_LOG";
+
+/// The log file, once the configuration says where it is; see `log_to_file`.
+static LOG_FILE: Mutex<Option<fs::File>> = Mutex::new(None);
+
+/// How log records are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// For humans.
+    Text,
+    /// One JSON object per line, for machines: `timestamp`, `level`,
+    /// `target`, and `message`, plus `event` and its fields for records
+    /// logged with `event`.
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+/// An event's name and fields.
+type Event = (&'static str, Vec<(&'static str, json::Value)>);
+
+thread_local! {
+    /// The name and fields of the event being logged on this thread, if any;
+    /// see `event`. The `log` crate we use has no stable way to attach
+    /// fields to a record, so they travel alongside.
+    static EVENT: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+/// Log `message` at `level` as the event `name`, with `fields`; `target` is
+/// typically `module_path!()`. In text format it's just the message; in JSON
+/// format the event name and fields are included too.
+pub fn event(
+    target: &str,
+    level: log::Level,
+    name: &'static str,
+    fields: Vec<(&'static str, json::Value)>,
+    message: fmt::Arguments,
+) {
+    EVENT.with(|event| event.replace(Some((name, fields))));
+    log::log!(target: target, level, "{}", message);
+    EVENT.with(|event| event.replace(None));
+}
+
+/// Work out a log level from direnv's logging conventions, if possible.
+///
+/// When direnv evaluates `.envrc` our output on stderr is interleaved with
+/// direnv's own logging, so we try to match its verbosity. An empty
+/// `DIRENV_LOG_FORMAT` is how users ask direnv to be quiet, and we follow
+/// suit. `DIRENV_LOG_LEVEL`, if set, is interpreted like our own levels.
+pub fn level_from_direnv(
+    log_format: Option<OsString>,
+    log_level: Option<OsString>,
+) -> Option<log::LevelFilter> {
+    if let Some(level) = log_level.and_then(|level| parse_level(&level.to_string_lossy())) {
+        return Some(level);
+    }
+    match log_format {
+        Some(ref format) if format.is_empty() => Some(log::LevelFilter::Error),
+        _ => None,
+    }
+}
+
+fn parse_level(level: &str) -> Option<log::LevelFilter> {
+    use log::LevelFilter::*;
+    match level.trim().to_lowercase().as_str() {
+        "off" | "none" => Some(Off),
+        "error" => Some(Error),
+        "warn" | "warning" => Some(Warn),
+        "info" => Some(Info),
+        "debug" => Some(Debug),
+        "trace" => Some(Trace),
+        _ => None,
+    }
+}
+
+/// The level for log records in general, and the levels for particular
+/// targets, like `firstaide::env`, and the modules within them.
+#[derive(Debug, PartialEq)]
+pub struct Levels {
+    pub default: log::LevelFilter,
+    pub targets: Vec<(String, log::LevelFilter)>,
+}
+
+/// Parse filter directives, as in `FILTER_VAR`, into a default level, if
+/// given, and levels for targets. A target alone means all its records.
+/// Directives that make no sense are returned separately.
+pub fn parse_filter(
+    spec: &str,
+) -> (
+    Option<log::LevelFilter>,
+    Vec<(String, log::LevelFilter)>,
+    Vec<String>,
+) {
+    let (mut default, mut targets, mut invalid) = (None, Vec::new(), Vec::new());
+    for directive in spec.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            _ if directive.is_empty() => (),
+            Some((target, level)) => match parse_level(level) {
+                Some(level) if !target.trim().is_empty() => {
+                    targets.push((target.trim().to_owned(), level))
+                }
+                _ => invalid.push(directive.to_owned()),
+            },
+            None => match parse_level(directive) {
+                Some(level) => default = Some(level),
+                None => targets.push((directive.to_owned(), log::LevelFilter::Trace)),
+            },
+        }
+    }
+    (default, targets, invalid)
+}
+
+/// Choose log levels from the command-line flags – each `-v` or `-q` is a
+/// step from the default – then from `FILTER_VAR`, falling back to direnv's
+/// conventions, then to a default. Levels for targets come from `FILTER_VAR`
+/// only.
+pub fn levels(verbose: u64, quiet: u64) -> Levels {
+    use log::LevelFilter::*;
+    let (default, targets, invalid) = parse_filter(&env::var(FILTER_VAR).unwrap_or_default());
+    for directive in invalid {
+        // Logging is not yet set up.
+        eprintln!(
+            "firstaide: ignoring {} directive {:?}",
+            FILTER_VAR, directive
+        );
+    }
+    let default = match (verbose, quiet) {
+        (0, 0) => default
+            .or_else(|| {
+                level_from_direnv(
+                    env::var_os("DIRENV_LOG_FORMAT"),
+                    env::var_os("DIRENV_LOG_LEVEL"),
+                )
+            })
+            .unwrap_or(Info),
+        (1, _) => Debug,
+        (_, 0) => Trace,
+        (_, 1) => Warn,
+        (_, _) => Error,
+    };
+    Levels { default, targets }
+}
+
+/// Log to `path` as well as to stderr, from now on, rotating it first if it's
+/// grown too large. Hooks run by direnv, for example, may have their stderr
+/// swallowed or garbled, but they leave a trail here. Lines are marked with
+/// our PID to tell invocations apart, and each invocation starts with its
+/// command line.
+pub fn log_to_file(path: &Path) -> io::Result<()> {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+    if log_file.is_some() {
+        return Ok(());
+    }
+    crate::rotate::rotate(path, LOG_FILE_MAX_SIZE, LOG_FILE_KEEP)?;
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let args: Vec<_> = env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    writeln!(file, "{}  {}", file_prefix("START"), args.join(" "))?;
+    *log_file = Some(file);
+    Ok(())
+}
+
+fn file_prefix(level: impl fmt::Display) -> String {
+    format!(
+        "{}  {}  {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        std::process::id(),
+        level
+    )
+}
+
+pub fn init(levels: Levels, format: Format) -> Result<(), log::SetLoggerError> {
+    let log_level = levels.default;
+    // When direnv is evaluating `.envrc` it prefixes its own lines with
+    // "direnv: " and no timestamp; we do likewise with "firstaide: " so that
+    // lines from both can be grepped for uniformly.
+    let in_envrc = env::var_os("DIRENV_IN_ENVRC").is_some();
+    let stderr = fern::Dispatch::new()
+        // Perform allocation-free log formatting, for text at least.
+        .format(move |out, message, record| {
+            // Out of the way of the build's progress, if it's being drawn.
+            crate::progress::clear_line();
+            if format == Format::Json {
+                out.finish(format_args!("{}", to_json(message, record)))
+            } else if in_envrc {
+                out.finish(format_args!("firstaide: {}  {}", record.level(), message))
+            } else {
+                out.finish(format_args!(
+                    "firstaide: {}  {}  {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    record.level(),
+                    message
+                ))
+            }
+        })
+        .level(log_level);
+    let stderr = (levels.targets.iter())
+        .fold(stderr, |stderr, (target, level)| {
+            stderr.level_for(target.clone(), *level)
+        })
+        .chain(std::io::stderr());
+    // The log file, when there is one, gets at least informational messages,
+    // whatever the level for stderr, since no one is watching it.
+    let file = fern::Dispatch::new().level(log_level.max(log::LevelFilter::Info));
+    let file = (levels.targets.iter())
+        .fold(file, |file, (target, level)| {
+            file.level_for(target.clone(), (*level).max(log::LevelFilter::Info))
+        })
+        .chain(fern::Output::call(|record| {
+            let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(file) = log_file.as_mut() {
+                // Nowhere to report a failure to log.
+                let message = crate::term::unstyled(&record.args().to_string());
+                let _ = writeln!(file, "{}  {}", file_prefix(record.level()), message);
+            }
+        }));
+    fern::Dispatch::new()
+        .chain(stderr)
+        .chain(file)
+        // Apply globally.
+        .apply()
+}
+
+fn to_json(message: &fmt::Arguments, record: &log::Record) -> json::Value {
+    let mut pairs: Vec<(String, json::Value)> = vec![
+        (
+            "timestamp".into(),
+            (chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"))
+                .to_string()
+                .into(),
+        ),
+        ("level".into(), record.level().to_string().into()),
+        ("target".into(), record.target().into()),
+        ("message".into(), message.to_string().into()),
+    ];
+    EVENT.with(|event| {
+        if let Some((name, fields)) = &*event.borrow() {
+            pairs.push(("event".into(), (*name).into()));
+            for (key, value) in fields {
+                pairs.push(((*key).into(), value.clone()));
+            }
+        }
+    });
+    json::Value::Object(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::LevelFilter::*;
+
+    #[test]
+    fn json_records_include_event_fields() {
+        let record = log::Record::builder()
+            .level(log::Level::Info)
+            .target("firstaide::cmds::build")
+            .build();
+        EVENT.with(|event| {
+            event.replace(Some(("build.finish", vec![("duration_ms", 1500.into())])))
+        });
+        let value = to_json(&format_args!("Built."), &record);
+        EVENT.with(|event| event.replace(None));
+        assert_eq!(
+            Some("INFO"),
+            value.get("level").and_then(json::Value::as_str)
+        );
+        assert_eq!(
+            Some("firstaide::cmds::build"),
+            value.get("target").and_then(json::Value::as_str)
+        );
+        assert_eq!(
+            Some("Built."),
+            value.get("message").and_then(json::Value::as_str)
+        );
+        assert_eq!(
+            Some("build.finish"),
+            value.get("event").and_then(json::Value::as_str)
+        );
+        assert_eq!(Some(&json::Value::Number(1500)), value.get("duration_ms"));
+    }
+
+    #[test]
+    fn parses_filter_directives() {
+        let (default, targets, invalid) =
+            parse_filter("info, firstaide::env=trace,firstaide::sums,bogus=loud,");
+        assert_eq!(Some(Info), default);
+        assert_eq!(
+            vec![
+                ("firstaide::env".to_owned(), Trace),
+                ("firstaide::sums".to_owned(), Trace),
+            ],
+            targets
+        );
+        assert_eq!(vec!["bogus=loud".to_owned()], invalid);
+        assert_eq!((None, vec![], vec![]), parse_filter(""));
+    }
+
+    #[test]
+    fn direnv_defaults_give_no_level() {
+        assert_eq!(None, level_from_direnv(None, None));
+        assert_eq!(None, level_from_direnv(Some("direnv: %s".into()), None));
+    }
+
+    #[test]
+    fn empty_direnv_log_format_is_quiet() {
+        assert_eq!(Some(Error), level_from_direnv(Some("".into()), None));
+    }
+
+    #[test]
+    fn direnv_log_level_takes_precedence() {
+        assert_eq!(
+            Some(Debug),
+            level_from_direnv(Some("".into()), Some("DEBUG".into()))
+        );
+        assert_eq!(Some(Warn), level_from_direnv(None, Some("warning".into())));
+        assert_eq!(None, level_from_direnv(None, Some("bogus".into())));
+    }
+}
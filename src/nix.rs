@@ -0,0 +1,447 @@
+//! Working with Nix.
+//!
+//! Environments built with Nix refer to paths in the Nix store, on `PATH` for
+//! example. Nothing stops `nix-collect-garbage` from deleting those paths, at
+//! which point the cached environment is broken, so, with `nix.gc_roots`, we
+//! register them as GC roots after each build.
+//!
+//! Nix's own configuration matters too: with `keep-outputs` or
+//! `keep-derivations` off, garbage collection deletes what's needed to build
+//! the environment again, so `check` looks for those in `nix.conf`.
+//!
+//! With `nix.capture = "print-dev-env"`, the environment is not dumped from
+//! inside a shell but read from `nix print-dev-env --json`; see `dev_env`.
+
+use crate::env;
+use crate::json;
+use crate::os::{self, OsStrExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::var_os;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const STORE_DIR: &[u8] = b"/nix/store/";
+
+/// Length of the hash at the start of a store path's name.
+const HASH_LEN: usize = 32;
+
+/// Store paths, like `/nix/store/<hash>-<name>`, to which values set by
+/// `diff` refer, in order and without duplicates. Paths within store paths,
+/// like `/nix/store/<hash>-<name>/bin`, are truncated to the store path.
+pub fn store_paths(diff: &env::Diff) -> Vec<PathBuf> {
+    let mut paths = BTreeSet::new();
+    for change in diff {
+        let value = match change {
+            env::Added(_, value) | env::Changed(_, _, value) => value.as_bytes(),
+            env::Removed(_, _) => continue,
+        };
+        let mut rest = value;
+        while let Some(start) = find(rest, STORE_DIR) {
+            let name = &rest[start + STORE_DIR.len()..];
+            let len = name.iter().take_while(|&&b| is_name_byte(b)).count();
+            if len > HASH_LEN + 1 && name[HASH_LEN] == b'-' {
+                let path = &rest[start..start + STORE_DIR.len() + len];
+                paths.insert(PathBuf::from(&*os::from_bytes(path)));
+            }
+            rest = &name[len..];
+        }
+    }
+    paths.into_iter().collect()
+}
+
+/// Characters allowed in the names of store paths.
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"+-._?=".contains(&b)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Register `paths` as indirect GC roots: links in `dir`, named for the paths
+/// they point to, that Nix knows about. Links from before that are no longer
+/// wanted are removed, but only once the new ones are in place, so that a
+/// garbage collection in the meantime doesn't catch paths in both. Paths that
+/// do not exist are skipped. Returns the number of roots.
+pub fn add_gc_roots(dir: &Path, paths: &[PathBuf]) -> io::Result<usize> {
+    fs::create_dir_all(dir)?;
+    let mut links = BTreeSet::new();
+    for path in paths.iter().filter(|path| path.exists()) {
+        let link = match path.file_name() {
+            Some(name) => dir.join(name),
+            None => continue,
+        };
+        let status = Command::new("nix-store")
+            .arg("--add-root")
+            .arg(&link)
+            .arg("--indirect")
+            .arg("--realise")
+            .arg(path)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "nix-store could not add root for {:?}: {}",
+                path, status
+            )));
+        }
+        links.insert(link);
+    }
+    for entry in fs::read_dir(dir)? {
+        let link = entry?.path();
+        if !links.contains(&link) {
+            fs::remove_file(&link)?;
+        }
+    }
+    Ok(links.len())
+}
+
+/// Variables that `nix develop` does not take from the environment it builds.
+/// These are specific to the build sandbox or the shell.
+const DEV_ENV_IGNORED: &[&str] = &[
+    "BASHOPTS",
+    "HOME",
+    "NIX_BUILD_TOP",
+    "NIX_ENFORCE_PURITY",
+    "NIX_LOG_FD",
+    "NIX_REMOTE",
+    "PPID",
+    "SHELL",
+    "SHELLOPTS",
+    "SHLVL",
+    "TEMP",
+    "TEMPDIR",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "UID",
+];
+
+/// Variables that `nix develop` extends with their values from outside,
+/// rather than replaces.
+const DEV_ENV_SAVED: &[&str] = &["PATH", "XDG_DATA_DIRS"];
+
+/// The environment that `nix develop` would start with when run in
+/// `outside`, according to the `json` that `nix print-dev-env --json` prints.
+/// Its `variables` are of several types: `exported` variables are set, as in
+/// `nix develop`, but plain `var` and `array` variables are local to the shell
+/// so are left out. Note that `shellHook` is not run.
+pub fn dev_env(json: &str, outside: &[env::Item]) -> Result<env::Env, String> {
+    let document = json::parse(json).map_err(|err| err.to_string())?;
+    let variables = match document.get("variables") {
+        Some(json::Value::Object(variables)) => variables,
+        _ => return Err("no variables".into()),
+    };
+    let mut env: BTreeMap<OsString, OsString> = outside.iter().cloned().collect();
+    for (name, variable) in variables {
+        let kind = variable.get("type").and_then(json::Value::as_str);
+        let value = variable.get("value");
+        match (kind, value) {
+            (Some("exported"), Some(json::Value::String(value))) => {
+                if DEV_ENV_IGNORED.contains(&name.as_str()) {
+                    continue;
+                }
+                let mut value = OsString::from(value);
+                if DEV_ENV_SAVED.contains(&name.as_str()) {
+                    if let Some(saved) = outside.iter().find(|(key, _)| key == name.as_str()) {
+                        value.push(env::PATH_SEPARATOR);
+                        value.push(&saved.1);
+                    }
+                }
+                env.insert(name.into(), value);
+            }
+            (Some("exported"), _) => return Err(format!("{} is not a string", name)),
+            (Some("var" | "array" | "associative"), _) => continue,
+            (Some(kind), _) => log::debug!("Ignoring {} of unknown type {:?}.", name, kind),
+            (None, _) => return Err(format!("{} has no type", name)),
+        }
+    }
+    Ok(env.into_iter().collect())
+}
+
+/// How deeply `include` directives may nest, to catch cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum ConfError {
+    Io(PathBuf, io::Error),
+    Syntax(PathBuf, usize, String),
+    TooDeep(PathBuf),
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ConfError::*;
+        match self {
+            Io(path, err) => write!(f, "could not read {:?}: {}", path, err),
+            Syntax(path, line, message) => write!(f, "{:?}, line {}: {}", path, line, message),
+            TooDeep(path) => write!(f, "includes nested too deeply at {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for ConfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ConfError::*;
+        match self {
+            Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Nix's configuration, as read from `nix.conf` files and `NIX_CONFIG`.
+#[derive(Debug, Default)]
+pub struct Conf {
+    settings: BTreeMap<String, String>,
+    /// The files that were read, in order, including those included.
+    pub files: Vec<PathBuf>,
+}
+
+impl Conf {
+    /// Read the system `nix.conf`, then users' – see `conf_files` – then
+    /// `NIX_CONFIG`, each overriding settings from those before. Files that
+    /// don't exist are skipped.
+    pub fn load() -> Result<Self, ConfError> {
+        let mut conf = Self::default();
+        for file in conf_files() {
+            match fs::read_to_string(&file) {
+                Ok(text) => conf.parse(&text, &file, 0)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(ConfError::Io(file, err)),
+            }
+        }
+        if let Some(text) = var_os("NIX_CONFIG") {
+            conf.parse(&text.to_string_lossy(), Path::new("NIX_CONFIG"), 0)?;
+        }
+        Ok(conf)
+    }
+
+    /// Parse `text`, read from `file`, into these settings. As in Nix, a `#`
+    /// starts a comment, and words are separated by any whitespace, so
+    /// `name   =  a   b` sets `name` to `a b`. `include PATH` reads another
+    /// file, relative to this one; `!include PATH` does the same but ignores
+    /// a missing file. `extra-name = c` appends to the value of `name`.
+    pub fn parse(&mut self, text: &str, file: &Path, depth: usize) -> Result<(), ConfError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(ConfError::TooDeep(file.to_path_buf()));
+        }
+        self.files.push(file.to_path_buf());
+        let syntax = |line: usize, message: &str| {
+            ConfError::Syntax(file.to_path_buf(), line + 1, message.to_owned())
+        };
+        for (line, text) in text.lines().enumerate() {
+            let text = match text.find('#') {
+                Some(index) => &text[..index],
+                None => text,
+            };
+            let words: Vec<&str> = text.split_whitespace().collect();
+            match words.as_slice() {
+                [] => continue,
+                [include @ ("include" | "!include"), path] => {
+                    let path = match file.parent() {
+                        Some(dir) => dir.join(path),
+                        None => PathBuf::from(path),
+                    };
+                    match fs::read_to_string(&path) {
+                        Ok(text) => self.parse(&text, &path, depth + 1)?,
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                            if *include == "include" {
+                                return Err(ConfError::Io(path, err));
+                            }
+                        }
+                        Err(err) => return Err(ConfError::Io(path, err)),
+                    }
+                }
+                ["include" | "!include", ..] => return Err(syntax(line, "expected one path")),
+                [name, "=", value @ ..] => {
+                    let value = value.join(" ");
+                    match name.strip_prefix("extra-") {
+                        Some(name) => {
+                            let setting = self.settings.entry(name.to_owned()).or_default();
+                            if !setting.is_empty() && !value.is_empty() {
+                                setting.push(' ');
+                            }
+                            setting.push_str(&value);
+                        }
+                        None => {
+                            self.settings.insert(name.to_string(), value);
+                        }
+                    }
+                }
+                _ => return Err(syntax(line, "expected `name = value`")),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.settings.get(name).map(String::as_str)
+    }
+
+    /// A boolean setting, or `default` when it's not set. Nix accepts only
+    /// `true` and `false`; anything else is `None`.
+    pub fn get_bool(&self, name: &str, default: bool) -> Option<bool> {
+        match self.get(name) {
+            None => Some(default),
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Where Nix looks for `nix.conf`, in the order it reads them: in
+/// `NIX_CONF_DIR`, by default `/etc/nix`; then, unless `NIX_USER_CONF_FILES`
+/// lists files explicitly, in each of `XDG_CONFIG_DIRS`, least important
+/// first, and in `XDG_CONFIG_HOME`, by default `~/.config`.
+pub fn conf_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let conf_dir = var_os("NIX_CONF_DIR").unwrap_or_else(|| "/etc/nix".into());
+    files.push(Path::new(&conf_dir).join("nix.conf"));
+    if let Some(user_files) = var_os("NIX_USER_CONF_FILES") {
+        let user_files: Vec<PathBuf> = std::env::split_paths(&user_files).collect();
+        files.extend(user_files.into_iter().rev());
+    } else {
+        let config_dirs = var_os("XDG_CONFIG_DIRS").unwrap_or_else(|| "/etc/xdg".into());
+        let config_dirs: Vec<PathBuf> = std::env::split_paths(&config_dirs).collect();
+        files.extend(config_dirs.iter().rev().map(|dir| dir.join("nix/nix.conf")));
+        let config_home = match var_os("XDG_CONFIG_HOME") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => dirs::home_dir().map(|home| home.join(".config")),
+        };
+        if let Some(config_home) = config_home {
+            files.push(config_home.join("nix/nix.conf"));
+        }
+    }
+    files
+}
+
+/// Problems with `conf` that put the environment at risk, with what to do
+/// about them.
+pub fn check(conf: &Conf) -> Vec<String> {
+    let mut problems = Vec::new();
+    // Nix's defaults: keep-outputs is off, keep-derivations is on.
+    for (name, default) in &[("keep-outputs", false), ("keep-derivations", true)] {
+        match conf.get_bool(name, *default) {
+            Some(true) => (),
+            Some(false) => problems.push(format!(
+                concat!(
+                    "{0} is off in nix.conf, so garbage collection deletes what's ",
+                    "needed to rebuild this environment; add `{0} = true` to {1:?}",
+                ),
+                name,
+                conf_files()
+                    .first()
+                    .map(PathBuf::as_path)
+                    .unwrap_or(Path::new("nix.conf")),
+            )),
+            None => problems.push(format!(
+                "{} in nix.conf is {:?}, which is neither true nor false",
+                name,
+                conf.get(name).unwrap_or_default(),
+            )),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_env_sets_exported_variables_only() {
+        let json = r#"{
+            "bashFunctions": {"genericBuild": "..."},
+            "variables": {
+                "PATH": {"type": "exported", "value": "/nix/bin"},
+                "CC": {"type": "exported", "value": "gcc"},
+                "HOME": {"type": "exported", "value": "/homeless-shelter"},
+                "out": {"type": "var", "value": "/nix/store/out"},
+                "flags": {"type": "array", "value": ["-a", "-b"]}
+            }
+        }"#;
+        let outside: env::Env = vec![
+            ("HOME".into(), "/home/me".into()),
+            ("PATH".into(), "/bin".into()),
+        ];
+        let expected: env::Env = vec![
+            ("CC".into(), "gcc".into()),
+            ("HOME".into(), "/home/me".into()),
+            ("PATH".into(), "/nix/bin:/bin".into()),
+        ];
+        assert_eq!(Ok(expected), dev_env(json, &outside));
+        assert!(dev_env(r#"{"variables": {"X": {"value": "x"}}}"#, &outside).is_err());
+        assert!(dev_env("{}", &outside).is_err());
+    }
+
+    #[test]
+    fn conf_parses_comments_whitespace_and_extras() {
+        let mut conf = Conf::default();
+        let text = concat!(
+            "# A comment.\n",
+            "keep-outputs = true # Another.\n",
+            "  substituters   =   https://a   https://b  \n",
+            "extra-substituters = https://c\n",
+            "\n",
+            "extra-features = x\n",
+        );
+        assert!(conf.parse(text, Path::new("nix.conf"), 0).is_ok());
+        assert_eq!(Some(true), conf.get_bool("keep-outputs", false));
+        assert_eq!(
+            Some("https://a https://b https://c"),
+            conf.get("substituters")
+        );
+        assert_eq!(Some("x"), conf.get("features"));
+        assert!(conf.parse("oops\n", Path::new("nix.conf"), 0).is_err());
+    }
+
+    #[test]
+    fn conf_includes_relative_to_the_including_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("nix.conf");
+        fs::write(dir.path().join("more.conf"), "keep-derivations = false\n").unwrap();
+        let mut conf = Conf::default();
+        let text = "include more.conf\n!include missing.conf\n";
+        assert!(conf.parse(text, &file, 0).is_ok());
+        assert_eq!(Some(false), conf.get_bool("keep-derivations", true));
+        assert_eq!(2, check(&conf).len());
+        assert!(conf.parse("include missing.conf\n", &file, 0).is_err());
+    }
+
+    #[test]
+    fn store_paths_are_found_in_values() {
+        let hash = "0123456789abcdfghijklmnpqrsvwxyz";
+        let diff = env::Diff::from(&[
+            env::Added(
+                "PATH".into(),
+                format!(
+                    "/nix/store/{0}-foo-1.0/bin:/usr/bin:/nix/store/{0}-bar/bin",
+                    hash
+                )
+                .into(),
+            ),
+            env::Changed(
+                "CC".into(),
+                "cc".into(),
+                format!("/nix/store/{}-gcc-wrapper/bin/cc", hash).into(),
+            ),
+            env::Added("SHORT".into(), "/nix/store/abc-short".into()),
+            env::Removed("GONE".into(), format!("/nix/store/{}-gone", hash).into()),
+        ]);
+        let expected: Vec<PathBuf> = vec![
+            format!("/nix/store/{}-bar", hash).into(),
+            format!("/nix/store/{}-foo-1.0", hash).into(),
+            format!("/nix/store/{}-gcc-wrapper", hash).into(),
+        ];
+        assert_eq!(expected, store_paths(&diff));
+    }
+}
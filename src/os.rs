@@ -0,0 +1,126 @@
+//! What differs between Unix and Windows, so that the rest of firstaide need
+//! not say. Windows builds need Rust 1.89 or later, for `File::lock`.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The bytes of an OS string with `as_bytes`. On Unix they're whatever the OS
+/// says; on Windows they're in the OS string's own encoding, a superset of
+/// UTF-8.
+#[cfg(unix)]
+pub use std::os::unix::ffi::OsStrExt;
+
+#[cfg(windows)]
+pub trait OsStrExt {
+    fn as_bytes(&self) -> &[u8];
+}
+
+#[cfg(windows)]
+impl OsStrExt for OsStr {
+    fn as_bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+}
+
+/// `bytes`, e.g. from a program's output, as an OS string. On Windows, bytes
+/// that are not UTF-8 are replaced.
+#[cfg(unix)]
+pub fn from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Borrowed(OsStr::from_bytes(bytes))
+}
+
+#[cfg(windows)]
+pub fn from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(string) => Cow::Borrowed(OsStr::new(string)),
+        Cow::Owned(string) => Cow::Owned(string.into()),
+    }
+}
+
+/// Like `from_bytes`, but owned.
+#[cfg(unix)]
+pub fn from_vec(bytes: Vec<u8>) -> OsString {
+    std::os::unix::ffi::OsStringExt::from_vec(bytes)
+}
+
+#[cfg(windows)]
+pub fn from_vec(bytes: Vec<u8>) -> OsString {
+    match String::from_utf8(bytes) {
+        Ok(string) => string.into(),
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned().into(),
+    }
+}
+
+/// Make `options` create files that only the owner can read. Windows has no
+/// modes; there, files get the permissions of the directory they're in.
+pub fn private_file(options: &mut fs::OpenOptions) -> &mut fs::OpenOptions {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(options, 0o600);
+    options
+}
+
+/// Make `builder` create directories that only the owner can use; see
+/// `private_file`.
+pub fn private_dir(builder: &mut fs::DirBuilder) -> &mut fs::DirBuilder {
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(builder, 0o700);
+    builder
+}
+
+/// Can `metadata`'s file be run? On Windows, any file can be tried.
+pub fn is_executable(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    return metadata.is_file()
+        && std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o111 != 0;
+    #[cfg(windows)]
+    return metadata.is_file();
+}
+
+/// Make a new `link` that points at `target`, as a symlink would: relative
+/// targets are relative to the directory `link` ends up in. Symlinks on
+/// Windows need privileges that most users lack, so there `link` is a file
+/// holding `target`. Read it back with `pointee`.
+#[cfg(unix)]
+pub fn point(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn point(target: &Path, link: &Path) -> io::Result<()> {
+    fs::write(link, target.as_os_str().as_encoded_bytes())
+}
+
+/// Where `link`, made by `point`, points.
+#[cfg(unix)]
+pub fn pointee(link: &Path) -> io::Result<PathBuf> {
+    fs::read_link(link)
+}
+
+#[cfg(windows)]
+pub fn pointee(link: &Path) -> io::Result<PathBuf> {
+    Ok(from_vec(fs::read(link)?).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointers_point_where_they_were_made_to() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let link = dir.path().join("cache");
+        point("cache.abc".as_ref(), &link).unwrap();
+        assert_eq!(Path::new("cache.abc"), pointee(&link).unwrap());
+        assert!(pointee(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn bytes_become_os_strings() {
+        assert_eq!(OsStr::new("abc"), from_bytes(b"abc"));
+        assert_eq!(OsString::from("abc"), from_vec(b"abc".to_vec()));
+        assert_eq!(b"abc", OsStr::new("abc").as_bytes());
+    }
+}
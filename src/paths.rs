@@ -0,0 +1,108 @@
+//! One policy for turning the paths that firstaide encounters – in config, from
+//! `watch_exe`, on the command line – into absolute paths. When the same file
+//! can be spelled two ways, checksums, watches, and relocatable paths disagree,
+//! and the environment looks stale when it is not.
+
+use path_absolutize::Absolutize;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How to resolve paths. Configured with `resolve_paths`.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Resolve `.` and `..` lexically, and leave symlinks alone. A project
+    /// reached via a symlink is known by the symlink's path.
+    #[default]
+    Logical,
+    /// Follow symlinks. A project reached via a symlink is known by its
+    /// physical path.
+    Physical,
+}
+
+impl Policy {
+    /// Make `path` absolute, relative to `base`, and resolve it according to
+    /// this policy. The file need not exist; with `Physical`, symlinks are
+    /// followed as far as the path does exist.
+    pub fn resolve<B, P>(self, base: B, path: P) -> io::Result<PathBuf>
+    where
+        B: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        let path = base.as_ref().join(path).absolutize()?;
+        match self {
+            Policy::Logical => Ok(path),
+            Policy::Physical => physical(&path),
+        }
+    }
+
+    /// Make `path`, or the current directory, absolute and resolve it
+    /// according to this policy. For paths given on the command line.
+    pub fn resolve_here<P: AsRef<Path>>(self, path: Option<P>) -> io::Result<PathBuf> {
+        let here = std::env::current_dir()?;
+        match path {
+            Some(path) => self.resolve(here, path),
+            None => self.resolve(here, "."),
+        }
+    }
+}
+
+/// Canonicalize the longest part of `path` that exists, then append the rest.
+/// `path` must already be absolute and free of `.` and `..`.
+fn physical(path: &Path) -> io::Result<PathBuf> {
+    let mut rest = Vec::new();
+    for ancestor in path.ancestors() {
+        match fs::canonicalize(ancestor) {
+            Ok(mut resolved) => {
+                resolved.extend(rest.iter().rev());
+                return Ok(resolved);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if let Some(name) = ancestor.file_name() {
+                    rest.push(name);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(path.to_path_buf())
+}
+
+// Windows needs privileges to make symlinks.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix;
+
+    /// A project directory, `real`, and a symlink to it, `link`.
+    fn symlinked_project() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let real = root.join("real");
+        let link = root.join("link");
+        fs::create_dir(&real).unwrap();
+        fs::write(real.join("file"), b"").unwrap();
+        unix::fs::symlink(&real, &link).unwrap();
+        (dir, real, link)
+    }
+
+    #[test]
+    fn logical_keeps_symlinked_project_root() {
+        let (_dir, _real, link) = symlinked_project();
+        let resolve = |path| Policy::Logical.resolve(&link, path).unwrap();
+        assert_eq!(link.join("file"), resolve("file"));
+        assert_eq!(link.join("file"), resolve("./sub/../file"));
+        assert_eq!(link.join("missing"), resolve("missing"));
+    }
+
+    #[test]
+    fn physical_follows_symlinked_project_root() {
+        let (_dir, real, link) = symlinked_project();
+        let resolve = |path: &Path| Policy::Physical.resolve(&link, path).unwrap();
+        assert_eq!(real.join("file"), resolve("file".as_ref()));
+        assert_eq!(real.join("file"), resolve(&link.join("file")));
+        assert_eq!(real.join("missing/file"), resolve("missing/file".as_ref()));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::term;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a build is up to, written into the cache dir so that other
+/// invocations – `status`, `hook` – can report on it.
+#[derive(Serialize, Deserialize)]
+pub struct Progress {
+    pub pid: u32,
+    pub started: String,
+    pub phase: String,
+}
+
+impl Progress {
+    pub fn load<T: AsRef<Path>>(filename: T) -> Option<Self> {
+        let data = fs::read(filename).ok()?;
+        toml::from_slice(&data).ok()
+    }
+
+    pub fn started(&self) -> Option<DateTime<Local>> {
+        DateTime::parse_from_rfc3339(&self.started)
+            .ok()
+            .map(|started| started.with_timezone(&Local))
+    }
+
+    /// How long ago the build started, for humans.
+    pub fn started_ago(&self) -> String {
+        match self.started() {
+            Some(started) => ago(Local::now().signed_duration_since(started)),
+            None => "at an unknown time".into(),
+        }
+    }
+}
+
+/// The progress of the build in progress, if there is one. The lock tells us
+/// whether a build is really running, and the progress file tells us more.
+pub fn current(config: &crate::config::Config) -> Option<Progress> {
+    if crate::lock::is_locked(config.lock_file()) {
+        Progress::load(config.build_progress_file())
+    } else {
+        None
+    }
+}
+
+/// Records the progress of a build in progress; the record is removed when
+/// this is dropped, i.e. when the build finishes, successfully or not. Each
+/// phase is shown on `display` too.
+pub struct Tracker {
+    filename: PathBuf,
+    progress: Progress,
+    /// When the current phase started, for `timings`.
+    phase_started: Option<Instant>,
+    display: Display,
+}
+
+impl Tracker {
+    pub fn start<T: Into<PathBuf>>(filename: T, display: Display) -> io::Result<Self> {
+        let tracker = Self {
+            filename: filename.into(),
+            progress: Progress {
+                pid: std::process::id(),
+                started: Local::now().to_rfc3339(),
+                phase: "Starting".into(),
+            },
+            phase_started: None,
+            display,
+        };
+        tracker.save()?;
+        Ok(tracker)
+    }
+
+    pub fn phase<T: Into<String>>(&mut self, phase: T) {
+        self.finish_phase();
+        self.phase_started = Some(Instant::now());
+        self.progress.phase = phase.into();
+        self.display.phase(&self.progress.phase);
+        if let Err(err) = self.save() {
+            log::warn!("Could not record progress: {}", err);
+        }
+    }
+
+    /// The build succeeded; see `Display::succeeded`.
+    pub fn succeeded(&mut self) {
+        self.display.succeeded();
+    }
+
+    fn finish_phase(&mut self) {
+        if let Some(started) = self.phase_started.take() {
+            crate::timings::record(&self.progress.phase, started.elapsed());
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        // Write then rename so that readers never see a partial record.
+        let data = toml::to_vec(&self.progress).map_err(io::Error::other)?;
+        let temp = self.filename.with_extension("new");
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &self.filename)
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.finish_phase();
+        let _ = fs::remove_file(&self.filename);
+    }
+}
+
+/// Set while a `Display` is drawing, so that log lines can clear its line
+/// out of the way; see `clear_line`.
+static DRAWING: AtomicBool = AtomicBool::new(false);
+
+/// Spinner frames, drawn in turn.
+const FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Shows the phases of a build, for humans. On a terminal, each phase gets a
+/// line with a spinner and the time elapsed, which is ticked off as the next
+/// phase starts. Elsewhere, each phase is logged instead.
+pub struct Display {
+    current: Arc<Mutex<Option<(String, Instant)>>>,
+    stop: Arc<AtomicBool>,
+    ticker: Option<thread::JoinHandle<()>>,
+    succeeded: bool,
+}
+
+impl Display {
+    /// Draw on stdout when `enabled` and it's a terminal.
+    pub fn start(enabled: bool) -> Self {
+        let current: Arc<Mutex<Option<(String, Instant)>>> = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let ticker = if enabled && io::stdout().is_terminal() {
+            DRAWING.store(true, Ordering::SeqCst);
+            let (current, stop) = (Arc::clone(&current), Arc::clone(&stop));
+            Some(thread::spawn(move || {
+                for frame in FRAMES.iter().cycle() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Some((phase, started)) = &*current.lock().unwrap() {
+                        draw(
+                            &term::Style::Bold.paint(frame),
+                            phase,
+                            started.elapsed(),
+                            false,
+                        );
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }))
+        } else {
+            None
+        };
+        Self {
+            current,
+            stop,
+            ticker,
+            succeeded: false,
+        }
+    }
+
+    pub fn phase(&mut self, phase: &str) {
+        // The log has every phase, but on a terminal they're plain to see, so
+        // there's no need to repeat them there.
+        let level = if self.ticker.is_some() {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        };
+        crate::logging::event(
+            module_path!(),
+            level,
+            "build.phase",
+            vec![("phase", phase.into())],
+            format_args!("{}.", phase),
+        );
+        if self.ticker.is_some() {
+            let mut current = self.current.lock().unwrap();
+            if let Some((phase, started)) = current.take() {
+                let glyph = term::Style::Okay.paint("✔");
+                draw(&glyph, &phase, started.elapsed(), true);
+            }
+            *current = Some((phase.into(), Instant::now()));
+        }
+    }
+
+    /// Everything went to plan, so the last phase is ticked off when this is
+    /// dropped. Otherwise it's crossed out.
+    pub fn succeeded(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        if let Some(ticker) = self.ticker.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            let _ = ticker.join();
+            if let Some((phase, started)) = self.current.lock().unwrap().take() {
+                let glyph = if self.succeeded {
+                    term::Style::Okay.paint("✔")
+                } else {
+                    term::Style::Error.paint("✘")
+                };
+                draw(&glyph, &phase, started.elapsed(), true);
+            }
+            DRAWING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Redraw the current line with `phase`, and end it when `done`.
+fn draw(glyph: &str, phase: &str, elapsed: Duration, done: bool) {
+    let elapsed = term::Style::Dim.paint(&format!("({})", self::elapsed(elapsed)));
+    let end = if done { "\n" } else { "" };
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "\r\x1b[2K{} {} {}{}", glyph, phase, elapsed, end);
+    let _ = stdout.flush();
+}
+
+/// Clear the line that a `Display` is drawing on, if any, so that what's
+/// written next starts on a clean line; it'll be redrawn shortly.
+pub fn clear_line() {
+    if DRAWING.load(Ordering::SeqCst) {
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "\r\x1b[2K");
+        let _ = stdout.flush();
+    }
+}
+
+/// Format an elapsed time precisely enough to watch it tick.
+fn elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    if seconds < 60 {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    } else {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+/// Format a duration as a short, approximate, "time ago" string.
+pub fn ago(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds();
+    if seconds < 5 {
+        "just now".into()
+    } else if seconds < 60 {
+        format!("{}s ago", seconds)
+    } else if seconds < 60 * 60 {
+        format!("{}m ago", seconds / 60)
+    } else if seconds < 60 * 60 * 24 {
+        format!("{}h ago", seconds / (60 * 60))
+    } else {
+        format!("{}d ago", seconds / (60 * 60 * 24))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn formats_time_ago() {
+        assert_eq!("just now", ago(Duration::seconds(2)));
+        assert_eq!("42s ago", ago(Duration::seconds(42)));
+        assert_eq!("5m ago", ago(Duration::seconds(5 * 60 + 10)));
+        assert_eq!("3h ago", ago(Duration::hours(3)));
+        assert_eq!("2d ago", ago(Duration::days(2)));
+    }
+
+    #[test]
+    fn formats_elapsed() {
+        assert_eq!("0.3s", elapsed(std::time::Duration::from_millis(300)));
+        assert_eq!("59.0s", elapsed(std::time::Duration::from_secs(59)));
+        assert_eq!("2m05s", elapsed(std::time::Duration::from_secs(125)));
+    }
+
+    #[test]
+    fn tracker_records_and_removes_progress() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let filename = dir.path().join("progress");
+        {
+            let mut tracker = Tracker::start(&filename, Display::start(false)).unwrap();
+            tracker.phase("Testing");
+            let progress = Progress::load(&filename).unwrap();
+            assert_eq!(std::process::id(), progress.pid);
+            assert_eq!("Testing", progress.phase);
+            assert!(progress.started().is_some());
+        }
+        assert!(Progress::load(&filename).is_none());
+    }
+}
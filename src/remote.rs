@@ -0,0 +1,660 @@
+//! Remote caches.
+//!
+//! A cache file is pushed in parts, each stored under its SHA-256, followed
+//! by a manifest, stored under the cache's signature, that lists the parts in
+//! order. The manifest goes last, so a push that fails part way is invisible
+//! to others, and a later push with `resume` need only send the parts that
+//! did not make it.
+//!
+//! Pulling reverses this, checking each part against its SHA-256, the whole
+//! against the manifest's size, its signing key against the trusted keys, if
+//! any, and the cache within against the signature it was pulled for.
+//! Nothing is written until all of these pass.
+
+use crate::cache;
+use crate::config;
+use crate::creds;
+use crate::error::Code;
+use crate::os;
+use crypto_hash::{hex_digest, Algorithm};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before each retry of a failed request.
+#[cfg(not(test))]
+const RETRY_DELAYS: &[Duration] = &[
+    Duration::from_secs(1),
+    Duration::from_secs(4),
+    Duration::from_secs(16),
+];
+#[cfg(test)]
+const RETRY_DELAYS: &[Duration] = &[Duration::from_secs(0)];
+
+const MANIFEST_HEADER: &str = "firstaide-manifest 1";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Creds(creds::Error),
+    Cache(cache::Error),
+    NotConfigured,
+    Url(String),
+    Request(String),
+    Integrity(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Creds(err) => write!(f, "{}", err),
+            Cache(err) => write!(f, "{}", err),
+            NotConfigured => write!(f, "no remote cache; set url in [cache.remote]"),
+            Url(url) => write!(f, "remote cache URL not supported: {:?}", url),
+            Request(message) => write!(f, "remote cache request failed: {}", message),
+            Integrity(message) => write!(f, "remote cache entry is not intact: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            Creds(err) => Some(err),
+            Cache(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        use Error::*;
+        match self {
+            Io(_) => Code::Io,
+            Cache(err) => err.code(),
+            Creds(_) | NotConfigured | Url(_) => Code::Config,
+            Request(_) => Code::Network,
+            Integrity(_) => Code::CacheCorrupt,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(error: cache::Error) -> Self {
+        Error::Cache(error)
+    }
+}
+
+impl From<creds::Error> for Error {
+    fn from(error: creds::Error) -> Self {
+        Error::Creds(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Somewhere to keep parts and manifests. Keys are relative paths, like
+/// `parts/<sha256>`.
+pub trait Backend {
+    fn has(&self, key: &str) -> Result<bool>;
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    /// The data stored under `key`, or `None` if there's nothing there.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+}
+
+/// The backend for `url` in `[cache.remote]`: `file://` URLs and plain paths
+/// are directories, `http://` and `https://` URLs are served with PUT, HEAD,
+/// and GET, and `s3://bucket/prefix` URLs are S3 buckets.
+pub fn backend(config: &config::Config) -> Result<Box<dyn Backend>> {
+    let url = config.remote.url.as_ref().ok_or(Error::NotConfigured)?;
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let token = match config.remote_auth.token() {
+            Ok(token) => Some(token),
+            Err(creds::Error::NotFound(message)) => {
+                log::debug!("No token for remote cache: {}", message);
+                None
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Box::new(Http::new(url, token)?))
+    } else if let Some(path) = url.strip_prefix("s3://") {
+        Ok(Box::new(S3::new(path)?))
+    } else if let Some(path) = url.strip_prefix("file://") {
+        Ok(Box::new(Dir(path.into())))
+    } else if !url.contains("://") {
+        Ok(Box::new(Dir(config.build_dir.join(url))))
+    } else {
+        Err(Error::Url(url.clone()))
+    }
+}
+
+/// A directory, perhaps on a network mount.
+pub struct Dir(pub PathBuf);
+
+impl Backend for Dir {
+    fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.0.join(key).is_file())
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.0.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write atomically so that a reader never sees a partial part.
+        let temp_path = path.with_extension("new");
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.0.join(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// An HTTP server that accepts PUT. There's no HTTP client in our
+/// dependencies, so we drive `curl`, as we drive the keyring tools in `creds`.
+pub struct Http {
+    url: String,
+    /// Holds a curl config with the token, if any, so that it does not appear
+    /// in the process table.
+    config_dir: tempfile::TempDir,
+}
+
+/// Quote `value` for a curl config file: in double quotes, escaping backslashes,
+/// double quotes, and the control characters that curl unescapes.
+fn curlrc_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\t' => quoted.push_str("\\t"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\x0b' => quoted.push_str("\\v"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Http {
+    fn new(url: &str, token: Option<String>) -> Result<Self> {
+        let config_dir = tempfile::TempDir::new()?;
+        let mut curlrc = os::private_file(fs::OpenOptions::new().write(true).create_new(true))
+            .open(config_dir.path().join("curlrc"))?;
+        writeln!(curlrc, "silent\nshow-error\nlocation")?;
+        if let Some(token) = token {
+            writeln!(
+                curlrc,
+                "header = {}",
+                curlrc_quote(&format!("Authorization: Bearer {}", token))
+            )?;
+        }
+        Ok(Self {
+            url: url.trim_end_matches('/').into(),
+            config_dir,
+        })
+    }
+
+    /// A curl command for `key` that writes the response body to `output`.
+    fn curl(&self, key: &str, output: &Path) -> Command {
+        let mut command = Command::new("curl");
+        command
+            .arg("--config")
+            .arg(self.config_dir.path().join("curlrc"))
+            .arg("--write-out")
+            .arg("%{http_code}")
+            .arg("--output")
+            .arg(output)
+            .arg(format!("{}/{}", self.url, key));
+        command
+    }
+
+    /// Run curl, returning the HTTP status code.
+    fn status(&self, mut command: Command, data: Option<&[u8]>) -> Result<u16> {
+        log::debug!("{:?}", command);
+        let mut child = command
+            .stdin(if data.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| Error::Request(format!("curl could not be run: {}", err)))?;
+        if let (Some(mut stdin), Some(data)) = (child.stdin.take(), data) {
+            stdin.write_all(data)?;
+        }
+        let output = child.wait_with_output()?;
+        let code = String::from_utf8_lossy(&output.stdout);
+        match code.trim().parse() {
+            Ok(code) if output.status.success() => Ok(code),
+            _ => Err(Error::Request(format!(
+                "curl exited with {}",
+                output.status
+            ))),
+        }
+    }
+}
+
+impl Backend for Http {
+    fn has(&self, key: &str) -> Result<bool> {
+        let mut command = self.curl(key, Path::new("/dev/null"));
+        command.arg("--head");
+        match self.status(command, None)? {
+            200..=299 => Ok(true),
+            404 => Ok(false),
+            code => Err(Error::Request(format!("HEAD {}: HTTP {}", key, code))),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let mut command = self.curl(key, Path::new("/dev/null"));
+        command.arg("--upload-file").arg("-");
+        match self.status(command, Some(data))? {
+            200..=299 => Ok(()),
+            code => Err(Error::Request(format!("PUT {}: HTTP {}", key, code))),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let output = tempfile::NamedTempFile::new()?;
+        let command = self.curl(key, output.path());
+        match self.status(command, None)? {
+            200..=299 => Ok(Some(fs::read(output.path())?)),
+            404 => Ok(None),
+            code => Err(Error::Request(format!("GET {}: HTTP {}", key, code))),
+        }
+    }
+}
+
+/// An S3 bucket, with keys under an optional prefix. There's no S3 client in
+/// our dependencies either, so we drive the AWS CLI, which finds credentials,
+/// the region, and so on, in the usual places.
+pub struct S3 {
+    bucket: String,
+    prefix: String,
+}
+
+impl S3 {
+    /// From `bucket/prefix`, i.e. an `s3://` URL without the scheme.
+    fn new(path: &str) -> Result<Self> {
+        let mut parts = path.trim_end_matches('/').splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some(bucket), prefix) if !bucket.is_empty() => Ok(Self {
+                bucket: bucket.into(),
+                prefix: prefix
+                    .map(|prefix| format!("{}/", prefix))
+                    .unwrap_or_default(),
+            }),
+            _ => Err(Error::Url(format!("s3://{}", path))),
+        }
+    }
+
+    fn s3api(&self, operation: &str, key: &str) -> Command {
+        let mut command = Command::new("aws");
+        command
+            .arg("s3api")
+            .arg(operation)
+            .arg("--bucket")
+            .arg(&self.bucket)
+            .arg("--key")
+            .arg(format!("{}{}", self.prefix, key))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        command
+    }
+
+    /// Run the AWS CLI: `Ok(true)` on success, `Ok(false)` when the key does
+    /// not exist, and an error otherwise.
+    fn run(&self, mut command: Command, key: &str) -> Result<bool> {
+        log::debug!("{:?}", command);
+        let output = command
+            .output()
+            .map_err(|err| Error::Request(format!("aws could not be run: {}", err)))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            Ok(true)
+        } else if stderr.contains("NoSuchKey") || stderr.contains("Not Found") {
+            Ok(false)
+        } else {
+            Err(Error::Request(format!("{}: {}", key, stderr.trim())))
+        }
+    }
+}
+
+impl Backend for S3 {
+    fn has(&self, key: &str) -> Result<bool> {
+        self.run(self.s3api("head-object", key), key)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let mut body = tempfile::NamedTempFile::new()?;
+        body.write_all(data)?;
+        let mut command = self.s3api("put-object", key);
+        command.arg("--body").arg(body.path());
+        match self.run(command, key)? {
+            true => Ok(()),
+            false => Err(Error::Request(format!("{}: could not put", key))),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let output = tempfile::NamedTempFile::new()?;
+        let mut command = self.s3api("get-object", key);
+        command.arg(output.path());
+        match self.run(command, key)? {
+            true => Ok(Some(fs::read(output.path())?)),
+            false => Ok(None),
+        }
+    }
+}
+
+/// The parts of a cache file, in order.
+pub struct Manifest {
+    pub size: u64,
+    pub parts: Vec<String>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> String {
+        let mut text = format!("{}\n{}\n", MANIFEST_HEADER, self.size);
+        for part in &self.parts {
+            text.push_str(part);
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn decode(text: &str) -> Result<Self> {
+        let invalid = |message: &str| Error::Integrity(format!("manifest {}", message));
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(invalid("has the wrong header"));
+        }
+        let size = match lines.next().map(str::parse) {
+            Some(Ok(size)) => size,
+            _ => return Err(invalid("has no size")),
+        };
+        let mut parts = Vec::new();
+        for part in lines {
+            // Parts become keys, so nothing but hex digits, please.
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid("lists a part that is not a hex digest"));
+            }
+            parts.push(part.to_owned());
+        }
+        Ok(Self { size, parts })
+    }
+}
+
+pub fn manifest_key(sig: &str) -> String {
+    format!("manifests/{}", sig)
+}
+
+pub fn part_key(sha256: &str) -> String {
+    format!("parts/{}", sha256)
+}
+
+#[derive(Default)]
+pub struct Pushed {
+    pub sent: usize,
+    pub skipped: usize,
+}
+
+/// Push `cache_file` to the configured remote cache.
+pub fn push_file(config: &config::Config, cache_file: &Path, resume: bool) -> Result<Pushed> {
+    let backend = backend(config)?;
+    let name = cache_file.file_name().unwrap_or_default().to_string_lossy();
+    let sig = name.trim_start_matches("cache.");
+    // Aliases mean nothing without the caches they stand in for, so we push
+    // the whole cache instead.
+    let data = match cache::alias_target(cache_file, &config.cache_keys) {
+        Some(_) => {
+            cache::Cache::load(cache_file, &config.cache_keys)?.to_bytes(&config.cache_keys)?
+        }
+        None => fs::read(cache_file)?,
+    };
+    push(&*backend, sig, &data, config.remote.chunk_size, resume)
+}
+
+/// Push a cache file's `data` in parts of `chunk_size` bytes, then its
+/// manifest. With `resume`, parts that the backend already has are skipped.
+pub fn push(
+    backend: &dyn Backend,
+    sig: &str,
+    data: &[u8],
+    chunk_size: usize,
+    resume: bool,
+) -> Result<Pushed> {
+    let mut pushed = Pushed::default();
+    let mut parts = Vec::new();
+    for chunk in data.chunks(chunk_size.max(1)) {
+        let sha256 = hex_digest(Algorithm::SHA256, chunk);
+        let key = part_key(&sha256);
+        if resume && retry(|| backend.has(&key))? {
+            log::debug!("Remote cache already has {}.", key);
+            pushed.skipped += 1;
+        } else {
+            log::debug!("Pushing {} ({} bytes).", key, chunk.len());
+            retry(|| backend.put(&key, chunk))?;
+            pushed.sent += 1;
+        }
+        parts.push(sha256);
+    }
+    let manifest = Manifest {
+        size: data.len() as u64,
+        parts,
+    };
+    retry(|| backend.put(&manifest_key(sig), manifest.encode().as_bytes()))?;
+    Ok(pushed)
+}
+
+/// Pull the cache with signature `sig` from the configured remote cache into
+/// the cache dir, unless it's not there, in which case `None`. The cache is
+/// checked, as described above, before it's written.
+pub fn pull_file(config: &config::Config, sig: &str) -> Result<Option<PathBuf>> {
+    let backend = backend(config)?;
+    let data = match pull(&*backend, sig)? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    (config.cache_keys.verify(&data)).map_err(cache::Error::from)?;
+    let cache = cache::Cache::from_bytes(&data, &config.cache_keys)?;
+    if cache.sums.sig() != sig {
+        return Err(Error::Integrity(format!(
+            "pulled {} but got {}",
+            sig,
+            cache.sums.sig()
+        )));
+    }
+    fs::create_dir_all(&config.cache_dir)?;
+    let cache_file = config.cache_file(&cache.sums);
+    // Pulls of the same cache may race; each writes its own temporary file.
+    let mut temp = tempfile::NamedTempFile::new_in(&config.cache_dir)?;
+    temp.write_all(&data)?;
+    temp.persist(&cache_file).map_err(io::Error::from)?;
+    Ok(Some(cache_file))
+}
+
+/// Pull the manifest for `sig` and then the parts it lists, checking each
+/// against its SHA-256, and the whole against the manifest's size.
+pub fn pull(backend: &dyn Backend, sig: &str) -> Result<Option<Vec<u8>>> {
+    let manifest = match retry(|| backend.get(&manifest_key(sig)))? {
+        Some(manifest) => Manifest::decode(&String::from_utf8_lossy(&manifest))?,
+        None => return Ok(None),
+    };
+    if manifest.size > cache::MAX_SIZE {
+        return Err(Error::Integrity(format!(
+            "manifest says {} bytes, more than a cache can be",
+            manifest.size
+        )));
+    }
+    let mut data = Vec::with_capacity(manifest.size as usize);
+    for sha256 in &manifest.parts {
+        let key = part_key(sha256);
+        log::debug!("Pulling {}.", key);
+        let part = match retry(|| backend.get(&key))? {
+            Some(part) => part,
+            None => return Err(Error::Integrity(format!("{} is missing", key))),
+        };
+        if hex_digest(Algorithm::SHA256, &part) != *sha256 {
+            return Err(Error::Integrity(format!(
+                "{} does not match its SHA-256",
+                key
+            )));
+        }
+        data.extend(part);
+    }
+    if data.len() as u64 != manifest.size {
+        return Err(Error::Integrity(format!(
+            "got {} bytes but the manifest says {}",
+            data.len(),
+            manifest.size
+        )));
+    }
+    Ok(Some(data))
+}
+
+fn retry<T, F>(mut request: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut delays = RETRY_DELAYS.iter();
+    loop {
+        match request() {
+            Ok(result) => return Ok(result),
+            Err(err) => match delays.next() {
+                Some(delay) => {
+                    log::warn!("{}; retrying in {}s.", err, delay.as_secs());
+                    thread::sleep(*delay);
+                }
+                None => return Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn curlrc_quote_escapes() {
+        assert_eq!(r#""Bearer abc""#, curlrc_quote("Bearer abc"));
+        assert_eq!(r#""a\"b\\c\n""#, curlrc_quote("a\"b\\c\n"));
+    }
+
+    #[test]
+    fn pushes_parts_then_manifest() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let backend = Dir(dir.path().into());
+        let pushed = push(&backend, "abc", b"0123456789", 4, false).ok().unwrap();
+        assert_eq!(3, pushed.sent);
+        let manifest = fs::read_to_string(dir.path().join("manifests/abc")).unwrap();
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(&[MANIFEST_HEADER, "10"], &lines[..2]);
+        assert_eq!(5, lines.len());
+        for part in &lines[2..] {
+            assert!(backend.has(&part_key(part)).ok().unwrap());
+        }
+    }
+
+    /// Fails every put after the first `limit`.
+    struct Flaky {
+        dir: Dir,
+        limit: Cell<usize>,
+    }
+
+    impl Backend for Flaky {
+        fn has(&self, key: &str) -> Result<bool> {
+            self.dir.has(key)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.dir.get(key)
+        }
+
+        fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+            match self.limit.get() {
+                0 => Err(Error::Request("connection reset".into())),
+                limit => {
+                    self.limit.set(limit - 1);
+                    self.dir.put(key, data)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resumes_where_it_left_off() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let backend = Flaky {
+            dir: Dir(dir.path().into()),
+            limit: Cell::new(2),
+        };
+        assert!(push(&backend, "abc", b"0123456789", 4, false).is_err());
+        assert!(!dir.path().join("manifests/abc").exists());
+
+        backend.limit.set(2);
+        let pushed = push(&backend, "abc", b"0123456789", 4, true).ok().unwrap();
+        assert_eq!((1, 2), (pushed.sent, pushed.skipped));
+        assert!(dir.path().join("manifests/abc").exists());
+    }
+
+    #[test]
+    fn pulls_what_was_pushed_and_checks_it() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let backend = Dir(dir.path().into());
+        assert!(pull(&backend, "abc").ok().unwrap().is_none());
+        push(&backend, "abc", b"0123456789", 4, false).ok().unwrap();
+        let data = pull(&backend, "abc").ok().unwrap();
+        assert_eq!(Some(b"0123456789".to_vec()), data);
+
+        // Corrupt a part.
+        let sha256 = hex_digest(Algorithm::SHA256, b"4567");
+        backend.put(&part_key(&sha256), b"4568").ok().unwrap();
+        match pull(&backend, "abc") {
+            Err(Error::Integrity(message)) => assert!(message.contains(&sha256)),
+            _ => panic!("corrupt part was not detected"),
+        }
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let manifest = Manifest {
+            size: 10,
+            parts: vec!["a".repeat(64), "b".repeat(64)],
+        };
+        let decoded = Manifest::decode(&manifest.encode()).ok().unwrap();
+        assert_eq!((10, manifest.parts), (decoded.size, decoded.parts));
+        assert!(Manifest::decode("firstaide-manifest 1\n10\nnope\n").is_err());
+        assert!(Manifest::decode("something else\n").is_err());
+    }
+}
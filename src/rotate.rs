@@ -0,0 +1,54 @@
+//! Rotation for append-only files – logs, history, the audit log – so that
+//! they don't grow without bound: `path` becomes `path.1`, `path.1` becomes
+//! `path.2`, and so on.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Shift `path` to `path.1`, `path.1` to `path.2`, and so on, if `path` is
+/// larger than `max_size`. Only `keep` rotated files are kept.
+pub fn rotate(path: &Path, max_size: u64, keep: usize) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > max_size => (),
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+/// The `n`th rotated file of `path`, i.e. `path.n`.
+pub fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_and_keeps_only_so_many() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.log");
+        for generation in 0..4 {
+            fs::write(&path, format!("{}\n", generation)).unwrap();
+            rotate(&path, 1, 2).unwrap();
+        }
+        assert!(!path.exists());
+        assert_eq!("3\n", fs::read_to_string(rotated(&path, 1)).unwrap());
+        assert_eq!("2\n", fs::read_to_string(rotated(&path, 2)).unwrap());
+        assert!(!rotated(&path, 3).exists());
+    }
+}
@@ -0,0 +1,238 @@
+//! User-level service definitions – launchd agents on macOS, systemd user
+//! units elsewhere – for running a long-lived firstaide process per project.
+
+use crate::os::OsStrExt;
+use crypto_hash::{hex_digest, Algorithm};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub struct Service {
+    /// Unique to the project; derived from its build directory.
+    pub name: String,
+    /// The command to run, program first.
+    pub args: Vec<PathBuf>,
+    pub working_dir: PathBuf,
+    pub log_file: PathBuf,
+    pub path: std::ffi::OsString,
+}
+
+impl Service {
+    pub fn new(build_dir: &Path, args: Vec<PathBuf>, log_file: PathBuf) -> Self {
+        let digest = hex_digest(Algorithm::SHA1, build_dir.as_os_str().as_bytes());
+        Self {
+            name: format!("firstaide-{}", &digest[..12]),
+            args,
+            working_dir: build_dir.to_path_buf(),
+            log_file,
+            // Services start with a bare environment; give ours the PATH we
+            // have now so that it can find direnv, nix, and so on.
+            path: std::env::var_os("PATH").unwrap_or_default(),
+        }
+    }
+
+    /// Where the service definition should be installed.
+    pub fn unit_file(&self) -> io::Result<PathBuf> {
+        let home = dirs::home_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "home directory not found"))?;
+        if cfg!(target_os = "macos") {
+            Ok(home
+                .join("Library/LaunchAgents")
+                .join(format!("com.github.allenap.{}.plist", self.name)))
+        } else {
+            let config = dirs::config_dir().unwrap_or_else(|| home.join(".config"));
+            Ok(config
+                .join("systemd/user")
+                .join(format!("{}.service", self.name)))
+        }
+    }
+
+    /// The service definition itself.
+    pub fn unit(&self) -> String {
+        if cfg!(target_os = "macos") {
+            self.launchd_plist()
+        } else {
+            self.systemd_unit()
+        }
+    }
+
+    /// Commands to run, in order, to start the service once installed.
+    pub fn commands_to_load(&self, unit_file: &Path) -> Vec<Command> {
+        if cfg!(target_os = "macos") {
+            vec![launchctl(&["load", "-w"], unit_file)]
+        } else {
+            vec![
+                systemctl(&["daemon-reload"]),
+                systemctl(&["enable", "--now", &self.systemd_unit_name()]),
+            ]
+        }
+    }
+
+    /// Commands to run, in order, to stop the service before uninstalling.
+    pub fn commands_to_unload(&self, unit_file: &Path) -> Vec<Command> {
+        if cfg!(target_os = "macos") {
+            vec![launchctl(&["unload", "-w"], unit_file)]
+        } else {
+            vec![systemctl(&["disable", "--now", &self.systemd_unit_name()])]
+        }
+    }
+
+    /// Commands to run, in order, after uninstalling.
+    pub fn commands_after_unload(&self) -> Vec<Command> {
+        if cfg!(target_os = "macos") {
+            vec![]
+        } else {
+            vec![systemctl(&["daemon-reload"])]
+        }
+    }
+
+    fn systemd_unit_name(&self) -> String {
+        format!("{}.service", self.name)
+    }
+
+    fn systemd_unit(&self) -> String {
+        let exec: Vec<String> = self.args.iter().map(systemd_quote).collect();
+        format!(
+            concat!(
+                "[Unit]\n",
+                "Description=firstaide for {}\n",
+                "\n",
+                "[Service]\n",
+                "ExecStart={}\n",
+                "WorkingDirectory={}\n",
+                "Environment={}\n",
+                "StandardOutput=append:{}\n",
+                "StandardError=inherit\n",
+                "Restart=on-failure\n",
+                "\n",
+                "[Install]\n",
+                "WantedBy=default.target\n",
+            ),
+            self.working_dir.display(),
+            exec.join(" "),
+            self.working_dir.display(),
+            systemd_quote(&{
+                let mut env = std::ffi::OsString::from("PATH=");
+                env.push(&self.path);
+                env
+            }),
+            self.log_file.display(),
+        )
+    }
+
+    fn launchd_plist(&self) -> String {
+        let args: Vec<String> = (self.args.iter())
+            .map(|arg| format!("    <string>{}</string>\n", xml_escape(arg)))
+            .collect();
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+                "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+                "<plist version=\"1.0\">\n",
+                "<dict>\n",
+                "  <key>Label</key>\n",
+                "  <string>com.github.allenap.{}</string>\n",
+                "  <key>ProgramArguments</key>\n",
+                "  <array>\n",
+                "{}",
+                "  </array>\n",
+                "  <key>WorkingDirectory</key>\n",
+                "  <string>{}</string>\n",
+                "  <key>EnvironmentVariables</key>\n",
+                "  <dict>\n",
+                "    <key>PATH</key>\n",
+                "    <string>{}</string>\n",
+                "  </dict>\n",
+                "  <key>StandardOutPath</key>\n",
+                "  <string>{}</string>\n",
+                "  <key>StandardErrorPath</key>\n",
+                "  <string>{}</string>\n",
+                "  <key>RunAtLoad</key>\n",
+                "  <true/>\n",
+                "  <key>KeepAlive</key>\n",
+                "  <true/>\n",
+                "</dict>\n",
+                "</plist>\n",
+            ),
+            self.name,
+            args.concat(),
+            xml_escape(&self.working_dir),
+            xml_escape(&self.path),
+            xml_escape(&self.log_file),
+            xml_escape(&self.log_file),
+        )
+    }
+}
+
+fn launchctl(args: &[&str], unit_file: &Path) -> Command {
+    let mut command = Command::new("launchctl");
+    command.args(args).arg(unit_file);
+    command
+}
+
+fn systemctl(args: &[&str]) -> Command {
+    let mut command = Command::new("systemctl");
+    command.arg("--user").args(args);
+    command
+}
+
+/// Quote for systemd unit files, which understand C-style escapes within
+/// double quotes. Specifiers (`%`) must be doubled.
+fn systemd_quote<T: AsRef<OsStr>>(s: T) -> String {
+    let s = s.as_ref().to_string_lossy();
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '%' => out.push_str("%%"),
+            '$' => out.push_str("$$"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn xml_escape<T: AsRef<OsStr>>(s: T) -> String {
+    let s = s.as_ref().to_string_lossy();
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn systemd_quote_escapes_specials() {
+        assert_eq!(r#""/a b/\"c\"/%%d/$$e""#, systemd_quote("/a b/\"c\"/%d/$e"));
+    }
+
+    #[test]
+    fn xml_escape_escapes_specials() {
+        assert_eq!("a&amp;b&lt;c&gt;", xml_escape("a&b<c>"));
+    }
+
+    #[test]
+    fn service_name_depends_on_build_dir() {
+        let a = Service::new(Path::new("/a"), vec![], "log".into());
+        let b = Service::new(Path::new("/b"), vec![], "log".into());
+        assert!(a.name.starts_with("firstaide-"));
+        assert_ne!(a.name, b.name);
+    }
+}
@@ -0,0 +1,471 @@
+//! Rendering environment changes for the shells that firstaide speaks. Each
+//! shell owns its quoting rules, and its script, in `shells`, that reports the
+//! status; adding one is a matter of implementing `ShellRenderer` and listing
+//! it in `NAMES` and `renderer`.
+//!
+//! The direnv hook is Bash, and is assembled from the scripts in `cmds/hook`,
+//! but the environment within it is rendered by `Bash` here too.
+
+use crate::env;
+use crate::os::OsStrExt;
+use crate::template::{Template, Value};
+use shell_quote::bash;
+use std::ffi::OsStr;
+
+/// The names by which shells are chosen, e.g. with `hook --shell`.
+pub const NAMES: &[&str] = &["bash", "zsh", "fish", "nu", "elvish", "xonsh", "powershell"];
+
+/// The renderer for the shell called `name`, one of `NAMES`.
+pub fn renderer(name: &str) -> Option<Box<dyn ShellRenderer>> {
+    match name {
+        "bash" => Some(Box::new(Bash)),
+        "zsh" => Some(Box::new(Zsh)),
+        "fish" => Some(Box::new(Fish)),
+        "nu" => Some(Box::new(Nu)),
+        "elvish" => Some(Box::new(Elvish)),
+        "xonsh" => Some(Box::new(Xonsh)),
+        "powershell" => Some(Box::new(PowerShell)),
+        _ => None,
+    }
+}
+
+pub trait ShellRenderer {
+    /// Quote `word` so that the shell reads it back verbatim.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>);
+
+    /// A statement that exports `name` with `value`, without a newline.
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>);
+
+    /// A statement that removes `name` from the environment, without a
+    /// newline. It must not fail when `name` is already absent.
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>);
+
+    /// The script that reports the status to stderr, with `__LABEL__`,
+    /// `__COLOR__` (an SGR number), `__STATUS__`, and `__NEXT__` placeholders.
+    fn status_script(&self) -> &'static [u8];
+
+    /// Statements that report the status to stderr, per `status_script`.
+    fn report(&self, label: &str, color: &str, status: &str, next: &str) -> Vec<u8> {
+        // Quoted here for this shell, so substituted verbatim.
+        let word = |text: &str| {
+            let mut word = Vec::new();
+            self.quote_into(text.as_ref(), &mut word);
+            Value::Bash(word)
+        };
+        Template::new("status", self.status_script()).render(&[
+            ("LABEL", word(label)),
+            ("COLOR", word(color)),
+            ("STATUS", word(status)),
+            ("NEXT", word(next)),
+        ])
+    }
+
+    /// Statements to apply `diff`, one per line.
+    fn dump(&self, diff: &env::Diff) -> Vec<u8> {
+        let mut output = Vec::new();
+        for change in diff {
+            match change {
+                env::Added(name, value) | env::Changed(name, _, value) => {
+                    self.set_into(name, value, &mut output)
+                }
+                env::Removed(name, _) => self.unset_into(name, &mut output),
+            }
+            output.push(b'\n');
+        }
+        output
+    }
+}
+
+/// `export` and `unset`, with Bash's quoting, which uses `$'…'` when there
+/// are control characters, so that values never span lines.
+pub struct Bash;
+
+impl ShellRenderer for Bash {
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        bash::escape_into(word, output);
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"export ");
+        self.quote_into(name, output);
+        output.push(b'=');
+        self.quote_into(value, output);
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"unset ");
+        self.quote_into(name, output);
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.sh")
+    }
+}
+
+/// zsh understands Bash's quoting, `$'…'` included, its `export` and
+/// `unset`, and its status script.
+pub struct Zsh;
+
+impl ShellRenderer for Zsh {
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        Bash.quote_into(word, output)
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        Bash.set_into(name, value, output)
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        Bash.unset_into(name, output)
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        Bash.status_script()
+    }
+}
+
+/// `set -gx` and `set -e`.
+pub struct Fish;
+
+impl ShellRenderer for Fish {
+    /// Within single quotes fish gives special meaning to only backslash and
+    /// the single quote, which are escaped with a backslash.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        output.push(b'\'');
+        for &byte in word.as_bytes() {
+            if byte == b'\\' || byte == b'\'' {
+                output.push(b'\\');
+            }
+            output.push(byte);
+        }
+        output.push(b'\'');
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"set -gx ");
+        self.quote_into(name, output);
+        output.push(b' ');
+        self.quote_into(value, output);
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"set -e ");
+        self.quote_into(name, output);
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.fish")
+    }
+}
+
+/// One `load-env` with a record of everything that is set, then `hide-env`
+/// for each variable that is removed. `PATH` is given as a list, as nushell
+/// keeps it. Names and values that are not UTF-8 are converted lossily.
+pub struct Nu;
+
+impl ShellRenderer for Nu {
+    /// A double-quoted string, in which a backslash introduces an escape,
+    /// and nothing is interpolated. Control characters are escaped so that
+    /// the output stays one line per variable.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        output.push(b'"');
+        for ch in word.to_string_lossy().chars() {
+            match ch {
+                '"' => output.extend(b"\\\""),
+                '\\' => output.extend(b"\\\\"),
+                '\n' => output.extend(b"\\n"),
+                '\r' => output.extend(b"\\r"),
+                '\t' => output.extend(b"\\t"),
+                ch if ch.is_control() => output.extend(format!("\\u{{{:x}}}", ch as u32).bytes()),
+                ch => output.extend(ch.encode_utf8(&mut [0; 4]).bytes()),
+            }
+        }
+        output.push(b'"');
+    }
+
+    /// An entry in the record given to `load-env`.
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        self.quote_into(name, output);
+        output.extend(b": ");
+        if name == "PATH" {
+            output.push(b'[');
+            let value = value.to_string_lossy();
+            for (index, entry) in value.split(env::PATH_SEPARATOR).enumerate() {
+                if index > 0 {
+                    output.extend(b", ");
+                }
+                self.quote_into(entry.as_ref(), output);
+            }
+            output.push(b']');
+        } else {
+            self.quote_into(value, output);
+        }
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"hide-env --ignore-errors ");
+        self.quote_into(name, output);
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.nu")
+    }
+
+    fn dump(&self, diff: &env::Diff) -> Vec<u8> {
+        let mut record = Vec::new();
+        let mut hides = Vec::new();
+        for change in diff {
+            match change {
+                env::Added(name, value) | env::Changed(name, _, value) => {
+                    record.extend(b"  ");
+                    self.set_into(name, value, &mut record);
+                    record.push(b'\n');
+                }
+                env::Removed(name, _) => {
+                    self.unset_into(name, &mut hides);
+                    hides.push(b'\n');
+                }
+            }
+        }
+        if record.is_empty() {
+            hides
+        } else {
+            let mut output = b"load-env {\n".to_vec();
+            output.extend(record);
+            output.extend(b"}\n");
+            output.extend(hides);
+            output
+        }
+    }
+}
+
+/// `set-env` and `unset-env`. Names and values that are not UTF-8 are
+/// converted lossily.
+pub struct Elvish;
+
+impl ShellRenderer for Elvish {
+    /// A double-quoted string, which elvish never interpolates. Control
+    /// characters are escaped so that values never span lines.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        output.push(b'"');
+        for ch in word.to_string_lossy().chars() {
+            match ch {
+                '"' => output.extend(b"\\\""),
+                '\\' => output.extend(b"\\\\"),
+                '\n' => output.extend(b"\\n"),
+                '\r' => output.extend(b"\\r"),
+                '\t' => output.extend(b"\\t"),
+                ch if ch.is_control() => output.extend(format!("\\u{:04x}", ch as u32).bytes()),
+                ch => output.extend(ch.encode_utf8(&mut [0; 4]).bytes()),
+            }
+        }
+        output.push(b'"');
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"set-env ");
+        self.quote_into(name, output);
+        output.push(b' ');
+        self.quote_into(value, output);
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"unset-env ");
+        self.quote_into(name, output);
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.elv")
+    }
+}
+
+/// Assignments to, and `pop`s from, `${...}`, xonsh's environment, so that
+/// any name will do. Names and values that are not UTF-8 are converted
+/// lossily.
+pub struct Xonsh;
+
+impl ShellRenderer for Xonsh {
+    /// A single-quoted Python string.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        output.push(b'\'');
+        for ch in word.to_string_lossy().chars() {
+            match ch {
+                '\'' => output.extend(b"\\'"),
+                '\\' => output.extend(b"\\\\"),
+                '\n' => output.extend(b"\\n"),
+                '\r' => output.extend(b"\\r"),
+                '\t' => output.extend(b"\\t"),
+                ch if ch.is_control() => output.extend(format!("\\x{:02x}", ch as u32).bytes()),
+                ch => output.extend(ch.encode_utf8(&mut [0; 4]).bytes()),
+            }
+        }
+        output.push(b'\'');
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"${...}[");
+        self.quote_into(name, output);
+        output.extend(b"] = ");
+        self.quote_into(value, output);
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"${...}.pop(");
+        self.quote_into(name, output);
+        output.extend(b", None)");
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.xsh")
+    }
+}
+
+/// `Set-Item` and `Remove-Item` on the `Env:` drive. Names and values that are
+/// not UTF-8 are converted lossily; PowerShell could not hold them anyway.
+pub struct PowerShell;
+
+impl ShellRenderer for PowerShell {
+    /// A verbatim (single-quoted) string. PowerShell treats the curly single
+    /// quotes as quotes too, so those are doubled up as well as `'`.
+    fn quote_into(&self, word: &OsStr, output: &mut Vec<u8>) {
+        output.push(b'\'');
+        for ch in word.to_string_lossy().chars() {
+            if matches!(ch, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+                output.extend(ch.encode_utf8(&mut [0; 4]).bytes());
+            }
+            output.extend(ch.encode_utf8(&mut [0; 4]).bytes());
+        }
+        output.push(b'\'');
+    }
+
+    fn set_into(&self, name: &OsStr, value: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"Set-Item -LiteralPath ");
+        self.quote_into(format!("Env:{}", name.to_string_lossy()).as_ref(), output);
+        output.extend(b" -Value ");
+        self.quote_into(value, output);
+    }
+
+    fn unset_into(&self, name: &OsStr, output: &mut Vec<u8>) {
+        output.extend(b"Remove-Item -LiteralPath ");
+        self.quote_into(format!("Env:{}", name.to_string_lossy()).as_ref(), output);
+        output.extend(b" -ErrorAction SilentlyContinue");
+    }
+
+    fn status_script(&self) -> &'static [u8] {
+        include_bytes!("shells/status.ps1")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(shell: &str, diff: &env::Diff) -> String {
+        String::from_utf8(renderer(shell).unwrap().dump(diff)).unwrap()
+    }
+
+    #[test]
+    fn every_name_has_a_renderer() {
+        for name in NAMES {
+            assert!(renderer(name).is_some(), "{}", name);
+        }
+        assert!(renderer("csh").is_none());
+    }
+
+    #[test]
+    fn every_shell_reports_with_its_own_script() {
+        for name in NAMES {
+            let renderer = renderer(name).unwrap();
+            let report = renderer.report("NOTE", "33", "it's \"stale\"", "Use x.");
+            let report = String::from_utf8(report).unwrap();
+            let mut status = Vec::new();
+            renderer.quote_into("it's \"stale\"".as_ref(), &mut status);
+            let status = String::from_utf8(status).unwrap();
+            assert!(report.contains(&status), "{}: {}", name, report);
+            for placeholder in ["__LABEL__", "__COLOR__", "__STATUS__", "__NEXT__"] {
+                assert!(!report.contains(placeholder), "{}: {}", name, report);
+            }
+        }
+    }
+
+    #[test]
+    fn bash_exports_and_unsets() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "it's".into()),
+            env::Removed("GONE".into(), "before".into()),
+        ]);
+        let expected = "export GREETING=$'it\\'s'\nunset GONE\n";
+        assert_eq!(expected, dump("bash", &diff));
+        assert_eq!(expected, dump("zsh", &diff));
+    }
+
+    #[test]
+    fn fish_sets_and_erases() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "it's \\o/".into()),
+            env::Removed("GONE".into(), "before".into()),
+        ]);
+        assert_eq!(
+            "set -gx 'GREETING' 'it\\'s \\\\o/'\nset -e 'GONE'\n",
+            dump("fish", &diff)
+        );
+    }
+
+    #[test]
+    fn nu_loads_and_hides() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "say \"hi\"\\\n\u{1}".into()),
+            env::Changed("PATH".into(), "/bin".into(), "/nix/bin:/bin".into()),
+            env::Removed("GONE".into(), "x".into()),
+        ]);
+        assert_eq!(
+            concat!(
+                "load-env {\n",
+                "  \"GREETING\": \"say \\\"hi\\\"\\\\\\n\\u{1}\"\n",
+                "  \"PATH\": [\"/nix/bin\", \"/bin\"]\n",
+                "}\n",
+                "hide-env --ignore-errors \"GONE\"\n",
+            ),
+            dump("nu", &diff)
+        );
+    }
+
+    #[test]
+    fn elvish_sets_and_unsets() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "say \"hi\"\n\u{1}".into()),
+            env::Removed("GONE".into(), "x".into()),
+        ]);
+        assert_eq!(
+            "set-env \"GREETING\" \"say \\\"hi\\\"\\n\\u0001\"\nunset-env \"GONE\"\n",
+            dump("elvish", &diff)
+        );
+    }
+
+    #[test]
+    fn xonsh_assigns_and_pops() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "it's\n\u{1}".into()),
+            env::Removed("GONE".into(), "x".into()),
+        ]);
+        assert_eq!(
+            "${...}['GREETING'] = 'it\\'s\\n\\x01'\n${...}.pop('GONE', None)\n",
+            dump("xonsh", &diff)
+        );
+    }
+
+    #[test]
+    fn powershell_sets_and_removes() {
+        let diff = env::Diff::from(&[
+            env::Added("GREETING".into(), "it's \u{2019}quoted\u{2019}".into()),
+            env::Removed("GONE".into(), "x".into()),
+        ]);
+        assert_eq!(
+            concat!(
+                "Set-Item -LiteralPath 'Env:GREETING' -Value 'it''s \u{2019}\u{2019}quoted\u{2019}\u{2019}'\n",
+                "Remove-Item -LiteralPath 'Env:GONE' -ErrorAction SilentlyContinue\n",
+            ),
+            dump("powershell", &diff)
+        );
+    }
+}
@@ -0,0 +1,6 @@
+if isatty stderr
+    printf '\e[1;%sm%s\e[0m: %s\n' __COLOR__ __LABEL__ __STATUS__ >&2
+else
+    printf '%s: %s\n' __LABEL__ __STATUS__ >&2
+end
+printf -- '--> %s\n' __NEXT__ >&2
@@ -0,0 +1,6 @@
+if (-not [Console]::IsErrorRedirected) {
+    [Console]::Error.WriteLine("$([char]27)[1;" + __COLOR__ + 'm' + __LABEL__ + "$([char]27)[0m: " + __STATUS__)
+} else {
+    [Console]::Error.WriteLine(__LABEL__ + ': ' + __STATUS__)
+}
+[Console]::Error.WriteLine('--> ' + __NEXT__)
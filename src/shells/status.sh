@@ -0,0 +1,7 @@
+# shellcheck shell=bash
+if [ -t 2 ]; then
+    printf '\033[1;%sm%s\033[0m: %s\n' __COLOR__ __LABEL__ __STATUS__ >&2
+else
+    printf '%s: %s\n' __LABEL__ __STATUS__ >&2
+fi
+printf -- '--> %s\n' __NEXT__ >&2
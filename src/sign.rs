@@ -0,0 +1,290 @@
+//! Signed cache files, so that caches from a shared location – a remote cache,
+//! say, filled by CI – can be trusted.
+//!
+//! A signed file is `MAGIC`, then the length of the signature as a 32-bit
+//! little-endian integer, then the signature, then the file as it would be
+//! otherwise. Signatures are made with Ed25519 SSH keys. There's no Ed25519
+//! implementation in our dependencies, so we drive `ssh-keygen -Y`, which
+//! wraps the signature in its own armoured format, with our namespace.
+
+use crate::os;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const MAGIC: &[u8] = b"firstaide-signed\0";
+
+/// Signatures for caches cannot be mistaken for those for anything else.
+const NAMESPACE: &str = "firstaide-cache";
+
+/// The principal in the allowed signers file we write for `ssh-keygen`.
+const PRINCIPAL: &str = "firstaide";
+
+/// The private key, if set, instead of `key_file`. Useful in CI.
+pub const KEY_VAR: &str = "FIRSTAIDE_CACHE_SIGNING_KEY";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Sign(String),
+    Unsigned,
+    Malformed,
+    Untrusted(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Sign(message) => write!(f, "could not sign: {}", message),
+            Unsigned => write!(f, "cache is not signed but trusted keys are configured"),
+            Malformed => write!(f, "cache signature is malformed"),
+            Untrusted(message) => write!(f, "cache is not signed by a trusted key: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configured in `[cache.signing]`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Signing {
+    /// A private Ed25519 key in OpenSSH format, without a passphrase, with
+    /// which to sign caches on save. `KEY_VAR` takes precedence.
+    pub key_file: Option<PathBuf>,
+    /// Public keys, like `ssh-ed25519 AAAA... ci@example.com`. When there are
+    /// any, caches must be signed by one of them to load.
+    #[serde(default)]
+    pub trusted: Vec<String>,
+}
+
+impl Signing {
+    /// Problems with the configuration, if any.
+    pub fn check(&self) -> std::result::Result<(), String> {
+        match self
+            .trusted
+            .iter()
+            .find(|key| !key.starts_with("ssh-ed25519 "))
+        {
+            Some(key) => Err(format!("trusted key is not an Ed25519 SSH key: {:?}", key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sign `data` if there's a key with which to do so.
+    pub fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let key = std::env::var_os(KEY_VAR);
+        if key.is_none() && self.key_file.is_none() {
+            return Ok(data);
+        }
+        let scratch = tempfile::TempDir::new()?;
+        let key_file = match (key, &self.key_file) {
+            (Some(key), _) => {
+                let key_file = scratch.path().join("key");
+                let mut file =
+                    os::private_file(fs::OpenOptions::new().write(true).create_new(true))
+                        .open(&key_file)?;
+                file.write_all(key.to_string_lossy().trim().as_bytes())?;
+                file.write_all(b"\n")?;
+                key_file
+            }
+            (None, Some(key_file)) => key_file.clone(),
+            (None, None) => return Ok(data),
+        };
+        let data_file = scratch.path().join("data");
+        fs::write(&data_file, &data)?;
+        let output = ssh_keygen()
+            .arg("sign")
+            .arg("-f")
+            .arg(&key_file)
+            .arg("-n")
+            .arg(NAMESPACE)
+            .arg(&data_file)
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(Error::Sign(stderr.trim().into()));
+        }
+        let signature = fs::read(data_file.with_extension("sig"))?;
+        let mut signed = Vec::with_capacity(MAGIC.len() + 4 + signature.len() + data.len());
+        signed.extend(MAGIC);
+        signed.extend(&(signature.len() as u32).to_le_bytes());
+        signed.extend(signature);
+        signed.extend(data);
+        Ok(signed)
+    }
+
+    /// What was signed, without checking the signature, or `data` as is if
+    /// it's not signed. Caches built here, or already checked when they were
+    /// pulled, are opened this way, since checking means running `ssh-keygen`.
+    pub fn strip(data: Vec<u8>) -> Result<Vec<u8>> {
+        match split(&data)? {
+            Some((_, payload)) => Ok(payload.to_vec()),
+            None => Ok(data),
+        }
+    }
+
+    /// Check the signature on `data` against the trusted keys, if there are
+    /// any, and return what was signed. Without trusted keys, signatures are
+    /// stripped without being checked, and unsigned data is returned as is.
+    pub fn verify(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let (signature, payload) = match split(&data)? {
+            Some(parts) => parts,
+            None if self.trusted.is_empty() => return Ok(data),
+            None => return Err(Error::Unsigned),
+        };
+        if self.trusted.is_empty() {
+            return Ok(payload.to_vec());
+        }
+        let scratch = tempfile::TempDir::new()?;
+        let signers_file = scratch.path().join("allowed_signers");
+        let signers: String = (self.trusted.iter())
+            .map(|key| format!("{} namespaces=\"{}\" {}\n", PRINCIPAL, NAMESPACE, key))
+            .collect();
+        fs::write(&signers_file, signers)?;
+        let signature_file = scratch.path().join("signature");
+        fs::write(&signature_file, signature)?;
+        let mut child = ssh_keygen()
+            .arg("verify")
+            .arg("-f")
+            .arg(&signers_file)
+            .arg("-I")
+            .arg(PRINCIPAL)
+            .arg("-n")
+            .arg(NAMESPACE)
+            .arg("-s")
+            .arg(&signature_file)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(payload)?;
+        }
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            Ok(payload.to_vec())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(Error::Untrusted(stderr.trim().into()))
+        }
+    }
+}
+
+/// The signature and the signed data, or `None` if `data` is not signed.
+fn split(data: &[u8]) -> Result<Option<(&[u8], &[u8])>> {
+    let rest = match data.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 4 => rest,
+        Some(_) => return Err(Error::Malformed),
+        None => return Ok(None),
+    };
+    let (len, rest) = rest.split_at(4);
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(len);
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > rest.len() {
+        return Err(Error::Malformed);
+    }
+    Ok(Some(rest.split_at(len)))
+}
+
+fn ssh_keygen() -> Command {
+    let mut command = Command::new("ssh-keygen");
+    command
+        .arg("-Y")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// Generate a new key pair in `dir`, returning the private key file and the
+/// public key. Signing needs `ssh-keygen`, and so do tests that sign.
+#[cfg(test)]
+pub(crate) fn generate(dir: &std::path::Path, name: &str) -> (PathBuf, String) {
+    let key_file = dir.join(name);
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+        .arg(&key_file)
+        .stdin(Stdio::null())
+        .status()
+        .expect("could not run ssh-keygen");
+    assert!(status.success(), "ssh-keygen failed");
+    let public = fs::read_to_string(key_file.with_extension("pub")).unwrap();
+    (key_file, public.trim().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_and_verifies() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (key_file, trusted) = generate(dir.path(), "ci");
+        let (_, other) = generate(dir.path(), "other");
+        let signer = Signing {
+            key_file: Some(key_file),
+            trusted: Vec::new(),
+        };
+        let signed = signer.sign(b"ALICE=alice".to_vec()).ok().unwrap();
+        assert!(signed.starts_with(MAGIC));
+
+        let verifier = |key: &str| Signing {
+            trusted: vec![key.to_owned()],
+            ..Signing::default()
+        };
+        let verified = verifier(&trusted).verify(signed.clone()).ok().unwrap();
+        assert_eq!(b"ALICE=alice".to_vec(), verified);
+        match verifier(&other).verify(signed.clone()) {
+            Err(Error::Untrusted(_)) => (),
+            _ => panic!("expected Untrusted"),
+        }
+        let mut tampered = signed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        match verifier(&trusted).verify(tampered) {
+            Err(Error::Untrusted(_)) => (),
+            _ => panic!("expected Untrusted"),
+        }
+        match verifier(&trusted).verify(b"ALICE=alice".to_vec()) {
+            Err(Error::Unsigned) => (),
+            _ => panic!("expected Unsigned"),
+        }
+        assert_eq!(
+            b"ALICE=alice".to_vec(),
+            Signing::strip(signed).ok().unwrap()
+        );
+    }
+
+    #[test]
+    fn without_trusted_keys_signatures_are_stripped() {
+        let signing = Signing::default();
+        let mut signed = MAGIC.to_vec();
+        signed.extend(&3u32.to_le_bytes());
+        signed.extend(b"sigdata");
+        assert_eq!(b"data".to_vec(), signing.verify(signed).ok().unwrap());
+        assert_eq!(
+            b"data".to_vec(),
+            signing.verify(b"data".to_vec()).ok().unwrap()
+        );
+        assert!(signing.verify(MAGIC.to_vec()).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Cheap change detection for watched files, by size and modification time,
+//! so that callers that must be fast can avoid calculating checksums.
+
+use crate::config;
+use crate::env;
+use crate::sums;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// The size and modification time of each file, if it exists.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Stamps(Vec<(PathBuf, Option<(u64, SystemTime)>)>);
+
+impl Stamps {
+    pub fn of(files: &[PathBuf]) -> Self {
+        Self(
+            (files.iter())
+                .map(|file| {
+                    let stamp = fs::metadata(file)
+                        .and_then(|m| Ok((m.len(), m.modified()?)))
+                        .ok();
+                    (file.clone(), stamp)
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatCache {
+    stamps: Stamps,
+    sums: sums::Checksums,
+    /// How long each file took to hash, in the same order as `sums`.
+    timings: Vec<Duration>,
+}
+
+/// Checksums of the watched files, reusing those from last time when none
+/// of the files appear to have changed. The stat cache lives in the cache dir,
+/// and is written only if that exists, and never by an observer.
+pub fn checksums(config: &config::Config) -> io::Result<sums::Checksums> {
+    checksums_timed(config).map(|(sums, _)| sums)
+}
+
+/// Like `checksums`, but also report how long each file took to hash when the
+/// checksums were calculated – which may have been some time ago.
+pub fn checksums_timed(config: &config::Config) -> io::Result<(sums::Checksums, Vec<Duration>)> {
+    let files = config.watch_files()?;
+    let stamps = Stamps::of(&files);
+    let stat_cache_file = config.stat_cache_file();
+    if let Ok(data) = fs::read(&stat_cache_file) {
+        if let Ok(stat_cache) = bincode::deserialize::<StatCache>(&data) {
+            if stat_cache.stamps == stamps {
+                // Stamps say nothing of variables, so check them afresh.
+                let sums = stat_cache
+                    .sums
+                    .with_vars(&config.watch_env, env::var_before_direnv);
+                return Ok((sums, stat_cache.timings));
+            }
+        }
+    }
+    let (sums, timings) = config.checksums_of_timed(&files)?;
+    if config.cache_dir.is_dir() && config.writable().is_ok() {
+        let stat_cache = StatCache {
+            stamps,
+            sums,
+            timings,
+        };
+        match bincode::serialize(&stat_cache) {
+            Ok(data) => {
+                // Write atomically; a prompt may be reading concurrently.
+                let temp_file = stat_cache_file.with_extension("new");
+                fs::write(&temp_file, data)?;
+                fs::rename(&temp_file, &stat_cache_file)?;
+            }
+            Err(err) => log::debug!("Could not encode stat cache: {}", err),
+        }
+        Ok((stat_cache.sums, stat_cache.timings))
+    } else {
+        Ok((sums, timings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_change_when_files_change() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        let files = vec![file.clone()];
+        let missing = Stamps::of(&files);
+        fs::write(&file, b"one").unwrap();
+        let present = Stamps::of(&files);
+        assert!(missing != present);
+        assert!(present == Stamps::of(&files));
+        fs::write(&file, b"three").unwrap();
+        assert!(present != Stamps::of(&files));
+    }
+}
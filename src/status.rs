@@ -1,7 +1,14 @@
+use crate::i18n;
+use crate::term;
 use std::fmt;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EnvironmentStatus {
     Okay,
+    /// Stale, but only because of changes that don't matter much, like to
+    /// documentation, so the environment is still used; see `staleness.soft`
+    /// in config.
+    SoftStale,
     Stale,
     Unknown,
 }
@@ -10,9 +17,10 @@ impl fmt::Display for EnvironmentStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use EnvironmentStatus::*;
         match self {
-            Okay => write!(f, "Environment is up to date!"),
-            Stale => write!(f, "Environment is STALE!"),
-            Unknown => write!(f, "Environment not built or otherwise broken!"),
+            Okay => write!(f, "{}", i18n::tr("status.okay")),
+            SoftStale => write!(f, "{}", i18n::tr("status.soft-stale")),
+            Stale => write!(f, "{}", i18n::tr("status.stale")),
+            Unknown => write!(f, "{}", i18n::tr("status.unknown")),
         }
     }
 }
@@ -22,10 +30,33 @@ impl EnvironmentStatus {
         format!("{}", self)
     }
 
+    /// A short, stable name, for use in scripts.
+    pub fn name(&self) -> &'static str {
+        use EnvironmentStatus::*;
+        match self {
+            Okay => "ok",
+            SoftStale => "soft-stale",
+            Stale => "stale",
+            Unknown => "none",
+        }
+    }
+
+    /// For humans: colored and with a glyph, when output is colored.
+    pub fn marked(&self) -> String {
+        use EnvironmentStatus::*;
+        let (style, glyph) = match self {
+            Okay => (term::Style::Okay, "✔"),
+            SoftStale => (term::Style::Warning, "●"),
+            Stale => (term::Style::Warning, "▲"),
+            Unknown => (term::Style::Error, "✘"),
+        };
+        style.mark(glyph, &self.display())
+    }
+
     pub fn code(&self) -> u8 {
         use EnvironmentStatus::*;
         match self {
-            Okay => 0,
+            Okay | SoftStale => 0,
             Stale => 1,
             Unknown => 2,
         }
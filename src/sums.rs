@@ -1,11 +1,15 @@
+use crate::os::OsStrExt;
+use bincode;
 use crypto_hash::{hex_digest, Algorithm};
 use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use bincode;
+use std::time::{Duration, Instant};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Checksums(Vec<Checksum>);
 
 impl Checksums {
@@ -21,6 +25,55 @@ impl Checksums {
         Ok(Self(sums))
     }
 
+    /// Like `from`, but also report how long each file took to hash, in the
+    /// same order.
+    pub fn from_timed<T>(filenames: &[T]) -> io::Result<(Self, Vec<Duration>)>
+    where
+        T: AsRef<Path>,
+    {
+        let mut sums = Vec::new();
+        let mut timings = Vec::new();
+        for filename in filenames {
+            let started = Instant::now();
+            let sum = Checksum::from(filename)?;
+            timings.push(started.elapsed());
+            sums.push(sum);
+        }
+        Ok((Self(sums), timings))
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Checksum> {
+        self.0.iter()
+    }
+
+    /// Make paths relative to `base` where possible.
+    pub fn relative_to(self, base: &Path) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|sum| sum.relative_to(base))
+                .collect(),
+        )
+    }
+
+    /// Replace any checksums of environment variables with those of the
+    /// variables in `names`, with values from `lookup`.
+    pub fn with_vars<F>(self, names: &[String], lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<OsString>,
+    {
+        let files = (self.0.into_iter()).filter(|sum| sum.path().is_some());
+        Self(
+            files
+                .chain(
+                    names
+                        .iter()
+                        .map(|name| Checksum::of_var(name, lookup(name))),
+                )
+                .collect(),
+        )
+    }
+
     pub fn sig(&self) -> String {
         // Default bincode config is unlimited so should not error, hence
         // unwrapping is safe.
@@ -37,10 +90,13 @@ impl IntoIterator for Checksums {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub enum Checksum {
     Found(PathBuf, Sha1),
     NotFound(PathBuf),
+    /// An environment variable and, if it's set, a checksum of its value. The
+    /// value itself is not kept since caches may be shared.
+    Var(String, Option<Sha1>),
 }
 
 impl Checksum {
@@ -56,15 +112,57 @@ impl Checksum {
         }
     }
 
-    pub fn path(&self) -> &Path {
+    pub fn of_var(name: &str, value: Option<OsString>) -> Self {
+        let sha1 = value.map(|value| Sha1::of(value.as_bytes()));
+        Checksum::Var(name.into(), sha1)
+    }
+
+    fn relative_to(self, base: &Path) -> Self {
+        let relative = |path: PathBuf| match path.strip_prefix(base) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path,
+        };
+        match self {
+            Checksum::Found(path, sha1) => Checksum::Found(relative(path), sha1),
+            Checksum::NotFound(path) => Checksum::NotFound(relative(path)),
+            var @ Checksum::Var(..) => var,
+        }
+    }
+
+    /// The file that this is a checksum of, if it's of a file.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Checksum::Found(path, _) => Some(path),
+            Checksum::NotFound(path) => Some(path),
+            Checksum::Var(..) => None,
+        }
+    }
+
+    pub fn subject(&self) -> Subject<'_> {
+        match self {
+            Checksum::Found(path, _) | Checksum::NotFound(path) => Subject::File(path),
+            Checksum::Var(name, _) => Subject::Var(name),
+        }
+    }
+}
+
+/// What a checksum is of.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Subject<'a> {
+    File(&'a Path),
+    Var(&'a str),
+}
+
+impl fmt::Display for Subject<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Checksum::Found(path, _) => path,
-            Checksum::NotFound(path) => path,
+            Subject::File(path) => write!(f, "{}", path.display()),
+            Subject::Var(name) => write!(f, "${}", name),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Sha1(pub String);
 
 impl Sha1 {
@@ -72,10 +170,94 @@ impl Sha1 {
     where
         T: AsRef<Path>,
     {
-        Ok(Self(hex_digest(Algorithm::SHA1, &fs::read(filename)?)))
+        Ok(Self::of(&fs::read(filename)?))
+    }
+
+    pub fn of(data: &[u8]) -> Self {
+        Self(hex_digest(Algorithm::SHA1, data))
     }
 }
 
 pub fn equal(a: &Checksums, b: &Checksums) -> bool {
     a.0.iter().eq(b.0.iter())
 }
+
+/// Files and variables whose checksums differ between `a` and `b`, including
+/// those that appear in only one of them.
+pub fn changed<'a>(a: &'a Checksums, b: &'a Checksums) -> Vec<Subject<'a>> {
+    let mut subjects: Vec<Subject> = Vec::new();
+    for sum in a.0.iter().filter(|sum| !b.0.contains(sum)) {
+        subjects.push(sum.subject());
+    }
+    for sum in b.0.iter().filter(|sum| !a.0.contains(sum)) {
+        if !subjects.contains(&sum.subject()) {
+            subjects.push(sum.subject());
+        }
+    }
+    subjects.sort();
+    subjects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_finds_modified_added_and_removed_paths() {
+        let a = Checksums(vec![
+            found("same", "1"),
+            found("modified", "2"),
+            missing("removed"),
+        ]);
+        let b = Checksums(vec![
+            found("same", "1"),
+            found("modified", "3"),
+            found("added", "4"),
+        ]);
+        assert_eq!(
+            vec![
+                Subject::File(Path::new("added")),
+                Subject::File(Path::new("modified")),
+                Subject::File(Path::new("removed"))
+            ],
+            changed(&a, &b)
+        );
+    }
+
+    #[test]
+    fn vars_are_replaced_and_compared() {
+        let names = [String::from("FIRSTAIDE_TEST_WATCH_ENV")];
+        let name = names[0].as_str();
+        let value = |value: &'static str| move |_: &str| Some(OsString::from(value));
+        let a = Checksums(vec![found("file", "1")]).with_vars(&names, value("one"));
+        let a = a.with_vars(&names, value("one"));
+        assert_eq!(2, a.iter().count());
+        let b = a.clone().with_vars(&names, value("two"));
+        assert_eq!(vec![Subject::Var(name)], changed(&a, &b));
+        assert_ne!(a.sig(), b.sig());
+        let c = b.with_vars(&names, |_| None);
+        assert_eq!("$FIRSTAIDE_TEST_WATCH_ENV", changed(&a, &c)[0].to_string());
+    }
+
+    #[test]
+    fn relative_to_strips_base_where_possible() {
+        let sums = Checksums(vec![found("/base/a", "1"), missing("/elsewhere/b")]);
+        let sums = sums.relative_to(Path::new("/base"));
+        let paths: Vec<&Path> = sums.iter().filter_map(Checksum::path).collect();
+        assert_eq!(vec![Path::new("a"), Path::new("/elsewhere/b")], paths);
+    }
+
+    #[test]
+    fn changed_is_empty_when_equal() {
+        let a = Checksums(vec![found("same", "1"), missing("gone")]);
+        assert!(changed(&a, &a).is_empty());
+    }
+
+    fn found(path: &str, sha1: &str) -> Checksum {
+        Checksum::Found(path.into(), Sha1(sha1.into()))
+    }
+
+    fn missing(path: &str) -> Checksum {
+        Checksum::NotFound(path.into())
+    }
+}
@@ -0,0 +1,274 @@
+//! The Bash scripts from which the hook is assembled, with `__NAME__`
+//! placeholders. Projects can replace any of them with their own, via
+//! `hook.scripts` in config, to change what the hook says or does.
+//!
+//! Placeholders are substituted in one pass, so a value that happens to
+//! contain something that looks like a placeholder is left alone.
+
+use bstr::ByteSlice;
+use shell_quote::bash;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The scripts that make up the hook, by name, with their placeholders.
+pub const SCRIPTS: &[(&str, &[u8], &[&str])] = &[
+    ("helpers", include_bytes!("cmds/hook/helpers.sh"), &[]),
+    (
+        "active",
+        include_bytes!("cmds/hook/active.sh"),
+        &["OKAY", "MINIMAL", "NEXT", "PAD", "MESSAGE"],
+    ),
+    (
+        "soft-stale",
+        include_bytes!("cmds/hook/soft-stale.sh"),
+        &["SOFT_STALE", "BUILDING", "IN_PROGRESS", "REBUILD"],
+    ),
+    (
+        "stale",
+        include_bytes!("cmds/hook/stale.sh"),
+        &[
+            "STALE",
+            "BUILDING",
+            "IN_PROGRESS",
+            "REBUILD",
+            "LOADING_STALE",
+        ],
+    ),
+    (
+        "inactive",
+        include_bytes!("cmds/hook/inactive.sh"),
+        &["UNBUILT", "BUILDING", "IN_PROGRESS", "BUILD"],
+    ),
+    (
+        "fallback",
+        include_bytes!("cmds/hook/fallback.sh"),
+        &["FALLBACK"],
+    ),
+    (
+        "overrides",
+        include_bytes!("cmds/hook/overrides.sh"),
+        &["OVERRIDES"],
+    ),
+    (
+        "no-direnv",
+        include_bytes!("cmds/hook/no-direnv.sh"),
+        &["NO_DIRENV"],
+    ),
+    (
+        "error",
+        include_bytes!("cmds/hook/error.sh"),
+        &["FAILED", "MESSAGE", "DETAILS"],
+    ),
+];
+
+/// A value to substitute for a placeholder.
+pub enum Value {
+    /// Text, which is quoted as a single Bash word.
+    Text(String),
+    /// Bash, already quoted as need be, which is substituted verbatim.
+    Bash(Vec<u8>),
+}
+
+pub struct Template {
+    name: String,
+    source: Vec<u8>,
+    /// Is this one of `SCRIPTS`, rather than the project's own?
+    builtin: bool,
+}
+
+impl Template {
+    /// The script called `name`, from `scripts` if it's there, otherwise the
+    /// built-in one. Panics if `name` is not in `SCRIPTS`.
+    pub fn load(name: &str, scripts: &BTreeMap<String, PathBuf>) -> io::Result<Self> {
+        let (source, builtin) = match scripts.get(name) {
+            Some(path) => (fs::read(path)?, false),
+            None => (builtin(name).to_vec(), true),
+        };
+        Ok(Self {
+            name: name.into(),
+            source,
+            builtin,
+        })
+    }
+
+    /// A script that is not one of `SCRIPTS`, like the shells' own in
+    /// `shells`, called `name` in warnings.
+    pub fn new(name: &str, source: &[u8]) -> Self {
+        Self {
+            name: name.into(),
+            source: source.to_vec(),
+            builtin: true,
+        }
+    }
+
+    /// The built-in script called `name`. Panics if `name` is not in
+    /// `SCRIPTS`.
+    pub fn builtin(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            source: builtin(name).to_vec(),
+            builtin: true,
+        }
+    }
+
+    /// The script without its comment lines, for the minimal hook, if it's a
+    /// built-in one. The project's own scripts are left as they are, since in
+    /// a heredoc, say, a line starting with `#` is not a comment.
+    pub fn minimal(self) -> Self {
+        if !self.builtin {
+            return self;
+        }
+        let mut source = Vec::with_capacity(self.source.len());
+        for line in self.source.lines_with_terminator() {
+            if !line.trim_start().starts_with(b"#") {
+                source.extend(line);
+            }
+        }
+        Self { source, ..self }
+    }
+
+    /// The script with each `__NAME__` replaced by its value in `values`.
+    /// Placeholders without a value are left as they are, and reported.
+    pub fn render(&self, values: &[(&str, Value)]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(self.source.len());
+        let mut rest = &self.source[..];
+        while let Some(start) = rest.find(b"__") {
+            output.extend(&rest[..start]);
+            rest = &rest[start..];
+            match placeholder(rest) {
+                Some(name) => {
+                    match values.iter().find(|(key, _)| key.as_bytes() == name) {
+                        Some((_, Value::Text(text))) => bash::escape_into(text, &mut output),
+                        Some((_, Value::Bash(bash))) => output.extend(bash),
+                        None => {
+                            log::warn!(
+                                "Unknown placeholder __{}__ in {} hook script.",
+                                name.to_str_lossy(),
+                                self.name
+                            );
+                            output.extend(&rest[..name.len() + 4]);
+                        }
+                    }
+                    rest = &rest[name.len() + 4..];
+                }
+                None => {
+                    output.extend(b"__");
+                    rest = &rest[2..];
+                }
+            }
+        }
+        output.extend(rest);
+        output
+    }
+}
+
+fn builtin(name: &str) -> &'static [u8] {
+    match SCRIPTS.iter().find(|(script, _, _)| *script == name) {
+        Some((_, source, _)) => source,
+        None => panic!("no hook script called {:?}", name),
+    }
+}
+
+/// Is `name` one of `SCRIPTS`?
+pub fn is_script(name: &str) -> bool {
+    SCRIPTS.iter().any(|(script, _, _)| *script == name)
+}
+
+/// The name in `text`, which starts with `__`, if it's a placeholder: one or
+/// more of `A-Z`, `0-9`, and `_`, starting with a letter, followed by `__`.
+fn placeholder(text: &[u8]) -> Option<&[u8]> {
+    let body = &text[2..];
+    let end = body.find(b"__")?;
+    let name = &body[..end];
+    let valid = name.first().is_some_and(u8::is_ascii_uppercase)
+        && (name.iter()).all(|&b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+    Some(name).filter(|_| valid)
+}
+
+/// The override scripts' contents, so that changes to them can be noticed
+/// without watching. Unreadable scripts count as empty here; they're reported
+/// when the hook is rendered.
+pub fn contents(scripts: &BTreeMap<String, PathBuf>) -> Vec<Vec<u8>> {
+    (scripts.values())
+        .map(|path| fs::read(path).unwrap_or_default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(source: &str) -> Template {
+        Template {
+            name: "test".into(),
+            source: source.into(),
+            builtin: false,
+        }
+    }
+
+    #[test]
+    fn substitutes_placeholders_once() {
+        let rendered = template("echo __GREETING__ __WHO__; __unknown__ __X__ ___").render(&[
+            ("GREETING", Value::Text("it's __WHO__".into())),
+            ("WHO", Value::Bash(b"\"$USER\"".to_vec())),
+        ]);
+        assert_eq!(
+            "echo $'it\\'s __WHO__' \"$USER\"; __unknown__ __X__ ___",
+            rendered.to_str_lossy()
+        );
+    }
+
+    #[test]
+    fn builtin_scripts_have_their_placeholders() {
+        for (name, source, placeholders) in SCRIPTS {
+            for placeholder in *placeholders {
+                let placeholder = format!("__{}__", placeholder);
+                assert!(
+                    source.contains_str(&placeholder),
+                    "{} {}",
+                    name,
+                    placeholder
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn loads_overrides() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("stale.sh");
+        fs::write(&path, "echo __STALE__\n").unwrap();
+        let scripts = BTreeMap::from([("stale".to_string(), path)]);
+        let stale = Template::load("stale", &scripts).unwrap();
+        assert_eq!(
+            b"echo stale\n".to_vec(),
+            stale.render(&[("STALE", Value::Text("stale".into()))])
+        );
+        let inactive = Template::load("inactive", &scripts).unwrap();
+        assert_eq!(builtin("inactive"), &inactive.source[..]);
+        assert!(is_script("error"));
+        assert!(!is_script("stubs"));
+    }
+
+    #[test]
+    fn minimal_strips_comments_only_from_builtin_scripts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("stale.sh");
+        fs::write(&path, "cat <<EOF\n# Not a comment.\nEOF\n").unwrap();
+        let scripts = BTreeMap::from([("stale".to_string(), path)]);
+        let stale = Template::load("stale", &scripts).unwrap().minimal();
+        assert_eq!(
+            "cat <<EOF\n# Not a comment.\nEOF\n",
+            stale.source.to_str_lossy()
+        );
+        let inactive = Template::load("inactive", &scripts).unwrap().minimal();
+        assert!(!inactive.source.is_empty());
+        assert!(inactive.source.lines().all(|line| !line.starts_with(b"#")));
+        // Which is safe only while they have no heredocs.
+        for (name, source, _) in SCRIPTS {
+            assert!(!source.contains_str("<<"), "{} has a heredoc", name);
+        }
+    }
+}
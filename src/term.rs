@@ -0,0 +1,151 @@
+//! Human output on the terminal: colors and status glyphs, according to
+//! `--color` and `NO_COLOR`. Output for machines – JSON, hooks, and the like
+//! – never goes through here.
+
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+
+/// When to color output, per `--color`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Choice {
+    /// When stdout is a terminal, and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl Choice {
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("always") => Choice::Always,
+            Some("never") => Choice::Never,
+            _ => Choice::Auto,
+        }
+    }
+}
+
+/// The choice, and whether to color stdout and stderr.
+static CHOICE: OnceLock<(Choice, bool, bool)> = OnceLock::new();
+
+/// Decide, once, whether to color output. Without this, there's no color.
+pub fn init(choice: Choice) {
+    let enabled = |terminal: bool| match choice {
+        Choice::Always => true,
+        Choice::Never => false,
+        Choice::Auto => {
+            let dumb = env::var_os("TERM").is_some_and(|term| term == "dumb");
+            !no_color() && !dumb && terminal
+        }
+    };
+    let (stdout, stderr) = (
+        enabled(io::stdout().is_terminal()),
+        enabled(io::stderr().is_terminal()),
+    );
+    CHOICE.get_or_init(|| (choice, stdout, stderr));
+}
+
+/// Is `NO_COLOR` set? See https://no-color.org/; an empty value does not
+/// count.
+pub fn no_color() -> bool {
+    env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// What was chosen with `--color`.
+pub fn choice() -> Choice {
+    CHOICE.get().map_or(Choice::Auto, |(choice, _, _)| *choice)
+}
+
+/// Is output to be colored?
+pub fn enabled() -> bool {
+    CHOICE.get().is_some_and(|(_, stdout, _)| *stdout)
+}
+
+/// Is output on stderr, like errors, to be colored?
+pub fn enabled_stderr() -> bool {
+    CHOICE.get().is_some_and(|(_, _, stderr)| *stderr)
+}
+
+/// `text` without styles, for where they would be noise, like log files.
+pub fn unstyled(text: &str) -> String {
+    let mut unstyled = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b[") {
+        unstyled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest.find('m') {
+            Some(end) => rest = &rest[end + 1..],
+            None => break,
+        }
+    }
+    unstyled.push_str(rest);
+    unstyled
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Okay,
+    Warning,
+    Error,
+    Bold,
+    Dim,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Okay => "1;32",
+            Style::Warning => "1;33",
+            Style::Error => "1;31",
+            Style::Bold => "1",
+            Style::Dim => "2",
+        }
+    }
+
+    /// `text` in this style, if output is to be colored, else as it is.
+    pub fn paint(self, text: &str) -> String {
+        paint(enabled(), self, text)
+    }
+
+    /// `text` in this style if `enabled`, else as it is; for output that's
+    /// not for the terminal, like the prompt, which decides for itself.
+    pub fn paint_if(self, enabled: bool, text: &str) -> String {
+        paint(enabled, self, text)
+    }
+
+    /// `text` in this style, preceded by `glyph`, if output is to be colored;
+    /// else `text` as it is. Terminals that do color generally do Unicode.
+    pub fn mark(self, glyph: &str, text: &str) -> String {
+        if enabled() {
+            format!("{} {}", paint(true, self, glyph), paint(true, self, text))
+        } else {
+            text.into()
+        }
+    }
+}
+
+fn paint(enabled: bool, style: Style, text: &str) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", style.code(), text)
+    } else {
+        text.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paints_only_when_enabled() {
+        assert_eq!("\x1b[1;31mno\x1b[0m", paint(true, Style::Error, "no"));
+        assert_eq!("no", paint(false, Style::Error, "no"));
+    }
+
+    #[test]
+    fn unstyled_removes_styles() {
+        let text = format!("{} and {}", paint(true, Style::Error, "no"), "yes");
+        assert_eq!("no and yes", unstyled(&text));
+        assert_eq!("plain", unstyled("plain"));
+    }
+}
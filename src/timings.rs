@@ -0,0 +1,69 @@
+//! How long things take: config loading, capturing environments, hashing,
+//! and so on. Each is logged at debug level as it finishes, and, with
+//! `--timings`, they're all reported together at the end.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static TIMINGS: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+
+/// Record timings for `report`, from now on.
+pub fn enable() {
+    STARTED.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Run `f`, and record how long it took as `what`.
+pub fn time<T, F: FnOnce() -> T>(what: &str, f: F) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(what, started.elapsed());
+    result
+}
+
+/// Record that `what` took `elapsed`.
+pub fn record(what: &str, elapsed: Duration) {
+    log::debug!("Timing: {} took {}.", what, format(elapsed));
+    if ENABLED.load(Ordering::Relaxed) {
+        let mut timings = TIMINGS.lock().unwrap_or_else(|err| err.into_inner());
+        timings.push((what.into(), elapsed));
+    }
+}
+
+/// Write the timings recorded so far to stderr, in the order they finished,
+/// with the total since `enable`. Not to stdout: the hook's output, for one,
+/// is evaluated by the shell.
+pub fn report() -> io::Result<()> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let timings = TIMINGS.lock().unwrap_or_else(|err| err.into_inner());
+    let total = STARTED.get().map(Instant::elapsed).unwrap_or_default();
+    let stderr = io::stderr();
+    let mut handle = stderr.lock();
+    writeln!(&mut handle, "Timings:")?;
+    for (what, elapsed) in timings.iter() {
+        writeln!(&mut handle, "  {:>10}  {}", format(*elapsed), what)?;
+    }
+    writeln!(&mut handle, "  {:>10}  total", format(total))?;
+    Ok(())
+}
+
+fn format(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_in_milliseconds() {
+        assert_eq!("1500.0ms", format(Duration::from_millis(1500)));
+        assert_eq!("0.3ms", format(Duration::from_micros(260)));
+    }
+}
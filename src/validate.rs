@@ -0,0 +1,305 @@
+//! Explaining why configuration is not valid: which key, in which file, at
+//! which line and column, and – for unknown keys – which key was probably
+//! meant. Parsing and type checking are left to serde and `toml`; this turns
+//! their errors into something a human can act on.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct Invalid {
+    pub file: PathBuf,
+    /// Zero-based line and column, when we could find the key.
+    pub line_col: Option<(usize, usize)>,
+    pub problem: Problem,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    Syntax(String),
+    UnknownKey {
+        key: String,
+        suggestion: Option<String>,
+    },
+    WrongType {
+        key: String,
+        message: String,
+    },
+    Missing {
+        key: String,
+    },
+    Other(String),
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.file)?;
+        if let Some((line, col)) = self.line_col {
+            write!(f, ", line {}, column {}", line + 1, col + 1)?;
+        }
+        write!(f, ": {}", self.problem)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Problem::*;
+        match self {
+            Syntax(message) => write!(f, "{}", message),
+            UnknownKey {
+                key,
+                suggestion: Some(suggestion),
+            } => write!(f, "unknown key `{}`; did you mean `{}`?", key, suggestion),
+            UnknownKey {
+                key,
+                suggestion: None,
+            } => write!(f, "unknown key `{}`", key),
+            WrongType { key, message } => write!(f, "`{}`: {}", key, message),
+            Missing { key } => write!(f, "`{}` is required but missing", key),
+            Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A file that could not be parsed at all. `toml` already knows where.
+pub fn syntax(file: &Path, err: &toml::de::Error) -> Invalid {
+    let message = err.to_string();
+    let message = match message.rfind(" at line ") {
+        Some(at) if err.line_col().is_some() => message[..at].to_string(),
+        _ => message,
+    };
+    Invalid {
+        file: file.to_path_buf(),
+        line_col: err.line_col(),
+        problem: Problem::Syntax(message),
+    }
+}
+
+/// Explain an error from deserializing configuration that was merged from
+/// `sources`, each a file and its contents. When a key is set in more than one
+/// source, the first in which it's found is blamed, so put them in order of
+/// precedence. Problems that aren't about any one key are blamed on the last.
+pub fn explain(err: &toml::de::Error, sources: &[(&Path, &str)]) -> Invalid {
+    let message = err.to_string();
+    // Errors from within a table say which, e.g. "… for key `hook`".
+    let (message, table) = match message.rfind(" for key `") {
+        Some(at) if message.ends_with('`') => (
+            &message[..at],
+            &message[at + " for key `".len()..message.len() - 1],
+        ),
+        _ => (message.as_str(), ""),
+    };
+    let join = |name: &str| match table {
+        "" => name.to_string(),
+        table => format!("{}.{}", table, name),
+    };
+
+    let problem = if let Some(rest) = message.strip_prefix("unknown field `") {
+        let (name, expected) = rest.split_once('`').unwrap_or((rest, ""));
+        Problem::UnknownKey {
+            key: join(name),
+            suggestion: suggest(name, &quoted(expected)).map(join),
+        }
+    } else if let Some(rest) = message.strip_prefix("missing field `") {
+        let name = rest.split('`').next().unwrap_or(rest);
+        Problem::Missing { key: join(name) }
+    } else if table.is_empty() {
+        Problem::Other(message.into())
+    } else {
+        Problem::WrongType {
+            key: table.into(),
+            message: message.into(),
+        }
+    };
+
+    // Point at the key itself; for a missing key, at its table.
+    let key = match &problem {
+        Problem::UnknownKey { key, .. } | Problem::WrongType { key, .. } => key.as_str(),
+        _ => table,
+    };
+    let path: Vec<&str> = key.split('.').filter(|name| !name.is_empty()).collect();
+    let found = (sources.iter())
+        .filter(|_| !path.is_empty())
+        .find_map(|(file, source)| locate(source, &path).map(|line_col| (file, line_col)));
+    match found {
+        Some((file, line_col)) => Invalid {
+            file: file.to_path_buf(),
+            line_col: Some(line_col),
+            problem,
+        },
+        None => Invalid {
+            file: (sources.last())
+                .map(|(file, _)| file.to_path_buf())
+                .unwrap_or_default(),
+            line_col: None,
+            problem,
+        },
+    }
+}
+
+/// The names in backticks in serde's list of expected fields, e.g. "expected
+/// one of `cache_dir`, `builder`".
+fn quoted(expected: &str) -> Vec<&str> {
+    expected.split('`').skip(1).step_by(2).collect()
+}
+
+/// The candidate closest to `name`, if it's close enough to be a typo.
+fn suggest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let limit = std::cmp::max(1, name.chars().count() / 3);
+    (candidates.iter())
+        .map(|candidate| (distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance: how many insertions, deletions, and substitutions
+/// turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Where, in TOML `source`, the key at `path` is defined: the line and column
+/// of its table header, of the key, or of an inline table that contains it.
+/// This is a line-by-line scan, not a parser; it does not need to be, since
+/// `source` has already been parsed successfully.
+fn locate(source: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let mut table: Vec<String> = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let column = line.len() - trimmed.len();
+        if trimmed.starts_with('#') {
+            continue;
+        } else if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[');
+            table = split_key(header.split(']').next().unwrap_or(""));
+            if table == path {
+                return Some((number, column));
+            }
+        } else if let Some((name, _)) = trimmed.split_once('=') {
+            let name = split_key(name);
+            if name.is_empty() {
+                continue;
+            }
+            let key: Vec<&str> = (table.iter().chain(name.iter()))
+                .map(String::as_str)
+                .collect();
+            if path.starts_with(&key) {
+                return Some((number, column));
+            }
+        }
+    }
+    None
+}
+
+/// Split a dotted key like `cache.remote."url"` into its parts.
+fn split_key(key: &str) -> Vec<String> {
+    (key.split('.'))
+        .map(|part| part.trim().trim_matches(|c| c == '"' || c == '\''))
+        .filter(|part| !part.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Data {
+        cache_dir: String,
+        #[serde(default)]
+        hook: Option<Hook>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Hook {
+        minimal: Option<bool>,
+        outside_ttl: Option<u64>,
+    }
+
+    fn check(sources: &[(&Path, &str)]) -> String {
+        let mut value = toml::Value::Table(Default::default());
+        for (_, source) in sources.iter().rev() {
+            let over: toml::Value = toml::from_str(source).unwrap();
+            if let (Some(value), toml::Value::Table(over)) = (value.as_table_mut(), over) {
+                value.extend(over);
+            }
+        }
+        explain(&value.try_into::<Data>().unwrap_err(), sources).to_string()
+    }
+
+    #[test]
+    fn explains_unknown_keys_with_suggestions() {
+        let file = Path::new("a.toml");
+        assert_eq!(
+            "\"a.toml\", line 2, column 1: unknown key `cache_dri`; did you mean `cache_dir`?",
+            check(&[(file, "# Cache.\ncache_dri = \".cache\"\n")])
+        );
+        assert_eq!(
+            "\"a.toml\", line 4, column 3: unknown key `hook.wibble`",
+            check(&[(file, "cache_dir = \".cache\"\n\n[hook]\n  wibble = true\n")])
+        );
+    }
+
+    #[test]
+    fn explains_wrong_types_in_the_file_that_set_them() {
+        let project = Path::new("project.toml");
+        let local = Path::new("local.toml");
+        assert_eq!(
+            "\"local.toml\", line 1, column 1: `hook.outside_ttl`: \
+             invalid type: string \"soon\", expected u64",
+            check(&[
+                (local, "hook = { outside_ttl = \"soon\" }\n"),
+                (project, "cache_dir = \".cache\"\n"),
+            ])
+        );
+    }
+
+    #[test]
+    fn explains_missing_keys() {
+        let file = Path::new("a.toml");
+        assert_eq!(
+            "\"a.toml\": `cache_dir` is required but missing",
+            check(&[(file, "[hook]\nminimal = true\n")])
+        );
+    }
+
+    #[test]
+    fn explains_syntax_errors() {
+        let err = toml::from_str::<toml::Value>("a = 1\nb = \n").unwrap_err();
+        let invalid = syntax(Path::new("a.toml"), &err);
+        assert_eq!(Some((1, 4)), invalid.line_col);
+        assert!(!invalid.to_string().contains(" at line "));
+    }
+
+    #[test]
+    fn measures_distance() {
+        assert_eq!(0, distance("ttl", "ttl"));
+        assert_eq!(1, distance("ttll", "ttl"));
+        assert_eq!(2, distance("cache_dri", "cache_dir"));
+        assert_eq!(None, suggest("zzz", &["cache_dir", "hook"]));
+    }
+}
@@ -0,0 +1,57 @@
+//! What this build of firstaide is, and what it can do.
+
+use crate::cache;
+use crate::json;
+
+/// Bumped when the shape of `json()` changes incompatibly.
+const SCHEMA: i64 = 1;
+
+pub fn text() -> String {
+    format!("firstaide {}", crate_version!())
+}
+
+/// Whether `version`, that of another firstaide, has the same major and minor
+/// version as this one. Versions that cannot be parsed do not.
+pub fn compatible(version: &str) -> bool {
+    let ours = semver::Version::parse(crate_version!()).expect("our version is valid");
+    match semver::Version::parse(version) {
+        Ok(theirs) => (theirs.major, theirs.minor) == (ours.major, ours.minor),
+        Err(_) => false,
+    }
+}
+
+pub fn json() -> json::Value {
+    let features = env!("FIRSTAIDE_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(json::Value::from)
+        .collect();
+    json::Value::object(vec![
+        ("schema", SCHEMA.into()),
+        ("name", "firstaide".into()),
+        ("version", crate_version!().into()),
+        ("git_commit", env!("FIRSTAIDE_GIT_COMMIT").into()),
+        ("build_date", env!("FIRSTAIDE_BUILD_DATE").into()),
+        ("features", json::Value::Array(features)),
+        (
+            "formats",
+            json::Value::object(vec![("cache", i64::from(cache::VERSION).into())]),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatible_when_major_and_minor_match() {
+        let ours = semver::Version::parse(crate_version!()).unwrap();
+        let patched = format!("{}.{}.{}", ours.major, ours.minor, ours.patch + 1);
+        let bumped = format!("{}.{}.0", ours.major, ours.minor + 1);
+        assert!(compatible(crate_version!()));
+        assert!(compatible(&patched));
+        assert!(!compatible(&bumped));
+        assert!(!compatible(""));
+    }
+}
@@ -0,0 +1,216 @@
+//! Waiting for the watched files to change, for the commands that keep going:
+//! `status --watch` and `--wait-for`, `daemon`, and `watch run`.
+//!
+//! Changes are noticed as they happen, with inotify or kqueue via `notify`.
+//! The watched files are checked every `interval` all the same, since neither
+//! watched variables nor files that `watch_exe` starts to list raise events,
+//! and where `notify` is not available that is all there is.
+
+use crate::config;
+use notify::Watcher as _;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The first wait after a failure; it doubles with each failure in a row.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// The longest wait after a failure.
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+pub struct Watcher<'a> {
+    config: &'a config::Config,
+    interval: Duration,
+    notify: Option<notify::RecommendedWatcher>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// The watched files, as `watch_exe` last listed them.
+    files: Vec<PathBuf>,
+    /// The directories that hold them. Editors often save by renaming a new
+    /// file over the old one, which a watch on the file itself would miss.
+    dirs: Vec<PathBuf>,
+    /// Failures in a row, for the backoff.
+    failures: u32,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(config: &'a config::Config, interval: Duration) -> Self {
+        let (sender, events) = mpsc::channel();
+        let notify = match notify::recommended_watcher(sender) {
+            Ok(notify) => Some(notify),
+            Err(err) => {
+                log::warn!(
+                    "Cannot watch for changes ({}); checking every {}s instead.",
+                    err,
+                    interval.as_secs()
+                );
+                None
+            }
+        };
+        Self {
+            config,
+            interval,
+            notify,
+            events,
+            files: Vec::new(),
+            dirs: Vec::new(),
+            failures: 0,
+        }
+    }
+
+    /// Wait until a watched file changes, or `interval` passes, or `limit`
+    /// passes if that is sooner.
+    pub fn wait(&mut self, limit: Option<Duration>) {
+        self.failures = 0;
+        self.rewatch();
+        let timeout = limit.map_or(self.interval, |limit| limit.min(self.interval));
+        if self.notify.is_none() {
+            thread::sleep(timeout);
+            return;
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.events.recv_timeout(remaining) {
+                Ok(Ok(event)) if self.concerns(&event) => break,
+                Ok(Ok(_)) => continue,
+                Ok(Err(err)) => {
+                    // Events may have been lost, so check anyway.
+                    log::debug!("Watching for changes: {}", err);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        // Changes come in bursts, e.g. a write then a rename; take them all.
+        while self.events.try_recv().is_ok() {}
+    }
+
+    /// Log `err`, then wait before trying again: a second at first, doubling
+    /// with each failure in a row, up to a minute.
+    pub fn retry_after(&mut self, err: impl fmt::Display) {
+        let delay = backoff(self.failures);
+        log::warn!("{}; trying again in {}s.", err, delay.as_secs());
+        self.failures = self.failures.saturating_add(1);
+        thread::sleep(delay);
+    }
+
+    /// Watch the directories of the files that `watch_exe` lists now, and
+    /// stop watching those it no longer does.
+    fn rewatch(&mut self) {
+        let files = match self.config.watch_files() {
+            Ok(files) => files,
+            Err(err) => {
+                // Keep watching what we were; the caller will hear of this.
+                log::debug!("Could not list watched files: {}", err);
+                return;
+            }
+        };
+        if let Some(notify) = self.notify.as_mut() {
+            let mut dirs: Vec<PathBuf> = (files.iter())
+                .filter_map(|file| file.parent())
+                .map(Path::to_path_buf)
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in self.dirs.iter().filter(|dir| !dirs.contains(dir)) {
+                // It may have gone already, taking its watch with it.
+                notify.unwatch(dir).ok();
+            }
+            let watching = std::mem::take(&mut self.dirs);
+            self.dirs = (dirs.into_iter())
+                .filter(|dir| {
+                    watching.contains(dir)
+                        || match notify.watch(dir, notify::RecursiveMode::NonRecursive) {
+                            Ok(()) => true,
+                            Err(err) => {
+                                // Perhaps it does not exist yet; try again next time.
+                                log::debug!("Cannot watch {:?}: {}", dir, err);
+                                false
+                            }
+                        }
+                })
+                .collect();
+        }
+        self.files = files;
+    }
+
+    /// Is `event` about one of the watched files? Names are compared, not
+    /// whole paths, since `notify` may report a path through a symlink
+    /// differently; at worst that means an extra check.
+    fn concerns(&self, event: &notify::Event) -> bool {
+        (event.paths.iter())
+            .any(|path| (self.files.iter()).any(|file| file.file_name() == path.file_name()))
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    (BACKOFF_MIN * 2u32.pow(failures.min(6))).min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A project that watches `watched` and nothing else.
+    fn project() -> (tempfile::TempDir, config::Config) {
+        let (dir, config) = config::test_project("");
+        fs::write(dir.path().join("watch.sh"), "#!/bin/sh\necho watched\n").unwrap();
+        fs::write(dir.path().join("watched"), "before").unwrap();
+        (dir, config)
+    }
+
+    #[test]
+    fn wakes_when_a_watched_file_changes() {
+        let (dir, config) = project();
+        let mut watcher = Watcher::new(&config, Duration::from_secs(60));
+        watcher.rewatch();
+        let watched = dir.path().join("watched");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(watched, "after").unwrap();
+        });
+        let started = Instant::now();
+        watcher.wait(None);
+        writer.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn sleeps_through_changes_to_other_files() {
+        let (dir, config) = project();
+        let interval = Duration::from_millis(500);
+        let mut watcher = Watcher::new(&config, interval);
+        watcher.rewatch();
+        let other = dir.path().join("other");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(other, "after").unwrap();
+        });
+        let started = Instant::now();
+        watcher.wait(None);
+        writer.join().unwrap();
+        assert!(started.elapsed() >= interval);
+    }
+
+    #[test]
+    fn wakes_after_the_interval_or_limit_regardless() {
+        let (_dir, config) = project();
+        let mut watcher = Watcher::new(&config, Duration::from_millis(50));
+        let started = Instant::now();
+        watcher.wait(None);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let mut watcher = Watcher::new(&config, Duration::from_secs(60));
+        let started = Instant::now();
+        watcher.wait(Some(Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn backs_off_up_to_a_minute() {
+        let backoffs: Vec<u64> = (0..9).map(|n| backoff(n).as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 60, 60, 60], backoffs);
+        assert_eq!(BACKOFF_MAX, backoff(u32::MAX));
+    }
+}
@@ -0,0 +1,198 @@
+//! Workspaces: several firstaide projects under one root, as in a monorepo,
+//! listed in `.firstaide-workspace.toml` at the root:
+//!
+//! ```toml
+//! members = ["services/api", "services/web", "tools"]
+//! ```
+//!
+//! Each member is a directory with its own `.firstaide.toml`. Commands that
+//! take `--all` act on every member in turn. Paths are resolved according to
+//! `resolve_paths`, as in `.firstaide.toml`.
+
+use crate::config;
+use crate::error::Code;
+use crate::paths;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const FILENAME: &str = ".firstaide-workspace.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Toml(PathBuf, toml::de::Error),
+    NotFound(PathBuf),
+    NoMembers(PathBuf),
+    Member(PathBuf),
+    NoConfig(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(err) => write!(f, "input/output error: {}", err),
+            Toml(path, err) => write!(f, "workspace {:?} is not valid: {}", path, err),
+            NotFound(dir) => write!(f, "no {} in {:?} or above", FILENAME, dir),
+            NoMembers(path) => write!(f, "workspace {:?} has no members", path),
+            Member(dir) => write!(f, "workspace member {:?} is not a directory", dir),
+            NoConfig(dir) => write!(f, "workspace member {:?} has no {}", dir, config::FILENAME),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Io(err) => Some(err),
+            Toml(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// What kind of failure this is.
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Io(_) => Code::Io,
+            _ => Code::Config,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(crate::error::traced(error))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Deserialize)]
+struct WorkspaceData {
+    members: Vec<PathBuf>,
+    #[serde(default)]
+    resolve_paths: paths::Policy,
+}
+
+pub struct Workspace {
+    pub root: PathBuf,
+    /// Absolute paths, in the order listed.
+    pub members: Vec<PathBuf>,
+}
+
+impl Workspace {
+    /// Find the workspace that `dir`, or the current directory, is in.
+    pub fn find<T: AsRef<Path>>(dir: Option<T>) -> Result<Self> {
+        // Until the workspace is loaded its `resolve_paths` is not known, so
+        // this is the default.
+        let dir = paths::Policy::default().resolve_here(dir)?;
+        let file = (dir.ancestors())
+            .map(|path| path.join(FILENAME))
+            .find(|path| path.is_file())
+            .ok_or(Error::NotFound(dir))?;
+        Self::load(&file)
+    }
+
+    fn load(file: &Path) -> Result<Self> {
+        let data: WorkspaceData = toml::from_slice(&fs::read(file)?)
+            .map_err(|err| Error::Toml(file.to_path_buf(), err))?;
+        let root =
+            (data.resolve_paths).resolve(file.parent().unwrap_or_else(|| Path::new("/")), ".")?;
+        if data.members.is_empty() {
+            return Err(Error::NoMembers(file.to_path_buf()));
+        }
+        let mut members = Vec::with_capacity(data.members.len());
+        for member in data.members {
+            let member = data.resolve_paths.resolve(&root, member)?;
+            if !member.is_dir() {
+                return Err(Error::Member(member));
+            }
+            // Otherwise the member would quietly use the config of a
+            // directory above it, perhaps the root's.
+            if !member.join(config::FILENAME).is_file() {
+                return Err(Error::NoConfig(member));
+            }
+            members.push(member);
+        }
+        Ok(Self { root, members })
+    }
+
+    /// How to refer to `member` for humans: relative to the root.
+    pub fn name<'a>(&self, member: &'a Path) -> &'a Path {
+        member.strip_prefix(&self.root).unwrap_or(member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_workspace_and_members() {
+        let root = tempfile::TempDir::new().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        for member in ["services/api", "tools"] {
+            fs::create_dir_all(root.join(member)).unwrap();
+            fs::write(root.join(member).join(config::FILENAME), "").unwrap();
+        }
+        fs::write(
+            root.join(FILENAME),
+            "members = [\"services/api\", \"tools\"]\n",
+        )
+        .unwrap();
+        let workspace = Workspace::find(Some(root.join("services/api")))
+            .ok()
+            .unwrap();
+        assert_eq!(root, workspace.root);
+        assert_eq!(
+            vec![root.join("services/api"), root.join("tools")],
+            workspace.members
+        );
+        assert_eq!(Path::new("tools"), workspace.name(&workspace.members[1]));
+
+        fs::write(root.join(FILENAME), "members = [\"missing\"]\n").unwrap();
+        match Workspace::find(Some(&root)) {
+            Err(Error::Member(dir)) => assert_eq!(root.join("missing"), dir),
+            _ => panic!("expected Member"),
+        }
+    }
+
+    #[test]
+    fn members_need_their_own_config() {
+        let root = tempfile::TempDir::new().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        fs::create_dir(root.join("tools")).unwrap();
+        fs::write(root.join(config::FILENAME), "").unwrap();
+        fs::write(root.join(FILENAME), "members = [\"tools\"]\n").unwrap();
+        match Workspace::find(Some(&root)) {
+            Err(Error::NoConfig(dir)) => assert_eq!(root.join("tools"), dir),
+            _ => panic!("expected NoConfig"),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn members_are_resolved_by_policy() {
+        let root = tempfile::TempDir::new().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        fs::create_dir(root.join("real")).unwrap();
+        fs::write(root.join("real").join(config::FILENAME), "").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        fs::write(root.join(FILENAME), "members = [\"link\"]\n").unwrap();
+        let workspace = Workspace::find(Some(&root)).ok().unwrap();
+        assert_eq!(vec![root.join("link")], workspace.members);
+        fs::write(
+            root.join(FILENAME),
+            "members = [\"link\"]\nresolve_paths = \"physical\"\n",
+        )
+        .unwrap();
+        let workspace = Workspace::find(Some(&root)).ok().unwrap();
+        assert_eq!(vec![root.join("real")], workspace.members);
+    }
+}